@@ -1,5 +1,34 @@
+#[cfg(feature = "editor")]
 extern crate lalrpop;
 
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Writes the source embedding the pack named by `DEMO_PACK` into the player, which includes it
+///
+/// Relative paths are resolved against the folder of the manifest, so that the pack can be given the same way as on
+/// the command line of the packer, instead of relative to the source of the player.
+fn embed_demo_pack() {
+    println!("cargo:rerun-if-env-changed=DEMO_PACK");
+    let pack = env::var("DEMO_PACK").expect("DEMO_PACK has to name the pack which is embedded into the player");
+    let pack = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join(&pack);
+    let pack = pack
+        .canonicalize()
+        .unwrap_or_else(|e| panic!("Could not find the pack {}: {}", pack.display(), e));
+    println!("cargo:rerun-if-changed={}", pack.display());
+
+    let source = format!("static DEMO_PACK: &[u8] = include_bytes!({:?});\n", pack);
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("demo_pack.rs");
+    fs::write(out_path, source).unwrap();
+}
+
 fn main() {
+    // Only the editor parses scripts, players run the bytecode of a pack
+    #[cfg(feature = "editor")]
     lalrpop::process_root().unwrap();
+
+    if env::var_os("CARGO_FEATURE_PLAYER").is_some() {
+        embed_demo_pack();
+    }
 }
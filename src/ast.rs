@@ -36,6 +36,7 @@ pub struct FunctionCallExpr {
     pub source_slice: SourceSlice,
     pub function: SourceSlice,
     pub args: Vec<ValueExpr>,
+    pub named_args: Vec<KeyValuePairExpr>,
 }
 impl AstNode for FunctionCallExpr {
     fn source_slice(&self) -> SourceSlice {
@@ -105,6 +106,21 @@ impl ValueExpr {
             _ => Err(()),
         }
     }
+
+    /// Negates a value, literals are folded into negative literals so that they can be used where literals are expected
+    ///
+    /// Other values are negated by a call of `-`.
+    pub fn negate(source_slice: SourceSlice, operator: SourceSlice, value: ValueExpr) -> ValueExpr {
+        match value {
+            ValueExpr::FloatLiteral(_, value) => ValueExpr::FloatLiteral(source_slice, -value),
+            value => ValueExpr::FunctionCall(FunctionCallExpr {
+                source_slice: source_slice,
+                function: operator,
+                args: vec![value],
+                named_args: Vec::new(),
+            }),
+        }
+    }
 }
 impl AstNode for ValueExpr {
     fn source_slice(&self) -> SourceSlice {
@@ -123,7 +139,7 @@ impl AstNode for ValueExpr {
 
 // Rendering operations
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RenderTargetDef {
     pub source_slice: SourceSlice,
     pub name: SourceSlice,
@@ -131,6 +147,8 @@ pub struct RenderTargetDef {
     pub height: ValueExpr,
     pub formats: Vec<(SourceSlice, RenderTargetFormat)>,
     pub has_depth: bool,
+    // Defined with `define_oit_rt`, the buffers are built-in
+    pub oit: bool,
 }
 impl RenderTargetDef {
     pub fn new(
@@ -148,6 +166,20 @@ impl RenderTargetDef {
             height: height,
             formats: formats,
             has_depth: has_depth,
+            oit: false,
+        }
+    }
+
+    /// A target for weighted blended order-independent transparency, see `bytecode::OIT_BUFFERS`
+    pub fn new_oit(source_slice: SourceSlice, name: SourceSlice, width: ValueExpr, height: ValueExpr) -> Self {
+        Self {
+            source_slice: source_slice,
+            name: name,
+            width: width,
+            height: height,
+            formats: Vec::new(),
+            has_depth: true,
+            oit: true,
         }
     }
 }
@@ -157,43 +189,79 @@ impl AstNode for RenderTargetDef {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Stmt {
     FunctionCall(FunctionCallExpr),
     Return {
         expr: ValueExpr,
     },
+    Let {
+        name: SourceSlice,
+        expr: ValueExpr,
+    },
     Conditional {
         condition: ValueExpr,
         a: Vec<Stmt>,
         b: Option<Vec<Stmt>>,
     },
+    // Runs the block for each whole number from the start up to the end, which is excluded
+    Loop {
+        variable: SourceSlice,
+        start: ValueExpr,
+        end: ValueExpr,
+        block: Vec<Stmt>,
+    },
+}
+
+impl AstNode for Stmt {
+    fn source_slice(&self) -> SourceSlice {
+        match self {
+            Stmt::FunctionCall(f) => f.source_slice(),
+            Stmt::Return { expr } => expr.source_slice(),
+            Stmt::Let { name, .. } => *name,
+            Stmt::Conditional { condition, .. } => condition.source_slice(),
+            Stmt::Loop { variable, .. } => *variable,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Type {
     Float32,
     LinColor,
+    Vec2,
+    Vec3,
+    Vec4,
+    Mat4,
     Str,
     Void,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Parameter {
     pub name: SourceSlice,
     pub value_type: Type,
+    pub default: Option<ValueExpr>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Function {
+    pub source_slice: SourceSlice,
     pub name: SourceSlice,
     pub params: Vec<Parameter>,
     pub block: Vec<Stmt>,
     pub return_type: Option<Type>,
 }
 impl Function {
-    pub fn new(name: SourceSlice, params: Vec<Parameter>, block: Vec<Stmt>, return_type: Option<Type>) -> Self {
+    pub fn new(
+        source_slice: SourceSlice,
+        name: SourceSlice,
+        params: Vec<Parameter>,
+        block: Vec<Stmt>,
+        return_type: Option<Type>,
+    ) -> Self {
         Function {
+            source_slice: source_slice,
             name: name,
             params: params,
             block: block,
@@ -201,17 +269,228 @@ impl Function {
         }
     }
 }
+impl AstNode for Function {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MetadataValue {
+    Ratio(f32, f32),
+    Float(f32),
+    Str(SourceSlice),
+    Bool(bool),
+}
+
+/// An entry of the `metadata` block, e.g. `aspect 16:9;`
+#[derive(Debug, Clone)]
+pub struct MetadataEntry {
+    pub source_slice: SourceSlice,
+    pub key: SourceSlice,
+    pub value: MetadataValue,
+}
+impl AstNode for MetadataEntry {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
 
-#[derive(Debug)]
+/// An object of the scene, e.g. `object "crate" { mesh: "crate.obj", visible: sync.crate.visible }`
+///
+/// Objects are drawn by `draw_scene`, the properties are checked by the type checker.
+#[derive(Debug, Clone)]
+pub struct SceneObject {
+    pub source_slice: SourceSlice,
+    pub name: SourceSlice,
+    pub properties: Vec<KeyValuePairExpr>,
+}
+impl SceneObject {
+    pub fn get_property(&self, source: &str, key: &str) -> Option<&ValueExpr> {
+        find_property(&self.properties, source, key)
+    }
+}
+impl AstNode for SceneObject {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
+
+/// A light of the scene, e.g. `light key { type: "point", pos: {"x": 1.0, "y": 2.0, "z": 0.0}, intensity: sync.key }`
+///
+/// The properties are checked by the type checker.
+#[derive(Debug, Clone)]
+pub struct Light {
+    pub source_slice: SourceSlice,
+    pub name: SourceSlice,
+    pub properties: Vec<KeyValuePairExpr>,
+}
+impl Light {
+    pub fn get_property(&self, source: &str, key: &str) -> Option<&ValueExpr> {
+        find_property(&self.properties, source, key)
+    }
+}
+impl AstNode for Light {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
+
+/// Hints for a sync track, e.g. `track "flash" { min: 0.0, max: 1.0, interpolation: "step" }`
+///
+/// The properties have to be literals, they are checked when the hints are collected.
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub source_slice: SourceSlice,
+    pub name: SourceSlice,
+    pub properties: Vec<KeyValuePairExpr>,
+}
+impl Track {
+    pub fn get_property(&self, source: &str, key: &str) -> Option<&ValueExpr> {
+        find_property(&self.properties, source, key)
+    }
+}
+impl AstNode for Track {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
+
+/// Static meshes which are merged into one mesh when the demo is loaded, and drawn with `draw_batch`, e.g.
+/// `batch "city" [{ mesh: "house.obj", transform: {"translate_x": 4.0} }, { mesh: "tower.obj" }]`
+///
+/// The properties of the parts have to be literals, they are checked when the batches are collected.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub source_slice: SourceSlice,
+    pub name: SourceSlice,
+    pub parts: Vec<BatchPart>,
+}
+impl AstNode for Batch {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
+
+/// A mesh of a batch with its transform, given like the properties of an object
+#[derive(Debug, Clone)]
+pub struct BatchPart {
+    pub source_slice: SourceSlice,
+    pub properties: Vec<KeyValuePairExpr>,
+}
+impl BatchPart {
+    pub fn get_property(&self, source: &str, key: &str) -> Option<&ValueExpr> {
+        find_property(&self.properties, source, key)
+    }
+}
+impl AstNode for BatchPart {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
+
+fn find_property<'a>(properties: &'a [KeyValuePairExpr], source: &str, key: &str) -> Option<&'a ValueExpr> {
+    properties
+        .iter()
+        .find(|p| p.key.to_slice(source) == key)
+        .map(|p| &p.value)
+}
+
+/// A `//` line comment or a `/* */` block comment
+///
+/// The lexer skips comments, they are collected by `find_comments` and kept in `Program::comments`. Line comments end
+/// before the line break, trailing whitespace is not part of either kind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comment {
+    pub source_slice: SourceSlice,
+    pub block: bool,
+}
+impl AstNode for Comment {
+    fn source_slice(&self) -> SourceSlice {
+        self.source_slice
+    }
+}
+
+/// The comments of a script, in the order of the source
+///
+/// The script has to be parsed without errors, otherwise a string literal which is not closed may hide comments.
+#[cfg(feature = "editor")]
+pub fn find_comments(source: &str) -> Vec<Comment> {
+    let mut comments = Vec::new();
+    let mut position = 0;
+    while let Some(offset) = source[position..].find(|c| c == '"' || c == '/') {
+        let begin = position + offset;
+        let rest = &source[begin..];
+        let end = if rest.starts_with('"') {
+            // String literals are skipped, they may contain `//`
+            position = rest[1..].find('"').map_or(source.len(), |n| begin + n + 2);
+            continue;
+        } else if rest.starts_with("//") {
+            begin + rest.find('\n').unwrap_or(rest.len())
+        } else if rest.starts_with("/*") {
+            begin + rest[2..].find("*/").map_or(rest.len(), |n| n + 4)
+        } else {
+            position = begin + 1;
+            continue;
+        };
+        comments.push(Comment {
+            source_slice: SourceSlice::new(begin, begin + source[begin..end].trim_end().len()),
+            block: rest.starts_with("/*"),
+        });
+        position = end;
+    }
+    comments
+}
+
+#[derive(Debug, Clone)]
 pub struct Program {
+    pub metadata: Vec<MetadataEntry>,
+    // The `metadata` blocks, their entries are merged into `metadata`
+    pub metadata_blocks: Vec<SourceSlice>,
     pub render_targets: Vec<RenderTargetDef>,
+    pub objects: Vec<SceneObject>,
+    pub batches: Vec<Batch>,
+    pub lights: Vec<Light>,
+    pub tracks: Vec<Track>,
     pub functions: Vec<Function>,
+    // All comments of the script, filled in after parsing
+    pub comments: Vec<Comment>,
 }
 impl Program {
     pub fn new() -> Self {
         Program {
+            metadata: Vec::new(),
+            metadata_blocks: Vec::new(),
             render_targets: Vec::new(),
+            objects: Vec::new(),
+            batches: Vec::new(),
+            lights: Vec::new(),
+            tracks: Vec::new(),
             functions: Vec::new(),
+            comments: Vec::new(),
+        }
+    }
+
+    /// The comments directly in front of a node, such as the documentation of a function
+    ///
+    /// Comments belong to the node if only whitespace without a blank line separates them from it.
+    pub fn leading_comments(&self, source: &str, node: &dyn AstNode) -> &[Comment] {
+        let begin = node.source_slice().begin;
+        let last = self.comments.iter().take_while(|c| c.source_slice.end <= begin).count();
+        let mut first = last;
+        let mut end = begin;
+        while first > 0 {
+            let comment = self.comments[first - 1].source_slice;
+            let gap = &source[comment.end..end];
+            // Comments behind other code on their line belong to that code
+            let line_start = source[..comment.begin].rfind('\n').map_or(0, |n| n + 1);
+            let behind_code = !source[line_start..comment.begin].trim().is_empty();
+            if behind_code || !gap.trim().is_empty() || gap.matches('\n').count() > 1 {
+                break;
+            }
+            first -= 1;
+            end = comment.begin;
         }
+        &self.comments[first..last]
     }
 }
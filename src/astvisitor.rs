@@ -1,80 +1,145 @@
 use crate::ast;
 
+/// A node passed to the callback of `Visitor::walk`
+#[derive(Clone, Copy)]
+pub enum Node<'a> {
+    Stmt(&'a ast::Stmt),
+    Expr(&'a ast::ValueExpr),
+}
+
 /// Trait for traversing AST nodes and gathering information
+///
+/// Statements are visited before the expressions and statements they contain. The walk stops at the first error
+/// returned by the callback.
 pub trait Visitor {
-    fn visit_sync_tracks<F: FnMut(&str)>(&self, source: &str, visit: &mut F);
+    fn walk<E, F: FnMut(Node) -> Result<(), E>>(&self, visit: &mut F) -> Result<(), E>;
+
+    /// Visits the statements calling a function, which includes all render ops
+    fn walk_calls<E, F: FnMut(&ast::FunctionCallExpr) -> Result<(), E>>(&self, visit: &mut F) -> Result<(), E> {
+        self.walk(&mut |node| match node {
+            Node::Stmt(ast::Stmt::FunctionCall(call)) => visit(call),
+            _ => Ok(()),
+        })
+    }
+
+    /// Visits the names of all used sync tracks, e.g. `"cam:x"` for `sync.cam.x`
+    fn visit_sync_tracks<F: FnMut(&str)>(&self, source: &str, visit: &mut F) {
+        self.walk(&mut |node| -> Result<(), ()> {
+            if let Node::Expr(ast::ValueExpr::PropertyOf(_, p, a)) = node {
+                if let ast::ValueExpr::Var(p) = **p {
+                    if p.to_slice(source) == "sync" {
+                        let a = a.iter().map(|a| a.to_owned(source)).collect::<Vec<String>>();
+                        visit(&a.join(":"));
+                    }
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(())
+    }
 }
 
 impl Visitor for ast::Program {
-    fn visit_sync_tracks<F: FnMut(&str)>(&self, source: &str, visit: &mut F) {
+    fn walk<E, F: FnMut(Node) -> Result<(), E>>(&self, visit: &mut F) -> Result<(), E> {
         for target_def in &self.render_targets {
-            target_def.width.visit_sync_tracks(source, visit);
-            target_def.height.visit_sync_tracks(source, visit);
+            target_def.width.walk(visit)?;
+            target_def.height.walk(visit)?;
+        }
+
+        for object in &self.objects {
+            for property in &object.properties {
+                property.value.walk(visit)?;
+            }
+        }
+
+        for light in &self.lights {
+            for property in &light.properties {
+                property.value.walk(visit)?;
+            }
         }
 
         for function in &self.functions {
-            function.visit_sync_tracks(source, visit);
+            function.walk(visit)?;
         }
+        Ok(())
     }
 }
 
 impl Visitor for ast::Function {
-    fn visit_sync_tracks<F: FnMut(&str)>(&self, source: &str, visit: &mut F) {
-        self.block.visit_sync_tracks(source, visit);
+    fn walk<E, F: FnMut(Node) -> Result<(), E>>(&self, visit: &mut F) -> Result<(), E> {
+        self.block.walk(visit)
     }
 }
 
 // TODO: CodeBlock should be its own type
 impl Visitor for Vec<ast::Stmt> {
-    fn visit_sync_tracks<F: FnMut(&str)>(&self, source: &str, visit: &mut F) {
+    fn walk<E, F: FnMut(Node) -> Result<(), E>>(&self, visit: &mut F) -> Result<(), E> {
         for render_op in self {
-            render_op.visit_sync_tracks(source, visit);
+            render_op.walk(visit)?;
         }
+        Ok(())
     }
 }
 
 impl Visitor for ast::Stmt {
-    fn visit_sync_tracks<F: FnMut(&str)>(&self, source: &str, visit: &mut F) {
+    fn walk<E, F: FnMut(Node) -> Result<(), E>>(&self, visit: &mut F) -> Result<(), E> {
+        visit(Node::Stmt(self))?;
         match self {
             ast::Stmt::FunctionCall(function_call) => {
                 for arg in &function_call.args {
-                    arg.visit_sync_tracks(source, visit);
+                    arg.walk(visit)?;
+                }
+                for named_arg in &function_call.named_args {
+                    named_arg.value.walk(visit)?;
                 }
             }
-            ast::Stmt::Return { expr } => {
-                expr.visit_sync_tracks(source, visit);
+            ast::Stmt::Return { expr } | ast::Stmt::Let { expr, .. } => {
+                expr.walk(visit)?;
             }
             ast::Stmt::Conditional { condition, a, b } => {
-                condition.visit_sync_tracks(source, visit);
-                a.visit_sync_tracks(source, visit);
-                b.as_ref().map(|b| b.visit_sync_tracks(source, visit));
+                condition.walk(visit)?;
+                a.walk(visit)?;
+                if let Some(b) = b {
+                    b.walk(visit)?;
+                }
+            }
+            ast::Stmt::Loop { start, end, block, .. } => {
+                start.walk(visit)?;
+                end.walk(visit)?;
+                block.walk(visit)?;
             }
         }
+        Ok(())
     }
 }
 
 impl Visitor for ast::ValueExpr {
-    fn visit_sync_tracks<F: FnMut(&str)>(&self, source: &str, visit: &mut F) {
+    fn walk<E, F: FnMut(Node) -> Result<(), E>>(&self, visit: &mut F) -> Result<(), E> {
+        visit(Node::Expr(self))?;
         match self {
-            ast::ValueExpr::PropertyOf(_, p, a) => {
-                if let ast::ValueExpr::Var(p) = **p {
-                    if p.to_slice(source) == "sync" {
-                        let a = a.iter().map(|a| a.to_owned(source)).collect::<Vec<String>>();
-                        visit(&a.join(":"));
-                    }
-                }
-            }
             ast::ValueExpr::FunctionCall(function_call) => {
                 for arg in &function_call.args {
-                    arg.visit_sync_tracks(source, visit);
+                    arg.walk(visit)?;
+                }
+                for named_arg in &function_call.named_args {
+                    named_arg.value.walk(visit)?;
+                }
+            }
+            ast::ValueExpr::Dictionary(d) => {
+                for kv in &d.entries {
+                    kv.value.walk(visit)?;
                 }
             }
             ast::ValueExpr::BinaryOp(_, _, a, b) => {
-                a.visit_sync_tracks(source, visit);
-                b.visit_sync_tracks(source, visit);
+                a.walk(visit)?;
+                b.walk(visit)?;
+            }
+            ast::ValueExpr::PropertyOf(_, p, _) => {
+                p.walk(visit)?;
             }
 
             _ => {}
         }
+        Ok(())
     }
 }
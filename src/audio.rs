@@ -0,0 +1,98 @@
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::{self, Sink, Source};
+
+/// The music of a demo, decoded into memory and played on the default output device
+///
+/// The soundtrack is the clock of the sync trackers which play it: the time is the position of the sample which was
+/// last handed to the device, so the visuals follow the music even if the device falls behind or skips ahead. After
+/// the end, silence is played so that the clock keeps going.
+pub struct Soundtrack {
+    sink: Sink,
+    // Index of the next sample, the channels of each frame are interleaved
+    position: Arc<AtomicUsize>,
+    channels: u16,
+    sample_rate: u32,
+}
+impl Soundtrack {
+    /// Decodes an OGG, MP3 or WAV file, the soundtrack is paused at its start
+    pub fn from_bytes(path: &str, bytes: Vec<u8>) -> Result<Self, String> {
+        let decoder =
+            rodio::Decoder::new(Cursor::new(bytes)).map_err(|e| format!("Could not decode {}: {}", path, e))?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let samples = decoder.collect::<Vec<i16>>();
+        let position = Arc::new(AtomicUsize::new(0));
+
+        let device = rodio::default_output_device().ok_or_else(|| format!("No audio output device found"))?;
+        let sink = Sink::new(&device);
+        sink.pause();
+        sink.append(SoundtrackSource {
+            samples: samples,
+            position: position.clone(),
+            channels: channels,
+            sample_rate: sample_rate,
+        });
+        Ok(Soundtrack {
+            sink: sink,
+            position: position,
+            channels: channels,
+            sample_rate: sample_rate,
+        })
+    }
+
+    pub fn play(&self) {
+        self.sink.play();
+    }
+
+    pub fn pause(&self) {
+        self.sink.pause();
+    }
+
+    /// Continues the playback at the given time, times before the start are clamped to it
+    pub fn seek(&self, time_s: f64) {
+        let frame = (time_s.max(0.0) * self.sample_rate as f64) as usize;
+        self.position.store(frame * self.channels as usize, Ordering::SeqCst);
+    }
+
+    pub fn get_time(&self) -> f64 {
+        let frame = self.position.load(Ordering::SeqCst) / self.channels as usize;
+        frame as f64 / self.sample_rate as f64
+    }
+}
+
+/// Streams the decoded samples to the device from the position of the soundtrack, which it advances
+struct SoundtrackSource {
+    samples: Vec<i16>,
+    position: Arc<AtomicUsize>,
+    channels: u16,
+    sample_rate: u32,
+}
+impl Iterator for SoundtrackSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let index = self.position.fetch_add(1, Ordering::SeqCst);
+        Some(self.samples.get(index).cloned().unwrap_or(0))
+    }
+}
+impl Source for SoundtrackSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -1,34 +1,67 @@
-use ast::{self, SourceSlice, Stmt};
+use ast::{self, AstNode, SourceSlice, Stmt};
 use astvisitor::Visitor;
 use color::LinearRGBA;
+use deadcode;
+use diagnostics::{self, Severity};
+use framegraph;
+use serialize::{Deserialize, Reader, Serialize, Writer};
 use std::collections::{HashMap, HashSet};
 use std::error;
 use std::error::Error;
 use std::fmt;
-use types::{BinaryOperator, BlendMode, RenderTargetFormat, ZTestMode, CullingMode};
+use typecheck::TypeChecker;
+use types::{
+    AssertMode, BinaryOperator, BlendMode, CullingMode, LensEffect, LightType, RenderTargetFormat, StereoMode,
+    TrackInterpolation, ZTestMode,
+};
 
 #[derive(Debug, Clone)]
 pub struct SemanticError {
     slice: SourceSlice,
     error: String,
+    // Hints printed below the error, such as suggestions for fixing it
+    notes: Vec<String>,
 }
 pub struct SourceSnippet<'a> {
     source: &'a str,
     slice: SourceSlice,
+    // Lines shown before and after the highlighted ones
+    context_lines: usize,
+    // Severity whose color the highlighting is printed in, if colors are used
+    color: Option<Severity>,
 }
 impl SemanticError {
     pub fn error_from_ast(ast: &dyn ast::AstNode, error: String) -> SemanticError {
         SemanticError {
             slice: ast.source_slice(),
             error: error,
+            notes: Vec::new(),
         }
     }
 
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn get_slice(&self) -> SourceSlice {
+        self.slice
+    }
+    pub fn get_message(&self) -> &str {
+        &self.error
+    }
+    pub fn get_notes(&self) -> &[String] {
+        &self.notes
+    }
+
     pub fn source_snippet<'a>(&self, source: &'a str) -> SourceSnippet<'a> {
-        SourceSnippet {
-            source: source,
-            slice: self.slice,
-        }
+        SourceSnippet::new(self.slice, source)
+    }
+
+    /// Formats the error with its location and notes, see `diagnostics::render`
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let snippet = Some(self.source_snippet(source));
+        diagnostics::render(Severity::Error, &self.error, snippet, &self.notes, color)
     }
 }
 impl fmt::Display for SemanticError {
@@ -36,54 +69,203 @@ impl fmt::Display for SemanticError {
         write!(f, "{}: {}", self.description(), self.error)
     }
 }
+/// Problem in the program which does not prevent it from being compiled
+#[derive(Debug, Clone)]
+pub struct SemanticWarning {
+    slice: SourceSlice,
+    warning: String,
+}
+impl SemanticWarning {
+    pub fn warning_from_ast(ast: &dyn ast::AstNode, warning: String) -> SemanticWarning {
+        SemanticWarning {
+            slice: ast.source_slice(),
+            warning: warning,
+        }
+    }
+
+    pub fn source_snippet<'a>(&self, source: &'a str) -> SourceSnippet<'a> {
+        SourceSnippet::new(self.slice, source)
+    }
+
+    pub fn get_slice(&self) -> SourceSlice {
+        self.slice
+    }
+    pub fn get_message(&self) -> &str {
+        &self.warning
+    }
+
+    /// Formats the warning with its location, see `diagnostics::render`
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let snippet = Some(self.source_snippet(source));
+        diagnostics::render(Severity::Warning, &self.warning, snippet, &[], color)
+    }
+}
+impl Serialize for SemanticWarning {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.slice);
+        writer.write(&self.warning);
+    }
+}
+impl Deserialize for SemanticWarning {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(SemanticWarning {
+            slice: reader.read()?,
+            warning: reader.read()?,
+        })
+    }
+}
+impl fmt::Display for SemanticWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Warning: {}", self.warning)
+    }
+}
+/// Columns between tab stops when tabs are expanded in source snippets
+const SNIPPET_TAB_WIDTH: usize = 4;
+/// Lines of context shown around the highlighted lines of source snippets by default
+const SNIPPET_CONTEXT_LINES: usize = 1;
+
+/// Combining and zero-width characters, which stay on the character they belong to
+const ZERO_WIDTH_CHARS: [(u32, u32); 7] = [
+    (0x0300, 0x036F),
+    (0x1AB0, 0x1AFF),
+    (0x1DC0, 0x1DFF),
+    (0x200B, 0x200F),
+    (0x20D0, 0x20FF),
+    (0xFE00, 0xFE0F),
+    (0xFE20, 0xFE2F),
+];
+/// Characters taking two terminal columns, mostly CJK and emoji
+const WIDE_CHARS: [(u32, u32); 10] = [
+    (0x1100, 0x115F),
+    (0x2E80, 0xA4CF),
+    (0xAC00, 0xD7A3),
+    (0xF900, 0xFAFF),
+    (0xFE30, 0xFE4F),
+    (0xFF00, 0xFF60),
+    (0xFFE0, 0xFFE6),
+    (0x1F300, 0x1F64F),
+    (0x1F900, 0x1F9FF),
+    (0x20000, 0x3FFFD),
+];
+
+/// Number of terminal columns taken by a character
+fn char_width(c: char) -> usize {
+    let in_ranges = |ranges: &[(u32, u32)]| ranges.iter().any(|&(lo, hi)| lo <= c as u32 && c as u32 <= hi);
+    if in_ranges(&ZERO_WIDTH_CHARS) {
+        0
+    } else if in_ranges(&WIDE_CHARS) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Column at which the character at the given byte offset of a line is displayed, with tabs expanded
+fn display_column(line: &str, byte_offset: usize) -> usize {
+    line[..byte_offset].chars().fold(0, |column, c| match c {
+        '\t' => (column / SNIPPET_TAB_WIDTH + 1) * SNIPPET_TAB_WIDTH,
+        c => column + char_width(c),
+    })
+}
+
+/// The line with its tabs replaced by spaces, up to the same tab stops as used by `display_column`
+fn expand_tabs(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    for c in line.chars() {
+        if c == '\t' {
+            let column = display_column(&result, result.len());
+            result.push_str(&" ".repeat(SNIPPET_TAB_WIDTH - column % SNIPPET_TAB_WIDTH));
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
 impl<'a> SourceSnippet<'a> {
     pub fn new<'n>(slice: SourceSlice, source: &'n str) -> SourceSnippet<'n> {
         SourceSnippet {
             slice: slice,
             source: source,
+            context_lines: SNIPPET_CONTEXT_LINES,
+            color: None,
         }
     }
 
-    fn transform_position(&self, pos: usize) -> Option<(usize, usize)> {
-        let mut counter = 0;
-        for (l_idx, l) in self.source.split('\n').enumerate() {
-            if counter + l.len() + 1 > pos {
-                return Some((l_idx, pos - counter));
-            }
-            counter += l.len() + 1;
+    /// Prints the highlighting in the color of the given severity, and the line numbers in blue
+    pub fn with_color(mut self, color: Option<Severity>) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Line and column at which the snippet starts, both counted from 1
+    pub fn location(&self) -> (usize, usize) {
+        let (line, offset) = self.transform_position(self.slice.begin);
+        let source_line = self.source.split('\n').nth(line).unwrap_or("");
+        (line + 1, display_column(source_line, offset.min(source_line.len())) + 1)
+    }
+
+    /// Sets the number of lines shown before and after the highlighted lines
+    pub fn with_context_lines(mut self, context_lines: usize) -> Self {
+        self.context_lines = context_lines;
+        self
+    }
+
+    /// Line and byte offset within the line of a position in the source
+    ///
+    /// Positions past the end are moved to the end, and positions within a character to its start.
+    fn transform_position(&self, pos: usize) -> (usize, usize) {
+        let mut pos = pos.min(self.source.len());
+        while !self.source.is_char_boundary(pos) {
+            pos -= 1;
         }
-        None
+        let line_start = self.source[..pos].rfind('\n').map_or(0, |i| i + 1);
+        (self.source[..line_start].matches('\n').count(), pos - line_start)
     }
 }
 impl<'a> fmt::Display for SourceSnippet<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let lo_pos = self.transform_position(self.slice.begin).unwrap();
-        let hi_pos = self.transform_position(self.slice.end).unwrap();
-
-        let error_highlighting: String;
-        if lo_pos == hi_pos {
-            let source_line = self.source.lines().skip(lo_pos.0).next().unwrap();
-            error_highlighting = format!("{}\n{}^", source_line, " ".repeat(lo_pos.1));
-        } else {
-            let mut source_lines = String::new();
-            let mut caret = lo_pos.1;
-            for line in lo_pos.0..hi_pos.0 + 1 {
-                let source_line = self.source.lines().skip(line).next().unwrap();
-                let underline = " ".repeat(caret + 5)
-                    + &"~".repeat(if line != hi_pos.0 {
-                        source_line.len() - caret
-                    } else {
-                        hi_pos.1 - caret
-                    });
-                caret = 0;
-
-                source_lines += &format!("{:03}: {}\n{}\n", line + 1, source_line, &underline);
+        let (lo_line, lo_offset) = self.transform_position(self.slice.begin);
+        let (hi_line, hi_offset) = self.transform_position(self.slice.end);
+        let lines: Vec<&str> = self.source.split('\n').map(|l| l.trim_end_matches('\r')).collect();
+
+        let first = lo_line.saturating_sub(self.context_lines);
+        let last = (hi_line + self.context_lines).min(lines.len() - 1);
+        for line in first..last + 1 {
+            let source_line = lines[line];
+            if line != first {
+                writeln!(f)?;
+            }
+            let line_number = format!("{:03}:", line + 1);
+            let line_number = diagnostics::paint(diagnostics::BLUE, &line_number, self.color.is_some());
+            write!(f, "{} {}", line_number, expand_tabs(source_line))?;
+            if line < lo_line || line > hi_line {
+                continue;
             }
 
-            error_highlighting = source_lines;
+            // Offsets past the end of a line can only point at the removed `\r`
+            let begin = if line == lo_line {
+                display_column(source_line, lo_offset.min(source_line.len()))
+            } else {
+                0
+            };
+            let marker = if lo_line == hi_line && lo_offset == hi_offset {
+                format!("^")
+            } else {
+                let end = if line == hi_line {
+                    display_column(source_line, hi_offset.min(source_line.len()))
+                } else {
+                    display_column(source_line, source_line.len())
+                };
+                "~".repeat(end.saturating_sub(begin).max(1))
+            };
+            let marker = match self.color {
+                Some(severity) => diagnostics::paint(severity.color(), &marker, true),
+                None => marker,
+            };
+            write!(f, "\n{}{}", " ".repeat(begin + 5), marker)?;
         }
-
-        write!(f, "{}", error_highlighting)
+        Ok(())
     }
 }
 impl error::Error for SemanticError {
@@ -96,17 +278,290 @@ impl error::Error for SemanticError {
     }
 }
 
+/// Adds a resource found in the script, unless it is already used elsewhere
+fn push_unique<T: PartialEq>(resources: &mut Vec<T>, resource: T) {
+    if !resources.contains(&resource) {
+        resources.push(resource);
+    }
+}
+
 /// Utility function for extracting string literals from ast expessions
 fn expect_ast_string(ast: &ast::ValueExpr, source: &str) -> Result<String, SemanticError> {
     ast.as_string(source)
         .map_err(|_| SemanticError::error_from_ast(ast, format!("Expected string literal")))
 }
 
+fn split_paths(paths: &str) -> Vec<String> {
+    paths
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_owned())
+        .collect()
+}
+
+fn parse_scene_times(times: &str) -> Result<Vec<f32>, String> {
+    let mut scenes: Vec<f32> = Vec::new();
+    for time in split_paths(times) {
+        let time_s: f32 = time.parse().map_err(|_| format!("Not a time in seconds: {}", time))?;
+        if time_s < 0.0 || scenes.last().map_or(false, |&last| time_s <= last) {
+            return Err(format!("The scenes have to start at 0 or later, in ascending order"));
+        }
+        scenes.push(time_s);
+    }
+    Ok(scenes)
+}
+
+/// Index of a definition which was collected from the program before the functions are compiled
+///
+/// Fails if the collection missed the definition, instead of assuming that it is always found.
+fn definition_index<T: PartialEq>(
+    defs: &[T],
+    def: &T,
+    ast: &dyn ast::AstNode,
+    kind: &str,
+) -> Result<u32, SemanticError> {
+    defs.iter()
+        .position(|d| d == def)
+        .map(|idx| idx as u32)
+        .ok_or_else(|| SemanticError::error_from_ast(ast, format!("Unknown {}", kind)))
+}
+
+/// Arguments which reference resources, as (function, argument index, description)
+///
+/// Resources are loaded before the demo runs, hence these arguments have to be string literals.
+const RESOURCE_ARGUMENTS: [(&str, usize, &str); 8] = [
+    ("program", 0, "shader dictionary"),
+    ("draw_model", 0, "model file"),
+    ("uniform_texture_srgb", 1, "texture file"),
+    ("uniform_texture_linear", 1, "texture file"),
+    ("draw_textured_quad", 4, "texture file"),
+    ("uniform_ibl", 0, "IBL folder"),
+    ("draw_skybox", 0, "IBL folder"),
+    ("draw_batch", 0, "batch name"),
+];
+
+/// Functions which are compiled to their own ops when called as statements
+pub const BUILTIN_STATEMENTS: [&str; 39] = [
+    "program",
+    "bind_rt",
+    "pipeline_set_blending",
+    "pipeline_set_write_mask",
+    "pipeline_set_ztest",
+    "pipeline_set_culling",
+    "set_clip_plane",
+    "uniform_float",
+    "uniform_color",
+    "uniform_vec3",
+    "uniform_mat4",
+    "uniform_texture_srgb",
+    "uniform_texture_linear",
+    "uniform_ibl",
+    "uniform_rtt",
+    "draw_fullscreenquad",
+    "grab_screen",
+    "post_taa",
+    "post_auto_exposure",
+    "post_volumetrics",
+    "post_oit_resolve",
+    "begin_2d",
+    "end_2d",
+    "draw_rect",
+    "draw_textured_quad",
+    "draw_model",
+    "draw_scene",
+    "draw_batch",
+    "draw_skybox",
+    "camera_lookat",
+    "camera_perspective",
+    "set_model_transform",
+    "clear",
+    "print",
+    "trace",
+    "assert",
+    "viewport",
+    "set_title",
+    "show_cursor",
+];
+
+/// Functions which are evaluated in expressions, besides the ones of `easing::FUNCTIONS`
+pub const BUILTIN_EXPRESSIONS: [&str; 12] = [
+    "LinColor",
+    "Vec2",
+    "Vec3",
+    "Vec4",
+    "translate",
+    "rotate",
+    "perspective",
+    "probe",
+    "reduce_luminance",
+    "histogram",
+    "readback",
+    "envelope",
+];
+
+/// Built-ins which refer to render targets by name (the part before the `.` of their string arguments), as statements
+/// or in expressions
+pub const RENDER_TARGET_FUNCTIONS: [&str; 14] = [
+    "bind_rt",
+    "uniform_rtt",
+    "pipeline_set_blending",
+    "post_taa",
+    "post_auto_exposure",
+    "post_volumetrics",
+    "post_lensflare",
+    "post_ca",
+    "post_vignette",
+    "post_grain",
+    "post_oit_resolve",
+    "reduce_luminance",
+    "histogram",
+    "readback",
+];
+
+/// Names of the variables provided by the runtime, the index of a name is its global slot
+pub const GLOBALS: [&str; 3] = ["width", "height", "time"];
+
+/// Components of the transform of scene objects, rotations are in radians and the scale is uniform
+pub const TRANSFORM_KEYS: [&str; 7] = [
+    "translate_x",
+    "translate_y",
+    "translate_z",
+    "rotate_x",
+    "rotate_y",
+    "rotate_z",
+    "scale",
+];
+
+/// Number of lights which are packed into the light uniforms, see `LightDef`
+pub const MAX_LIGHTS: usize = 16;
+
+/// Material maps which hold colors, their textures are decoded from sRGB while all other maps are linear
+const SRGB_MATERIAL_MAPS: [&str; 4] = ["albedo", "base_color", "diffuse", "emissive"];
+
+pub fn is_srgb_material_map(key: &str) -> bool {
+    SRGB_MATERIAL_MAPS.contains(&key)
+}
+
+/// Returns the name of the uniform an entry of a material is bound to
+///
+/// Textures are bound to `t_<Key>` and values to `u_<Key>`, with the key in camel case: `base_color` is bound to
+/// `t_BaseColor` or `u_BaseColor`.
+pub fn material_uniform_name(key: &str, texture: bool) -> String {
+    let camel_case: String = key
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect();
+    format!("{}_{}", if texture { "t" } else { "u" }, camel_case)
+}
+
+/// Value of an entry in the material of `draw_model`
+enum MaterialValue<'a> {
+    Texture(TextureDef),
+    Color(&'a ast::ValueExpr),
+    Float(&'a ast::ValueExpr),
+}
+
+/// Returns the uniform name and the value of each entry in the material dictionary of `draw_model`
+///
+/// Only color literals and `LinColor` calls are bound as colors, see `material_uniform_name` for the names.
+fn material_entries<'a>(
+    source: &str,
+    material: &'a ast::ValueExpr,
+) -> Result<Vec<(String, MaterialValue<'a>)>, SemanticError> {
+    let material = material
+        .as_dictionary()
+        .map_err(|_| SemanticError::error_from_ast(material, format!("Expected a dictionary of material maps")))?;
+    let mut entries = Vec::new();
+    for entry in &material.entries {
+        let key = entry.key.to_slice(source);
+        entries.push(match &entry.value {
+            ast::ValueExpr::StringLiteral(path) => (
+                material_uniform_name(key, true),
+                MaterialValue::Texture(TextureDef {
+                    path: path.to_owned(source),
+                    srgb: is_srgb_material_map(key),
+                    options: TextureImportOptions::default(),
+                }),
+            ),
+            ast::ValueExpr::ColorLiteral(..) => (material_uniform_name(key, false), MaterialValue::Color(&entry.value)),
+            ast::ValueExpr::FunctionCall(call) if call.function.to_slice(source) == "LinColor" => {
+                (material_uniform_name(key, false), MaterialValue::Color(&entry.value))
+            }
+            value => (material_uniform_name(key, false), MaterialValue::Float(value)),
+        });
+    }
+    Ok(entries)
+}
+
+/// Returns the (1-based) line number on which the given ast node starts
+fn line_number(ast: &dyn ast::AstNode, source: &str) -> usize {
+    source[..ast.source_slice().begin].matches('\n').count() + 1
+}
+
+/// Options which influence how a script is compiled
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CompileOptions {
+    pub assert_mode: AssertMode,
+    /// Keep functions which are not reachable from `main`, so that they can be called with `runtime::evaluate`
+    pub keep_unused_functions: bool,
+}
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            assert_mode: AssertMode::Halt,
+            keep_unused_functions: false,
+        }
+    }
+}
+
+/// Properties of the demo declared in the `metadata` block
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    // (width, height), the demo is letterboxed to this aspect ratio
+    pub aspect: Option<(f32, f32)>,
+    pub stereo_mode: Option<StereoMode>,
+    // Distance between the eyes in scene units, only used in stereo
+    pub eye_separation: Option<f32>,
+    // Depth goes from 1 at the near plane to 0 at the far plane, for a better precision far away
+    pub reversed_z: bool,
+    // Folders watched for changes instead of the resources of the demo, relative to the script
+    pub watch: Vec<String>,
+    // Files and folders whose changes are ignored, relative to the script
+    pub watch_exclude: Vec<String>,
+    // Frame rate held by scaling down the passes which are drawn at a fraction of their target
+    pub dynamic_resolution: Option<f32>,
+    // Length of the demo in seconds, players exit after it and packing bakes the sync tracks up to it
+    pub duration: Option<f32>,
+    // Start times of the scenes in seconds in ascending order, marked on the scrub bar of the editor
+    pub scenes: Vec<f32>,
+    // Music played along with the demo, relative to the script, see `audio::Soundtrack`
+    pub soundtrack: Option<String>,
+}
+
+/// Hints for the sync editor declared with `track "name" { ... }`, so that a new session starts with sane scales
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackHint {
+    pub name: String,
+    // (min, max), the values of the track are clamped to this range
+    pub range: Option<(f32, f32)>,
+    pub interpolation: Option<TrackInterpolation>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ValueExpr {
     // Indirect value
     FunctionCall(FunctionCall),
-    Var(String, Vec<String>),
+    Var(String),           // unresolved, only exists until `resolve_variables` is called
+    Local(usize, String),  // slot, name
+    Global(usize, String), // slot, name
+    SyncTrack(String),
 
     // Constants
     ConstFloat(f32),
@@ -124,19 +579,17 @@ impl ValueExpr {
             ast::ValueExpr::FloatLiteral(_, v) => Ok(ValueExpr::ConstFloat(*v)),
             ast::ValueExpr::ColorLiteral(_, c) => Ok(ValueExpr::ConstLinColor(*c)),
             ast::ValueExpr::StringLiteral(s) => Ok(ValueExpr::ConstString(s.to_owned(source))),
-            ast::ValueExpr::Var(var) => Ok(ValueExpr::Var(var.to_owned(source), Vec::new())),
-            ast::ValueExpr::PropertyOf(_, v, props) => {
-                let v = ValueExpr::from_ast(source, v)?;
-                if let ValueExpr::Var(v, mut p) = v {
-                    p.extend(props.iter().map(|x| x.to_slice(source).to_owned()));
-                    Ok(ValueExpr::Var(v, p))
-                } else {
-                    Err(SemanticError::error_from_ast(
-                        ast,
-                        format!("The `.` operator can only be used with variable names"),
-                    ))
+            ast::ValueExpr::Var(var) => Ok(ValueExpr::Var(var.to_owned(source))),
+            ast::ValueExpr::PropertyOf(_, v, props) => match **v {
+                ast::ValueExpr::Var(v) if v.to_slice(source) == "sync" => {
+                    let props: Vec<&str> = props.iter().map(|x| x.to_slice(source)).collect();
+                    Ok(ValueExpr::SyncTrack(props.join(":")))
                 }
-            }
+                _ => Err(SemanticError::error_from_ast(
+                    ast,
+                    format!("The `.` operator can only be used with sync tracks"),
+                )),
+            },
             ast::ValueExpr::Dictionary(d) => Ok(ValueExpr::ConstDict(
                 d.entries
                     .iter()
@@ -163,12 +616,228 @@ impl ValueExpr {
             }
         }
     }
+
+    /// Replaces variable names by the slot of the local or global they refer to
+    pub fn resolve_variables(&mut self, locals: &[String]) {
+        let resolved = match self {
+            ValueExpr::Var(name) => {
+                if let Some(slot) = locals.iter().rposition(|l| l == name) {
+                    Some(ValueExpr::Local(slot, name.clone()))
+                } else if let Some(slot) = GLOBALS.iter().position(|g| g == name) {
+                    Some(ValueExpr::Global(slot, name.clone()))
+                } else {
+                    None
+                }
+            }
+            ValueExpr::FunctionCall(function_call) => {
+                for arg in &mut function_call.args {
+                    arg.resolve_variables(locals);
+                }
+                None
+            }
+            ValueExpr::ConstDict(entries) => {
+                for value in entries.values_mut() {
+                    value.resolve_variables(locals);
+                }
+                None
+            }
+            ValueExpr::BinaryOp(_, l, r) => {
+                l.resolve_variables(locals);
+                r.resolve_variables(locals);
+                None
+            }
+            _ => None,
+        };
+        if let Some(resolved) = resolved {
+            *self = resolved;
+        }
+    }
+
+    /// Returns true if the expression calls one of the given functions
+    pub fn calls_any<T>(&self, functions: &HashMap<String, T>) -> bool {
+        match self {
+            ValueExpr::FunctionCall(function_call) => {
+                functions.contains_key(&function_call.function)
+                    || function_call.args.iter().any(|arg| arg.calls_any(functions))
+            }
+            ValueExpr::ConstDict(entries) => entries.values().any(|value| value.calls_any(functions)),
+            ValueExpr::BinaryOp(_, l, r) => l.calls_any(functions) || r.calls_any(functions),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ValueExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueExpr::FunctionCall(function_call) => {
+                write!(f, "{}(", function_call.function)?;
+                for (i, arg) in function_call.args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            ValueExpr::Var(name) | ValueExpr::Local(_, name) | ValueExpr::Global(_, name) => write!(f, "{}", name),
+            ValueExpr::SyncTrack(track) => write!(f, "sync.{}", track.replace(':', ".")),
+            ValueExpr::ConstFloat(v) => write!(f, "{}", v),
+            ValueExpr::ConstLinColor(c) => write!(f, "LinColor({}, {}, {}, {})", c.r, c.g, c.b, c.a),
+            ValueExpr::ConstString(s) => write!(f, "\"{}\"", s),
+            ValueExpr::ConstDict(_) => write!(f, "{{...}}"),
+            ValueExpr::BinaryOp(op, l, r) => write!(f, "({} {} {})", l, op, r),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct TextureDef {
     pub path: String,
     pub srgb: bool,
+    pub options: TextureImportOptions,
+}
+
+/// Conventions of a texture file, which are converted to those of the engine when the file is decoded
+///
+/// The options are passed as named arguments where the texture is used, e.g.
+/// `uniform_texture_srgb("t_Albedo", "albedo.png", exposure: -1.0, premultiply_alpha: 1, flip_y: 0)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureImportOptions {
+    // Colors are scaled by 2^exposure, in linear space
+    pub exposure: f32,
+    // Colors are multiplied by alpha, for files which store straight alpha
+    pub premultiply_alpha: bool,
+    // Files store their top row first, while OpenGL expects the bottom row first
+    pub flip_y: bool,
+}
+impl Default for TextureImportOptions {
+    fn default() -> Self {
+        TextureImportOptions {
+            exposure: 0.0,
+            premultiply_alpha: false,
+            flip_y: true,
+        }
+    }
+}
+
+/// Reads the import options passed to a function which uses a texture file, see `TextureImportOptions`
+fn texture_import_options(
+    source: &str,
+    function_call: &ast::FunctionCallExpr,
+) -> Result<TextureImportOptions, SemanticError> {
+    let mut options = TextureImportOptions::default();
+    for named_arg in &function_call.named_args {
+        let value = match named_arg.value {
+            ast::ValueExpr::FloatLiteral(_, value) => value,
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    &named_arg.value,
+                    format!("Expected a number literal"),
+                ))
+            }
+        };
+        let flag = || {
+            if value == 0.0 || value == 1.0 {
+                Ok(value == 1.0)
+            } else {
+                Err(SemanticError::error_from_ast(
+                    &named_arg.value,
+                    format!("Expected either 0 or 1"),
+                ))
+            }
+        };
+        match named_arg.key.to_slice(source) {
+            "exposure" => options.exposure = value,
+            "premultiply_alpha" => options.premultiply_alpha = flag()?,
+            "flip_y" => options.flip_y = flag()?,
+            key => {
+                return Err(SemanticError::error_from_ast(
+                    named_arg,
+                    format!("Unknown argument `{}`", key),
+                ))
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// A mesh drawn by `draw_model` or by scene objects
+#[derive(Debug, PartialEq)]
+pub struct ModelDef {
+    // OBJ file, or the heightmap of a terrain
+    pub path: String,
+    pub terrain: Option<TerrainDef>,
+}
+
+/// A grid mesh displaced by a heightmap, given as `terrain("height.png", size, max_height, resolution)`
+///
+/// The grid is centered on the origin in the XZ plane with the top of the heightmap towards -Z, its vertices are
+/// raised by the first channel of the heightmap times the maximum height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainDef {
+    // Width and depth of the grid
+    pub size: f32,
+    pub max_height: f32,
+    // Number of quads along each side
+    pub resolution: u32,
+}
+
+/// Largest resolution of terrains, which keeps the mesh of a terrain below 17 million vertices
+pub const MAX_TERRAIN_RESOLUTION: u32 = 4096;
+
+/// Reads the mesh of `draw_model` or of an object, which is either a model file or a `terrain` call
+fn model_def(source: &str, mesh: &ast::ValueExpr) -> Result<ModelDef, SemanticError> {
+    let call = match mesh {
+        ast::ValueExpr::FunctionCall(call) if call.function.to_slice(source) == "terrain" => call,
+        _ => {
+            return Ok(ModelDef {
+                path: expect_ast_string(mesh, source)?,
+                terrain: None,
+            })
+        }
+    };
+
+    BlockBytecode::expect_args_count(call, 4)?;
+    let mut values = Vec::new();
+    for arg in &call.args[1..] {
+        match arg {
+            ast::ValueExpr::FloatLiteral(_, value) => values.push(*value),
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    arg,
+                    format!(
+                        "The arguments of `terrain` have to be literals, resources are loaded before the demo runs"
+                    ),
+                ))
+            }
+        }
+    }
+    if !(values[0] > 0.0) {
+        return Err(SemanticError::error_from_ast(
+            &call.args[1],
+            format!("The size of a terrain has to be positive"),
+        ));
+    }
+    let resolution = values[2];
+    if resolution.fract() != 0.0 || resolution < 1.0 || resolution > MAX_TERRAIN_RESOLUTION as f32 {
+        return Err(SemanticError::error_from_ast(
+            &call.args[3],
+            format!(
+                "The resolution of a terrain has to be a whole number from 1 to {}",
+                MAX_TERRAIN_RESOLUTION
+            ),
+        ));
+    }
+
+    Ok(ModelDef {
+        path: expect_ast_string(&call.args[0], source)?,
+        terrain: Some(TerrainDef {
+            size: values[0],
+            max_height: values[1],
+            resolution: resolution as u32,
+        }),
+    })
 }
 
 #[derive(Debug, PartialEq)]
@@ -176,6 +845,28 @@ pub struct IblDef {
     pub folder: String,
 }
 
+/// Number of planes which can be set with `set_clip_plane`, the minimum supported by OpenGL
+pub const MAX_CLIP_PLANES: u32 = 8;
+
+/// Largest magnitude of the bounds of a `for` loop, adding 1 to a larger f32 does not change it
+const MAX_LOOP_BOUND: f32 = 16_777_216.0;
+
+fn loop_bounds_error() -> String {
+    format!(
+        "Loop bounds have to be finite numbers between -{0} and {0}",
+        MAX_LOOP_BOUND
+    )
+}
+
+/// Buffers of the targets defined with `define_oit_rt`
+///
+/// Transparent surfaces add their premultiplied color, weighted by depth, to `accum` with the `add` blend mode, and
+/// their alpha to `revealage` with `oit_coverage_blend`. `post_oit_resolve` composites the result.
+pub const OIT_BUFFERS: [(&str, RenderTargetFormat); 2] = [
+    ("accum", RenderTargetFormat::Rgba16F),
+    ("revealage", RenderTargetFormat::R16F),
+];
+
 #[derive(Debug, PartialEq)]
 pub struct RenderTargetDef {
     pub name: String,
@@ -184,16 +875,56 @@ pub struct RenderTargetDef {
     pub height: ValueExpr,
     pub formats: Vec<(String, RenderTargetFormat)>,
     pub has_depth: bool,
+    // Cleared to the initial values of the OIT buffers, see `OIT_BUFFERS`
+    pub oit: bool,
 }
 impl RenderTargetDef {
     pub fn from_ast(source: &str, op: &ast::RenderTargetDef) -> Result<Self, SemanticError> {
+        // The size of render targets can only depend on globals
+        let mut width = ValueExpr::from_ast(source, &op.width)?;
+        let mut height = ValueExpr::from_ast(source, &op.height)?;
+        width.resolve_variables(&[]);
+        height.resolve_variables(&[]);
+
+        // Depth buffers come last, so that the color buffers are numbered like their attachments
+        let mut depth_buffer: Option<&ast::SourceSlice> = None;
+        for (name, format) in &op.formats {
+            if let Some(depth_buffer) = depth_buffer {
+                return Err(SemanticError::error_from_ast(
+                    name,
+                    format!(
+                        "Buffers cannot follow the depth buffer `{}`",
+                        depth_buffer.to_slice(source)
+                    ),
+                ));
+            }
+            if format.is_depth() {
+                if op.has_depth {
+                    return Err(SemanticError::error_from_ast(
+                        name,
+                        format!("A target defined with `define_rt_with_depth` cannot have a depth buffer"),
+                    ));
+                }
+                depth_buffer = Some(name);
+            }
+        }
+
+        let formats = if op.oit {
+            OIT_BUFFERS
+                .iter()
+                .map(|&(name, format)| (name.to_owned(), format))
+                .collect()
+        } else {
+            op.formats.iter().map(|f| (f.0.to_owned(source), f.1)).collect()
+        };
         Ok(RenderTargetDef {
             name: op.name.to_slice(source).to_owned(),
 
-            width: ValueExpr::from_ast(source, &op.width)?,
-            height: ValueExpr::from_ast(source, &op.height)?,
-            formats: op.formats.iter().map(|f| (f.0.to_owned(source), f.1)).collect(),
+            width: width,
+            height: height,
+            formats: formats,
             has_depth: op.has_depth,
+            oit: op.oit,
         })
     }
 }
@@ -206,6 +937,8 @@ pub struct ProgramDef {
     pub geom: Option<String>,
     pub frag: Option<String>,
     pub comp: Option<String>,
+    // Defines the SDF of a program which raymarches the scene, see `postfx::RAYMARCH_FRAGMENT_HEADER`
+    pub raymarch: Option<String>,
 }
 impl ProgramDef {
     pub fn from_ast(source: &str, op: &ast::ValueExpr) -> Result<Self, SemanticError> {
@@ -216,6 +949,7 @@ impl ProgramDef {
             geom: None,
             frag: None,
             comp: None,
+            raymarch: None,
         };
 
         let dict = &op
@@ -227,7 +961,11 @@ impl ProgramDef {
             let shader_source = expect_ast_string(&kv.value, source)?;
             match shader_type.as_ref() {
                 "vert" => program.vert = Some(shader_source.to_owned()),
+                "tess_ctrl" => program.tess_ctrl = Some(shader_source.to_owned()),
+                "tess_eval" => program.tess_eval = Some(shader_source.to_owned()),
+                "geom" => program.geom = Some(shader_source.to_owned()),
                 "frag" => program.frag = Some(shader_source.to_owned()),
+                "raymarch" => program.raymarch = Some(shader_source.to_owned()),
                 _ => {
                     return Err(SemanticError::error_from_ast(
                         &kv.key,
@@ -237,39 +975,127 @@ impl ProgramDef {
             }
         }
 
-        if program.vert.is_none() || program.frag.is_none() {
+        let has_extra_stages = program.tess_ctrl.is_some() || program.tess_eval.is_some() || program.geom.is_some();
+        if program.raymarch.is_some() {
+            if program.vert.is_some() || program.frag.is_some() || has_extra_stages {
+                return Err(SemanticError::error_from_ast(
+                    op,
+                    format!("raymarch programs cannot have other shaders"),
+                ));
+            }
+        } else if program.vert.is_none() || program.frag.is_none() {
             return Err(SemanticError::error_from_ast(
                 op,
                 format!("vert and frag shaders are mandatory!"),
             ));
+        } else if program.tess_ctrl.is_some() && program.tess_eval.is_none() {
+            // The evaluation stage is what generates the primitives, a control stage alone does nothing
+            return Err(SemanticError::error_from_ast(
+                op,
+                format!("tess_ctrl shaders need a tess_eval shader"),
+            ));
         }
         return Ok(program);
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSignature {
+    pub params: Vec<(String, ast::Type)>,
+    pub return_type: Option<ast::Type>,
+}
+impl FunctionSignature {
+    pub fn from_ast(source: &str, ast: &ast::Function) -> Self {
+        FunctionSignature {
+            params: ast
+                .params
+                .iter()
+                .map(|p| (p.name.to_owned(source), p.value_type))
+                .collect(),
+            return_type: ast.return_type,
+        }
+    }
+}
+
 pub struct ProgramHeader {
+    options: CompileOptions,
+    metadata: Metadata,
     sync_tracks: HashSet<String>,
+    track_hints: Vec<TrackHint>,
+    uniform_names: Vec<String>,
+    function_signatures: HashMap<String, FunctionSignature>,
     target_defs: Vec<RenderTargetDef>,
     program_defs: Vec<ProgramDef>,
-    model_defs: Vec<String>,
+    model_defs: Vec<ModelDef>,
     texture_defs: Vec<TextureDef>,
     ibl_defs: Vec<IblDef>,
+    scene_objects: Vec<SceneObjectDef>,
+    batch_defs: Vec<BatchDef>,
+    lights: Vec<LightDef>,
     external_res: HashSet<String>,
 }
 impl ProgramHeader {
-    pub fn new() -> Self {
+    pub fn new(options: CompileOptions) -> Self {
         ProgramHeader {
+            options: options,
+            metadata: Metadata::default(),
             sync_tracks: HashSet::new(),
+            track_hints: Vec::new(),
+            uniform_names: Vec::new(),
+            function_signatures: HashMap::new(),
 
             target_defs: Vec::new(),
             program_defs: Vec::new(),
             model_defs: Vec::new(),
             texture_defs: Vec::new(),
             ibl_defs: Vec::new(),
+            scene_objects: Vec::new(),
+            batch_defs: Vec::new(),
+            lights: Vec::new(),
             external_res: HashSet::new(),
         }
     }
 }
+impl Serialize for ProgramHeader {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.options);
+        writer.write(&self.metadata);
+        writer.write(&self.sync_tracks);
+        writer.write(&self.track_hints);
+        writer.write(&self.uniform_names);
+        writer.write(&self.function_signatures);
+        writer.write(&self.target_defs);
+        writer.write(&self.program_defs);
+        writer.write(&self.model_defs);
+        writer.write(&self.texture_defs);
+        writer.write(&self.ibl_defs);
+        writer.write(&self.scene_objects);
+        writer.write(&self.batch_defs);
+        writer.write(&self.lights);
+        writer.write(&self.external_res);
+    }
+}
+impl Deserialize for ProgramHeader {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(ProgramHeader {
+            options: reader.read()?,
+            metadata: reader.read()?,
+            sync_tracks: reader.read()?,
+            track_hints: reader.read()?,
+            uniform_names: reader.read()?,
+            function_signatures: reader.read()?,
+            target_defs: reader.read()?,
+            program_defs: reader.read()?,
+            model_defs: reader.read()?,
+            texture_defs: reader.read()?,
+            ibl_defs: reader.read()?,
+            scene_objects: reader.read()?,
+            batch_defs: reader.read()?,
+            lights: reader.read()?,
+            external_res: reader.read()?,
+        })
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct FunctionCall {
@@ -280,47 +1106,299 @@ pub struct FunctionCall {
 #[derive(Debug)]
 pub enum BytecodeOp {
     BindRt(u32),
+    BindScaledRt(u32, ValueExpr), // target, f32 fraction of its size the pass is drawn at
     BindScreenRt,
+    InvalidateRt, // discards the contents of the bound target
     BindProgram(u32),
 
     Viewport(ValueExpr, ValueExpr, ValueExpr, ValueExpr), // f32, f32, f32, f32
     Clear(ValueExpr),                                     // color
+    ClearOit,                                             // clears the buffers of an OIT target
+    ClearBuffers {
+        colors: Vec<(u32, ValueExpr)>, // buffer, color
+        depth: Option<ValueExpr>,      // f32
+    },
 
     PipelineSetBlending(u32, BlendMode),        // buffer, blending
     PipelineSetWriteMask(ValueExpr, ValueExpr), // write_color, write_depth
     PipelineSetZTest(ZTestMode),
     PipelineSetCulling(CullingMode),
+    PipelineSetClipPlane(u32, ValueExpr, ValueExpr, ValueExpr, ValueExpr), // index, f32 a, b, c, d of ax+by+cz+d=0
 
-    UniformFloat(String, ValueExpr),
-    UniformColor(String, ValueExpr),
-    UniformTexture(String, u32),
+    UniformFloat(u32, ValueExpr), // uniform, value
+    UniformColor(u32, ValueExpr), // uniform, value
+    UniformVec3(u32, ValueExpr),  // uniform, value
+    UniformMat4(u32, ValueExpr),  // uniform, value
+    UniformTexture(u32, u32),     // uniform, texture
     UniformIbl(u32),
-    UniformRt(String, u32, u32),
+    UniformRt(u32, u32, u32, bool), // uniform, target, buffer, srgb_decode
 
     DrawQuad,
-    DrawModel(u32),
+    DrawModel(u32, bool), // model, bind the materials of its MTL file
+    // Members of the `gl_resources::DRAW_PARAMS_BLOCK` by name, for the next draw
+    BindDrawParams(Vec<(String, ValueExpr)>),
+    DrawScene,
+    DrawBatch(u32),  // batch
+    DrawSkybox(u32), // ibl
+    CameraLookAt {
+        eye: ValueExpr,    // vec3
+        center: ValueExpr, // vec3
+        up: ValueExpr,     // vec3
+    },
+    CameraPerspective {
+        fov_y: ValueExpr, // f32 in radians
+        near: ValueExpr,  // f32
+        far: ValueExpr,   // f32
+    },
+    SetModelTransform(ValueExpr), // mat4
+    Begin2d,
+    End2d,
+    DrawRect {
+        x: ValueExpr,
+        y: ValueExpr,
+        width: ValueExpr,
+        height: ValueExpr,
+        color: ValueExpr,
+    },
+    DrawTexturedQuad {
+        x: ValueExpr,
+        y: ValueExpr,
+        width: ValueExpr,
+        height: ValueExpr,
+        texture: u32,
+    },
+    GrabScreen(u32), // uniform
+    // Buffers are given as (target, buffer)
+    PostTaa {
+        color: (u32, u32),
+        depth: (u32, u32),
+        motion: (u32, u32),
+        history: u32,
+    },
+    PostAutoExposure {
+        source: (u32, u32),
+        target: u32,
+        speed: ValueExpr, // f32, in 1/s
+    },
+    PostVolumetrics {
+        depth: (u32, u32),
+        lights: Vec<u32>,
+        target: u32,
+        density: ValueExpr, // f32, extinction per unit of distance
+    },
+    PostOitResolve {
+        accum: (u32, u32),
+        revealage: (u32, u32),
+        target: u32,
+    },
+    PostLens {
+        effect: LensEffect,
+        source: (u32, u32),
+        param: ValueExpr, // f32, the strength of the effect, or the seed of the grain
+    },
+
+    Print {
+        args: Vec<ValueExpr>,
+        line: Option<usize>, // only set for `trace`
+    },
+    Assert {
+        condition: ValueExpr,
+        message: String,
+        line: usize,
+        halt: bool,
+    },
+    SetTitle(ValueExpr),   // title of the window, any value
+    ShowCursor(ValueExpr), // f32, the cursor is shown over the window if positive
 
     FunctionCall(FunctionCall),
     Return {
         expr: ValueExpr,
     },
+    // Stores the value in the slot of a local, dropping the locals of ended scopes above it
+    Let {
+        slot: usize,
+        name: String,
+        expr: ValueExpr,
+    },
 
-    Conditional {
+    // Control flow, offsets are relative to the jump op itself
+    Jump {
+        offset: isize,
+    },
+    JumpIfNot {
         condition: ValueExpr,
-        a: BlockBytecode,
-        b: Option<BlockBytecode>,
+        offset: isize,
     },
 }
+impl BytecodeOp {
+    /// The expressions which are evaluated when the op is executed
+    fn exprs(&self) -> Vec<&ValueExpr> {
+        match self {
+            BytecodeOp::BindScaledRt(_, scale) => vec![scale],
+            BytecodeOp::Viewport(x, y, w, h) => vec![x, y, w, h],
+            BytecodeOp::Clear(color) => vec![color],
+            BytecodeOp::ClearBuffers { colors, depth } => colors.iter().map(|c| &c.1).chain(depth).collect(),
+            BytecodeOp::PipelineSetWriteMask(write_color, write_depth) => vec![write_color, write_depth],
+            BytecodeOp::PipelineSetClipPlane(_, a, b, c, d) => vec![a, b, c, d],
+            BytecodeOp::UniformFloat(_, value)
+            | BytecodeOp::UniformColor(_, value)
+            | BytecodeOp::UniformVec3(_, value)
+            | BytecodeOp::UniformMat4(_, value) => vec![value],
+            BytecodeOp::CameraLookAt { eye, center, up } => vec![eye, center, up],
+            BytecodeOp::CameraPerspective { fov_y, near, far } => vec![fov_y, near, far],
+            BytecodeOp::SetModelTransform(matrix) => vec![matrix],
+            BytecodeOp::PostAutoExposure { speed, .. } => vec![speed],
+            BytecodeOp::PostVolumetrics { density, .. } => vec![density],
+            BytecodeOp::PostLens { param, .. } => vec![param],
+            BytecodeOp::DrawRect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => vec![x, y, width, height, color],
+            BytecodeOp::DrawTexturedQuad {
+                x, y, width, height, ..
+            } => vec![x, y, width, height],
+            BytecodeOp::Print { args, .. } => args.iter().collect(),
+            BytecodeOp::BindDrawParams(params) => params.iter().map(|p| &p.1).collect(),
+            BytecodeOp::Assert { condition, .. } => vec![condition],
+            BytecodeOp::SetTitle(title) => vec![title],
+            BytecodeOp::ShowCursor(show) => vec![show],
+            BytecodeOp::FunctionCall(function_call) => function_call.args.iter().collect(),
+            BytecodeOp::Return { expr } | BytecodeOp::Let { expr, .. } => vec![expr],
+            BytecodeOp::JumpIfNot { condition, .. } => vec![condition],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Resolves the variables of the expressions to slots, see `ValueExpr::resolve_variables`
+    fn resolve_variables(&mut self, locals: &[String]) {
+        match self {
+            BytecodeOp::Viewport(x, y, w, h) => {
+                x.resolve_variables(locals);
+                y.resolve_variables(locals);
+                w.resolve_variables(locals);
+                h.resolve_variables(locals);
+            }
+            BytecodeOp::BindScaledRt(_, scale) => scale.resolve_variables(locals),
+            BytecodeOp::Clear(color) => color.resolve_variables(locals),
+            BytecodeOp::ClearBuffers { colors, depth } => {
+                for (_, color) in colors {
+                    color.resolve_variables(locals);
+                }
+                if let Some(depth) = depth {
+                    depth.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::PipelineSetWriteMask(write_color, write_depth) => {
+                write_color.resolve_variables(locals);
+                write_depth.resolve_variables(locals);
+            }
+            BytecodeOp::PipelineSetClipPlane(_, a, b, c, d) => {
+                for value in vec![a, b, c, d] {
+                    value.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::UniformFloat(_, value)
+            | BytecodeOp::UniformColor(_, value)
+            | BytecodeOp::UniformVec3(_, value)
+            | BytecodeOp::UniformMat4(_, value) => value.resolve_variables(locals),
+            BytecodeOp::CameraLookAt { eye, center, up } => {
+                for value in vec![eye, center, up] {
+                    value.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::CameraPerspective { fov_y, near, far } => {
+                for value in vec![fov_y, near, far] {
+                    value.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::SetModelTransform(matrix) => matrix.resolve_variables(locals),
+            BytecodeOp::PostAutoExposure { speed, .. } => speed.resolve_variables(locals),
+            BytecodeOp::PostVolumetrics { density, .. } => density.resolve_variables(locals),
+            BytecodeOp::PostLens { param, .. } => param.resolve_variables(locals),
+            BytecodeOp::DrawRect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => {
+                for value in vec![x, y, width, height, color] {
+                    value.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::DrawTexturedQuad {
+                x, y, width, height, ..
+            } => {
+                for value in vec![x, y, width, height] {
+                    value.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::Print { args, .. } => {
+                for arg in args {
+                    arg.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::BindDrawParams(params) => {
+                for (_, value) in params {
+                    value.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::Assert { condition, .. } => condition.resolve_variables(locals),
+            BytecodeOp::SetTitle(value) | BytecodeOp::ShowCursor(value) => value.resolve_variables(locals),
+            BytecodeOp::FunctionCall(function_call) => {
+                for arg in &mut function_call.args {
+                    arg.resolve_variables(locals);
+                }
+            }
+            BytecodeOp::Return { expr } | BytecodeOp::Let { expr, .. } => expr.resolve_variables(locals),
+            BytecodeOp::JumpIfNot { condition, .. } => condition.resolve_variables(locals),
+            _ => {}
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct BlockBytecode {
     bytecode: Vec<BytecodeOp>,
+    // The statement which generated each op
+    source_slices: Vec<SourceSlice>,
+}
+impl Serialize for BlockBytecode {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.bytecode);
+        writer.write(&self.source_slices);
+    }
+}
+impl Deserialize for BlockBytecode {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(BlockBytecode {
+            bytecode: reader.read()?,
+            source_slices: reader.read()?,
+        })
+    }
 }
 impl BlockBytecode {
-    pub fn from_ast(source: &str, block: &Vec<Stmt>, header: &ProgramHeader) -> Result<Self, SemanticError> {
-        let mut bytecode = BlockBytecode { bytecode: Vec::new() };
+    /// Compiles the statements of a block, resolving their variables to slots
+    ///
+    /// The locals are a stack of the names in scope, on top of the function parameters. Each `let` pushes a new slot,
+    /// so it shadows earlier locals of the same name, and the slots of the block are popped at its end.
+    pub fn from_ast(
+        source: &str,
+        block: &Vec<Stmt>,
+        header: &ProgramHeader,
+        locals: &mut Vec<String>,
+    ) -> Result<Self, SemanticError> {
+        let mut bytecode = BlockBytecode {
+            bytecode: Vec::new(),
+            source_slices: Vec::new(),
+        };
+        let scope = locals.len();
 
         for op in block {
+            let first = bytecode.bytecode.len();
             match op {
                 ast::Stmt::FunctionCall(function_call) => {
                     if function_call.function.to_slice(source) == "program" {
@@ -335,34 +1413,111 @@ impl BlockBytecode {
                         bytecode.emit_pipeline_set_ztest(source, function_call)?;
                     } else if function_call.function.to_slice(source) == "pipeline_set_culling" {
                         bytecode.emit_pipeline_set_culling(source, function_call)?;
+                    } else if function_call.function.to_slice(source) == "set_clip_plane" {
+                        bytecode.emit_set_clip_plane(source, function_call)?;
                     } else if function_call.function.to_slice(source) == "uniform_float" {
                         Self::expect_args_count(function_call, 2)?;
                         bytecode.bytecode.push(BytecodeOp::UniformFloat(
-                            expect_ast_string(&function_call.args[0], source)?,
+                            Self::uniform_id(source, &function_call.args[0], &header.uniform_names)?,
                             ValueExpr::from_ast(source, &function_call.args[1])?,
                         ));
                     } else if function_call.function.to_slice(source) == "uniform_color" {
                         Self::expect_args_count(function_call, 2)?;
                         bytecode.bytecode.push(BytecodeOp::UniformColor(
-                            expect_ast_string(&function_call.args[0], source)?,
+                            Self::uniform_id(source, &function_call.args[0], &header.uniform_names)?,
+                            ValueExpr::from_ast(source, &function_call.args[1])?,
+                        ));
+                    } else if function_call.function.to_slice(source) == "uniform_vec3" {
+                        Self::expect_args_count(function_call, 2)?;
+                        bytecode.bytecode.push(BytecodeOp::UniformVec3(
+                            Self::uniform_id(source, &function_call.args[0], &header.uniform_names)?,
+                            ValueExpr::from_ast(source, &function_call.args[1])?,
+                        ));
+                    } else if function_call.function.to_slice(source) == "uniform_mat4" {
+                        Self::expect_args_count(function_call, 2)?;
+                        bytecode.bytecode.push(BytecodeOp::UniformMat4(
+                            Self::uniform_id(source, &function_call.args[0], &header.uniform_names)?,
                             ValueExpr::from_ast(source, &function_call.args[1])?,
                         ));
                     } else if function_call.function.to_slice(source) == "uniform_texture_srgb" {
-                        bytecode.emit_uniform_texture(source, function_call, &header.texture_defs, true)?;
+                        bytecode.emit_uniform_texture(source, function_call, header, true)?;
                     } else if function_call.function.to_slice(source) == "uniform_texture_linear" {
-                        bytecode.emit_uniform_texture(source, function_call, &header.texture_defs, false)?;
+                        bytecode.emit_uniform_texture(source, function_call, header, false)?;
                     } else if function_call.function.to_slice(source) == "uniform_ibl" {
                         bytecode.emit_uniform_ibl(source, function_call, &header.ibl_defs)?;
                     } else if function_call.function.to_slice(source) == "uniform_rtt" {
-                        bytecode.emit_uniform_render_target_as_texture(source, function_call, &header.target_defs)?
+                        bytecode.emit_uniform_render_target_as_texture(source, function_call, header)?
                     } else if function_call.function.to_slice(source) == "draw_fullscreenquad" {
                         bytecode.bytecode.push(BytecodeOp::DrawQuad);
+                    } else if function_call.function.to_slice(source) == "grab_screen" {
+                        Self::expect_args_count(function_call, 1)?;
+                        bytecode.bytecode.push(BytecodeOp::GrabScreen(Self::uniform_id(
+                            source,
+                            &function_call.args[0],
+                            &header.uniform_names,
+                        )?));
+                    } else if function_call.function.to_slice(source) == "post_taa" {
+                        bytecode.emit_post_taa(source, function_call, &header.target_defs)?;
+                    } else if function_call.function.to_slice(source) == "post_auto_exposure" {
+                        bytecode.emit_post_auto_exposure(source, function_call, &header.target_defs)?;
+                    } else if function_call.function.to_slice(source) == "post_volumetrics" {
+                        bytecode.emit_post_volumetrics(source, function_call, header)?;
+                    } else if function_call.function.to_slice(source) == "post_oit_resolve" {
+                        bytecode.emit_post_oit_resolve(source, function_call, &header.target_defs)?;
+                    } else if let Some(effect) = LensEffect::from_function(function_call.function.to_slice(source)) {
+                        bytecode.emit_post_lens(source, function_call, effect, &header.target_defs)?;
+                    } else if function_call.function.to_slice(source) == "begin_2d" {
+                        Self::expect_args_count(function_call, 0)?;
+                        bytecode.bytecode.push(BytecodeOp::Begin2d);
+                    } else if function_call.function.to_slice(source) == "end_2d" {
+                        Self::expect_args_count(function_call, 0)?;
+                        bytecode.bytecode.push(BytecodeOp::End2d);
+                    } else if function_call.function.to_slice(source) == "draw_rect" {
+                        Self::expect_args_count(function_call, 5)?;
+                        bytecode.bytecode.push(BytecodeOp::DrawRect {
+                            x: ValueExpr::from_ast(source, &function_call.args[0])?,
+                            y: ValueExpr::from_ast(source, &function_call.args[1])?,
+                            width: ValueExpr::from_ast(source, &function_call.args[2])?,
+                            height: ValueExpr::from_ast(source, &function_call.args[3])?,
+                            color: ValueExpr::from_ast(source, &function_call.args[4])?,
+                        });
+                    } else if function_call.function.to_slice(source) == "draw_textured_quad" {
+                        bytecode.emit_draw_textured_quad(source, function_call, &header.texture_defs)?;
                     } else if function_call.function.to_slice(source) == "draw_model" {
-                        bytecode.emit_draw_model(source, function_call, &header.model_defs)?;
-                    } else if function_call.function.to_slice(source) == "clear" {
+                        bytecode.emit_draw_model(source, function_call, header)?;
+                    } else if function_call.function.to_slice(source) == "draw_scene" {
+                        Self::expect_args_count(function_call, 0)?;
+                        bytecode.bytecode.push(BytecodeOp::DrawScene);
+                    } else if function_call.function.to_slice(source) == "draw_batch" {
+                        bytecode.emit_draw_batch(source, function_call, header)?;
+                    } else if function_call.function.to_slice(source) == "draw_skybox" {
+                        bytecode.emit_draw_skybox(source, function_call, &header.ibl_defs)?;
+                    } else if function_call.function.to_slice(source) == "camera_lookat" {
+                        Self::expect_args_count(function_call, 3)?;
+                        bytecode.bytecode.push(BytecodeOp::CameraLookAt {
+                            eye: ValueExpr::from_ast(source, &function_call.args[0])?,
+                            center: ValueExpr::from_ast(source, &function_call.args[1])?,
+                            up: ValueExpr::from_ast(source, &function_call.args[2])?,
+                        });
+                    } else if function_call.function.to_slice(source) == "camera_perspective" {
+                        Self::expect_args_count(function_call, 3)?;
+                        bytecode.bytecode.push(BytecodeOp::CameraPerspective {
+                            fov_y: ValueExpr::from_ast(source, &function_call.args[0])?,
+                            near: ValueExpr::from_ast(source, &function_call.args[1])?,
+                            far: ValueExpr::from_ast(source, &function_call.args[2])?,
+                        });
+                    } else if function_call.function.to_slice(source) == "set_model_transform" {
                         Self::expect_args_count(function_call, 1)?;
-                        let linear = ValueExpr::from_ast(source, &function_call.args[0])?;
-                        bytecode.bytecode.push(BytecodeOp::Clear(linear));
+                        let matrix = ValueExpr::from_ast(source, &function_call.args[0])?;
+                        bytecode.bytecode.push(BytecodeOp::SetModelTransform(matrix));
+                    } else if function_call.function.to_slice(source) == "clear" {
+                        bytecode.emit_clear(source, function_call)?;
+                    } else if function_call.function.to_slice(source) == "print" {
+                        bytecode.emit_print(source, function_call, false)?;
+                    } else if function_call.function.to_slice(source) == "trace" {
+                        bytecode.emit_print(source, function_call, true)?;
+                    } else if function_call.function.to_slice(source) == "assert" {
+                        bytecode.emit_assert(source, function_call, header.options.assert_mode)?;
                     } else if function_call.function.to_slice(source) == "viewport" {
                         Self::expect_args_count(function_call, 4)?;
                         let x = ValueExpr::from_ast(source, &function_call.args[0])?;
@@ -370,30 +1525,85 @@ impl BlockBytecode {
                         let w = ValueExpr::from_ast(source, &function_call.args[2])?;
                         let h = ValueExpr::from_ast(source, &function_call.args[3])?;
                         bytecode.emit_viewport(x, y, w, h);
+                    } else if function_call.function.to_slice(source) == "set_title" {
+                        Self::expect_args_count(function_call, 1)?;
+                        let title = ValueExpr::from_ast(source, &function_call.args[0])?;
+                        bytecode.bytecode.push(BytecodeOp::SetTitle(title));
+                    } else if function_call.function.to_slice(source) == "show_cursor" {
+                        Self::expect_args_count(function_call, 1)?;
+                        let show = ValueExpr::from_ast(source, &function_call.args[0])?;
+                        bytecode.bytecode.push(BytecodeOp::ShowCursor(show));
                     } else {
-                        bytecode.emit_function_call(source, &function_call.function, &function_call.args)?;
+                        bytecode.emit_function_call(source, function_call, &header.function_signatures)?;
                     }
                 }
                 ast::Stmt::Return { expr } => bytecode.bytecode.push(BytecodeOp::Return {
                     expr: ValueExpr::from_ast(source, expr)?,
                 }),
+                ast::Stmt::Let { name, expr } => {
+                    // The value is resolved before the local exists, so it refers to a shadowed local of the same name
+                    let mut expr = ValueExpr::from_ast(source, expr)?;
+                    expr.resolve_variables(locals);
+                    locals.push(name.to_owned(source));
+                    bytecode.bytecode.push(BytecodeOp::Let {
+                        slot: locals.len() - 1,
+                        name: name.to_owned(source),
+                        expr: expr,
+                    });
+                }
 
                 ast::Stmt::Conditional { condition, a, b } => {
-                    let condition = ValueExpr::from_ast(source, condition)?;
-                    let a = BlockBytecode::from_ast(source, a, header)?;
+                    let mut condition = ValueExpr::from_ast(source, condition)?;
+                    condition.resolve_variables(locals);
+                    let a = BlockBytecode::from_ast(source, a, header, locals)?;
                     let b = b
                         .as_ref()
-                        .map(|b| BlockBytecode::from_ast(source, b, header))
+                        .map(|b| BlockBytecode::from_ast(source, b, header, locals))
                         .transpose()?;
-                    bytecode.bytecode.push(BytecodeOp::Conditional {
-                        condition: condition,
-                        a: a,
-                        b: b,
-                    });
+                    bytecode.emit_conditional(op.source_slice(), condition, a, b);
+                }
+                ast::Stmt::Loop {
+                    variable,
+                    start: start_ast,
+                    end: end_ast,
+                    block,
+                } => {
+                    let mut start = ValueExpr::from_ast(source, start_ast)?;
+                    let mut end = ValueExpr::from_ast(source, end_ast)?;
+                    for (bound, value) in &[(start_ast, &start), (end_ast, &end)] {
+                        if let ValueExpr::ConstFloat(value) = value {
+                            if !(value.abs() <= MAX_LOOP_BOUND) {
+                                return Err(SemanticError::error_from_ast(*bound, loop_bounds_error()));
+                            }
+                        }
+                    }
+                    start.resolve_variables(locals);
+                    end.resolve_variables(locals);
+
+                    // The end is evaluated once, into a hidden local below the loop variable
+                    let variable = variable.to_owned(source);
+                    let end_slot = locals.len();
+                    locals.push(format!("{}.end", variable));
+                    locals.push(variable.clone());
+                    let body = BlockBytecode::from_ast(source, block, header, locals)?;
+                    locals.truncate(end_slot);
+                    let line = line_number(op, source);
+                    bytecode.emit_loop(op.source_slice(), line, end_slot, variable, start, end, body);
+                }
+            }
+
+            // The ops of nested blocks are already resolved in their own scope
+            if let ast::Stmt::FunctionCall(_) | ast::Stmt::Return { .. } = op {
+                for op in &mut bytecode.bytecode[first..] {
+                    op.resolve_variables(locals);
                 }
             }
+
+            let slice = op.source_slice();
+            bytecode.source_slices.resize(bytecode.bytecode.len(), slice);
         }
 
+        locals.truncate(scope);
         Ok(bytecode)
     }
 
@@ -401,7 +1611,152 @@ impl BlockBytecode {
         &self.bytecode
     }
 
+    pub fn get_source_slices(&self) -> &[SourceSlice] {
+        &self.source_slices
+    }
+
+    /// Resolves the variables of all ops to slots, see `ValueExpr::resolve_variables`
+    pub fn resolve_variables(&mut self, locals: &[String]) {
+        for op in &mut self.bytecode {
+            op.resolve_variables(locals);
+        }
+    }
+
+    /// Removes binds of programs and render targets which are already bound
+    ///
+    /// Binding a target resets the state of the pass, so the bind is kept if the viewport or the pipeline state changed
+    /// since the target was bound. What is bound is forgotten at jumps, their targets, and calls of user functions.
+    pub fn eliminate_redundant_binds(&mut self, user_functions: &HashMap<String, FunctionSignature>) {
+        let jump_targets: HashSet<usize> = self
+            .bytecode
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| match op {
+                BytecodeOp::Jump { offset } | BytecodeOp::JumpIfNot { offset, .. } => {
+                    Some((i as isize + offset) as usize)
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut keep = vec![true; self.bytecode.len()];
+        let mut program = None;
+        // The bound target (`None` for the screen), as long as the state of its pass did not change
+        let mut target: Option<Option<u32>> = None;
+        for (i, op) in self.bytecode.iter().enumerate() {
+            if jump_targets.contains(&i) || op.exprs().iter().any(|e| e.calls_any(user_functions)) {
+                program = None;
+                target = None;
+            }
+            match op {
+                BytecodeOp::BindProgram(program_id) => {
+                    keep[i] = program != Some(*program_id);
+                    program = Some(*program_id);
+                }
+                BytecodeOp::BindRt(rt_id) => {
+                    keep[i] = target != Some(Some(*rt_id));
+                    target = Some(Some(*rt_id));
+                }
+                BytecodeOp::BindScreenRt => {
+                    keep[i] = target != Some(None);
+                    target = Some(None);
+                }
+                // Scaled passes end by blending over their target, binding them again starts a new pass
+                BytecodeOp::BindScaledRt(..) => target = None,
+                BytecodeOp::Viewport(..)
+                | BytecodeOp::PipelineSetBlending(..)
+                | BytecodeOp::PipelineSetWriteMask(..)
+                | BytecodeOp::PipelineSetZTest(..)
+                | BytecodeOp::PipelineSetCulling(..)
+                | BytecodeOp::PipelineSetClipPlane(..) => target = None,
+                BytecodeOp::InvalidateRt
+                | BytecodeOp::Clear(..)
+                | BytecodeOp::ClearBuffers { .. }
+                | BytecodeOp::UniformFloat(..)
+                | BytecodeOp::UniformColor(..)
+                | BytecodeOp::UniformVec3(..)
+                | BytecodeOp::UniformMat4(..)
+                | BytecodeOp::UniformTexture(..)
+                | BytecodeOp::UniformIbl(..)
+                | BytecodeOp::UniformRt(..)
+                | BytecodeOp::DrawQuad
+                | BytecodeOp::DrawModel(..)
+                | BytecodeOp::BindDrawParams(..)
+                | BytecodeOp::DrawBatch(..)
+                | BytecodeOp::CameraLookAt { .. }
+                | BytecodeOp::CameraPerspective { .. }
+                | BytecodeOp::SetModelTransform(..)
+                | BytecodeOp::DrawRect { .. }
+                | BytecodeOp::DrawTexturedQuad { .. }
+                | BytecodeOp::Print { .. }
+                | BytecodeOp::Assert { .. }
+                | BytecodeOp::SetTitle(..)
+                | BytecodeOp::ShowCursor(..)
+                | BytecodeOp::Let { .. } => {}
+                // Internal passes unbind the program, everything else may bind anything
+                _ => {
+                    program = None;
+                    target = None;
+                }
+            }
+        }
+        self.remove_ops(&keep);
+    }
+
+    /// Removes the ops which are not kept, and moves the offsets of the jumps accordingly
+    fn remove_ops(&mut self, keep: &[bool]) {
+        // Index of each op after the removal, and of the end of the block
+        let mut new_indices = Vec::with_capacity(keep.len() + 1);
+        let mut kept = 0;
+        for &k in keep {
+            new_indices.push(kept as isize);
+            if k {
+                kept += 1;
+            }
+        }
+        new_indices.push(kept as isize);
+
+        for (i, op) in self.bytecode.iter_mut().enumerate() {
+            match op {
+                BytecodeOp::Jump { offset } | BytecodeOp::JumpIfNot { offset, .. } => {
+                    let target = (i as isize + *offset) as usize;
+                    *offset = new_indices[target] - new_indices[i];
+                }
+                _ => {}
+            }
+        }
+
+        // `retain` visits the elements in order
+        let mut kept = keep.iter();
+        self.bytecode.retain(|_| *kept.next().unwrap());
+        let mut kept = keep.iter();
+        self.source_slices.retain(|_| *kept.next().unwrap());
+    }
+
+    fn uniform_id(source: &str, uniform: &ast::ValueExpr, uniform_names: &[String]) -> Result<u32, SemanticError> {
+        let uniform_name = expect_ast_string(uniform, source)?;
+        definition_index(uniform_names, &uniform_name, uniform, "uniform")
+    }
+
+    fn expect_no_named_args(function_call: &ast::FunctionCallExpr) -> Result<(), SemanticError> {
+        match function_call.named_args.first() {
+            Some(named_arg) => Err(SemanticError::error_from_ast(
+                named_arg,
+                format!("Named arguments are not supported by built-in functions"),
+            )),
+            None => Ok(()),
+        }
+    }
+
     fn expect_args_count(function_call: &ast::FunctionCallExpr, args_count: usize) -> Result<(), SemanticError> {
+        Self::expect_no_named_args(function_call)?;
+        Self::expect_positional_args_count(function_call, args_count)
+    }
+
+    fn expect_positional_args_count(
+        function_call: &ast::FunctionCallExpr,
+        args_count: usize,
+    ) -> Result<(), SemanticError> {
         if function_call.args.len() == args_count {
             Ok(())
         } else {
@@ -416,6 +1771,143 @@ impl BlockBytecode {
         }
     }
 
+    /// Inlines both branches of a conditional, connecting them with jumps
+    ///
+    /// The generated ops look like this:
+    /// ```text
+    ///     JumpIfNot(condition) -> else
+    ///     <a>
+    ///     Jump -> end                     (only if there is an else branch)
+    /// else:
+    ///     <b>
+    /// end:
+    /// ```
+    fn emit_conditional(
+        &mut self,
+        slice: SourceSlice,
+        condition: ValueExpr,
+        a: BlockBytecode,
+        b: Option<BlockBytecode>,
+    ) {
+        let skip_a = a.bytecode.len() + if b.is_some() { 2 } else { 1 };
+        self.push_op(
+            BytecodeOp::JumpIfNot {
+                condition: condition,
+                offset: skip_a as isize,
+            },
+            slice,
+        );
+        self.append(a);
+
+        if let Some(b) = b {
+            self.push_op(
+                BytecodeOp::Jump {
+                    offset: b.bytecode.len() as isize + 1,
+                },
+                slice,
+            );
+            self.append(b);
+        }
+    }
+
+    /// Emits a counted loop, with the end of the range in the given slot and the loop variable in the slot above it
+    ///
+    /// Assigning the variable drops the locals of the previous iteration, which are all above it. Bounds which are not
+    /// constant are checked when the loop starts, since the loop would never end with bounds beyond `MAX_LOOP_BOUND`.
+    fn emit_loop(
+        &mut self,
+        slice: SourceSlice,
+        line: usize,
+        end_slot: usize,
+        variable: String,
+        start: ValueExpr,
+        end: ValueExpr,
+        body: BlockBytecode,
+    ) {
+        let end_name = format!("{}.end", variable);
+        let variable_slot = end_slot + 1;
+        let current = ValueExpr::Local(variable_slot, variable.clone());
+        self.push_op(
+            BytecodeOp::Let {
+                slot: end_slot,
+                name: end_name.clone(),
+                expr: end,
+            },
+            slice,
+        );
+        self.push_op(
+            BytecodeOp::Let {
+                slot: variable_slot,
+                name: variable.clone(),
+                expr: start,
+            },
+            slice,
+        );
+        let checks = vec![
+            (BinaryOperator::Ge, current.clone(), -MAX_LOOP_BOUND),
+            (
+                BinaryOperator::Le,
+                ValueExpr::Local(end_slot, end_name.clone()),
+                MAX_LOOP_BOUND,
+            ),
+        ];
+        for (operator, bound, limit) in checks {
+            self.push_op(
+                BytecodeOp::Assert {
+                    condition: ValueExpr::BinaryOp(operator, Box::new(bound), Box::new(ValueExpr::ConstFloat(limit))),
+                    message: loop_bounds_error(),
+                    line: line,
+                    halt: true,
+                },
+                slice,
+            );
+        }
+
+        // Condition, body, increment and the jump back to the condition
+        let body_len = body.bytecode.len() as isize;
+        let condition = ValueExpr::BinaryOp(
+            BinaryOperator::Lt,
+            Box::new(current.clone()),
+            Box::new(ValueExpr::Local(end_slot, end_name)),
+        );
+        self.push_op(
+            BytecodeOp::JumpIfNot {
+                condition: condition,
+                offset: body_len + 3,
+            },
+            slice,
+        );
+        self.append(body);
+        self.push_op(
+            BytecodeOp::Let {
+                slot: variable_slot,
+                name: variable,
+                expr: ValueExpr::BinaryOp(
+                    BinaryOperator::Add,
+                    Box::new(current),
+                    Box::new(ValueExpr::ConstFloat(1.0)),
+                ),
+            },
+            slice,
+        );
+        self.push_op(
+            BytecodeOp::Jump {
+                offset: -(body_len + 2),
+            },
+            slice,
+        );
+    }
+
+    fn push_op(&mut self, op: BytecodeOp, slice: SourceSlice) {
+        self.bytecode.push(op);
+        self.source_slices.push(slice);
+    }
+
+    fn append(&mut self, mut other: BlockBytecode) {
+        self.bytecode.append(&mut other.bytecode);
+        self.source_slices.append(&mut other.source_slices);
+    }
+
     fn emit_viewport(&mut self, x: ValueExpr, y: ValueExpr, width: ValueExpr, height: ValueExpr) {
         self.bytecode.push(BytecodeOp::Viewport(x, y, width, height));
     }
@@ -425,21 +1917,133 @@ impl BlockBytecode {
         function_call: &ast::FunctionCallExpr,
         target_defs: &Vec<RenderTargetDef>,
     ) -> Result<(), SemanticError> {
-        Self::expect_args_count(function_call, 1)?;
+        Self::expect_positional_args_count(function_call, 1)?;
         let name = expect_ast_string(&function_call.args[0], source)?;
-        if name == "screen" {
-            self.bytecode.push(BytecodeOp::BindScreenRt);
-            return Ok(());
+
+        // What happens to the previous contents of the target: "load" keeps them, "clear" replaces them with
+        // `clear_color` (transparent black by default) and "dont_care" lets the driver discard them
+        let mut load = None;
+        let mut clear_color = None;
+        // Fraction of the size of the target the pass is drawn at, see `RenderContext::bind_scaled_render_target`
+        let mut scale = None;
+        for named_arg in &function_call.named_args {
+            match named_arg.key.to_slice(source) {
+                "load" => load = Some(named_arg),
+                "clear_color" => clear_color = Some(named_arg),
+                "scale" => scale = Some(named_arg),
+                key => {
+                    return Err(SemanticError::error_from_ast(
+                        named_arg,
+                        format!("Unknown argument `{}`", key),
+                    ))
+                }
+            }
         }
 
-        let idx = target_defs.iter().position(|t| t.name == name);
-        idx.map(|idx| self.bytecode.push(BytecodeOp::BindRt(idx as u32)))
-            .ok_or_else(|| {
+        if name == "screen" {
+            if let Some(scale) = scale {
+                return Err(SemanticError::error_from_ast(
+                    scale,
+                    format!("Only passes of render targets can be scaled"),
+                ));
+            }
+            self.bytecode.push(BytecodeOp::BindScreenRt);
+        } else {
+            let idx = target_defs.iter().position(|t| t.name == name).ok_or_else(|| {
                 SemanticError::error_from_ast(
                     &function_call.args[0],
                     format!("Trying to bind unknown render target {:?}", name),
                 )
-            })
+            })?;
+            match scale {
+                Some(scale) => self.bytecode.push(BytecodeOp::BindScaledRt(
+                    idx as u32,
+                    ValueExpr::from_ast(source, &scale.value)?,
+                )),
+                None => self.bytecode.push(BytecodeOp::BindRt(idx as u32)),
+            }
+        }
+
+        let load_action = match load {
+            Some(load) => expect_ast_string(&load.value, source)?,
+            None => "load".to_owned(),
+        };
+        match load_action.as_str() {
+            "clear" if target_defs.iter().any(|t| t.name == name && t.oit) => {
+                if let Some(clear_color) = clear_color {
+                    return Err(SemanticError::error_from_ast(
+                        clear_color,
+                        format!("OIT render targets are always cleared to their initial values"),
+                    ));
+                }
+                self.bytecode.push(BytecodeOp::ClearOit);
+            }
+            "clear" => {
+                let color = match clear_color {
+                    Some(clear_color) => ValueExpr::from_ast(source, &clear_color.value)?,
+                    None => ValueExpr::ConstLinColor(LinearRGBA::from_f32(0.0, 0.0, 0.0, 0.0)),
+                };
+                self.bytecode.push(BytecodeOp::Clear(color));
+            }
+            "load" | "dont_care" => {
+                if let Some(clear_color) = clear_color {
+                    return Err(SemanticError::error_from_ast(
+                        clear_color,
+                        format!("`clear_color` requires `load: \"clear\"`"),
+                    ));
+                }
+                if load_action == "dont_care" {
+                    self.bytecode.push(BytecodeOp::InvalidateRt);
+                }
+            }
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    &load.unwrap().value,
+                    format!("Expected one of \"load\", \"clear\" or \"dont_care\""),
+                ))
+            }
+        }
+        Ok(())
+    }
+    fn emit_clear(&mut self, source: &str, function_call: &ast::FunctionCallExpr) -> Result<(), SemanticError> {
+        Self::expect_args_count(function_call, 1)?;
+        let dict = match &function_call.args[0] {
+            ast::ValueExpr::Dictionary(dict) => dict,
+            arg => {
+                let linear = ValueExpr::from_ast(source, arg)?;
+                self.bytecode.push(BytecodeOp::Clear(linear));
+                return Ok(());
+            }
+        };
+
+        // Buffers are cleared individually: `colorN` is the Nth color buffer of the target, `depth` its depth buffer
+        let mut colors = Vec::new();
+        let mut depth = None;
+        for entry in &dict.entries {
+            let key = entry.key.to_slice(source);
+            let value = ValueExpr::from_ast(source, &entry.value)?;
+            let buffer = if key.starts_with("color") {
+                key["color".len()..].parse::<u32>().ok()
+            } else {
+                None
+            };
+            match (key, buffer) {
+                ("depth", _) => depth = Some(value),
+                (_, Some(buffer)) => colors.push((buffer, value)),
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        &entry.key,
+                        format!("Unknown buffer `{}`, expected `depth` or `color0`, `color1`, ...", key),
+                    ))
+                }
+            }
+        }
+
+        self.bytecode.push(BytecodeOp::ClearBuffers {
+            colors: colors,
+            depth: depth,
+        });
+        Ok(())
     }
     fn emit_pipeline_set_blending(
         &mut self,
@@ -530,6 +2134,33 @@ impl BlockBytecode {
         self.bytecode.push(BytecodeOp::PipelineSetCulling(mode));
         Ok(())
     }
+    fn emit_set_clip_plane(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+    ) -> Result<(), SemanticError> {
+        Self::expect_args_count(function_call, 5)?;
+        let index = match function_call.args[0] {
+            ast::ValueExpr::FloatLiteral(_, index)
+                if index >= 0.0 && index < MAX_CLIP_PLANES as f32 && index.fract() == 0.0 =>
+            {
+                index as u32
+            }
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    &function_call.args[0],
+                    format!("Expected the index of a clip plane, from 0 to {}", MAX_CLIP_PLANES - 1),
+                ))
+            }
+        };
+
+        let a = ValueExpr::from_ast(source, &function_call.args[1])?;
+        let b = ValueExpr::from_ast(source, &function_call.args[2])?;
+        let c = ValueExpr::from_ast(source, &function_call.args[3])?;
+        let d = ValueExpr::from_ast(source, &function_call.args[4])?;
+        self.bytecode.push(BytecodeOp::PipelineSetClipPlane(index, a, b, c, d));
+        Ok(())
+    }
 
     fn emit_program_bind(
         &mut self,
@@ -539,45 +2170,202 @@ impl BlockBytecode {
     ) -> Result<(), SemanticError> {
         Self::expect_args_count(function_call, 1)?;
         let prog = ProgramDef::from_ast(source, &function_call.args[0])?;
-        let idx = program_defs.iter().position(|d| *d == prog).unwrap();
+        let idx = definition_index(program_defs, &prog, &function_call.args[0], "program")?;
 
-        self.bytecode.push(BytecodeOp::BindProgram(idx as u32));
+        self.bytecode.push(BytecodeOp::BindProgram(idx));
         Ok(())
     }
     fn emit_draw_model(
         &mut self,
         source: &str,
         function_call: &ast::FunctionCallExpr,
-        model_defs: &Vec<String>,
+        header: &ProgramHeader,
     ) -> Result<(), SemanticError> {
-        Self::expect_args_count(function_call, 1)?;
-        let model_file = expect_ast_string(&function_call.args[0], source)?;
-        let idx = model_defs.iter().position(|d| *d == model_file).unwrap();
+        // The optional second argument is the material, which is bound to the current program before drawing
+        if function_call.args.len() != 2 {
+            Self::expect_positional_args_count(function_call, 1)?;
+        }
+        for named_arg in &function_call.named_args {
+            match named_arg.key.to_slice(source) {
+                "params" => self.emit_draw_params(source, &named_arg.value)?,
+                key => {
+                    return Err(SemanticError::error_from_ast(
+                        named_arg,
+                        format!("Unknown argument `{}`", key),
+                    ))
+                }
+            }
+        }
+        self.emit_model_draw(source, &function_call.args[0], function_call.args.get(1), header)
+    }
+    /// Emits the `params` of `draw_model`, which are written to the `gl_resources::DRAW_PARAMS_BLOCK`
+    fn emit_draw_params(&mut self, source: &str, params: &ast::ValueExpr) -> Result<(), SemanticError> {
+        let params = params
+            .as_dictionary()
+            .map_err(|_| SemanticError::error_from_ast(params, format!("Expected a dictionary of draw parameters")))?;
+        let mut values = Vec::new();
+        for entry in &params.entries {
+            values.push((entry.key.to_owned(source), ValueExpr::from_ast(source, &entry.value)?));
+        }
+        self.bytecode.push(BytecodeOp::BindDrawParams(values));
+        Ok(())
+    }
+    fn emit_model_draw(
+        &mut self,
+        source: &str,
+        model: &ast::ValueExpr,
+        material: Option<&ast::ValueExpr>,
+        header: &ProgramHeader,
+    ) -> Result<(), SemanticError> {
+        let model_def = model_def(source, model)?;
+        let idx = definition_index(&header.model_defs, &model_def, model, "model")?;
+
+        if let Some(material) = material {
+            self.emit_material(source, material, header)?;
+        }
+        // An explicit material replaces the one from the MTL file
+        let bind_mtl = material.is_none();
+        self.bytecode.push(BytecodeOp::DrawModel(idx, bind_mtl));
+        Ok(())
+    }
+    fn emit_draw_batch(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        header: &ProgramHeader,
+    ) -> Result<(), SemanticError> {
+        // Like for `draw_model`, the optional second argument is the material
+        if function_call.args.len() != 2 {
+            Self::expect_args_count(function_call, 1)?;
+        }
+        let name = expect_ast_string(&function_call.args[0], source)?;
+        let idx = header.batch_defs.iter().position(|b| b.name == name).ok_or_else(|| {
+            SemanticError::error_from_ast(&function_call.args[0], format!("Unknown batch `{}`", name))
+        })?;
+
+        if let Some(material) = function_call.args.get(1) {
+            self.emit_material(source, material, header)?;
+        }
+        self.bytecode.push(BytecodeOp::DrawBatch(idx as u32));
+        Ok(())
+    }
+    /// Binds the entries of a material dictionary to the uniforms of the current program
+    fn emit_material(
+        &mut self,
+        source: &str,
+        material: &ast::ValueExpr,
+        header: &ProgramHeader,
+    ) -> Result<(), SemanticError> {
+        for (uniform_name, value) in material_entries(source, material)? {
+            let uniform = definition_index(&header.uniform_names, &uniform_name, material, "uniform")?;
+            self.bytecode.push(match value {
+                MaterialValue::Texture(texture_def) => {
+                    let texture = definition_index(&header.texture_defs, &texture_def, material, "texture")?;
+                    BytecodeOp::UniformTexture(uniform, texture)
+                }
+                MaterialValue::Color(value) => BytecodeOp::UniformColor(uniform, ValueExpr::from_ast(source, value)?),
+                MaterialValue::Float(value) => BytecodeOp::UniformFloat(uniform, ValueExpr::from_ast(source, value)?),
+            });
+        }
+        Ok(())
+    }
+    fn emit_print(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        trace: bool,
+    ) -> Result<(), SemanticError> {
+        Self::expect_no_named_args(function_call)?;
+        let args: Result<Vec<ValueExpr>, SemanticError> = function_call
+            .args
+            .iter()
+            .map(|e| ValueExpr::from_ast(source, e))
+            .collect();
+        let line = if trace {
+            Some(line_number(function_call, source))
+        } else {
+            None
+        };
 
-        self.bytecode.push(BytecodeOp::DrawModel(idx as u32));
+        self.bytecode.push(BytecodeOp::Print {
+            args: args?,
+            line: line,
+        });
+        Ok(())
+    }
+    fn emit_assert(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        mode: AssertMode,
+    ) -> Result<(), SemanticError> {
+        let message = match function_call.args.len() {
+            1 => {
+                Self::expect_args_count(function_call, 1)?;
+                format!("`{}`", function_call.args[0].source_slice().to_slice(source))
+            }
+            _ => {
+                Self::expect_args_count(function_call, 2)?;
+                expect_ast_string(&function_call.args[1], source)?
+            }
+        };
+        let condition = ValueExpr::from_ast(source, &function_call.args[0])?;
+
+        if mode != AssertMode::Off {
+            self.bytecode.push(BytecodeOp::Assert {
+                condition: condition,
+                message: message,
+                line: line_number(function_call, source),
+                halt: mode == AssertMode::Halt,
+            });
+        }
         Ok(())
     }
     fn emit_uniform_texture(
         &mut self,
         source: &str,
         function_call: &ast::FunctionCallExpr,
-        texture_defs: &Vec<TextureDef>,
+        header: &ProgramHeader,
         srgb: bool,
     ) -> Result<(), SemanticError> {
-        Self::expect_args_count(function_call, 2)?;
+        Self::expect_positional_args_count(function_call, 2)?;
         let texture_file = expect_ast_string(&function_call.args[1], source)?;
         let texture_def = TextureDef {
             path: texture_file,
             srgb: srgb,
+            options: texture_import_options(source, function_call)?,
         };
-        let idx = texture_defs.iter().position(|d| *d == texture_def).unwrap();
+        let idx = definition_index(&header.texture_defs, &texture_def, &function_call.args[1], "texture")?;
 
         self.bytecode.push(BytecodeOp::UniformTexture(
-            expect_ast_string(&function_call.args[0], source)?,
-            idx as u32,
+            Self::uniform_id(source, &function_call.args[0], &header.uniform_names)?,
+            idx,
         ));
         Ok(())
     }
+    fn emit_draw_textured_quad(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        texture_defs: &[TextureDef],
+    ) -> Result<(), SemanticError> {
+        Self::expect_positional_args_count(function_call, 5)?;
+        let texture_def = TextureDef {
+            path: expect_ast_string(&function_call.args[4], source)?,
+            srgb: true,
+            options: texture_import_options(source, function_call)?,
+        };
+        let idx = definition_index(texture_defs, &texture_def, &function_call.args[4], "texture")?;
+
+        self.bytecode.push(BytecodeOp::DrawTexturedQuad {
+            x: ValueExpr::from_ast(source, &function_call.args[0])?,
+            y: ValueExpr::from_ast(source, &function_call.args[1])?,
+            width: ValueExpr::from_ast(source, &function_call.args[2])?,
+            height: ValueExpr::from_ast(source, &function_call.args[3])?,
+            texture: idx,
+        });
+        Ok(())
+    }
     fn emit_uniform_ibl(
         &mut self,
         source: &str,
@@ -587,32 +2375,45 @@ impl BlockBytecode {
         Self::expect_args_count(function_call, 1)?;
         let folder = expect_ast_string(&function_call.args[0], source)?;
         let ibl_def = IblDef { folder: folder };
-        let idx = ibl_defs.iter().position(|d| *d == ibl_def).unwrap();
+        let idx = definition_index(ibl_defs, &ibl_def, &function_call.args[0], "ibl")?;
 
-        self.bytecode.push(BytecodeOp::UniformIbl(idx as u32));
+        self.bytecode.push(BytecodeOp::UniformIbl(idx));
 
         Ok(())
     }
-    fn emit_uniform_render_target_as_texture(
+    fn emit_draw_skybox(
         &mut self,
         source: &str,
         function_call: &ast::FunctionCallExpr,
-        target_defs: &Vec<RenderTargetDef>,
+        ibl_defs: &Vec<IblDef>,
     ) -> Result<(), SemanticError> {
-        let uniform_name = expect_ast_string(&function_call.args[0], source)?;
-        let render_target = expect_ast_string(&function_call.args[1], source)?;
+        Self::expect_args_count(function_call, 1)?;
+        let folder = expect_ast_string(&function_call.args[0], source)?;
+        let ibl_def = IblDef { folder: folder };
+        let idx = definition_index(ibl_defs, &ibl_def, &function_call.args[0], "ibl")?;
 
+        self.bytecode.push(BytecodeOp::DrawSkybox(idx));
+
+        Ok(())
+    }
+    /// Resolves a `target.buffer` string to the index of the target and of the buffer
+    fn target_buffer(
+        source: &str,
+        arg: &ast::ValueExpr,
+        target_defs: &[RenderTargetDef],
+    ) -> Result<(u32, u32), SemanticError> {
+        let render_target = expect_ast_string(arg, source)?;
         let parts: Vec<&str> = render_target.split('.').collect();
         if parts.len() != 2 {
             return Err(SemanticError::error_from_ast(
-                &function_call.args[1],
+                arg,
                 format!("The name `{:?}` is not valid: use target.buffer", render_target),
             ));
         }
 
         let idx = target_defs.iter().position(|t| t.name == parts[0]).ok_or_else(|| {
             SemanticError::error_from_ast(
-                &function_call.args[1],
+                arg,
                 format!("Trying to bind unknown render target {:?} as texture", render_target),
             )
         })?;
@@ -623,94 +2424,607 @@ impl BlockBytecode {
             .position(|f| f.0 == parts[1])
             .ok_or_else(|| {
                 SemanticError::error_from_ast(
-                    &function_call.args[1],
+                    arg,
                     format!("Trying to bind unknown buffer {:?} as texture", render_target),
                 )
             })?;
-
-        self.bytecode
-            .push(BytecodeOp::UniformRt(uniform_name, idx as u32, buffer_idx as u32));
-
-        Ok(())
+        Ok((idx as u32, buffer_idx as u32))
     }
-
-    fn emit_function_call(
+    fn emit_post_taa(
         &mut self,
         source: &str,
-        function: &ast::SourceSlice,
-        args: &Vec<ast::ValueExpr>,
+        function_call: &ast::FunctionCallExpr,
+        target_defs: &[RenderTargetDef],
     ) -> Result<(), SemanticError> {
-        let args: Result<Vec<ValueExpr>, SemanticError> = args.iter().map(|e| ValueExpr::from_ast(source, e)).collect();
-        let args = args?;
-        self.bytecode.push(BytecodeOp::FunctionCall(FunctionCall {
-            function: function.to_owned(source),
-            args: args,
-        }));
+        Self::expect_args_count(function_call, 4)?;
+        let color = Self::target_buffer(source, &function_call.args[0], target_defs)?;
+        let depth = Self::target_buffer(source, &function_call.args[1], target_defs)?;
+        let motion = Self::target_buffer(source, &function_call.args[2], target_defs)?;
+
+        let history_arg = &function_call.args[3];
+        let history_name = expect_ast_string(history_arg, source)?;
+        let history = target_defs.iter().position(|t| t.name == history_name).ok_or_else(|| {
+            SemanticError::error_from_ast(history_arg, format!("Unknown history render target {:?}", history_name))
+        })?;
+        if [color.0, depth.0, motion.0].contains(&(history as u32)) {
+            return Err(SemanticError::error_from_ast(
+                history_arg,
+                format!("The history cannot be one of the resolved render targets"),
+            ));
+        }
+
+        self.bytecode.push(BytecodeOp::PostTaa {
+            color: color,
+            depth: depth,
+            motion: motion,
+            history: history as u32,
+        });
         Ok(())
     }
-}
+    fn emit_post_auto_exposure(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        target_defs: &[RenderTargetDef],
+    ) -> Result<(), SemanticError> {
+        Self::expect_args_count(function_call, 3)?;
+        let hdr = Self::target_buffer(source, &function_call.args[0], target_defs)?;
 
-pub struct Function {
-    pub name: String,
-    pub params: Vec<(String, ast::Type)>,
-    pub bytecode: BlockBytecode,
-}
-impl Function {
-    pub fn from_ast(source: &str, ast: &ast::Function, header: &ProgramHeader) -> Result<Self, SemanticError> {
-        let bytecode = BlockBytecode::from_ast(source, &ast.block, header)?;
-        let params = ast
-            .params
-            .iter()
-            .map(|p| (p.name.to_owned(source), p.value_type))
-            .collect();
+        let target_arg = &function_call.args[1];
+        let target_name = expect_ast_string(target_arg, source)?;
+        let target = target_defs.iter().position(|t| t.name == target_name).ok_or_else(|| {
+            SemanticError::error_from_ast(target_arg, format!("Unknown exposure render target {:?}", target_name))
+        })?;
+        if target as u32 == hdr.0 {
+            return Err(SemanticError::error_from_ast(
+                target_arg,
+                format!("The exposure cannot be written to the render target it is computed from"),
+            ));
+        }
+        match target_defs[target].formats.first() {
+            Some((_, format)) if !format.is_srgb() && !format.is_depth() => {}
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    target_arg,
+                    format!("The first buffer of the exposure render target must be a linear color buffer"),
+                ));
+            }
+        }
 
-        Ok(Function {
-            name: ast.name.to_owned(source),
-            params: params,
-            bytecode: bytecode,
-        })
+        self.bytecode.push(BytecodeOp::PostAutoExposure {
+            source: hdr,
+            target: target as u32,
+            speed: ValueExpr::from_ast(source, &function_call.args[2])?,
+        });
+        Ok(())
     }
-}
-
+    fn emit_post_oit_resolve(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        target_defs: &[RenderTargetDef],
+    ) -> Result<(), SemanticError> {
+        Self::expect_args_count(function_call, 3)?;
+        let accum = Self::target_buffer(source, &function_call.args[0], target_defs)?;
+        let revealage = Self::target_buffer(source, &function_call.args[1], target_defs)?;
+
+        let target_arg = &function_call.args[2];
+        let target_name = expect_ast_string(target_arg, source)?;
+        let target = target_defs.iter().position(|t| t.name == target_name).ok_or_else(|| {
+            SemanticError::error_from_ast(target_arg, format!("Unknown render target {:?}", target_name))
+        })?;
+        if [accum.0, revealage.0].contains(&(target as u32)) {
+            return Err(SemanticError::error_from_ast(
+                target_arg,
+                format!("Transparency cannot be resolved into the render target it is accumulated in"),
+            ));
+        }
+        match target_defs[target].formats.first() {
+            Some((_, format)) if !format.is_depth() => {}
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    target_arg,
+                    format!("The first buffer of the resolved render target must be a color buffer"),
+                ));
+            }
+        }
+
+        self.bytecode.push(BytecodeOp::PostOitResolve {
+            accum: accum,
+            revealage: revealage,
+            target: target as u32,
+        });
+        Ok(())
+    }
+    fn emit_post_lens(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        effect: LensEffect,
+        target_defs: &[RenderTargetDef],
+    ) -> Result<(), SemanticError> {
+        Self::expect_args_count(function_call, 2)?;
+        let color = Self::target_buffer(source, &function_call.args[0], target_defs)?;
+        if target_defs[color.0 as usize].formats[color.1 as usize].1.is_depth() {
+            return Err(SemanticError::error_from_ast(
+                &function_call.args[0],
+                format!("Lens effects can only be applied to color buffers"),
+            ));
+        }
+
+        self.bytecode.push(BytecodeOp::PostLens {
+            effect: effect,
+            source: color,
+            param: ValueExpr::from_ast(source, &function_call.args[1])?,
+        });
+        Ok(())
+    }
+    fn emit_post_volumetrics(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        header: &ProgramHeader,
+    ) -> Result<(), SemanticError> {
+        Self::expect_args_count(function_call, 4)?;
+        let target_defs = &header.target_defs;
+        let depth = Self::target_buffer(source, &function_call.args[0], target_defs)?;
+
+        // The lights are given by name, separated by commas
+        let lights_arg = &function_call.args[1];
+        let mut lights = Vec::new();
+        for name in expect_ast_string(lights_arg, source)?.split(',').map(|l| l.trim()) {
+            let light = header
+                .lights
+                .iter()
+                .position(|l| l.name == name)
+                .ok_or_else(|| SemanticError::error_from_ast(lights_arg, format!("Unknown light {:?}", name)))?;
+            lights.push(light as u32);
+        }
+
+        let target_arg = &function_call.args[2];
+        let target_name = expect_ast_string(target_arg, source)?;
+        let target = target_defs.iter().position(|t| t.name == target_name).ok_or_else(|| {
+            SemanticError::error_from_ast(target_arg, format!("Unknown fog render target {:?}", target_name))
+        })?;
+        if target as u32 == depth.0 {
+            return Err(SemanticError::error_from_ast(
+                target_arg,
+                format!("The fog cannot be written to the render target of the depth buffer"),
+            ));
+        }
+        match target_defs[target].formats.first() {
+            Some((_, format)) if !format.is_srgb() && !format.is_depth() => {}
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    target_arg,
+                    format!("The first buffer of the fog render target must be a linear color buffer"),
+                ));
+            }
+        }
+
+        self.bytecode.push(BytecodeOp::PostVolumetrics {
+            depth: depth,
+            lights: lights,
+            target: target as u32,
+            density: ValueExpr::from_ast(source, &function_call.args[3])?,
+        });
+        Ok(())
+    }
+    fn emit_uniform_render_target_as_texture(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        header: &ProgramHeader,
+    ) -> Result<(), SemanticError> {
+        Self::expect_positional_args_count(function_call, 2)?;
+        let uniform_id = Self::uniform_id(source, &function_call.args[0], &header.uniform_names)?;
+        let target_defs = &header.target_defs;
+        let (idx, buffer_idx) = Self::target_buffer(source, &function_call.args[1], target_defs)?;
+
+        // sRGB buffers are decoded to linear when sampled, unless `srgb_decode: 0` is passed
+        let mut srgb_decode = true;
+        for named_arg in &function_call.named_args {
+            if named_arg.key.to_slice(source) != "srgb_decode" {
+                return Err(SemanticError::error_from_ast(
+                    named_arg,
+                    format!("Unknown argument `{}`", named_arg.key.to_slice(source)),
+                ));
+            }
+            if !target_defs[idx as usize].formats[buffer_idx as usize].1.is_srgb() {
+                return Err(SemanticError::error_from_ast(
+                    named_arg,
+                    format!("`srgb_decode` only applies to Srgb8 and Srgba8 buffers"),
+                ));
+            }
+            srgb_decode = match named_arg.value {
+                ast::ValueExpr::FloatLiteral(_, value) if value == 0.0 || value == 1.0 => value == 1.0,
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        &named_arg.value,
+                        format!("Expected either 0 or 1"),
+                    ))
+                }
+            };
+        }
+
+        self.bytecode
+            .push(BytecodeOp::UniformRt(uniform_id, idx, buffer_idx, srgb_decode));
+
+        Ok(())
+    }
+
+    fn emit_function_call(
+        &mut self,
+        source: &str,
+        function_call: &ast::FunctionCallExpr,
+        function_signatures: &HashMap<String, FunctionSignature>,
+    ) -> Result<(), SemanticError> {
+        let function = function_call.function.to_owned(source);
+        if !function_signatures.contains_key(&function) {
+            return Err(SemanticError::error_from_ast(
+                function_call,
+                format!("Call to unknown function `{}`", function),
+            ));
+        }
+
+        let args: Result<Vec<ValueExpr>, SemanticError> = function_call
+            .args
+            .iter()
+            .map(|e| ValueExpr::from_ast(source, e))
+            .collect();
+        let args = args?;
+        self.bytecode.push(BytecodeOp::FunctionCall(FunctionCall {
+            function: function,
+            args: args,
+        }));
+        Ok(())
+    }
+}
+
+pub struct Function {
+    // The definition of the function in the script, each op knows its own statement in `bytecode`
+    pub source_slice: SourceSlice,
+    pub name: String,
+    pub params: Vec<(String, ast::Type)>,
+    pub return_type: Option<ast::Type>,
+    pub bytecode: BlockBytecode,
+}
+impl Function {
+    pub fn from_ast(source: &str, ast: &ast::Function, header: &ProgramHeader) -> Result<Self, SemanticError> {
+        let signature = FunctionSignature::from_ast(source, ast);
+        let mut locals: Vec<String> = signature.params.iter().map(|p| p.0.clone()).collect();
+        let mut bytecode = BlockBytecode::from_ast(source, &ast.block, header, &mut locals)?;
+        bytecode.eliminate_redundant_binds(&header.function_signatures);
+
+        Ok(Function {
+            source_slice: ast.source_slice,
+            name: ast.name.to_owned(source),
+            params: signature.params,
+            return_type: signature.return_type,
+            bytecode: bytecode,
+        })
+    }
+}
+
+/// An object of the scene, which is drawn by `draw_scene`
+#[derive(Debug)]
+pub struct SceneObjectDef {
+    pub name: String,
+    pub visible: Option<ValueExpr>,
+    pub transform: Vec<(String, ValueExpr)>, // by the keys in `TRANSFORM_KEYS`
+    // Binds the material of the object and draws its mesh
+    pub bytecode: BlockBytecode,
+}
+impl SceneObjectDef {
+    pub fn from_ast(source: &str, object: &ast::SceneObject, header: &ProgramHeader) -> Result<Self, SemanticError> {
+        // Like the size of render targets, the properties of objects can only depend on globals
+        let from_ast = |expr: &ast::ValueExpr| -> Result<ValueExpr, SemanticError> {
+            let mut expr = ValueExpr::from_ast(source, expr)?;
+            expr.resolve_variables(&[]);
+            Ok(expr)
+        };
+
+        let visible = object.get_property(source, "visible").map(&from_ast).transpose()?;
+        let mut transform = Vec::new();
+        if let Some(ast::ValueExpr::Dictionary(dict)) = object.get_property(source, "transform") {
+            for kv in &dict.entries {
+                transform.push((kv.key.to_owned(source), from_ast(&kv.value)?));
+            }
+        }
+
+        let mut bytecode = BlockBytecode {
+            bytecode: Vec::new(),
+            source_slices: Vec::new(),
+        };
+        let mesh = object
+            .get_property(source, "mesh")
+            .ok_or_else(|| SemanticError::error_from_ast(object, format!("Object has no mesh")))?;
+        bytecode.emit_model_draw(source, mesh, object.get_property(source, "material"), header)?;
+        let slice = object.source_slice();
+        bytecode.source_slices.resize(bytecode.bytecode.len(), slice);
+        bytecode.resolve_variables(&[]);
+
+        Ok(SceneObjectDef {
+            name: object.name.to_owned(source),
+            visible: visible,
+            transform: transform,
+            bytecode: bytecode,
+        })
+    }
+}
+
+/// Static meshes which are merged into one mesh when the demo is loaded, and drawn by `draw_batch`
+#[derive(Debug, PartialEq)]
+pub struct BatchDef {
+    pub name: String,
+    pub parts: Vec<BatchPartDef>,
+}
+/// A mesh of a batch, placed by a transform with the keys in `TRANSFORM_KEYS`
+#[derive(Debug, PartialEq)]
+pub struct BatchPartDef {
+    pub model: ModelDef,
+    pub transform: Vec<(String, f32)>,
+}
+impl BatchDef {
+    pub fn from_ast(source: &str, batch: &ast::Batch) -> Result<Self, SemanticError> {
+        let mut parts = Vec::new();
+        for part in &batch.parts {
+            let mesh = part
+                .get_property(source, "mesh")
+                .ok_or_else(|| SemanticError::error_from_ast(part, format!("Batch part has no mesh")))?;
+            let mut transform = Vec::new();
+            if let Some(ast::ValueExpr::Dictionary(dict)) = part.get_property(source, "transform") {
+                for kv in &dict.entries {
+                    match kv.value {
+                        ast::ValueExpr::FloatLiteral(_, value) => transform.push((kv.key.to_owned(source), value)),
+                        _ => {
+                            return Err(SemanticError::error_from_ast(
+                                &kv.value,
+                                format!(
+                                    "The transforms of batches have to be literals, batches are merged before the \
+                                     demo runs"
+                                ),
+                            ))
+                        }
+                    }
+                }
+            }
+            parts.push(BatchPartDef {
+                model: model_def(source, mesh)?,
+                transform: transform,
+            });
+        }
+
+        Ok(BatchDef {
+            name: batch.name.to_owned(source),
+            parts: parts,
+        })
+    }
+}
+
+/// A light of the scene, which is evaluated in every frame and bound to the light uniforms of all programs
+///
+/// Programs see the lights through these uniforms, positions and directions are in view space:
+/// - `int u_LightCount`
+/// - `vec4 u_LightPosition[]`: position, and the type in w (0 for directional, 1 for point and 2 for spot lights)
+/// - `vec4 u_LightDirection[]`: normalized direction the light shines in, and the cosine of the spot angle in w
+/// - `vec4 u_LightColor[]`: color multiplied by the intensity
+#[derive(Debug)]
+pub struct LightDef {
+    pub name: String,
+    pub light_type: LightType,
+    pub position: Vec<ValueExpr>,  // x, y, z
+    pub direction: Vec<ValueExpr>, // x, y, z
+    pub color: ValueExpr,
+    pub intensity: ValueExpr,
+    pub angle: ValueExpr, // half of the opening angle of spot lights, in radians
+}
+impl LightDef {
+    pub fn from_ast(source: &str, light: &ast::Light) -> Result<Self, SemanticError> {
+        // Like the size of render targets, the properties of lights can only depend on globals
+        let from_ast = |expr: &ast::ValueExpr| -> Result<ValueExpr, SemanticError> {
+            let mut expr = ValueExpr::from_ast(source, expr)?;
+            expr.resolve_variables(&[]);
+            Ok(expr)
+        };
+        let vector = |key: &str| -> Result<Vec<ValueExpr>, SemanticError> {
+            let dict = match light.get_property(source, key) {
+                Some(ast::ValueExpr::Dictionary(dict)) => dict,
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        light,
+                        format!("Light `{}` needs `{}`", light.name.to_slice(source), key),
+                    ))
+                }
+            };
+            // Omitted components are 0
+            let mut vector = vec![ValueExpr::ConstFloat(0.0); 3];
+            for kv in &dict.entries {
+                let component = kv.key.to_slice(source);
+                let idx = ["x", "y", "z"].iter().position(|&c| c == component).ok_or_else(|| {
+                    SemanticError::error_from_ast(kv, format!("Expected `x`, `y` or `z`, got `{}`", component))
+                })?;
+                vector[idx] = from_ast(&kv.value)?;
+            }
+            Ok(vector)
+        };
+
+        let type_arg = light.get_property(source, "type").ok_or_else(|| {
+            SemanticError::error_from_ast(light, format!("Light `{}` needs `type`", light.name.to_slice(source)))
+        })?;
+        let light_type = expect_ast_string(type_arg, source)?;
+        let light_type = LightType::from_str(&light_type).ok_or_else(|| {
+            SemanticError::error_from_ast(type_arg, format!("Not a valid light type: {}", light_type))
+        })?;
+        let position = match light_type {
+            LightType::Point | LightType::Spot => vector("pos")?,
+            LightType::Directional => Vec::new(),
+        };
+        let direction = match light_type {
+            LightType::Directional | LightType::Spot => vector("dir")?,
+            LightType::Point => Vec::new(),
+        };
+        let property = |key: &str, default: ValueExpr| match light.get_property(source, key) {
+            Some(value) => from_ast(value),
+            None => Ok(default),
+        };
+        let white = LinearRGBA::from_f32(1.0, 1.0, 1.0, 1.0);
+
+        Ok(LightDef {
+            name: light.name.to_owned(source),
+            light_type: light_type,
+            position: position,
+            direction: direction,
+            color: property("color", ValueExpr::ConstLinColor(white))?,
+            intensity: property("intensity", ValueExpr::ConstFloat(1.0))?,
+            angle: property("angle", ValueExpr::ConstFloat(std::f32::consts::FRAC_PI_6))?,
+        })
+    }
+}
+
+/// Identifies serialized bytecode, the version has to be increased whenever the bytecode or its encoding changes
+const BYTECODE_MAGIC: &[u8; 4] = b"DEBC";
+const BYTECODE_VERSION: u32 = 30;
+
 pub struct ProgramContainer {
+    source: String,
     header: ProgramHeader,
+    warnings: Vec<SemanticWarning>,
 
     // Bytecode
     functions: HashMap<String, Function>,
 }
 
 impl ProgramContainer {
-    pub fn from_ast(source: &str, ast: &ast::Program) -> Result<Self, SemanticError> {
-        let mut header = ProgramHeader::new();
+    pub fn from_ast(source: &str, ast: &ast::Program, options: CompileOptions) -> Result<Self, SemanticError> {
+        let mut header = ProgramHeader::new(options);
+        header.function_signatures = Self::collect_function_signatures(source, ast)?;
+        let mut ast = Self::resolve_call_arguments(source, ast)?;
+        TypeChecker::new(source, &header.function_signatures).check_program(&ast)?;
+        let mut warnings = deadcode::eliminate_dead_code(source, &mut ast, options.keep_unused_functions);
+        let ast = &ast;
+
+        Self::check_static_resources(source, ast)?;
+        header.metadata = Self::collect_metadata(source, ast)?;
         header.sync_tracks = Self::collect_sync_tracks(source, ast);
+        header.track_hints = Self::collect_track_hints(source, ast, &header.sync_tracks, &mut warnings)?;
+        header.uniform_names = Self::collect_uniform_names(source, ast)?;
         header.target_defs = Self::collect_target_defs(source, ast)?;
         header.program_defs = Self::collect_program_defs(source, ast)?;
         header.model_defs = Self::collect_model_defs(source, ast)?;
         header.texture_defs = Self::collect_texture_defs(source, ast)?;
         header.ibl_defs = Self::collect_ibl_defs(source, ast)?;
+        header.scene_objects = Self::collect_scene_objects(source, ast, &header)?;
+        header.batch_defs = Self::collect_batch_defs(source, ast)?;
+        header.lights = Self::collect_lights(source, ast)?;
         header.external_res =
             Self::collect_external_resources(&header.program_defs, &header.model_defs, &header.texture_defs);
+        for part in header.batch_defs.iter().flat_map(|b| &b.parts) {
+            header.external_res.insert(part.model.path.clone());
+        }
+        header.external_res.extend(header.metadata.soundtrack.clone());
+
+        let mut functions = HashMap::new();
+        for function in &ast.functions {
+            let name = function.name.to_owned(source);
+            let function = Function::from_ast(source, &function, &header)?;
+            functions.insert(name, function);
+        }
+        warnings.extend(framegraph::validate_frame_graph(
+            &header.target_defs,
+            &header.uniform_names,
+            &functions,
+        ));
+
+        Ok(ProgramContainer {
+            source: source.to_owned(),
+            header,
+            warnings,
+            functions,
+        })
+    }
+
+    /// Prints how many definitions of each kind the program has
+    ///
+    /// This is left to the callers of `from_ast`, the language server cannot print anything as stdout is its transport.
+    pub fn print_stats(&self) {
+        let header = &self.header;
         println!(" ~ Sync Tracks:     {:?}", header.sync_tracks.len());
+        println!(" ~ Uniforms:        {:?}", header.uniform_names.len());
         println!(" ~ Render Targets:  {:?}", header.target_defs.len());
         println!(" ~ Programs:        {:?}", header.program_defs.len());
         println!(" ~ Models:          {:?}", header.model_defs.len());
         println!(" ~ Textures:        {:?}", header.texture_defs.len());
+        println!(" ~ Scene Objects:   {:?}", header.scene_objects.len());
+        println!(" ~ Batches:         {:?}", header.batch_defs.len());
+        println!(" ~ Lights:          {:?}", header.lights.len());
         println!(" ~ Resources:       {:?}", header.external_res.len());
+        println!(" ~ Functions:       {:?}", self.functions.len());
+    }
 
-        let mut functions = HashMap::new();
-        println!(" ~ Functions:       {:?}", ast.functions.len());
-        for function in &ast.functions {
-            let name = function.name.to_owned(source);
-            let function = Function::from_ast(source, &function, &header)?;
-            functions.insert(name, function);
+    /// Encodes the compiled program, so that it can be loaded again without parsing and compiling the source
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_bytes(BYTECODE_MAGIC);
+        writer.write(&BYTECODE_VERSION);
+        writer.write(&self.source);
+        writer.write(&self.header);
+        writer.write(&self.warnings);
+        writer.write(&self.functions);
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes);
+        if reader.read_bytes(BYTECODE_MAGIC.len())? != BYTECODE_MAGIC {
+            return Err(format!("Data does not contain bytecode"));
         }
+        let version: u32 = reader.read()?;
+        if version != BYTECODE_VERSION {
+            return Err(format!(
+                "Bytecode version {} is not supported, expected version {}",
+                version, BYTECODE_VERSION
+            ));
+        }
+
+        let container = ProgramContainer {
+            source: reader.read()?,
+            header: reader.read()?,
+            warnings: reader.read()?,
+            functions: reader.read()?,
+        };
+        if !reader.is_at_end() {
+            return Err(format!("Unexpected data after the end of the bytecode"));
+        }
+        Ok(container)
+    }
 
-        Ok(ProgramContainer { header, functions })
+    pub fn get_source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn get_options(&self) -> CompileOptions {
+        self.header.options
+    }
+
+    pub fn get_warnings(&self) -> &[SemanticWarning] {
+        &self.warnings
     }
 
     pub fn get_sync_tracks(&self) -> &HashSet<String> {
         &self.header.sync_tracks
     }
+    pub fn get_track_hints(&self) -> &Vec<TrackHint> {
+        &self.header.track_hints
+    }
+
+    pub fn get_uniform_names(&self) -> &[String] {
+        &self.header.uniform_names
+    }
+
+    pub fn get_metadata(&self) -> &Metadata {
+        &self.header.metadata
+    }
 
     pub fn get_target_defs(&self) -> &Vec<RenderTargetDef> {
         &self.header.target_defs
@@ -720,7 +3034,7 @@ impl ProgramContainer {
         &self.header.program_defs
     }
 
-    pub fn get_model_defs(&self) -> &[String] {
+    pub fn get_model_defs(&self) -> &[ModelDef] {
         &self.header.model_defs
     }
 
@@ -732,6 +3046,23 @@ impl ProgramContainer {
         &self.header.ibl_defs
     }
 
+    pub fn get_scene_objects(&self) -> &[SceneObjectDef] {
+        &self.header.scene_objects
+    }
+
+    pub fn get_batch_defs(&self) -> &[BatchDef] {
+        &self.header.batch_defs
+    }
+
+    pub fn get_lights(&self) -> &[LightDef] {
+        &self.header.lights
+    }
+
+    /// Files the demo is loaded from besides the script, see `collect_external_resources`
+    pub fn get_external_resources(&self) -> &HashSet<String> {
+        &self.header.external_res
+    }
+
     pub fn get_function(&self, function: &str) -> Option<&Function> {
         self.functions.get(function)
     }
@@ -740,16 +3071,134 @@ impl ProgramContainer {
         self.functions.get(function).map(|f| &f.bytecode)
     }
 
-    fn walk_render_ops<F>(ast: &ast::Program, mut f: F) -> Result<(), SemanticError>
-    where
-        F: FnMut(&ast::Stmt) -> Result<(), SemanticError>,
-    {
-        for function in &ast.functions {
-            for op in &function.block {
-                f(op)?;
+    /// The statement which generated an op of a function
+    pub fn get_op_source(&self, function: &str, pc: usize) -> Option<SourceSlice> {
+        self.get_ops(function)
+            .and_then(|ops| ops.get_source_slices().get(pc))
+            .cloned()
+    }
+
+    /// Lists the ops of all functions in the order of the script, each with the line which generated it
+    pub fn disassemble(&self) -> String {
+        let line = |slice: SourceSlice| SourceSnippet::new(slice, &self.source).location().0;
+        let mut functions: Vec<&Function> = self.functions.values().collect();
+        functions.sort_by_key(|f| f.source_slice.begin);
+
+        let mut result = String::new();
+        for function in functions {
+            result += &format!("fn {} (line {})\n", function.name, line(function.source_slice));
+            let slices = function.bytecode.get_source_slices();
+            for (pc, op) in function.bytecode.get_bytecode().iter().enumerate() {
+                result += &format!("{:5} {:5}  {:?}\n", pc, line(slices[pc]), op);
             }
         }
-        Ok(())
+        result
+    }
+
+    /// Names of the functions which `main` calls as statements, in order, these are the passes of the demo
+    pub fn get_passes(&self) -> Vec<&str> {
+        let mut passes: Vec<&str> = Vec::new();
+        if let Some(ops) = self.get_ops("main") {
+            for op in ops.get_bytecode() {
+                if let BytecodeOp::FunctionCall(function_call) = op {
+                    if !passes.contains(&function_call.function.as_str()) {
+                        passes.push(&function_call.function);
+                    }
+                }
+            }
+        }
+        passes
+    }
+
+    /// Returns true if both programs reference exactly the same resources
+    ///
+    /// In this case the resources loaded for one program can be reused as they are for the other one.
+    pub fn has_same_resources(&self, other: &ProgramContainer) -> bool {
+        self.header.target_defs == other.header.target_defs
+            && self.header.program_defs == other.header.program_defs
+            && self.header.model_defs == other.header.model_defs
+            && self.header.texture_defs == other.header.texture_defs
+            && self.header.ibl_defs == other.header.ibl_defs
+            && self.header.batch_defs == other.header.batch_defs
+    }
+
+    fn collect_metadata(source: &str, ast: &ast::Program) -> Result<Metadata, SemanticError> {
+        let mut metadata = Metadata::default();
+        let mut keys = HashSet::new();
+        for entry in &ast.metadata {
+            let key = entry.key.to_slice(source);
+            if !keys.insert(key) {
+                return Err(SemanticError::error_from_ast(
+                    entry,
+                    format!("Multiple definitions of `{}` found", key),
+                ));
+            }
+
+            match (key, &entry.value) {
+                ("aspect", ast::MetadataValue::Ratio(width, height)) => {
+                    if *width <= 0.0 || *height <= 0.0 {
+                        return Err(SemanticError::error_from_ast(
+                            entry,
+                            format!("The aspect ratio must be positive"),
+                        ));
+                    }
+                    metadata.aspect = Some((*width, *height));
+                }
+                ("stereo_mode", ast::MetadataValue::Str(mode)) => {
+                    let mode = mode.to_slice(source);
+                    metadata.stereo_mode = Some(StereoMode::from_str(mode).ok_or_else(|| {
+                        SemanticError::error_from_ast(entry, format!("Not a valid stereo mode: {}", mode))
+                    })?);
+                }
+                ("eye_separation", ast::MetadataValue::Float(separation)) => {
+                    metadata.eye_separation = Some(*separation);
+                }
+                ("reversed_z", ast::MetadataValue::Bool(reversed_z)) => {
+                    metadata.reversed_z = *reversed_z;
+                }
+                // Paths are separated by commas, e.g. `watch "shaders, models";`
+                ("watch", ast::MetadataValue::Str(paths)) => {
+                    metadata.watch = split_paths(paths.to_slice(source));
+                }
+                ("watch_exclude", ast::MetadataValue::Str(paths)) => {
+                    metadata.watch_exclude = split_paths(paths.to_slice(source));
+                }
+                // The frame rate to hold, e.g. `dynamic_resolution 60;`
+                ("dynamic_resolution", ast::MetadataValue::Float(fps)) => {
+                    if *fps <= 0.0 {
+                        return Err(SemanticError::error_from_ast(
+                            entry,
+                            format!("The frame rate must be positive"),
+                        ));
+                    }
+                    metadata.dynamic_resolution = Some(*fps);
+                }
+                ("duration", ast::MetadataValue::Float(duration)) => {
+                    if *duration <= 0.0 {
+                        return Err(SemanticError::error_from_ast(
+                            entry,
+                            format!("The duration must be positive"),
+                        ));
+                    }
+                    metadata.duration = Some(*duration);
+                }
+                ("soundtrack", ast::MetadataValue::Str(path)) => {
+                    metadata.soundtrack = Some(path.to_owned(source));
+                }
+                // Times are separated by commas, e.g. `scenes "0, 12, 30.5";`
+                ("scenes", ast::MetadataValue::Str(times)) => {
+                    metadata.scenes = parse_scene_times(times.to_slice(source))
+                        .map_err(|e| SemanticError::error_from_ast(entry, e))?;
+                }
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        entry,
+                        format!("Unknown metadata `{}`", key),
+                    ))
+                }
+            }
+        }
+        Ok(metadata)
     }
 
     fn collect_sync_tracks(source: &str, ast: &ast::Program) -> HashSet<String> {
@@ -761,6 +3210,283 @@ impl ProgramContainer {
         tracks
     }
 
+    /// Hints are given with the name the track has in the editor, e.g. `"cam:x"` for `sync.cam.x`
+    fn collect_track_hints(
+        source: &str,
+        ast: &ast::Program,
+        sync_tracks: &HashSet<String>,
+        warnings: &mut Vec<SemanticWarning>,
+    ) -> Result<Vec<TrackHint>, SemanticError> {
+        let literal_float = |track: &ast::Track, key: &str| -> Result<Option<f32>, SemanticError> {
+            match track.get_property(source, key) {
+                Some(ast::ValueExpr::FloatLiteral(_, value)) => Ok(Some(*value)),
+                Some(value) => Err(SemanticError::error_from_ast(
+                    value,
+                    format!("Expected float literal for `{}`", key),
+                )),
+                None => Ok(None),
+            }
+        };
+
+        let mut result = Vec::new();
+        for track in &ast.tracks {
+            let name = track.name.to_owned(source);
+            if result.iter().any(|h: &TrackHint| h.name == name) {
+                return Err(SemanticError::error_from_ast(
+                    track,
+                    format!("Multiple definitions of `{}` found", name),
+                ));
+            }
+            for property in &track.properties {
+                let key = property.key.to_slice(source);
+                if !["min", "max", "interpolation"].contains(&key) {
+                    return Err(SemanticError::error_from_ast(
+                        &property.key,
+                        format!("Unknown track property `{}`", key),
+                    ));
+                }
+            }
+
+            let range = match (literal_float(track, "min")?, literal_float(track, "max")?) {
+                (Some(min), Some(max)) if min < max => Some((min, max)),
+                (None, None) => None,
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        track,
+                        format!("A range needs both `min` and `max`, with `min` below `max`"),
+                    ))
+                }
+            };
+            let interpolation = match track.get_property(source, "interpolation") {
+                Some(value) => {
+                    let interpolation = expect_ast_string(value, source)?;
+                    Some(TrackInterpolation::from_str(&interpolation).ok_or_else(|| {
+                        SemanticError::error_from_ast(value, format!("Not a valid interpolation: {}", interpolation))
+                    })?)
+                }
+                None => None,
+            };
+
+            if !sync_tracks.contains(&name) {
+                warnings.push(SemanticWarning::warning_from_ast(
+                    track,
+                    format!("Hints for the track `{}`, which is not used", name),
+                ));
+            }
+            result.push(TrackHint {
+                name: name,
+                range: range,
+                interpolation: interpolation,
+            });
+        }
+        Ok(result)
+    }
+
+    fn collect_function_signatures(
+        source: &str,
+        ast: &ast::Program,
+    ) -> Result<HashMap<String, FunctionSignature>, SemanticError> {
+        let mut result = HashMap::new();
+        for function in &ast.functions {
+            let name = function.name.to_owned(source);
+            if result.contains_key(&name) {
+                return Err(SemanticError::error_from_ast(
+                    &function.name,
+                    format!("Multiple definitions of function `{}` found", name),
+                ));
+            }
+            result.insert(name, FunctionSignature::from_ast(source, function));
+        }
+        Ok(result)
+    }
+
+    /// Rewrites calls to user functions, such that all arguments are passed by position
+    ///
+    /// Named arguments are moved to the position of the corresponding parameter, and omitted parameters are filled
+    /// with their default values.
+    fn resolve_call_arguments(source: &str, ast: &ast::Program) -> Result<ast::Program, SemanticError> {
+        let functions: HashMap<&str, &ast::Function> =
+            ast.functions.iter().map(|f| (f.name.to_slice(source), f)).collect();
+
+        for function in &ast.functions {
+            for param in &function.params {
+                if let Some(default) = &param.default {
+                    if Self::calls_user_function(source, default, &functions) {
+                        return Err(SemanticError::error_from_ast(
+                            default,
+                            format!("Default values cannot call user defined functions"),
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut resolved = ast.clone();
+        for target_def in &mut resolved.render_targets {
+            Self::resolve_expr_call_arguments(source, &mut target_def.width, &functions)?;
+            Self::resolve_expr_call_arguments(source, &mut target_def.height, &functions)?;
+        }
+        for object in &mut resolved.objects {
+            for property in &mut object.properties {
+                Self::resolve_expr_call_arguments(source, &mut property.value, &functions)?;
+            }
+        }
+        for light in &mut resolved.lights {
+            for property in &mut light.properties {
+                Self::resolve_expr_call_arguments(source, &mut property.value, &functions)?;
+            }
+        }
+        for function in &mut resolved.functions {
+            Self::resolve_block_call_arguments(source, &mut function.block, &functions)?;
+        }
+        Ok(resolved)
+    }
+    fn resolve_block_call_arguments(
+        source: &str,
+        block: &mut Vec<Stmt>,
+        functions: &HashMap<&str, &ast::Function>,
+    ) -> Result<(), SemanticError> {
+        for stmt in block {
+            match stmt {
+                ast::Stmt::FunctionCall(function_call) => {
+                    Self::resolve_function_call_arguments(source, function_call, functions)?
+                }
+                ast::Stmt::Return { expr } | ast::Stmt::Let { expr, .. } => {
+                    Self::resolve_expr_call_arguments(source, expr, functions)?
+                }
+                ast::Stmt::Conditional { condition, a, b } => {
+                    Self::resolve_expr_call_arguments(source, condition, functions)?;
+                    Self::resolve_block_call_arguments(source, a, functions)?;
+                    if let Some(b) = b {
+                        Self::resolve_block_call_arguments(source, b, functions)?;
+                    }
+                }
+                ast::Stmt::Loop { start, end, block, .. } => {
+                    Self::resolve_expr_call_arguments(source, start, functions)?;
+                    Self::resolve_expr_call_arguments(source, end, functions)?;
+                    Self::resolve_block_call_arguments(source, block, functions)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn resolve_expr_call_arguments(
+        source: &str,
+        expr: &mut ast::ValueExpr,
+        functions: &HashMap<&str, &ast::Function>,
+    ) -> Result<(), SemanticError> {
+        match expr {
+            ast::ValueExpr::PropertyOf(_, v, _) => Self::resolve_expr_call_arguments(source, v, functions),
+            ast::ValueExpr::Dictionary(d) => {
+                for kv in &mut d.entries {
+                    Self::resolve_expr_call_arguments(source, &mut kv.value, functions)?;
+                }
+                Ok(())
+            }
+            ast::ValueExpr::FunctionCall(function_call) => {
+                Self::resolve_function_call_arguments(source, function_call, functions)
+            }
+            ast::ValueExpr::BinaryOp(_, _, l, r) => {
+                Self::resolve_expr_call_arguments(source, l, functions)?;
+                Self::resolve_expr_call_arguments(source, r, functions)
+            }
+            _ => Ok(()),
+        }
+    }
+    fn resolve_function_call_arguments(
+        source: &str,
+        function_call: &mut ast::FunctionCallExpr,
+        functions: &HashMap<&str, &ast::Function>,
+    ) -> Result<(), SemanticError> {
+        for arg in &mut function_call.args {
+            Self::resolve_expr_call_arguments(source, arg, functions)?;
+        }
+        for named_arg in &mut function_call.named_args {
+            Self::resolve_expr_call_arguments(source, &mut named_arg.value, functions)?;
+        }
+
+        // Built-in functions handle their arguments themselves
+        let name = function_call.function.to_slice(source);
+        let function = match functions.get(name) {
+            Some(function) => function,
+            None => return Ok(()),
+        };
+
+        if function_call.args.len() > function.params.len() {
+            return Err(SemanticError::error_from_ast(
+                function_call,
+                format!(
+                    "Expected at most {} arguments for call to `{}`, but got {}.",
+                    function.params.len(),
+                    name,
+                    function_call.args.len()
+                ),
+            ));
+        }
+
+        let mut args: Vec<Option<ast::ValueExpr>> = function_call.args.drain(..).map(Some).collect();
+        args.resize(function.params.len(), None);
+        for named_arg in function_call.named_args.drain(..) {
+            let param_name = named_arg.key.to_slice(source);
+            let idx = function
+                .params
+                .iter()
+                .position(|p| p.name.to_slice(source) == param_name)
+                .ok_or_else(|| {
+                    SemanticError::error_from_ast(
+                        &named_arg.key,
+                        format!("Function `{}` has no parameter named `{}`", name, param_name),
+                    )
+                })?;
+            if args[idx].is_some() {
+                return Err(SemanticError::error_from_ast(
+                    &named_arg.key,
+                    format!("Parameter `{}` is passed more than once", param_name),
+                ));
+            }
+            args[idx] = Some(named_arg.value);
+        }
+
+        let args: Result<Vec<ast::ValueExpr>, SemanticError> = args
+            .into_iter()
+            .zip(function.params.iter())
+            .map(|(arg, param)| {
+                arg.or_else(|| param.default.clone()).ok_or_else(|| {
+                    SemanticError::error_from_ast(
+                        function_call as &ast::FunctionCallExpr,
+                        format!(
+                            "Missing argument `{}` for call to `{}`",
+                            param.name.to_slice(source),
+                            name
+                        ),
+                    )
+                })
+            })
+            .collect();
+        function_call.args = args?;
+        Ok(())
+    }
+    fn calls_user_function(source: &str, expr: &ast::ValueExpr, functions: &HashMap<&str, &ast::Function>) -> bool {
+        match expr {
+            ast::ValueExpr::PropertyOf(_, v, _) => Self::calls_user_function(source, v, functions),
+            ast::ValueExpr::Dictionary(d) => d
+                .entries
+                .iter()
+                .any(|kv| Self::calls_user_function(source, &kv.value, functions)),
+            ast::ValueExpr::FunctionCall(function_call) => {
+                functions.contains_key(function_call.function.to_slice(source))
+                    || function_call
+                        .args
+                        .iter()
+                        .any(|a| Self::calls_user_function(source, a, functions))
+            }
+            ast::ValueExpr::BinaryOp(_, _, l, r) => {
+                Self::calls_user_function(source, l, functions) || Self::calls_user_function(source, r, functions)
+            }
+            _ => false,
+        }
+    }
+
     fn collect_target_defs(source: &str, ast: &ast::Program) -> Result<Vec<RenderTargetDef>, SemanticError> {
         let mut result = Vec::new();
         for op in &ast.render_targets {
@@ -782,79 +3508,229 @@ impl ProgramContainer {
         }
         Ok(result)
     }
-    fn collect_program_defs(source: &str, ast: &ast::Program) -> Result<Vec<ProgramDef>, SemanticError> {
-        let mut result = Vec::new();
-        Self::walk_render_ops(ast, |render_op| {
-            if let ast::Stmt::FunctionCall(call) = render_op {
-                if call.function.to_slice(source) == "program" && call.args.len() == 1 {
-                    let program_def = ProgramDef::from_ast(source, &call.args[0])?;
-                    if !result.iter().any(|d: &ProgramDef| *d == program_def) {
-                        result.push(program_def);
+    /// Checks that resources are given by literals, see `RESOURCE_ARGUMENTS`
+    ///
+    /// Other expressions would slip through the collection of the resources, this reports them with a clear error.
+    fn check_static_resources(source: &str, ast: &ast::Program) -> Result<(), SemanticError> {
+        let static_error = |arg: &dyn ast::AstNode, function: &str, description: &str| {
+            SemanticError::error_from_ast(
+                arg,
+                format!(
+                    "The {} of `{}` has to be a literal, resources are loaded before the demo runs",
+                    description, function
+                ),
+            )
+        };
+
+        ast.walk_calls(&mut |call| {
+            let function = call.function.to_slice(source);
+            for &(_, index, description) in RESOURCE_ARGUMENTS.iter().filter(|r| r.0 == function) {
+                let arg = match call.args.get(index) {
+                    Some(arg) => arg,
+                    None => continue,
+                };
+                let is_static = match arg {
+                    ast::ValueExpr::StringLiteral(_) => function != "program",
+                    // The arguments of terrains are checked when they are collected
+                    ast::ValueExpr::FunctionCall(call) => {
+                        function == "draw_model" && call.function.to_slice(source) == "terrain"
+                    }
+                    ast::ValueExpr::Dictionary(dict) => {
+                        function == "program" && dict.entries.iter().all(|kv| kv.value.as_string(source).is_ok())
                     }
+                    _ => false,
+                };
+                if !is_static {
+                    return Err(static_error(arg, function, description));
                 }
             }
             Ok(())
         })?;
+
+        for mesh in ast.objects.iter().filter_map(|o| o.get_property(source, "mesh")) {
+            let is_static = match mesh {
+                ast::ValueExpr::StringLiteral(_) => true,
+                ast::ValueExpr::FunctionCall(call) => call.function.to_slice(source) == "terrain",
+                _ => false,
+            };
+            if !is_static {
+                return Err(static_error(mesh, "object", "mesh"));
+            }
+        }
+        for batch in &ast.batches {
+            for mesh in batch.parts.iter().filter_map(|p| p.get_property(source, "mesh")) {
+                let is_static = match mesh {
+                    ast::ValueExpr::StringLiteral(_) => true,
+                    ast::ValueExpr::FunctionCall(call) => call.function.to_slice(source) == "terrain",
+                    _ => false,
+                };
+                if !is_static {
+                    return Err(static_error(mesh, "batch", "mesh"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_program_defs(source: &str, ast: &ast::Program) -> Result<Vec<ProgramDef>, SemanticError> {
+        let mut result = Vec::new();
+        ast.walk_calls(&mut |call| {
+            if call.function.to_slice(source) == "program" && call.args.len() == 1 {
+                push_unique(&mut result, ProgramDef::from_ast(source, &call.args[0])?);
+            }
+            Ok(())
+        })?;
         Ok(result)
     }
-    fn collect_model_defs(source: &str, ast: &ast::Program) -> Result<Vec<String>, SemanticError> {
+    fn collect_uniform_names(source: &str, ast: &ast::Program) -> Result<Vec<String>, SemanticError> {
         let mut result = Vec::new();
-        Self::walk_render_ops(ast, |render_op| {
-            if let ast::Stmt::FunctionCall(call) = render_op {
-                if call.function.to_slice(source) == "draw_model" && call.args.len() == 1 {
-                    let model_path = expect_ast_string(&call.args[0], source)?;
-                    if !result.iter().any(|d| *d == model_path) {
-                        result.push(model_path);
-                    }
+        ast.walk_calls(&mut |call| {
+            let function = call.function.to_slice(source);
+            if (function == "uniform_float"
+                || function == "uniform_color"
+                || function == "uniform_vec3"
+                || function == "uniform_mat4"
+                || function == "uniform_texture_srgb"
+                || function == "uniform_texture_linear"
+                || function == "uniform_rtt")
+                && call.args.len() == 2
+            {
+                push_unique(&mut result, expect_ast_string(&call.args[0], source)?);
+            } else if function == "grab_screen" && call.args.len() == 1 {
+                push_unique(&mut result, expect_ast_string(&call.args[0], source)?);
+            } else if (function == "draw_model" || function == "draw_batch") && call.args.len() == 2 {
+                for (uniform_name, _) in material_entries(source, &call.args[1])? {
+                    push_unique(&mut result, uniform_name);
                 }
             }
             Ok(())
         })?;
+        for material in ast.objects.iter().filter_map(|o| o.get_property(source, "material")) {
+            for (uniform_name, _) in material_entries(source, material)? {
+                push_unique(&mut result, uniform_name);
+            }
+        }
+        Ok(result)
+    }
+    fn collect_model_defs(source: &str, ast: &ast::Program) -> Result<Vec<ModelDef>, SemanticError> {
+        let mut result = Vec::new();
+        ast.walk_calls(&mut |call| {
+            if call.function.to_slice(source) == "draw_model" && (call.args.len() == 1 || call.args.len() == 2) {
+                push_unique(&mut result, model_def(source, &call.args[0])?);
+            }
+            Ok(())
+        })?;
+        for mesh in ast.objects.iter().filter_map(|o| o.get_property(source, "mesh")) {
+            push_unique(&mut result, model_def(source, mesh)?);
+        }
         Ok(result)
     }
     fn collect_texture_defs(source: &str, ast: &ast::Program) -> Result<Vec<TextureDef>, SemanticError> {
         let mut result = Vec::new();
-        Self::walk_render_ops(ast, |render_op| {
-            if let ast::Stmt::FunctionCall(call) = render_op {
-                if (call.function.to_slice(source) == "uniform_texture_srgb"
-                    || call.function.to_slice(source) == "uniform_texture_linear")
-                    && call.args.len() == 2
-                {
-                    let texture_path = expect_ast_string(&call.args[1], source)?;
-                    let texture_srgb = call.function.to_slice(source) == "uniform_texture_srgb";
-                    let texture_def = TextureDef {
-                        path: texture_path,
-                        srgb: texture_srgb,
-                    };
-                    if !result.iter().any(|d| *d == texture_def) {
-                        result.push(texture_def);
+        ast.walk_calls(&mut |call| {
+            let function = call.function.to_slice(source);
+            let texture_arg = match function {
+                "uniform_texture_srgb" | "uniform_texture_linear" if call.args.len() == 2 => Some(&call.args[1]),
+                "draw_textured_quad" if call.args.len() == 5 => Some(&call.args[4]),
+                _ => None,
+            };
+            if let Some(texture_arg) = texture_arg {
+                let texture_path = expect_ast_string(texture_arg, source)?;
+                let texture_srgb = function != "uniform_texture_linear";
+                let texture_def = TextureDef {
+                    path: texture_path,
+                    srgb: texture_srgb,
+                    options: texture_import_options(source, call)?,
+                };
+                push_unique(&mut result, texture_def);
+            }
+            if (function == "draw_model" || function == "draw_batch") && call.args.len() == 2 {
+                for (_, value) in material_entries(source, &call.args[1])? {
+                    if let MaterialValue::Texture(texture_def) = value {
+                        push_unique(&mut result, texture_def);
                     }
                 }
             }
             Ok(())
         })?;
+        for material in ast.objects.iter().filter_map(|o| o.get_property(source, "material")) {
+            for (_, value) in material_entries(source, material)? {
+                if let MaterialValue::Texture(texture_def) = value {
+                    push_unique(&mut result, texture_def);
+                }
+            }
+        }
         Ok(result)
     }
     fn collect_ibl_defs(source: &str, ast: &ast::Program) -> Result<Vec<IblDef>, SemanticError> {
         let mut result = Vec::new();
-        Self::walk_render_ops(ast, |render_op| {
-            if let ast::Stmt::FunctionCall(call) = render_op {
-                if call.function.to_slice(source) == "uniform_ibl" && call.args.len() == 1 {
-                    let ibl_def = IblDef {
-                        folder: expect_ast_string(&call.args[0], source)?,
-                    };
-                    if !result.iter().any(|d| *d == ibl_def) {
-                        result.push(ibl_def);
-                    }
-                }
+        ast.walk_calls(&mut |call| {
+            let function = call.function.to_slice(source);
+            if (function == "uniform_ibl" || function == "draw_skybox") && call.args.len() == 1 {
+                let ibl_def = IblDef {
+                    folder: expect_ast_string(&call.args[0], source)?,
+                };
+                push_unique(&mut result, ibl_def);
             }
             Ok(())
         })?;
         Ok(result)
     }
+    fn collect_scene_objects(
+        source: &str,
+        ast: &ast::Program,
+        header: &ProgramHeader,
+    ) -> Result<Vec<SceneObjectDef>, SemanticError> {
+        let mut result = Vec::new();
+        for object in &ast.objects {
+            let object_def = SceneObjectDef::from_ast(source, object, header)?;
+            if result.iter().any(|o: &SceneObjectDef| o.name == object_def.name) {
+                return Err(SemanticError::error_from_ast(
+                    object,
+                    format!("Multiple definitions of `{}` found", object_def.name),
+                ));
+            }
+            result.push(object_def);
+        }
+        Ok(result)
+    }
+    fn collect_batch_defs(source: &str, ast: &ast::Program) -> Result<Vec<BatchDef>, SemanticError> {
+        let mut result = Vec::new();
+        for batch in &ast.batches {
+            let batch_def = BatchDef::from_ast(source, batch)?;
+            if result.iter().any(|b: &BatchDef| b.name == batch_def.name) {
+                return Err(SemanticError::error_from_ast(
+                    batch,
+                    format!("Multiple definitions of `{}` found", batch_def.name),
+                ));
+            }
+            result.push(batch_def);
+        }
+        Ok(result)
+    }
+    fn collect_lights(source: &str, ast: &ast::Program) -> Result<Vec<LightDef>, SemanticError> {
+        let mut result = Vec::new();
+        for light in &ast.lights {
+            let light_def = LightDef::from_ast(source, light)?;
+            if result.iter().any(|l: &LightDef| l.name == light_def.name) {
+                return Err(SemanticError::error_from_ast(
+                    light,
+                    format!("Multiple definitions of `{}` found", light_def.name),
+                ));
+            }
+            if result.len() == MAX_LIGHTS {
+                return Err(SemanticError::error_from_ast(
+                    light,
+                    format!("At most {} lights can be defined", MAX_LIGHTS),
+                ));
+            }
+            result.push(light_def);
+        }
+        Ok(result)
+    }
     fn collect_external_resources(
         progs: &Vec<ProgramDef>,
-        models: &Vec<String>,
+        models: &Vec<ModelDef>,
         textures: &Vec<TextureDef>,
     ) -> HashSet<String> {
         let mut result = HashSet::new();
@@ -865,10 +3741,11 @@ impl ProgramContainer {
             prog.geom.as_ref().map(|p| result.insert(p.clone()));
             prog.frag.as_ref().map(|p| result.insert(p.clone()));
             prog.comp.as_ref().map(|p| result.insert(p.clone()));
+            prog.raymarch.as_ref().map(|p| result.insert(p.clone()));
         }
 
         for model in models {
-            result.insert(model.clone());
+            result.insert(model.path.clone());
         }
 
         for texture in textures {
@@ -0,0 +1,97 @@
+use gl;
+use gl::types::{GLenum, GLint};
+
+use bytecode::{ProgramContainer, ValueExpr};
+use gl_resources::{self, RenderTarget};
+
+/// Version of the shaders of the engine, which also covers compute shaders, storage buffers and persistent mapping
+const REQUIRED_GL_VERSION: (GLint, GLint) = (4, 4);
+
+/// Limits and optional features of the current GL context
+struct Capabilities {
+    version: (GLint, GLint),
+    max_texture_size: GLint,
+    clip_control: bool,
+    // Whether the renderability of formats can be queried (GL 4.3 or ARB_internalformat_query2)
+    format_query: bool,
+}
+impl Capabilities {
+    fn query() -> Self {
+        let mut major = 0;
+        let mut minor = 0;
+        let mut max_texture_size = 0;
+        unsafe {
+            gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+            gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+            gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut max_texture_size);
+        }
+        let version = (major, minor);
+        Capabilities {
+            version: version,
+            max_texture_size: max_texture_size,
+            clip_control: version >= (4, 5) || gl_resources::has_any_extension(&["GL_ARB_clip_control"]),
+            format_query: gl::GetInternalformativ::is_loaded(),
+        }
+    }
+
+    fn is_color_renderable(&self, format: GLenum) -> bool {
+        if !self.format_query {
+            return true;
+        }
+        let mut support: GLint = 0;
+        unsafe {
+            gl::GetInternalformativ(gl::TEXTURE_2D, format, gl::FRAMEBUFFER_RENDERABLE, 1, &mut support);
+        }
+        support as GLenum != gl::NONE
+    }
+}
+
+/// Checks that the current GL context supports everything the program needs
+///
+/// The error lists every missing feature, so that a demo fails when it is loaded instead of producing GL errors while
+/// it runs. Sizes of render targets are only checked if they are constant.
+pub fn check_program(program: &ProgramContainer) -> Result<(), String> {
+    let caps = Capabilities::query();
+    let mut missing = Vec::new();
+
+    if caps.version < REQUIRED_GL_VERSION {
+        missing.push(format!(
+            "OpenGL {}.{} is required, the context provides {}.{}",
+            REQUIRED_GL_VERSION.0, REQUIRED_GL_VERSION.1, caps.version.0, caps.version.1
+        ));
+    }
+    if program.get_metadata().reversed_z && !caps.clip_control {
+        missing.push(format!("`reversed_z` requires OpenGL 4.5 or ARB_clip_control"));
+    }
+
+    for target in program.get_target_defs() {
+        for (name, format) in &target.formats {
+            if !format.is_depth() && !caps.is_color_renderable(RenderTarget::to_gl_format(*format)) {
+                missing.push(format!(
+                    "The format {:?} of `{}.{}` cannot be rendered to",
+                    format, target.name, name
+                ));
+            }
+        }
+        for size in [&target.width, &target.height].iter() {
+            if let ValueExpr::ConstFloat(size) = size {
+                if *size > caps.max_texture_size as f32 {
+                    missing.push(format!(
+                        "`{}` is {} pixels wide or high, textures are limited to {}",
+                        target.name, size, caps.max_texture_size
+                    ));
+                }
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        let report: Vec<String> = missing.iter().map(|m| format!(" - {}", m)).collect();
+        Err(format!(
+            "The GL context does not support the demo:\n{}",
+            report.join("\n")
+        ))
+    }
+}
@@ -1,4 +1,5 @@
-fn srgb_to_linear(value: f32) -> f32 {
+/// Decodes an sRGB channel in [0, 1], values outside of the range are clamped
+pub fn srgb_to_linear(value: f32) -> f32 {
     if value <= 0.0 {
         0.0
     } else if value <= 0.04045 {
@@ -10,7 +11,8 @@ fn srgb_to_linear(value: f32) -> f32 {
     }
 }
 
-fn linear_to_srgb(value: f32) -> f32 {
+/// Encodes a linear channel in [0, 1], values outside of the range are clamped
+pub fn linear_to_srgb(value: f32) -> f32 {
     if value <= 0.0 {
         0.0
     } else if value < 0.0031308 {
@@ -0,0 +1,87 @@
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+
+use gl;
+
+use ast::SourceSlice;
+use bytecode::{ProgramContainer, SourceSnippet};
+
+/// What the engine was doing, written to the crash file if it panics
+#[derive(Default)]
+struct CrashContext {
+    source: String,
+    resources: Vec<String>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<CrashContext> = RefCell::new(CrashContext::default());
+    // The op which is executed, cleared at the end of each frame
+    static CURRENT_OP: Cell<Option<SourceSlice>> = Cell::new(None);
+}
+
+/// Installs a panic hook which writes a crash report to the given file before the default hook runs
+///
+/// The report holds the panic message, the op of the script which was executed, the last GL error and the resources
+/// of the demo. GL objects are released while the panic unwinds, as long as the panic does not abort.
+pub fn install_panic_hook(crash_file: PathBuf) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let report = crash_report(&info.to_string());
+        match fs::write(&crash_file, report) {
+            Ok(()) => println!("Wrote crash report to {}", crash_file.display()),
+            Err(e) => println!("Failed to write crash report to {}: {}", crash_file.display(), e),
+        }
+        default_hook(info);
+    }));
+}
+
+/// Remembers the source and resources of a demo which was loaded, for crash reports
+pub fn set_program(program: &ProgramContainer) {
+    let mut resources: Vec<String> = program.get_external_resources().iter().cloned().collect();
+    resources.extend(program.get_ibl_defs().iter().map(|ibl| ibl.folder.clone()));
+    resources.sort();
+    CONTEXT.with(|context| {
+        *context.borrow_mut() = CrashContext {
+            source: program.get_source().to_owned(),
+            resources: resources,
+        }
+    });
+}
+
+/// Remembers the op which is executed, `None` once the frame is done
+pub fn set_current_op(slice: Option<SourceSlice>) {
+    CURRENT_OP.with(|op| op.set(slice));
+}
+
+fn crash_report(message: &str) -> String {
+    let mut report = format!("The engine crashed: {}\n", message);
+    CONTEXT.with(|context| {
+        let context = match context.try_borrow() {
+            Ok(context) => context,
+            Err(_) => return,
+        };
+        match CURRENT_OP.with(|op| op.get()) {
+            Some(slice) => report += &format!("\nWhile executing:\n{}\n", SourceSnippet::new(slice, &context.source)),
+            None => report += "\nNo script was executed\n",
+        }
+        report += &format!("\nLast GL error: {}\n", last_gl_error());
+        report += "\nResources:\n";
+        for resource in &context.resources {
+            report += &format!("    {}\n", resource);
+        }
+    });
+    report
+}
+
+fn last_gl_error() -> String {
+    // The panic may happen before the functions are loaded
+    if !gl::GetError::is_loaded() {
+        return format!("GL is not loaded");
+    }
+    match unsafe { gl::GetError() } {
+        gl::NO_ERROR => format!("none"),
+        error => format!("0x{:04X}", error),
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashSet;
+use std::mem;
+
+use ast::{self, Stmt, ValueExpr};
+use astvisitor::{Node, Visitor};
+use bytecode::{SemanticWarning, RENDER_TARGET_FUNCTIONS};
+use types::BinaryOperator;
+
+/// Removes code which is never executed, as well as render targets which are never used
+///
+/// All other resources are collected from the remaining code, hence resources which are only referenced by dead code
+/// are not loaded at all. Everything which is removed is reported as a warning. Functions which are never called are
+/// only removed unless `keep_unused_functions` is set.
+pub fn eliminate_dead_code(source: &str, ast: &mut ast::Program, keep_unused_functions: bool) -> Vec<SemanticWarning> {
+    let mut warnings = Vec::new();
+    for function in &mut ast.functions {
+        eliminate_dead_stmts(source, &mut function.block, &mut warnings);
+    }
+    if !keep_unused_functions {
+        remove_unused_functions(source, ast, &mut warnings);
+    }
+    remove_unused_render_targets(source, ast, &mut warnings);
+    warnings
+}
+
+/// Inlines conditionals with constant conditions and drops statements following a `return`
+fn eliminate_dead_stmts(source: &str, block: &mut Vec<Stmt>, warnings: &mut Vec<SemanticWarning>) {
+    let stmts = mem::replace(block, Vec::new());
+    for stmt in stmts {
+        match stmt {
+            Stmt::Conditional {
+                condition,
+                mut a,
+                mut b,
+            } => {
+                eliminate_dead_stmts(source, &mut a, warnings);
+                if let Some(b) = b.as_mut() {
+                    eliminate_dead_stmts(source, b, warnings);
+                }
+
+                // Branches declaring locals are kept in their own scope, they would shadow the locals that follow
+                let declares_locals = |block: &[Stmt]| {
+                    block.iter().any(|stmt| match stmt {
+                        Stmt::Let { .. } => true,
+                        _ => false,
+                    })
+                };
+                match constant_value(source, &condition) {
+                    Some(value) if value > 0.0 => {
+                        if b.is_some() {
+                            warnings.push(SemanticWarning::warning_from_ast(
+                                &condition,
+                                format!("Condition is always true, the else branch is never executed"),
+                            ));
+                        }
+                        if declares_locals(&a) {
+                            block.push(Stmt::Conditional {
+                                condition: condition,
+                                a: a,
+                                b: None,
+                            });
+                        } else {
+                            block.append(&mut a);
+                        }
+                    }
+                    Some(_) => {
+                        warnings.push(SemanticWarning::warning_from_ast(
+                            &condition,
+                            format!("Condition is always false, the branch is never executed"),
+                        ));
+                        let mut b = b.unwrap_or_default();
+                        if declares_locals(&b) {
+                            block.push(Stmt::Conditional {
+                                condition: condition,
+                                a: Vec::new(),
+                                b: Some(b),
+                            });
+                        } else {
+                            block.append(&mut b);
+                        }
+                    }
+                    None => block.push(Stmt::Conditional {
+                        condition: condition,
+                        a: a,
+                        b: b,
+                    }),
+                }
+            }
+            Stmt::Loop {
+                variable,
+                start,
+                end,
+                block: mut body,
+            } => {
+                eliminate_dead_stmts(source, &mut body, warnings);
+                block.push(Stmt::Loop {
+                    variable: variable,
+                    start: start,
+                    end: end,
+                    block: body,
+                });
+            }
+            stmt => block.push(stmt),
+        }
+    }
+
+    if let Some(idx) = block.iter().position(|stmt| match stmt {
+        Stmt::Return { .. } => true,
+        _ => false,
+    }) {
+        if let Some(unreachable) = block.get(idx + 1) {
+            warnings.push(SemanticWarning::warning_from_ast(
+                unreachable,
+                format!("Unreachable code after return"),
+            ));
+        }
+        block.truncate(idx + 1);
+    }
+}
+
+/// Returns the value of expressions which only consist of constants
+fn constant_value(source: &str, expr: &ValueExpr) -> Option<f32> {
+    match expr {
+        ValueExpr::FloatLiteral(_, value) => Some(*value),
+        ValueExpr::FunctionCall(function_call)
+            if function_call.function.to_slice(source) == "-" && function_call.args.len() == 1 =>
+        {
+            constant_value(source, &function_call.args[0]).map(|v| -v)
+        }
+        ValueExpr::BinaryOp(_, op, l, r) => {
+            let l = constant_value(source, l)?;
+            let r = constant_value(source, r)?;
+            let bool_value = |b: bool| if b { 1.0 } else { 0.0 };
+            Some(match op {
+                BinaryOperator::Add => l + r,
+                BinaryOperator::Sub => l - r,
+                BinaryOperator::Mul => l * r,
+                BinaryOperator::Div => l / r,
+
+                BinaryOperator::Lt => bool_value(l < r),
+                BinaryOperator::Le => bool_value(l <= r),
+                BinaryOperator::Gt => bool_value(l > r),
+                BinaryOperator::Ge => bool_value(l >= r),
+                BinaryOperator::Eq => bool_value(l == r),
+                BinaryOperator::Ne => bool_value(l != r),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Removes all functions which cannot be reached from `main`
+fn remove_unused_functions(source: &str, ast: &mut ast::Program, warnings: &mut Vec<SemanticWarning>) {
+    // Without an entry point nothing is reachable, this is reported when running the program
+    if !ast.functions.iter().any(|f| f.name.to_slice(source) == "main") {
+        return;
+    }
+
+    // Functions called by the properties of scene objects and lights are kept as well, they are evaluated in every
+    // frame or by `draw_scene`
+    let mut reachable = HashSet::new();
+    let mut pending = vec!["main"];
+    let objects = ast.objects.iter().flat_map(|o| &o.properties);
+    for property in objects.chain(ast.lights.iter().flat_map(|l| &l.properties)) {
+        collect_expr_calls(source, &property.value, &mut pending);
+    }
+    while let Some(name) = pending.pop() {
+        if !reachable.insert(name) {
+            continue;
+        }
+        if let Some(function) = ast.functions.iter().find(|f| f.name.to_slice(source) == name) {
+            collect_block_calls(source, &function.block, &mut pending);
+        }
+    }
+
+    for function in &ast.functions {
+        if !reachable.contains(function.name.to_slice(source)) {
+            warnings.push(SemanticWarning::warning_from_ast(
+                &function.name,
+                format!("Function `{}` is never called", function.name.to_slice(source)),
+            ));
+        }
+    }
+    ast.functions.retain(|f| reachable.contains(f.name.to_slice(source)));
+}
+
+fn collect_block_calls<'a>(source: &'a str, block: &[Stmt], calls: &mut Vec<&'a str>) {
+    for stmt in block {
+        match stmt {
+            Stmt::FunctionCall(function_call) => collect_call(source, function_call, calls),
+            Stmt::Return { expr } | Stmt::Let { expr, .. } => collect_expr_calls(source, expr, calls),
+            Stmt::Conditional { condition, a, b } => {
+                collect_expr_calls(source, condition, calls);
+                collect_block_calls(source, a, calls);
+                if let Some(b) = b {
+                    collect_block_calls(source, b, calls);
+                }
+            }
+            Stmt::Loop { start, end, block, .. } => {
+                collect_expr_calls(source, start, calls);
+                collect_expr_calls(source, end, calls);
+                collect_block_calls(source, block, calls);
+            }
+        }
+    }
+}
+
+fn collect_call<'a>(source: &'a str, function_call: &ast::FunctionCallExpr, calls: &mut Vec<&'a str>) {
+    calls.push(function_call.function.to_slice(source));
+    for arg in &function_call.args {
+        collect_expr_calls(source, arg, calls);
+    }
+    for named_arg in &function_call.named_args {
+        collect_expr_calls(source, &named_arg.value, calls);
+    }
+}
+
+fn collect_expr_calls<'a>(source: &'a str, expr: &ValueExpr, calls: &mut Vec<&'a str>) {
+    match expr {
+        ValueExpr::FunctionCall(function_call) => collect_call(source, function_call, calls),
+        ValueExpr::Dictionary(d) => {
+            for kv in &d.entries {
+                collect_expr_calls(source, &kv.value, calls);
+            }
+        }
+        ValueExpr::BinaryOp(_, _, l, r) => {
+            collect_expr_calls(source, l, calls);
+            collect_expr_calls(source, r, calls);
+        }
+        _ => {}
+    }
+}
+
+/// Removes all render targets which are never bound, used as a texture or configured
+fn remove_unused_render_targets(source: &str, ast: &mut ast::Program, warnings: &mut Vec<SemanticWarning>) {
+    let mut used = HashSet::new();
+    for function in &ast.functions {
+        function
+            .walk(&mut |node| -> Result<(), ()> {
+                let function_call = match node {
+                    Node::Stmt(Stmt::FunctionCall(function_call)) => function_call,
+                    Node::Expr(ValueExpr::FunctionCall(function_call)) => function_call,
+                    _ => return Ok(()),
+                };
+                if RENDER_TARGET_FUNCTIONS.contains(&function_call.function.to_slice(source)) {
+                    for arg in &function_call.args {
+                        if let ValueExpr::StringLiteral(name) = arg {
+                            used.insert(name.to_slice(source).split('.').next().unwrap());
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .unwrap_or(());
+    }
+
+    for target_def in &ast.render_targets {
+        if !used.contains(target_def.name.to_slice(source)) {
+            warnings.push(SemanticWarning::warning_from_ast(
+                target_def,
+                format!("Render target `{}` is never used", target_def.name.to_slice(source)),
+            ));
+        }
+    }
+    ast.render_targets.retain(|t| used.contains(t.name.to_slice(source)));
+}
@@ -1,78 +1,255 @@
+#[cfg(feature = "editor")]
 use lalrpop_util::ParseError;
-use std::fs::File;
+#[cfg(feature = "editor")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "editor")]
+use std::collections::BTreeSet;
+#[cfg(feature = "editor")]
+use std::env;
+#[cfg(feature = "editor")]
+use std::fs::{self, File};
+#[cfg(feature = "editor")]
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "editor")]
 use std::io::prelude::*;
 use std::path::Path;
+#[cfg(feature = "editor")]
+use std::path::PathBuf;
 
-use ast::SourceSlice;
-use bytecode::{ProgramContainer, SourceSnippet};
+#[cfg(feature = "editor")]
+use ast;
+#[cfg(feature = "editor")]
+use ast::{Program, SourceSlice};
+use audio::Soundtrack;
+use bytecode::ProgramContainer;
+#[cfg(feature = "editor")]
+use bytecode::{CompileOptions, SourceSnippet};
+use capabilities;
+use crash;
+use diagnostics;
+#[cfg(feature = "editor")]
+use diagnostics::Severity;
+use gl_resources::{RenderTarget, ResourceCache};
+#[cfg(feature = "editor")]
 use grammar::ProgramParser;
+use imageio;
+use pack::PackedDemo;
+use resources::{DiskProvider, ResourceProvider};
 use runtime;
-use runtime::RenderContext;
+use runtime::{DebugStop, ExecutionOptions, FrameRecord, RenderContext, ScrubBar, Value, WindowSettings};
 use sync::SyncTracker;
 
-fn report_parse_error(lo: usize, hi: usize, message: &str, source: &str) -> String {
-    format!(
-        "Parser Error: {}\n\n{}",
-        message,
-        SourceSnippet::new(SourceSlice::new(lo, hi), source)
+#[cfg(feature = "editor")]
+fn report_parse_error(lo: usize, hi: usize, message: &str, expected: &[String], source: &str) -> String {
+    // Only the literal tokens are listed, the names of the others are the regexes of the grammar
+    let literals: Vec<&str> = expected
+        .iter()
+        .filter(|e| e.starts_with('"'))
+        .map(|e| e.as_str())
+        .collect();
+    let notes = if literals.is_empty() {
+        Vec::new()
+    } else {
+        vec![format!("expected one of {}", literals.join(", "))]
+    };
+    let snippet = SourceSnippet::new(SourceSlice::new(lo, hi), source);
+    diagnostics::render(
+        Severity::Error,
+        &format!("Parser Error: {}", message),
+        Some(snippet),
+        &notes,
+        diagnostics::use_color(),
     )
 }
 
+/// Parses a script into its AST, errors are rendered with the offending part of the source
+#[cfg(feature = "editor")]
+pub fn parse_source(demo_src: &str) -> Result<Program, String> {
+    let mut program = ProgramParser::new().parse(demo_src).map_err(|e| match e {
+        ParseError::InvalidToken { location } => report_parse_error(location, location, "Invalid token", &[], demo_src),
+        ParseError::UnrecognizedToken { token, expected } => {
+            let message = format!("Unexpected token `{}`", &demo_src[token.0..token.2]);
+            report_parse_error(token.0, token.2, &message, &expected, demo_src)
+        }
+        e => report_parse_error(0, 0, &format!("{:?}", e), &[], demo_src),
+    })?;
+    program.comments = ast::find_comments(demo_src);
+    Ok(program)
+}
+
+/// Location of the cached bytecode for the given source, the file name is derived from a hash of the source
+#[cfg(feature = "editor")]
+fn bytecode_cache_path(source: &str, options: CompileOptions) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    options.assert_mode.hash(&mut hasher);
+    options.keep_unused_functions.hash(&mut hasher);
+    env::temp_dir()
+        .join("demoengine-cache")
+        .join(format!("{:016x}.bc", hasher.finish()))
+}
+
+#[cfg(feature = "editor")]
+fn load_cached_bytecode(source: &str, options: CompileOptions) -> Option<ProgramContainer> {
+    let bytes = fs::read(bytecode_cache_path(source, options)).ok()?;
+    let bytecode = ProgramContainer::from_bytes(&bytes)
+        .map_err(|e| println!("Ignoring invalid bytecode cache: {}", e))
+        .ok()?;
+
+    // Guard against hash collisions
+    if bytecode.get_source() == source && bytecode.get_options() == options {
+        Some(bytecode)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "editor")]
+fn store_cached_bytecode(bytecode: &ProgramContainer) {
+    let path = bytecode_cache_path(bytecode.get_source(), bytecode.get_options());
+    let result = fs::create_dir_all(path.parent().unwrap()).and_then(|_| fs::write(&path, bytecode.to_bytes()));
+    if let Err(e) = result {
+        println!("Failed to write bytecode cache {:?}: {}", path, e);
+    }
+}
+
+/// File in which editors keep the breakpoints of a script, see `runtime::Debugger`
+#[cfg(feature = "editor")]
+pub fn breakpoints_path(script: &Path) -> PathBuf {
+    script.with_extension("breakpoints")
+}
+
+/// Lines of the breakpoints of a script, one line number per line of the file
+///
+/// Without the file there are no breakpoints, lines which are not a number are ignored.
+#[cfg(feature = "editor")]
+pub fn read_breakpoints(script: &Path) -> BTreeSet<usize> {
+    fs::read_to_string(breakpoints_path(script))
+        .map(|text| text.lines().filter_map(|line| line.trim().parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Replaces the breakpoints of a script, the file is removed when there are none
+#[cfg(feature = "editor")]
+pub fn write_breakpoints(script: &Path, breakpoints: &BTreeSet<usize>) -> Result<(), String> {
+    let path = breakpoints_path(script);
+    let result = if breakpoints.is_empty() {
+        fs::remove_file(&path).or_else(|e| if path.exists() { Err(e) } else { Ok(()) })
+    } else {
+        let lines: Vec<String> = breakpoints.iter().map(|line| format!("{}\n", line)).collect();
+        fs::write(&path, lines.concat())
+    };
+    result.map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
+
 pub struct DemoScene {
     render_context: RenderContext,
     bytecode: ProgramContainer,
 }
 
 impl DemoScene {
-    pub fn from_file(path: &Path) -> Result<Self, String> {
+    #[cfg(feature = "editor")]
+    pub fn from_file(path: &Path, options: CompileOptions, resource_cache: ResourceCache) -> Result<Self, String> {
+        let bytecode = Self::compile_file(path, options)?;
+        Self::from_bytecode(path, bytecode, resource_cache)
+    }
+
+    /// Parses and compiles the demo file, without loading any of the resources it references
+    ///
+    /// Compiled programs are cached, if the file did not change since it was last compiled the cached bytecode is
+    /// loaded instead.
+    #[cfg(feature = "editor")]
+    pub fn compile_file(path: &Path, options: CompileOptions) -> Result<ProgramContainer, String> {
         println!("Opening demo: {:?}", path);
         assert!(path.is_file());
-        let parent_dir = path.parent().unwrap();
 
         let mut file = File::open(path).map_err(|e| format!("Failed to open demo file: {}", e))?;
         let mut demo_src = String::new();
         file.read_to_string(&mut demo_src).unwrap();
 
-        // Parsing => generates AST
-        let ast = ProgramParser::new().parse(&demo_src).map_err(|e| match e {
-            ParseError::InvalidToken { location } => report_parse_error(location, location, "Invalid token", &demo_src),
-            ParseError::UnrecognizedToken { token, .. } => {
-                let location = (token.0, token.2);
-                report_parse_error(location.0, location.1, "Unexpected token", &demo_src)
+        let bytecode = match load_cached_bytecode(&demo_src, options) {
+            Some(bytecode) => {
+                println!(" ~ Using cached bytecode");
+                bytecode
+            }
+            None => {
+                let bytecode = Self::compile_source(&demo_src, options)?;
+                store_cached_bytecode(&bytecode);
+                bytecode
             }
-            e => report_parse_error(0, 0, &format!("{:?}", e), &demo_src),
-        })?;
+        };
+
+        for warning in bytecode.get_warnings() {
+            println!("{}\n", warning.render(&demo_src, diagnostics::use_color()));
+        }
+        Ok(bytecode)
+    }
+
+    #[cfg(feature = "editor")]
+    fn compile_source(demo_src: &str, options: CompileOptions) -> Result<ProgramContainer, String> {
+        // Parsing => generates AST
+        let ast = parse_source(demo_src)?;
 
         // Compiling => generates Bytecode
-        let bytecode = ProgramContainer::from_ast(&demo_src, &ast)
-            .map_err(|e| format!("{}\n\n{}", e, e.source_snippet(&demo_src)))?;
+        let bytecode = ProgramContainer::from_ast(&demo_src, &ast, options)
+            .map_err(|e| e.render(&demo_src, diagnostics::use_color()))?;
+        bytecode.print_stats();
+        Ok(bytecode)
+    }
+
+    /// Creates the scene for already compiled bytecode, loading all referenced resources
+    ///
+    /// Resources found in the cache are shared instead of being loaded again.
+    pub fn from_bytecode(
+        path: &Path,
+        bytecode: ProgramContainer,
+        resource_cache: ResourceCache,
+    ) -> Result<Self, String> {
+        let parent_dir = path.parent().unwrap();
+        Self::from_provider(Box::new(DiskProvider::new(parent_dir)), bytecode, resource_cache)
+    }
+
+    /// Creates the scene for a packed demo, all files are read from the pack
+    pub fn from_pack(pack: PackedDemo, resource_cache: ResourceCache) -> Result<Self, String> {
+        let bytecode = pack.read_bytecode()?;
+        Self::from_provider(Box::new(pack), bytecode, resource_cache)
+    }
+
+    /// Creates the scene for already compiled bytecode, loading all referenced resources from the given provider
+    pub fn from_provider(
+        resources: Box<dyn ResourceProvider>,
+        bytecode: ProgramContainer,
+        resource_cache: ResourceCache,
+    ) -> Result<Self, String> {
+        capabilities::check_program(&bytecode)?;
 
         // Compile programs
-        let mut render_context = RenderContext::new(&parent_dir);
-        for program in bytecode.get_program_defs() {
-            // TODO: Right now we only support vert and frag shaders
-            let vert = program.vert.as_ref().ok_or_else(|| format!("Missing vertex shader"))?;
-            let frag = program
-                .frag
-                .as_ref()
-                .ok_or_else(|| format!("Missing fragment shader"))?;
-            render_context.push_new_shader(&vert, &frag)?;
-        }
+        let mut render_context = RenderContext::new(resources, resource_cache);
+        render_context.push_new_shaders(bytecode.get_program_defs())?;
+        render_context.resolve_uniform_locations(bytecode.get_uniform_names());
 
         // Load models
         for model in bytecode.get_model_defs() {
             render_context.push_new_model(model)?;
         }
 
+        // Merge the meshes of batches
+        for batch in bytecode.get_batch_defs() {
+            render_context.push_new_batch(batch)?;
+        }
+
         // Load textures
         for texture in bytecode.get_texture_defs() {
-            render_context.push_new_texture(&texture.path, texture.srgb)?;
+            render_context.push_new_texture(texture)?;
         }
 
         // Load ibl environments
         for ibl in bytecode.get_ibl_defs() {
             render_context.push_new_ibl(&ibl.folder)?;
         }
+        render_context.warn_unused_uniforms(bytecode.get_program_defs());
+        render_context.prune_resource_cache();
+        crash::set_program(&bytecode);
 
         Ok(Self {
             render_context: render_context,
@@ -80,18 +257,192 @@ impl DemoScene {
         })
     }
 
+    /// Replaces the bytecode of the scene, keeping all loaded resources
+    ///
+    /// This only succeeds if the new bytecode references the same resources as the current one, otherwise the bytecode
+    /// is handed back and the scene has to be recreated using `from_bytecode`.
+    pub fn swap_bytecode(&mut self, bytecode: ProgramContainer) -> Result<(), ProgramContainer> {
+        // A new scene reports the features which are not supported
+        if !self.bytecode.has_same_resources(&bytecode) || capabilities::check_program(&bytecode).is_err() {
+            return Err(bytecode);
+        }
+        self.render_context
+            .resolve_uniform_locations(bytecode.get_uniform_names());
+        self.render_context.warn_unused_uniforms(bytecode.get_program_defs());
+        crash::set_program(&bytecode);
+        self.bytecode = bytecode;
+        Ok(())
+    }
+
+    /// Releases the scene, keeping its resources so that they can be shared with a reloaded scene
+    pub fn into_resource_cache(self) -> ResourceCache {
+        self.render_context.into_resource_cache()
+    }
+
     pub fn get_bytecode(&self) -> &ProgramContainer {
         &self.bytecode
     }
 
-    pub fn draw(&mut self, width: f32, height: f32, time_s: f32, sync_track: &dyn SyncTracker) -> Result<(), String> {
-        runtime::execute(
+    /// Values of the `probe` calls in the last frames
+    pub fn get_probes(&self) -> &[runtime::Probe] {
+        self.render_context.get_probes()
+    }
+
+    /// Where the debugger stopped the last frame
+    pub fn get_debug_stop(&self) -> Option<&DebugStop> {
+        self.render_context.get_debug_stop()
+    }
+
+    /// Decodes the music given by `soundtrack` in the metadata, if there is any
+    pub fn load_soundtrack(&self) -> Result<Option<Soundtrack>, String> {
+        match &self.bytecode.get_metadata().soundtrack {
+            Some(path) => {
+                let bytes = self.render_context.read_resource(Path::new(path))?;
+                Soundtrack::from_bytes(path, bytes).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// State of the window set by the script, see `WindowSettings`
+    pub fn get_window_settings(&self) -> &WindowSettings {
+        self.render_context.get_window_settings()
+    }
+
+    /// Time on the scrub bar under a position of the window, given in pixels from its top left corner
+    ///
+    /// The position is outside of the bar if `None` is returned, see `ScrubBar::time_at`.
+    pub fn get_scrub_time(&self, window_height: f32, position: (f32, f32), time_s: f32, dragging: bool) -> Option<f32> {
+        let (x, y, width, height) = self.render_context.get_screen_rect();
+        let top = window_height - (y + height) as f32;
+        let scrub_bar = ScrubBar::new(self.bytecode.get_metadata(), time_s);
+        scrub_bar.time_at((width, height), (position.0 - x as f32, position.1 - top), dragging)
+    }
+
+    pub fn draw(
+        &mut self,
+        width: f32,
+        height: f32,
+        time_s: f32,
+        sync_track: &dyn SyncTracker,
+        options: &ExecutionOptions,
+    ) -> Result<(), String> {
+        let bytecode = &self.bytecode;
+        let result = runtime::execute(
+            &mut self.render_context,
+            bytecode,
+            width,
+            height,
+            time_s,
+            sync_track,
+            options,
+        );
+        crash::set_current_op(None);
+        result.map_err(|e| e.render(bytecode.get_source(), diagnostics::use_color()))
+    }
+
+    /// Renders a frame like `draw`, and returns what it did
+    pub fn record_frame(
+        &mut self,
+        width: f32,
+        height: f32,
+        time_s: f32,
+        sync_track: &dyn SyncTracker,
+        options: &ExecutionOptions,
+    ) -> Result<FrameRecord, String> {
+        self.render_context.start_recording();
+        let result = self.draw(width, height, time_s, sync_track, options);
+        let record = self.render_context.take_recording().unwrap_or_default();
+        result.map(|_| record)
+    }
+
+    /// Writes a color buffer of a render target, as left by the last frame, to an EXR file
+    ///
+    /// The buffer is given as `target.buffer`, the first buffer is used if only the target is given.
+    pub fn capture_target(&mut self, buffer_name: &str, path: &Path) -> Result<(), String> {
+        let (target_name, color_name) = match buffer_name.find('.') {
+            Some(i) => (&buffer_name[..i], Some(&buffer_name[i + 1..])),
+            None => (buffer_name, None),
+        };
+        let target_defs = self.bytecode.get_target_defs();
+        let target = target_defs
+            .iter()
+            .position(|rt| rt.name == target_name)
+            .ok_or_else(|| format!("Unknown render target \"{}\"", target_name))?;
+        let formats = &target_defs[target].formats;
+        let buffer = match color_name {
+            Some(color_name) => formats
+                .iter()
+                .position(|f| f.0 == color_name)
+                .ok_or_else(|| format!("Render target \"{}\" has no buffer \"{}\"", target_name, color_name))?,
+            None => 0,
+        };
+        if formats.get(buffer).map_or(true, |f| f.1.is_depth()) {
+            return Err(format!("\"{}\" is not a color buffer", buffer_name));
+        }
+
+        let (width, height, pixels) = self.render_context.read_target_buffer(target as u32, buffer as u32)?;
+        imageio::write_exr(path, width as usize, height as usize, &pixels)
+    }
+
+    /// Writes the area of the window the last frame was rendered to, as shown on screen, to a PNG file
+    pub fn capture_screen(&self, path: &Path) -> Result<(), String> {
+        let (width, height, pixels) = self.render_context.read_screen();
+        imageio::write_png(path, width, height, &pixels)
+    }
+
+    /// Reads the area of the screen the last frame was rendered to, see `RenderContext::read_screen`
+    pub fn read_screen(&self) -> (u32, u32, Vec<u8>) {
+        self.render_context.read_screen()
+    }
+
+    /// Renders the following frames into the given target instead of the window, or into the window again if `None`
+    ///
+    /// The first color buffer of the target takes the place of the screen, it should have the size passed to `draw`.
+    pub fn set_screen_target(&mut self, target: Option<&RenderTarget>) {
+        let framebuffer = target.map_or(0, |target| target.get_fbo_handle());
+        self.render_context.set_screen_framebuffer(framebuffer);
+    }
+
+    /// Writes the last frame like `capture_screen`, scaled down to fit into a square of the given size
+    pub fn capture_thumbnail(&self, path: &Path, size: u32) -> Result<(), String> {
+        let (width, height, pixels) = self.render_context.read_screen();
+        imageio::write_png_thumbnail(path, width, height, &pixels, size)
+    }
+
+    /// Makes the time jumping back by the given length continue the frame before, see `RenderContext::set_loop_length`
+    pub fn set_loop_length(&mut self, loop_length_s: Option<f32>) {
+        self.render_context.set_loop_length(loop_length_s);
+    }
+
+    /// Renders a frame like `draw`, calling the given function of the script instead of `main`
+    ///
+    /// This lets tools render specific parts of a demo at a specific time, without running the main loop. Functions
+    /// which are not called by `main` are only available if the demo was compiled with
+    /// `CompileOptions::keep_unused_functions`.
+    pub fn evaluate(
+        &mut self,
+        function: &str,
+        args: Vec<Value>,
+        width: f32,
+        height: f32,
+        time_s: f32,
+        sync_track: &dyn SyncTracker,
+        options: &ExecutionOptions,
+    ) -> Result<Value, String> {
+        let bytecode = &self.bytecode;
+        let result = runtime::evaluate(
             &mut self.render_context,
-            &self.bytecode,
+            bytecode,
+            function,
+            args,
             width,
             height,
             time_s,
             sync_track,
-        )
+            options,
+        );
+        crash::set_current_op(None);
+        result.map_err(|e| e.render(bytecode.get_source(), diagnostics::use_color()))
     }
 }
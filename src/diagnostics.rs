@@ -0,0 +1,101 @@
+use std::env;
+use std::io::{self, IsTerminal};
+
+use bytecode::SourceSnippet;
+
+/// ANSI escapes of the styles used in diagnostics, blue is used for locations, line numbers and notes
+const BOLD: &str = "\x1b[1m";
+pub const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+impl Severity {
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+
+    /// ANSI escape selecting the color in which the label and the highlighted source are printed
+    pub fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+            Severity::Note => BLUE,
+        }
+    }
+}
+
+/// Whether diagnostics are printed with colors, which is the case if stdout is a terminal and `NO_COLOR` is not set
+pub fn use_color() -> bool {
+    env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal()
+}
+
+/// Wraps the text in the given style, if colors are used
+pub fn paint(style: &str, text: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Formats a diagnostic like rustc: the severity and message, the location, the highlighted source and the notes
+pub fn render(
+    severity: Severity,
+    message: &str,
+    snippet: Option<SourceSnippet>,
+    notes: &[String],
+    color: bool,
+) -> String {
+    let mut result = format!(
+        "{}{}",
+        paint(severity.color(), severity.label(), color),
+        paint(BOLD, &format!(": {}", message), color)
+    );
+    if let Some(snippet) = snippet {
+        let (line, column) = snippet.location();
+        result += &format!("\n {} line {}, column {}\n", paint(BLUE, "-->", color), line, column);
+        result += &format!("{}", snippet.with_color(if color { Some(severity) } else { None }));
+    }
+    for note in notes {
+        result += &format!("\n {} {}", paint(BLUE, "= note:", color), note);
+    }
+    result
+}
+
+/// The candidate closest to a misspelled name, if it is close enough to be a likely typo
+pub fn closest_match<'a, I: IntoIterator<Item = &'a str>>(name: &str, candidates: I) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut candidates: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect();
+    // Sorting by name as well keeps the suggestion stable for candidates from hash maps
+    candidates.sort();
+    candidates.first().map(|&(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between two strings, counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,48 @@
+use glm;
+
+/// Vertex shader of the built-in rectangles, maps the fullscreen quad to the rectangle
+pub const RECT_VERTEX_SHADER: &str = "#version 440
+layout(location=0) in vec2 position;
+uniform mat4 u_ModelViewProjectionMatrix;
+out vec2 v_uv;
+void main() {
+  v_uv = position * 0.5 + 0.5;
+  gl_Position = u_ModelViewProjectionMatrix * vec4(position, 0.0, 1.0);
+}";
+
+pub const RECT_FRAGMENT_SHADER: &str = "#version 440
+layout(location=0) out vec4 color;
+uniform vec4 u_Color;
+void main() {
+  color = u_Color;
+}";
+
+pub const TEXTURED_RECT_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Texture;
+void main() {
+  color = texture(t_Texture, v_uv);
+}";
+
+/// Projection mapping pixels to clip space, with the origin in the top left corner and y pointing down
+pub fn pixel_projection(width: f32, height: f32) -> glm::Mat4 {
+    glm::Mat4::new(
+        glm::Vec4::new(2.0 / width, 0.0, 0.0, 0.0),
+        glm::Vec4::new(0.0, -2.0 / height, 0.0, 0.0),
+        glm::Vec4::new(0.0, 0.0, -1.0, 0.0),
+        glm::Vec4::new(-1.0, 1.0, 0.0, 1.0),
+    )
+}
+
+/// Transforms the fullscreen quad, which spans from -1 to 1, to the given rectangle
+///
+/// The top of the rectangle (smallest y) gets the v coordinate 0, like the top row of images.
+pub fn rect_transform(x: f32, y: f32, width: f32, height: f32) -> glm::Mat4 {
+    glm::Mat4::new(
+        glm::Vec4::new(width / 2.0, 0.0, 0.0, 0.0),
+        glm::Vec4::new(0.0, height / 2.0, 0.0, 0.0),
+        glm::Vec4::new(0.0, 0.0, 1.0, 0.0),
+        glm::Vec4::new(x + width / 2.0, y + height / 2.0, 0.0, 1.0),
+    )
+}
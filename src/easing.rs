@@ -0,0 +1,195 @@
+use std::f32::consts::PI;
+
+/// Expression built-ins for shaping animations, with the number of `float` arguments they take
+///
+/// The easing functions map t from 0 to 1 onto 0 to 1, t is clamped to that range first.
+pub const FUNCTIONS: [(&str, usize); 22] = [
+    ("ease_in_quad", 1),
+    ("ease_out_quad", 1),
+    ("ease_in_out_quad", 1),
+    ("ease_in_cubic", 1),
+    ("ease_out_cubic", 1),
+    ("ease_in_out_cubic", 1),
+    ("ease_in_sine", 1),
+    ("ease_out_sine", 1),
+    ("ease_in_out_sine", 1),
+    ("ease_in_expo", 1),
+    ("ease_out_expo", 1),
+    ("ease_in_out_expo", 1),
+    ("ease_out_back", 1),
+    ("ease_out_elastic", 1),
+    ("ease_out_bounce", 1),
+    ("step", 2),       // edge, x: 0 below the edge, 1 from the edge on
+    ("smoothstep", 3), // edge0, edge1, x: like in GLSL
+    ("pulse", 2),      // t, width: 1 for t in [0, width), 0 elsewhere
+    ("clamp", 3),      // x, min, max
+    ("mix", 3),        // a, b, t: linear interpolation, t is not clamped
+    ("fract", 1),
+    ("saturate", 1), // clamps to [0, 1]
+];
+
+/// Returns the number of arguments of the given built-in, or `None` if there is no such built-in
+pub fn arg_count(name: &str) -> Option<usize> {
+    FUNCTIONS.iter().find(|f| f.0 == name).map(|f| f.1)
+}
+
+/// Evaluates a built-in, the number of arguments has to match `arg_count`
+pub fn evaluate(name: &str, args: &[f32]) -> Option<f32> {
+    let t = saturate(args[0]);
+    let value = match name {
+        "ease_in_quad" => t * t,
+        "ease_out_quad" => 1.0 - (1.0 - t) * (1.0 - t),
+        "ease_in_out_quad" => {
+            if t < 0.5 {
+                2.0 * t * t
+            } else {
+                1.0 - (-2.0 * t + 2.0).powi(2) / 2.0
+            }
+        }
+        "ease_in_cubic" => t * t * t,
+        "ease_out_cubic" => 1.0 - (1.0 - t).powi(3),
+        "ease_in_out_cubic" => {
+            if t < 0.5 {
+                4.0 * t * t * t
+            } else {
+                1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+            }
+        }
+        "ease_in_sine" => 1.0 - (t * PI / 2.0).cos(),
+        "ease_out_sine" => (t * PI / 2.0).sin(),
+        "ease_in_out_sine" => -((t * PI).cos() - 1.0) / 2.0,
+        "ease_in_expo" => {
+            if t == 0.0 {
+                0.0
+            } else {
+                2.0f32.powf(10.0 * t - 10.0)
+            }
+        }
+        "ease_out_expo" => {
+            if t == 1.0 {
+                1.0
+            } else {
+                1.0 - 2.0f32.powf(-10.0 * t)
+            }
+        }
+        "ease_in_out_expo" => {
+            if t == 0.0 || t == 1.0 {
+                t
+            } else if t < 0.5 {
+                2.0f32.powf(20.0 * t - 10.0) / 2.0
+            } else {
+                (2.0 - 2.0f32.powf(-20.0 * t + 10.0)) / 2.0
+            }
+        }
+        "ease_out_back" => {
+            let c1 = 1.70158;
+            let c3 = c1 + 1.0;
+            1.0 + c3 * (t - 1.0).powi(3) + c1 * (t - 1.0).powi(2)
+        }
+        "ease_out_elastic" => {
+            if t == 0.0 || t == 1.0 {
+                t
+            } else {
+                2.0f32.powf(-10.0 * t) * ((t * 10.0 - 0.75) * (2.0 * PI / 3.0)).sin() + 1.0
+            }
+        }
+        "ease_out_bounce" => ease_out_bounce(t),
+        "step" => {
+            if args[1] < args[0] {
+                0.0
+            } else {
+                1.0
+            }
+        }
+        "smoothstep" => {
+            let x = saturate((args[2] - args[0]) / (args[1] - args[0]));
+            x * x * (3.0 - 2.0 * x)
+        }
+        "pulse" => {
+            if args[0] >= 0.0 && args[0] < args[1] {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        "clamp" => args[0].max(args[1]).min(args[2]),
+        "mix" => args[0] + (args[1] - args[0]) * args[2],
+        "fract" => args[0] - args[0].floor(),
+        "saturate" => t,
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn saturate(x: f32) -> f32 {
+    x.max(0.0).min(1.0)
+}
+
+fn ease_out_bounce(t: f32) -> f32 {
+    let n1 = 7.5625;
+    let d1 = 2.75;
+    if t < 1.0 / d1 {
+        n1 * t * t
+    } else if t < 2.0 / d1 {
+        let t = t - 1.5 / d1;
+        n1 * t * t + 0.75
+    } else if t < 2.5 / d1 {
+        let t = t - 2.25 / d1;
+        n1 * t * t + 0.9375
+    } else {
+        let t = t - 2.625 / d1;
+        n1 * t * t + 0.984375
+    }
+}
+
+/// State of an `envelope`, which follows a gate signal with attack, decay, sustain and release phases
+///
+/// All durations are in seconds. The envelope only sees the gate when it is evaluated, so it has to be evaluated in
+/// every frame to notice short gates.
+#[derive(Default)]
+pub struct Envelope {
+    gate: bool,
+    // Time of the last change of the gate, and the value of the envelope at that point
+    edge_time_s: f32,
+    edge_value: f32,
+    value: f32,
+}
+impl Envelope {
+    /// Returns the value of the envelope at the given time, which must not be before the previous update
+    pub fn update(&mut self, time_s: f32, gate: bool, attack: f32, decay: f32, sustain: f32, release: f32) -> f32 {
+        if gate != self.gate {
+            self.gate = gate;
+            self.edge_time_s = time_s;
+            self.edge_value = self.value;
+        }
+
+        let elapsed = time_s - self.edge_time_s;
+        self.value = if !gate {
+            self.edge_value * (1.0 - ramp(elapsed, release))
+        } else if elapsed < attack {
+            self.edge_value + (1.0 - self.edge_value) * ramp(elapsed, attack)
+        } else {
+            1.0 + (sustain - 1.0) * ramp(elapsed - attack, decay)
+        };
+        self.value
+    }
+
+    /// Value of the envelope as of the last update
+    pub fn get_value(&self) -> f32 {
+        self.value
+    }
+
+    /// Moves the last change of the gate by the given offset, for when the time is wrapped around
+    pub fn shift_time(&mut self, offset_s: f32) {
+        self.edge_time_s += offset_s;
+    }
+}
+
+/// Goes from 0 to 1 over the given duration, reaches 1 immediately if the duration is not positive
+fn ramp(elapsed: f32, duration: f32) -> f32 {
+    if duration > 0.0 {
+        saturate(elapsed / duration)
+    } else {
+        1.0
+    }
+}
@@ -0,0 +1,605 @@
+use ast::{
+    AstNode, Batch, Comment, DictionaryExpr, Function, FunctionCallExpr, KeyValuePairExpr, Light, MetadataEntry,
+    MetadataValue, Program, RenderTargetDef, SceneObject, SourceSlice, Stmt, Track, Type, ValueExpr,
+};
+use demoscene;
+use types::BinaryOperator;
+
+const INDENT: &str = "    ";
+
+/// Formats a script with canonical indentation and spacing
+///
+/// Comments and single blank lines between statements and definitions are kept. Lists which are broken after their
+/// opening bracket are printed with one entry per line, all others on a single line. Comments inside of expressions
+/// which are printed on a single line are moved behind the line.
+pub fn format_source(source: &str) -> Result<String, String> {
+    let program = demoscene::parse_source(source)?;
+    let mut formatter = Formatter {
+        source: source,
+        printed: vec![false; program.comments.len()],
+        comments: program.comments.clone(),
+        next_comment: 0,
+        output: String::new(),
+        indent: 0,
+        position: 0,
+    };
+    formatter.program(&program);
+    Ok(formatter.output)
+}
+
+/// A definition at the top level of a script
+enum Item<'a> {
+    Metadata(SourceSlice, Vec<&'a MetadataEntry>),
+    RenderTarget(&'a RenderTargetDef),
+    Object(&'a SceneObject),
+    Batch(&'a Batch),
+    Light(&'a Light),
+    Track(&'a Track),
+    Function(&'a Function),
+}
+
+/// Binding strength of an expression, operands binding weaker than required by the grammar are put in parentheses
+fn precedence(source: &str, expr: &ValueExpr) -> u8 {
+    match expr {
+        ValueExpr::BinaryOp(_, BinaryOperator::Add, _, _) | ValueExpr::BinaryOp(_, BinaryOperator::Sub, _, _) => 1,
+        ValueExpr::BinaryOp(_, BinaryOperator::Mul, _, _) | ValueExpr::BinaryOp(_, BinaryOperator::Div, _, _) => 2,
+        ValueExpr::BinaryOp(..) => 0,
+        ValueExpr::PropertyOf(..) => 3,
+        ValueExpr::FunctionCall(call) if call.function.to_slice(source) == "-" => 3,
+        _ => 4,
+    }
+}
+
+fn type_name(value_type: Type) -> &'static str {
+    match value_type {
+        Type::Float32 => "f32",
+        Type::LinColor => "color",
+        Type::Vec2 => "vec2",
+        Type::Vec3 => "vec3",
+        Type::Vec4 => "vec4",
+        Type::Mat4 => "mat4",
+        Type::Str | Type::Void => unreachable!(),
+    }
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    comments: Vec<Comment>,
+    // Comments are printed out of order by broken lists, all others in front of the next node
+    printed: Vec<bool>,
+    next_comment: usize,
+    output: String,
+    indent: usize,
+    // End of the part of the source which was printed, comments and blank lines are taken from the source after it
+    position: usize,
+}
+impl<'a> Formatter<'a> {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+        self.output.push_str(text);
+        self.output.push('\n');
+    }
+
+    /// Position of the next token, skipping whitespace and comments
+    fn skip_trivia(&self, mut position: usize) -> usize {
+        loop {
+            let rest = &self.source[position..];
+            position += rest.len() - rest.trim_start().len();
+            match self.comments.binary_search_by_key(&position, |c| c.source_slice.begin) {
+                Ok(index) => position = self.comments[index].source_slice.end,
+                Err(_) => return position,
+            }
+        }
+    }
+
+    /// Position of the next occurrence of the given token outside of comments
+    fn find_token(&self, mut position: usize, token: char) -> usize {
+        loop {
+            position = self.skip_trivia(position);
+            match self.source[position..].chars().next() {
+                Some(c) if c != token => position += c.len_utf8(),
+                _ => return position,
+            }
+        }
+    }
+
+    /// Position of the keyword in front of the given expression, such as `return` or `if`
+    fn keyword_begin(&self, begin: usize, keyword: &str) -> usize {
+        let mut position = begin;
+        loop {
+            position = self.source[..position]
+                .trim_end_matches(|c: char| c.is_whitespace() || c == '(')
+                .len();
+            match self.comments.iter().find(|c| c.source_slice.end == position) {
+                Some(comment) => position = comment.source_slice.begin,
+                None => return position - keyword.len(),
+            }
+        }
+    }
+
+    fn is_broken(&self, begin: usize, end: usize) -> bool {
+        self.source[begin..end].contains('\n')
+    }
+
+    /// Keeps a blank line in front of the node or comment starting at the given position if there is one
+    fn blank_line(&mut self, begin: usize, first: bool, separate: bool) {
+        if !first && (separate || self.source[self.position..begin].matches('\n').count() > 1) {
+            self.output.push('\n');
+        }
+    }
+
+    /// Prints the comments before the given position which were not printed yet
+    ///
+    /// Comments on the line of the previous node, or inside of it, are printed behind it. Returns whether nothing was
+    /// printed in the block so far, and whether the next line still has to be separated by a blank line.
+    fn comments(&mut self, limit: usize, mut first: bool, mut separate: bool) -> (bool, bool) {
+        while self.next_comment < self.comments.len() && self.comments[self.next_comment].source_slice.begin < limit {
+            let index = self.next_comment;
+            self.next_comment += 1;
+            if self.printed[index] {
+                continue;
+            }
+            self.printed[index] = true;
+            let comment = self.comments[index].source_slice;
+            let text = comment.to_slice(self.source);
+            let behind = comment.begin < self.position || !self.is_broken(self.position, comment.begin);
+            if behind && self.output.ends_with('\n') {
+                self.output.pop();
+                self.output.push(' ');
+                self.output.push_str(text);
+                self.output.push('\n');
+            } else {
+                self.blank_line(comment.begin, first, separate);
+                self.line(text);
+                first = false;
+                separate = false;
+            }
+            self.position = self.position.max(comment.end);
+        }
+        (first, separate)
+    }
+
+    /// Prints the comments in front of a node, and a blank line if there is one in front of it
+    fn leading(&mut self, begin: usize, first: bool, separate: bool) {
+        let (first, separate) = self.comments(begin, first, separate);
+        self.blank_line(begin, first, separate);
+    }
+
+    /// Prints the end of a block, after the comments inside of it
+    fn close_block(&mut self, close: usize, first: bool) {
+        self.comments(close, first, false);
+        self.indent -= 1;
+        if self.output.ends_with("{\n") {
+            self.output.pop();
+            self.output.push('}');
+        } else {
+            for _ in 0..self.indent {
+                self.output.push_str(INDENT);
+            }
+            self.output.push('}');
+        }
+        self.position = close + 1;
+    }
+
+    fn program(&mut self, program: &Program) {
+        let mut items: Vec<(SourceSlice, Item)> = Vec::new();
+        for block in &program.metadata_blocks {
+            let entries = program
+                .metadata
+                .iter()
+                .filter(|e| e.source_slice.begin > block.begin && e.source_slice.end < block.end)
+                .collect();
+            items.push((*block, Item::Metadata(*block, entries)));
+        }
+        let targets = program.render_targets.iter();
+        items.extend(targets.map(|t| (t.source_slice, Item::RenderTarget(t))));
+        items.extend(program.objects.iter().map(|o| (o.source_slice, Item::Object(o))));
+        items.extend(program.batches.iter().map(|b| (b.source_slice, Item::Batch(b))));
+        items.extend(program.lights.iter().map(|l| (l.source_slice, Item::Light(l))));
+        items.extend(program.tracks.iter().map(|t| (t.source_slice, Item::Track(t))));
+        items.extend(program.functions.iter().map(|f| (f.source_slice, Item::Function(f))));
+        items.sort_by_key(|item| item.0.begin);
+
+        let mut first = true;
+        let mut previous_function = false;
+        for (slice, item) in items {
+            // Functions are always separated from other definitions by a blank line
+            let is_function = match item {
+                Item::Function(_) => true,
+                _ => false,
+            };
+            self.leading(slice.begin, first, previous_function || is_function);
+            match item {
+                Item::Metadata(slice, entries) => self.metadata(slice, &entries),
+                Item::RenderTarget(target) => self.render_target(target),
+                Item::Object(object) => {
+                    let header = format!("object \"{}\"", object.name.to_slice(self.source));
+                    self.properties(&header, object.name.end, &object.properties, object.source_slice);
+                }
+                Item::Batch(batch) => self.batch(batch),
+                Item::Light(light) => {
+                    let header = format!("light {}", light.name.to_slice(self.source));
+                    self.properties(&header, light.name.end, &light.properties, light.source_slice);
+                }
+                Item::Track(track) => {
+                    let header = format!("track \"{}\"", track.name.to_slice(self.source));
+                    self.properties(&header, track.name.end, &track.properties, track.source_slice);
+                }
+                Item::Function(function) => self.function(function),
+            }
+            first = false;
+            previous_function = is_function;
+        }
+        self.comments(self.source.len(), first, previous_function);
+    }
+
+    /// The source of the given range without the comments inside of it, which are printed behind the node
+    fn without_comments(&self, begin: usize, end: usize) -> String {
+        let mut result = String::new();
+        let mut position = begin;
+        for comment in self.comments.iter().map(|c| c.source_slice) {
+            if comment.begin >= position && comment.end <= end {
+                result += &self.source[position..comment.begin];
+                position = comment.end;
+            }
+        }
+        result + &self.source[position..end]
+    }
+
+    fn metadata(&mut self, slice: SourceSlice, entries: &[&MetadataEntry]) {
+        self.line("metadata {");
+        self.indent += 1;
+        self.position = slice.begin;
+        let mut first = true;
+        for entry in entries {
+            self.leading(entry.source_slice.begin, first, false);
+            // Values are printed as they were written, the numbers are not normalized
+            let value = self.without_comments(entry.key.end, entry.source_slice.end - 1);
+            let value = match entry.value {
+                MetadataValue::Ratio(..) => value.split(':').map(str::trim).collect::<Vec<&str>>().join(":"),
+                _ => value.trim().to_owned(),
+            };
+            let text = format!("{} {};", entry.key.to_slice(self.source), value);
+            self.line(&text);
+            self.position = entry.source_slice.end;
+            first = false;
+        }
+        self.close_block(slice.end - 1, first);
+        self.output.push('\n');
+    }
+
+    fn render_target(&mut self, target: &RenderTargetDef) {
+        let indent = self.indent;
+        let mut args = vec![
+            (target.name, format!("\"{}\"", target.name.to_slice(self.source))),
+            (target.width.source_slice(), self.expr(&target.width, indent)),
+            (target.height.source_slice(), self.expr(&target.height, indent)),
+        ];
+        let function = if target.oit {
+            "define_oit_rt"
+        } else {
+            let formats = target
+                .formats
+                .iter()
+                .map(|(name, format)| (*name, format!("\"{}\": {}", name.to_slice(self.source), format.name())))
+                .collect();
+            let open = self.find_token(target.height.source_slice().end, '{');
+            let close = self.find_token(open, '}');
+            let broken = target
+                .formats
+                .first()
+                .map_or(false, |f| self.is_broken(open, f.0.begin));
+            let text = self.list("{", "}", (open, close), formats, broken, indent);
+            args.push((SourceSlice::new(open, close + 1), text));
+            if target.has_depth {
+                "define_rt_with_depth"
+            } else {
+                "define_rt"
+            }
+        };
+        let brackets = (
+            self.find_token(target.source_slice.begin, '('),
+            target.source_slice.end - 1,
+        );
+        let text = format!("{}{};", function, self.list("(", ")", brackets, args, false, indent));
+        self.line(&text);
+        self.position = self.find_token(target.source_slice.end, ';') + 1;
+    }
+
+    /// Prints objects, lights and tracks, whose properties are given in a block
+    fn properties(&mut self, header: &str, name_end: usize, properties: &[KeyValuePairExpr], slice: SourceSlice) {
+        let open = self.find_token(name_end, '{');
+        let broken = properties.first().map_or(false, |p| self.is_broken(open, p.key.begin));
+        let indent = self.indent;
+        let inner = if broken { indent + 1 } else { indent };
+        let properties: Vec<(SourceSlice, String)> = properties
+            .iter()
+            .map(|p| (p.source_slice(), self.named_arg(p, inner)))
+            .collect();
+        let text = if broken {
+            let list = self.list("{", "}", (open, slice.end - 1), properties, true, indent);
+            format!("{} {}", header, list)
+        } else {
+            let properties: Vec<String> = properties.into_iter().map(|p| p.1).collect();
+            format!("{} {{ {} }}", header, properties.join(", "))
+        };
+        self.line(&text);
+        self.position = slice.end;
+    }
+
+    /// Prints a batch, its parts are printed like the properties of objects
+    fn batch(&mut self, batch: &Batch) {
+        let open = self.find_token(batch.name.end, '[');
+        let broken = batch
+            .parts
+            .first()
+            .map_or(false, |p| self.is_broken(open, p.source_slice.begin));
+        let indent = self.indent;
+        let inner = if broken { indent + 1 } else { indent };
+        let mut parts = Vec::new();
+        for part in &batch.parts {
+            let properties: Vec<String> = part.properties.iter().map(|p| self.named_arg(p, inner)).collect();
+            parts.push((part.source_slice, format!("{{ {} }}", properties.join(", "))));
+        }
+        let list = self.list("[", "]", (open, batch.source_slice.end - 1), parts, broken, indent);
+        let text = format!("batch \"{}\" {}", batch.name.to_slice(self.source), list);
+        self.line(&text);
+        self.position = batch.source_slice.end;
+    }
+
+    fn function(&mut self, function: &Function) {
+        let indent = self.indent;
+        let mut params = Vec::new();
+        for param in &function.params {
+            let name = param.name.to_slice(self.source);
+            params.push(match &param.default {
+                Some(default) => format!(
+                    "{}: {} = {}",
+                    name,
+                    type_name(param.value_type),
+                    self.expr(default, indent)
+                ),
+                None => format!("{}: {}", name, type_name(param.value_type)),
+            });
+        }
+        let return_type = function
+            .return_type
+            .map(|t| format!(" -> {}", type_name(t)))
+            .unwrap_or_default();
+        let header = format!(
+            "fn {}({}){} {{",
+            function.name.to_slice(self.source),
+            params.join(", "),
+            return_type
+        );
+        self.line(&header);
+        self.position = function.name.end;
+        self.block(&function.block, Some(function.source_slice.end - 1));
+        self.output.push('\n');
+    }
+
+    /// Prints the statements of a block and its closing brace, returns the position after the brace
+    ///
+    /// The position of the closing brace is found after the last statement if it is not given.
+    fn block(&mut self, stmts: &[Stmt], close: Option<usize>) -> usize {
+        self.indent += 1;
+        let mut first = true;
+        for stmt in stmts {
+            self.stmt(stmt, first);
+            first = false;
+        }
+        let close = match close {
+            Some(close) => close,
+            // The opening brace follows the condition, if the block is empty
+            None if first => self.find_token(self.find_token(self.position, '{') + 1, '}'),
+            None => self.find_token(self.position, '}'),
+        };
+        self.close_block(close, first);
+        close + 1
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, first: bool) {
+        let indent = self.indent;
+        match stmt {
+            Stmt::FunctionCall(call) => {
+                self.leading(call.source_slice.begin, first, false);
+                let text = format!("{};", self.call(call, indent));
+                self.line(&text);
+                self.position = self.find_token(call.source_slice.end, ';') + 1;
+            }
+            Stmt::Return { expr } => {
+                let begin = self.keyword_begin(expr.source_slice().begin, "return");
+                self.leading(begin, first, false);
+                let text = format!("return {};", self.expr(expr, indent));
+                self.line(&text);
+                self.position = self.find_token(expr.source_slice().end, ';') + 1;
+            }
+            Stmt::Let { name, expr } => {
+                let begin = self.keyword_begin(name.begin, "let");
+                self.leading(begin, first, false);
+                let text = format!("let {} = {};", name.to_slice(self.source), self.expr(expr, indent));
+                self.line(&text);
+                self.position = self.find_token(expr.source_slice().end, ';') + 1;
+            }
+            Stmt::Conditional { condition, a, b } => {
+                let begin = self.keyword_begin(condition.source_slice().begin, "if");
+                self.leading(begin, first, false);
+                let text = format!("if {} {{", self.expr(condition, indent));
+                self.line(&text);
+                self.position = condition.source_slice().end;
+                let end = self.block(a, None);
+                if let Some(b) = b {
+                    self.output.push_str(" else {\n");
+                    self.position = self.skip_trivia(end) + "else".len();
+                    self.block(b, None);
+                }
+                self.output.push('\n');
+            }
+            Stmt::Loop {
+                variable,
+                start,
+                end,
+                block,
+            } => {
+                let begin = self.keyword_begin(variable.begin, "for");
+                self.leading(begin, first, false);
+                let text = format!(
+                    "for {} in {}..{} {{",
+                    variable.to_slice(self.source),
+                    self.expr(start, indent),
+                    self.expr(end, indent)
+                );
+                self.line(&text);
+                self.position = end.source_slice().end;
+                self.block(block, None);
+                self.output.push('\n');
+            }
+        }
+    }
+
+    /// Joins the entries of a list, either on one line or with one entry per line
+    ///
+    /// The brackets are the positions of the opening and closing bracket in the source. The comments between the
+    /// entries of broken lists are kept in place.
+    fn list(
+        &mut self,
+        open: &str,
+        close: &str,
+        brackets: (usize, usize),
+        entries: Vec<(SourceSlice, String)>,
+        broken: bool,
+        indent: usize,
+    ) -> String {
+        if !broken {
+            let entries: Vec<String> = entries.into_iter().map(|e| e.1).collect();
+            return format!("{}{}{}", open, entries.join(", "), close);
+        }
+        let mut result = open.to_owned();
+        let mut previous = brackets.0 + 1;
+        // Every entry of a broken list ends with a comma, which keeps diffs small when entries are added
+        for (slice, entry) in entries {
+            self.list_comments(&mut result, previous, slice.begin, indent + 1);
+            result += "\n";
+            result += &INDENT.repeat(indent + 1);
+            result += &entry;
+            result += ",";
+            previous = slice.end;
+        }
+        self.list_comments(&mut result, previous, brackets.1, indent + 1);
+        result += "\n";
+        result += &INDENT.repeat(indent);
+        result += close;
+        result
+    }
+
+    /// Adds the comments between two entries of a broken list, behind the previous entry if they are on its line
+    fn list_comments(&mut self, result: &mut String, mut begin: usize, end: usize, indent: usize) {
+        for index in 0..self.comments.len() {
+            let comment = self.comments[index].source_slice;
+            if self.printed[index] || comment.begin < begin || comment.begin >= end {
+                continue;
+            }
+            self.printed[index] = true;
+            if self.is_broken(begin, comment.begin) {
+                *result += "\n";
+                *result += &INDENT.repeat(indent);
+            } else {
+                *result += " ";
+            }
+            *result += comment.to_slice(self.source);
+            begin = comment.end;
+        }
+    }
+
+    fn named_arg(&mut self, arg: &KeyValuePairExpr, indent: usize) -> String {
+        format!("{}: {}", arg.key.to_slice(self.source), self.expr(&arg.value, indent))
+    }
+
+    fn call(&mut self, call: &FunctionCallExpr, indent: usize) -> String {
+        let function = call.function.to_slice(self.source);
+        // Negation is parsed as a call of `-`
+        if function == "-" {
+            return format!("-{}", self.operand(&call.args[0], 3, indent));
+        }
+        let open = self.find_token(call.function.end, '(');
+        let first_arg = call
+            .args
+            .first()
+            .map(|a| a.source_slice().begin)
+            .or_else(|| call.named_args.first().map(|a| a.key.begin));
+        let broken = first_arg.map_or(false, |begin| self.is_broken(open, begin));
+        let inner = if broken { indent + 1 } else { indent };
+        let mut args = Vec::new();
+        for arg in &call.args {
+            args.push((arg.source_slice(), self.expr(arg, inner)));
+        }
+        for arg in &call.named_args {
+            args.push((arg.source_slice(), self.named_arg(arg, inner)));
+        }
+        let close = self.find_token(args.last().map_or(open + 1, |a| a.0.end), ')');
+        format!(
+            "{}{}",
+            function,
+            self.list("(", ")", (open, close), args, broken, indent)
+        )
+    }
+
+    fn dictionary(&mut self, dictionary: &DictionaryExpr, indent: usize) -> String {
+        let slice = dictionary.source_slice;
+        let broken = dictionary
+            .entries
+            .first()
+            .map_or(false, |e| self.is_broken(slice.begin, e.key.begin));
+        let inner = if broken { indent + 1 } else { indent };
+        let mut entries = Vec::new();
+        for entry in &dictionary.entries {
+            let text = format!(
+                "\"{}\": {}",
+                entry.key.to_slice(self.source),
+                self.expr(&entry.value, inner)
+            );
+            entries.push((entry.source_slice(), text));
+        }
+        self.list("{", "}", (slice.begin, slice.end - 1), entries, broken, indent)
+    }
+
+    /// Prints an operand, in parentheses if it binds weaker than required
+    fn operand(&mut self, expr: &ValueExpr, min_precedence: u8, indent: usize) -> String {
+        if precedence(self.source, expr) < min_precedence {
+            format!("({})", self.expr(expr, indent))
+        } else {
+            self.expr(expr, indent)
+        }
+    }
+
+    fn expr(&mut self, expr: &ValueExpr, indent: usize) -> String {
+        match expr {
+            ValueExpr::Var(name) => name.to_owned(self.source),
+            // Negative literals are negations folded by the parser, the sign is printed next to the number
+            ValueExpr::FloatLiteral(slice, _) => self
+                .without_comments(slice.begin, slice.end)
+                .split_whitespace()
+                .collect(),
+            ValueExpr::ColorLiteral(slice, _) => slice.to_owned(self.source),
+            ValueExpr::StringLiteral(value) => format!("\"{}\"", value.to_slice(self.source)),
+            ValueExpr::PropertyOf(_, value, properties) => {
+                let mut result = self.operand(value, 4, indent);
+                for property in properties {
+                    result += ".";
+                    result += property.to_slice(self.source);
+                }
+                result
+            }
+            ValueExpr::Dictionary(dictionary) => self.dictionary(dictionary, indent),
+            ValueExpr::FunctionCall(call) => self.call(call, indent),
+            ValueExpr::BinaryOp(_, op, lhs, rhs) => {
+                let precedence = precedence(self.source, expr);
+                let lhs = self.operand(lhs, precedence, indent);
+                let rhs = self.operand(rhs, precedence + 1, indent);
+                format!("{} {} {}", lhs, op, rhs)
+            }
+        }
+    }
+}
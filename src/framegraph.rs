@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+
+use ast::SourceSlice;
+use bytecode::{BytecodeOp, Function, RenderTargetDef, SemanticWarning, ValueExpr, RENDER_TARGET_FUNCTIONS};
+
+/// Checks how the render targets are used during a frame, and warns about the usages which are most likely mistakes
+///
+/// - Render targets which are sampled while they are drawn to, the result of such feedback loops is undefined.
+/// - Render targets which are read, but never written to during the frame.
+/// - Render targets which are written to, but never read during the frame.
+///
+/// The frame is followed from `main` through all calls. Both branches of conditionals are assumed to run, one after
+/// the other, so the warnings describe what may happen rather than what happens in every frame.
+pub fn validate_frame_graph(
+    target_defs: &[RenderTargetDef],
+    uniform_names: &[String],
+    functions: &HashMap<String, Function>,
+) -> Vec<SemanticWarning> {
+    let mut graph = FrameGraph {
+        target_defs: target_defs,
+        uniform_names: uniform_names,
+        functions: functions,
+        call_stack: Vec::new(),
+        current_target: None,
+        sampled_targets: HashMap::new(),
+        reported_feedback: HashSet::new(),
+        reads: HashMap::new(),
+        writes: HashMap::new(),
+        warnings: Vec::new(),
+    };
+    graph.visit_function("main");
+
+    let mut reads: Vec<_> = graph.reads.iter().filter(|r| !graph.writes.contains_key(r.0)).collect();
+    reads.sort_by_key(|&(_, &(slice, _))| slice.begin);
+    for (target, (slice, pass)) in reads {
+        let warning = format!(
+            "Render target `{}` is read in the {}, but nothing is written to it during the frame",
+            graph.target_name(*target),
+            graph.pass_name(*pass)
+        );
+        graph.warnings.push(SemanticWarning::warning_from_ast(slice, warning));
+    }
+
+    let mut writes: Vec<_> = graph.writes.iter().filter(|w| !graph.reads.contains_key(w.0)).collect();
+    writes.sort_by_key(|w| w.1.begin);
+    for (target, slice) in writes {
+        let warning = format!(
+            "Render target `{}` is written to in the {}, but never read during the frame",
+            graph.target_name(*target),
+            graph.pass_name(Some(*target))
+        );
+        graph.warnings.push(SemanticWarning::warning_from_ast(slice, warning));
+    }
+
+    graph.warnings
+}
+
+struct FrameGraph<'a> {
+    target_defs: &'a [RenderTargetDef],
+    uniform_names: &'a [String],
+    functions: &'a HashMap<String, Function>,
+    // Functions which are being visited, recursive calls are not followed again
+    call_stack: Vec<&'a str>,
+
+    // The target which is drawn to, or `None` for the screen
+    current_target: Option<u32>,
+    // Render targets bound as textures to the uniforms of the current program
+    sampled_targets: HashMap<u32, u32>,
+    reported_feedback: HashSet<(u32, u32)>,
+
+    // Where each target is first read (and in which pass), and first written to
+    reads: HashMap<u32, (SourceSlice, Option<u32>)>,
+    writes: HashMap<u32, SourceSlice>,
+    warnings: Vec<SemanticWarning>,
+}
+impl<'a> FrameGraph<'a> {
+    fn visit_function(&mut self, name: &'a str) {
+        let function = match self.functions.get(name) {
+            Some(function) => function,
+            None => return,
+        };
+        if self.call_stack.contains(&name) {
+            return;
+        }
+
+        self.call_stack.push(name);
+        let ops = function.bytecode.get_bytecode();
+        let slices = function.bytecode.get_source_slices();
+        for (op, slice) in ops.iter().zip(slices.iter()) {
+            self.visit_op(op, *slice);
+        }
+        self.call_stack.pop();
+    }
+
+    fn visit_op(&mut self, op: &'a BytecodeOp, slice: SourceSlice) {
+        match op {
+            BytecodeOp::BindRt(target) => self.current_target = Some(*target),
+            BytecodeOp::BindScaledRt(target, scale) => {
+                self.visit_expr(scale, slice);
+                self.current_target = Some(*target);
+            }
+            BytecodeOp::BindScreenRt => self.current_target = None,
+            BytecodeOp::BindProgram(_) => self.sampled_targets.clear(),
+            BytecodeOp::Clear(color) => {
+                self.visit_expr(color, slice);
+                self.write_current_target(slice);
+            }
+            BytecodeOp::ClearOit => self.write_current_target(slice),
+            BytecodeOp::ClearBuffers { colors, depth } => {
+                for expr in colors.iter().map(|c| &c.1).chain(depth) {
+                    self.visit_expr(expr, slice);
+                }
+                self.write_current_target(slice);
+            }
+            BytecodeOp::UniformRt(uniform, target, _, _) => {
+                self.sampled_targets.insert(*uniform, *target);
+                self.read(*target, slice);
+            }
+            BytecodeOp::DrawQuad | BytecodeOp::DrawModel(..) | BytecodeOp::DrawScene | BytecodeOp::DrawBatch(..) => {
+                self.check_feedback(slice);
+                self.write_current_target(slice);
+            }
+            BytecodeOp::DrawTexturedQuad { .. } | BytecodeOp::DrawSkybox(..) => self.write_current_target(slice),
+            BytecodeOp::DrawRect { color, .. } => {
+                self.visit_expr(color, slice);
+                self.write_current_target(slice);
+            }
+            BytecodeOp::PostTaa {
+                color,
+                depth,
+                motion,
+                history,
+            } => {
+                for source in &[color, depth, motion] {
+                    self.read(source.0, slice);
+                    self.check_pass_source(source.0, slice);
+                }
+                // The history is written at the end of each frame, and read in the next one
+                self.read(*history, slice);
+                self.writes.entry(*history).or_insert(slice);
+                self.write_current_target(slice);
+            }
+            BytecodeOp::PostAutoExposure { source, target, speed } => {
+                self.visit_expr(speed, slice);
+                self.read(source.0, slice);
+                self.writes.entry(*target).or_insert(slice);
+            }
+            BytecodeOp::PostVolumetrics {
+                depth, target, density, ..
+            } => {
+                self.visit_expr(density, slice);
+                self.read(depth.0, slice);
+                self.writes.entry(*target).or_insert(slice);
+            }
+            BytecodeOp::PostOitResolve {
+                accum,
+                revealage,
+                target,
+            } => {
+                self.read(accum.0, slice);
+                self.read(revealage.0, slice);
+                self.writes.entry(*target).or_insert(slice);
+            }
+            BytecodeOp::PostLens { source, param, .. } => {
+                self.visit_expr(param, slice);
+                self.read(source.0, slice);
+                self.check_pass_source(source.0, slice);
+                self.write_current_target(slice);
+            }
+            BytecodeOp::FunctionCall(function_call) => {
+                for arg in &function_call.args {
+                    self.visit_expr(arg, slice);
+                }
+                self.visit_function(&function_call.function);
+            }
+            BytecodeOp::CameraLookAt {
+                eye: a,
+                center: b,
+                up: c,
+            }
+            | BytecodeOp::CameraPerspective {
+                fov_y: a,
+                near: b,
+                far: c,
+            } => {
+                for expr in &[a, b, c] {
+                    self.visit_expr(expr, slice);
+                }
+            }
+            BytecodeOp::Viewport(a, b, c, d) | BytecodeOp::PipelineSetClipPlane(_, a, b, c, d) => {
+                for expr in &[a, b, c, d] {
+                    self.visit_expr(expr, slice);
+                }
+            }
+            BytecodeOp::PipelineSetWriteMask(a, b) => {
+                self.visit_expr(a, slice);
+                self.visit_expr(b, slice);
+            }
+            BytecodeOp::UniformFloat(_, expr)
+            | BytecodeOp::UniformColor(_, expr)
+            | BytecodeOp::UniformVec3(_, expr)
+            | BytecodeOp::UniformMat4(_, expr)
+            | BytecodeOp::SetModelTransform(expr)
+            | BytecodeOp::Return { expr }
+            | BytecodeOp::Let { expr, .. }
+            | BytecodeOp::JumpIfNot { condition: expr, .. }
+            | BytecodeOp::Assert { condition: expr, .. }
+            | BytecodeOp::SetTitle(expr)
+            | BytecodeOp::ShowCursor(expr) => self.visit_expr(expr, slice),
+            BytecodeOp::Print { args, .. } => {
+                for arg in args {
+                    self.visit_expr(arg, slice);
+                }
+            }
+            BytecodeOp::BindDrawParams(params) => {
+                for (_, value) in params {
+                    self.visit_expr(value, slice);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Follows the calls in an expression, and records the targets read by built-ins
+    fn visit_expr(&mut self, expr: &'a ValueExpr, slice: SourceSlice) {
+        match expr {
+            ValueExpr::FunctionCall(function_call) => {
+                for arg in &function_call.args {
+                    self.visit_expr(arg, slice);
+                }
+                if RENDER_TARGET_FUNCTIONS.contains(&function_call.function.as_str()) {
+                    if let Some(ValueExpr::ConstString(name)) = function_call.args.first() {
+                        let target_name = name.split('.').next().unwrap();
+                        if let Some(target) = self.target_defs.iter().position(|t| t.name == target_name) {
+                            self.read(target as u32, slice);
+                        }
+                    }
+                } else {
+                    self.visit_function(&function_call.function);
+                }
+            }
+            ValueExpr::ConstDict(entries) => {
+                for value in entries.values() {
+                    self.visit_expr(value, slice);
+                }
+            }
+            ValueExpr::BinaryOp(_, l, r) => {
+                self.visit_expr(l, slice);
+                self.visit_expr(r, slice);
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, target: u32, slice: SourceSlice) {
+        let pass = self.current_target;
+        self.reads.entry(target).or_insert((slice, pass));
+    }
+
+    fn write_current_target(&mut self, slice: SourceSlice) {
+        if let Some(target) = self.current_target {
+            self.writes.entry(target).or_insert(slice);
+        }
+    }
+
+    /// Warns if a target which is sampled by the current program is also drawn to
+    fn check_feedback(&mut self, slice: SourceSlice) {
+        let current_target = match self.current_target {
+            Some(target) => target,
+            None => return,
+        };
+        let mut uniforms: Vec<u32> = self
+            .sampled_targets
+            .iter()
+            .filter(|s| *s.1 == current_target)
+            .map(|s| *s.0)
+            .collect();
+        uniforms.sort();
+        for uniform in uniforms {
+            if self.reported_feedback.insert((uniform, current_target)) {
+                let warning = format!(
+                    "Render target `{}` is sampled through uniform `{}` in the {}, the result is undefined",
+                    self.target_name(current_target),
+                    self.uniform_names[uniform as usize],
+                    self.pass_name(Some(current_target))
+                );
+                self.warnings.push(SemanticWarning::warning_from_ast(&slice, warning));
+            }
+        }
+    }
+
+    /// Warns if a built-in pass reads from the target it draws to
+    fn check_pass_source(&mut self, target: u32, slice: SourceSlice) {
+        if self.current_target == Some(target) {
+            let warning = format!(
+                "Render target `{}` is read by a post-processing pass which draws to it, the result is undefined",
+                self.target_name(target)
+            );
+            self.warnings.push(SemanticWarning::warning_from_ast(&slice, warning));
+        }
+    }
+
+    fn target_name(&self, target: u32) -> &str {
+        &self.target_defs[target as usize].name
+    }
+
+    fn pass_name(&self, target: Option<u32>) -> String {
+        match target {
+            Some(target) => format!("pass drawing to `{}`", self.target_name(target)),
+            None => format!("pass drawing to the screen"),
+        }
+    }
+}
@@ -1,94 +1,700 @@
 use gl;
-use gl::types::{GLchar, GLenum, GLfloat, GLint, GLuint, GLvoid};
+use gl::types::{GLbitfield, GLchar, GLenum, GLfloat, GLint, GLsizei, GLsync, GLuint, GLuint64, GLvoid};
 
-use std::collections::HashMap;
-use std::ffi::CString;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::fmt;
 use std::mem;
 use std::path::Path;
 use std::ptr;
+use std::rc::Rc;
+use std::slice;
 
+use bytecode::{TerrainDef, TextureImportOptions};
 use imageio::RawImage;
+use resources::ResourceProvider;
 use types::RenderTargetFormat;
 
+/// `GL_COMPLETION_STATUS_KHR`, which is not part of the generated bindings
+const COMPLETION_STATUS_KHR: GLenum = 0x91B1;
+/// `GL_TEXTURE_SRGB_DECODE_EXT` and its values, which are not part of the generated bindings either
+const TEXTURE_SRGB_DECODE_EXT: GLenum = 0x8A48;
+const DECODE_EXT: GLenum = 0x8A49;
+const SKIP_DECODE_EXT: GLenum = 0x8A4A;
+
+/// Stages of a graphics pipeline in the order the data flows through them, see `ShaderPipeline::check_interfaces`
+const GRAPHICS_STAGES: [GLenum; 5] = [
+    gl::VERTEX_SHADER,
+    gl::TESS_CONTROL_SHADER,
+    gl::TESS_EVALUATION_SHADER,
+    gl::GEOMETRY_SHADER,
+    gl::FRAGMENT_SHADER,
+];
+
+/// Returns true if the driver compiles shaders in the background (KHR_parallel_shader_compile)
+pub fn supports_parallel_shader_compile() -> bool {
+    has_any_extension(&["GL_KHR_parallel_shader_compile", "GL_ARB_parallel_shader_compile"])
+}
+
+/// Returns true if the context supports at least one of the given extensions
+pub fn has_any_extension(extensions: &[&str]) -> bool {
+    unsafe {
+        let mut count: GLint = 0;
+        gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut count);
+        (0..count).any(|i| {
+            let name = CStr::from_ptr(gl::GetStringi(gl::EXTENSIONS, i as GLuint) as *const GLchar);
+            let name = name.to_bytes();
+            extensions.iter().any(|e| name == e.as_bytes())
+        })
+    }
+}
+
+/// GPU resources keyed by a hash of the file contents they were created from
+///
+/// The cache is handed from one scene to the next when a demo is reloaded, so that resources whose files did not change
+/// are shared instead of being uploaded again.
+#[derive(Default)]
+pub struct ResourceCache {
+    pub shader_stages: HashMap<(u64, GLenum), Rc<ShaderStage>>,
+    pub models: HashMap<u64, Rc<Model>>,
+    pub textures: HashMap<(u64, bool), Rc<Texture>>,
+    // Last values of the uniforms set by the script, by (program, uniform name). Recompiled programs get them before
+    // their first draw, instead of starting with default values.
+    pub uniform_values: HashMap<(String, String), UniformValue>,
+}
+impl ResourceCache {
+    /// Drops all resources which are no longer used outside of the cache
+    pub fn prune(&mut self) {
+        self.shader_stages.retain(|_, r| Rc::strong_count(r) > 1);
+        self.models.retain(|_, r| Rc::strong_count(r) > 1);
+        self.textures.retain(|_, r| Rc::strong_count(r) > 1);
+    }
+}
+
+/// A single, separately compiled shader stage
+///
+/// Stages are linked as separable programs, so that the same stage can be combined with different other stages in a
+/// `ShaderPipeline` without compiling it again.
 #[derive(Debug)]
-pub struct ShaderProgram {
+pub struct ShaderStage {
     program_id: GLuint,
+    stage: GLenum,
 }
-impl ShaderProgram {
-    pub fn from_vert_frag(vert_source: &str, frag_source: &str) -> Result<Self, String> {
-        let program;
+impl ShaderStage {
+    /// Submits the stage to the driver, the stage must not be used before `finish_compile` succeeded
+    pub fn start_compile(source: &str, stage: GLenum) -> Result<Self, String> {
+        let src = CString::new(source).map_err(|_| format!("Shader source contains a null character"))?;
+        let program = unsafe { gl::CreateShaderProgramv(stage, 1, &src.as_ptr()) };
+
+        Ok(ShaderStage {
+            program_id: program,
+            stage: stage,
+        })
+    }
+
+    /// Checks whether the driver finished compiling the stage, without blocking
+    ///
+    /// Only valid if `supports_parallel_shader_compile` returns true.
+    pub fn is_compile_complete(&self) -> bool {
+        let mut status = gl::FALSE as GLint;
         unsafe {
-            let vs = Self::compile_shader(vert_source, gl::VERTEX_SHADER)?;
-            let fs = Self::compile_shader(frag_source, gl::FRAGMENT_SHADER)?;
+            gl::GetProgramiv(self.program_id, COMPLETION_STATUS_KHR, &mut status);
+        }
+        status == (gl::TRUE as GLint)
+    }
 
-            program = gl::CreateProgram();
-            gl::AttachShader(program, vs);
-            gl::AttachShader(program, fs);
-            gl::LinkProgram(program);
+    /// Waits for the compilation to finish, and reports compile errors
+    pub fn finish_compile(&self) -> Result<(), String> {
+        unsafe {
+            // Compilation errors are reported in the log of the program
             let mut status = gl::FALSE as GLint;
-            gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
-
+            gl::GetProgramiv(self.program_id, gl::LINK_STATUS, &mut status);
             if status != (gl::TRUE as GLint) {
                 let mut len: GLint = 0;
-                gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1);
-                gl::GetProgramInfoLog(program, len, ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
+                gl::GetProgramiv(self.program_id, gl::INFO_LOG_LENGTH, &mut len);
+                let mut buf = Vec::with_capacity(len.max(1) as usize);
+                buf.set_len((len.max(1) as usize) - 1);
+                gl::GetProgramInfoLog(self.program_id, len, ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
+
+                return Err(format!("Failed to compile shader {}", String::from_utf8(buf).unwrap()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Queries the inputs or outputs of the stage, built-in variables are left out
+    ///
+    /// The per-vertex arrays of tessellation and geometry stages are named without their index, like the variables
+    /// of the other stages they are matched with.
+    fn get_interface(&self, interface: GLenum) -> Vec<InterfaceVariable> {
+        let mut result = Vec::new();
+        unsafe {
+            let mut count: GLint = 0;
+            let mut max_len: GLint = 0;
+            gl::GetProgramInterfaceiv(self.program_id, interface, gl::ACTIVE_RESOURCES, &mut count);
+            gl::GetProgramInterfaceiv(self.program_id, interface, gl::MAX_NAME_LENGTH, &mut max_len);
 
-                return Err(format!("Failed to link:\n{}", String::from_utf8(buf).unwrap()));
+            let mut buf = vec![0u8; max_len.max(1) as usize];
+            let properties = [gl::TYPE, gl::LOCATION];
+            for i in 0..count {
+                let mut len: GLsizei = 0;
+                gl::GetProgramResourceName(
+                    self.program_id,
+                    interface,
+                    i as GLuint,
+                    buf.len() as GLsizei,
+                    &mut len,
+                    buf.as_mut_ptr() as *mut GLchar,
+                );
+                let name = String::from_utf8_lossy(&buf[..len as usize]);
+                if name.starts_with("gl_") {
+                    continue;
+                }
+
+                let mut values: [GLint; 2] = [0; 2];
+                gl::GetProgramResourceiv(
+                    self.program_id,
+                    interface,
+                    i as GLuint,
+                    properties.len() as GLsizei,
+                    properties.as_ptr(),
+                    values.len() as GLsizei,
+                    ptr::null_mut(),
+                    values.as_mut_ptr(),
+                );
+                result.push(InterfaceVariable {
+                    name: name.trim_end_matches("[0]").to_owned(),
+                    variable_type: values[0] as GLenum,
+                    location: values[1],
+                });
             }
         }
+        result
+    }
 
-        Ok(ShaderProgram { program_id: program })
+    /// Name of the stage, for messages
+    fn get_name(&self) -> &'static str {
+        match self.stage {
+            gl::VERTEX_SHADER => "vertex",
+            gl::TESS_CONTROL_SHADER => "tessellation control",
+            gl::TESS_EVALUATION_SHADER => "tessellation evaluation",
+            gl::GEOMETRY_SHADER => "geometry",
+            gl::FRAGMENT_SHADER => "fragment",
+            gl::COMPUTE_SHADER => "compute",
+            _ => "unknown",
+        }
     }
 
-    pub fn bind(&self) {
+    fn stage_bit(&self) -> GLbitfield {
+        match self.stage {
+            gl::VERTEX_SHADER => gl::VERTEX_SHADER_BIT,
+            gl::TESS_CONTROL_SHADER => gl::TESS_CONTROL_SHADER_BIT,
+            gl::TESS_EVALUATION_SHADER => gl::TESS_EVALUATION_SHADER_BIT,
+            gl::GEOMETRY_SHADER => gl::GEOMETRY_SHADER_BIT,
+            gl::FRAGMENT_SHADER => gl::FRAGMENT_SHADER_BIT,
+            gl::COMPUTE_SHADER => gl::COMPUTE_SHADER_BIT,
+            _ => 0,
+        }
+    }
+}
+impl Drop for ShaderStage {
+    fn drop(&mut self) {
         unsafe {
-            gl::UseProgram(self.program_id);
+            gl::DeleteProgram(self.program_id);
         }
     }
+}
+
+/// An input or output variable of a stage, see `ShaderStage::get_interface`
+struct InterfaceVariable {
+    name: String,
+    variable_type: GLenum,
+    // -1 unless the variable has an explicit location
+    location: GLint,
+}
 
-    pub fn get_uniform_location(&self, uniform_name: &str) -> Option<GLint> {
-        let loc;
+/// Location of a uniform within one of the stages of a pipeline
+#[derive(Debug, Clone, Copy)]
+pub struct UniformLocation {
+    pub program_id: GLuint,
+    pub location: GLint,
+}
+
+/// Value of a uniform which is not a sampler
+#[derive(Debug, Clone, Copy)]
+pub enum UniformValue {
+    Float(f32),
+    Vec3([f32; 3]),
+    Vec4([f32; 4]),
+    Mat4([f32; 16]), // column-major
+}
+impl UniformValue {
+    pub fn components(&self) -> &[f32] {
+        match self {
+            UniformValue::Float(v) => slice::from_ref(v),
+            UniformValue::Vec3(v) => v,
+            UniformValue::Vec4(v) => v,
+            UniformValue::Mat4(v) => v,
+        }
+    }
+
+    pub fn apply(&self, uniform: &UniformLocation) {
         unsafe {
-            loc = gl::GetUniformLocation(self.program_id, CString::new(uniform_name).unwrap().as_ptr());
+            match *self {
+                UniformValue::Float(v) => gl::ProgramUniform1f(uniform.program_id, uniform.location, v),
+                UniformValue::Vec3(v) => gl::ProgramUniform3f(uniform.program_id, uniform.location, v[0], v[1], v[2]),
+                UniformValue::Vec4(v) => {
+                    gl::ProgramUniform4f(uniform.program_id, uniform.location, v[0], v[1], v[2], v[3])
+                }
+                UniformValue::Mat4(v) => {
+                    gl::ProgramUniformMatrix4fv(uniform.program_id, uniform.location, 1, gl::FALSE, v.as_ptr())
+                }
+            }
         }
-        if loc != -1 {
-            Some(loc)
-        } else {
-            None
+    }
+}
+impl fmt::Display for UniformValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UniformValue::Float(v) => write!(f, "{}", v),
+            UniformValue::Vec3(v) => write!(f, "({}, {}, {})", v[0], v[1], v[2]),
+            UniformValue::Vec4(v) => write!(f, "({}, {}, {}, {})", v[0], v[1], v[2], v[3]),
+            UniformValue::Mat4(v) => write!(f, "{:?}", v),
         }
     }
+}
 
-    fn compile_shader(src: &str, shader_type: GLenum) -> Result<GLuint, String> {
+/// Returns true if uniforms of the given type are bound to a texture unit
+fn is_sampler_type(uniform_type: GLenum) -> bool {
+    match uniform_type {
+        gl::SAMPLER_1D
+        | gl::SAMPLER_2D
+        | gl::SAMPLER_3D
+        | gl::SAMPLER_CUBE
+        | gl::SAMPLER_1D_SHADOW
+        | gl::SAMPLER_2D_SHADOW
+        | gl::SAMPLER_1D_ARRAY
+        | gl::SAMPLER_2D_ARRAY
+        | gl::SAMPLER_1D_ARRAY_SHADOW
+        | gl::SAMPLER_2D_ARRAY_SHADOW
+        | gl::SAMPLER_2D_MULTISAMPLE
+        | gl::SAMPLER_2D_MULTISAMPLE_ARRAY
+        | gl::SAMPLER_CUBE_SHADOW
+        | gl::SAMPLER_CUBE_MAP_ARRAY
+        | gl::SAMPLER_CUBE_MAP_ARRAY_SHADOW
+        | gl::SAMPLER_BUFFER
+        | gl::SAMPLER_2D_RECT
+        | gl::SAMPLER_2D_RECT_SHADOW
+        | gl::INT_SAMPLER_2D
+        | gl::INT_SAMPLER_3D
+        | gl::INT_SAMPLER_CUBE
+        | gl::INT_SAMPLER_2D_ARRAY
+        | gl::UNSIGNED_INT_SAMPLER_2D
+        | gl::UNSIGNED_INT_SAMPLER_3D
+        | gl::UNSIGNED_INT_SAMPLER_CUBE
+        | gl::UNSIGNED_INT_SAMPLER_2D_ARRAY => true,
+        _ => false,
+    }
+}
+
+/// Number of floats of uniforms of the given type, for the float, vector and matrix types which can be set by scripts
+pub fn float_type_components(uniform_type: GLenum) -> Option<usize> {
+    match uniform_type {
+        gl::FLOAT => Some(1),
+        gl::FLOAT_VEC2 => Some(2),
+        gl::FLOAT_VEC3 => Some(3),
+        gl::FLOAT_VEC4 => Some(4),
+        gl::FLOAT_MAT4 => Some(16),
+        _ => None,
+    }
+}
+
+/// Uniform block filled by the engine from a `UniformRing`, programs opt in by declaring it:
+///
+/// ```glsl
+/// layout(std140) uniform EngineUniforms {
+///     mat4 u_ModelViewProjectionMatrix;
+///     mat4 u_ModelViewMatrix;
+///     mat4 u_ModelViewInvTranspMatrix;
+/// };
+/// ```
+///
+/// Programs without the block get the same matrices as individual uniforms.
+pub const ENGINE_UNIFORMS_BLOCK: &str = "EngineUniforms";
+pub const ENGINE_UNIFORMS_BINDING: GLuint = 0;
+
+/// Uniform block for values which change with every draw, filled from the `params` of `draw_model`:
+///
+/// ```glsl
+/// layout(std140) uniform DrawParams {
+///     float u_Phase;
+///     vec4 u_Tint;
+/// };
+/// ```
+///
+/// Members are matched by name, members without a value are zero. The block is written to a `UniformRing` once per
+/// draw, instead of setting each value as a uniform of every stage. Members can also be set like uniforms, e.g. with
+/// `uniform_float`, those values are kept for the following draws with the program which do not pass `params`.
+pub const DRAW_PARAMS_BLOCK: &str = "DrawParams";
+pub const DRAW_PARAMS_BINDING: GLuint = 1;
+
+/// Layout of the `DRAW_PARAMS_BLOCK` of a pipeline
+#[derive(Debug)]
+pub struct DrawParamsLayout {
+    pub size: usize,
+    // Byte offset and type of each member, by name
+    pub members: HashMap<String, (usize, GLenum)>,
+}
+impl DrawParamsLayout {
+    /// Writes the components of a value to the member with the given name, returns false if there is no such member
+    pub fn write(&self, data: &mut [u8], name: &str, components: &[f32]) -> Result<bool, String> {
+        let (offset, uniform_type) = match self.members.get(name) {
+            Some(&member) => member,
+            None => return Ok(false),
+        };
+        if float_type_components(uniform_type) != Some(components.len()) {
+            return Err(format!(
+                "`{}` has {} components, which does not match its type in the `{}` block",
+                name,
+                components.len(),
+                DRAW_PARAMS_BLOCK
+            ));
+        }
+        for (i, component) in components.iter().enumerate() {
+            let start = offset + i * mem::size_of::<f32>();
+            data[start..start + mem::size_of::<f32>()].copy_from_slice(&component.to_ne_bytes());
+        }
+        Ok(true)
+    }
+}
+
+/// Combination of shader stages which is used for drawing
+///
+/// Every sampler used by any of the stages is assigned its own texture unit when the pipeline is created, samplers with
+/// the same name in different stages share the unit.
+#[derive(Debug)]
+pub struct ShaderPipeline {
+    pipeline_id: GLuint,
+    program_ids: Vec<GLuint>,
+    texture_units: HashMap<String, GLuint>,
+    sampler_locations: Vec<(UniformLocation, GLuint)>,
+    // True if any stage declares the `ENGINE_UNIFORMS_BLOCK`
+    uses_engine_uniforms: bool,
+    // Layout of the `DRAW_PARAMS_BLOCK`, if any stage declares it
+    draw_params: Option<DrawParamsLayout>,
+    // True if the pipeline has a tessellation evaluation stage, it then draws patches instead of triangles
+    tessellated: bool,
+}
+impl ShaderPipeline {
+    pub fn from_stages(stages: &[&ShaderStage]) -> Result<Self, String> {
+        // Each stage is linked on its own, only the interfaces between them are left to check. Validating the pipeline
+        // instead would depend on the GL state at the time, like the texture units of the samplers.
+        for stage in stages {
+            stage.finish_compile()?;
+        }
+
+        // Texture units are assigned before any other check, and before the pipeline object exists. `bind` points the
+        // samplers to them, the stages are shared with other pipelines which use other units.
+        let mut texture_units = HashMap::new();
+        let mut sampler_locations = Vec::new();
+        for stage in stages {
+            for (name, location) in Self::get_active_samplers(stage.program_id) {
+                let next_unit = texture_units.len() as GLuint;
+                let unit = *texture_units.entry(name).or_insert(next_unit);
+                sampler_locations.push((location, unit));
+            }
+        }
+
+        let mut max_units: GLint = 0;
         unsafe {
-            let mut status = gl::FALSE as GLint;
-            let shader = gl::CreateShader(shader_type);
-            let src = CString::new(src).unwrap();
+            gl::GetIntegerv(gl::MAX_COMBINED_TEXTURE_IMAGE_UNITS, &mut max_units);
+        }
+        if texture_units.len() > max_units as usize {
+            return Err(format!(
+                "The shader uses {} textures, but only {} texture units are available",
+                texture_units.len(),
+                max_units
+            ));
+        }
 
-            gl::ShaderSource(shader, 1, &src.as_ptr(), ptr::null());
-            gl::CompileShader(shader);
-            gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
-            if status != (gl::TRUE as GLint) {
+        Self::check_interfaces(stages)?;
+
+        let mut pipeline = 0;
+        unsafe {
+            gl::GenProgramPipelines(1, &mut pipeline);
+            for stage in stages {
+                gl::UseProgramStages(pipeline, stage.stage_bit(), stage.program_id);
+            }
+        }
+
+        let mut uses_engine_uniforms = false;
+        let block_name = CString::new(ENGINE_UNIFORMS_BLOCK).unwrap();
+        for stage in stages {
+            unsafe {
+                let index = gl::GetUniformBlockIndex(stage.program_id, block_name.as_ptr());
+                if index != gl::INVALID_INDEX {
+                    gl::UniformBlockBinding(stage.program_id, index, ENGINE_UNIFORMS_BINDING);
+                    uses_engine_uniforms = true;
+                }
+            }
+        }
+
+        // Stages which share the block declare it the same way, std140 then gives them the same layout
+        let mut draw_params = None;
+        for stage in stages {
+            if let Some(layout) = Self::read_draw_params_layout(stage.program_id) {
+                draw_params = Some(layout);
+            }
+        }
+
+        Ok(ShaderPipeline {
+            pipeline_id: pipeline,
+            program_ids: stages.iter().map(|s| s.program_id).collect(),
+            texture_units: texture_units,
+            sampler_locations: sampler_locations,
+            uses_engine_uniforms: uses_engine_uniforms,
+            draw_params: draw_params,
+            tessellated: stages.iter().any(|s| s.stage == gl::TESS_EVALUATION_SHADER),
+        })
+    }
+
+    /// Checks that every input of a stage is written by the stage before it, with the same name or location and type
+    fn check_interfaces(stages: &[&ShaderStage]) -> Result<(), String> {
+        let mut stages: Vec<&ShaderStage> = stages
+            .iter()
+            .filter(|s| GRAPHICS_STAGES.contains(&s.stage))
+            .cloned()
+            .collect();
+        stages.sort_by_key(|s| GRAPHICS_STAGES.iter().position(|&stage| stage == s.stage));
+        for pair in stages.windows(2) {
+            let (producer, consumer) = (pair[0], pair[1]);
+            let outputs = producer.get_interface(gl::PROGRAM_OUTPUT);
+            for input in consumer.get_interface(gl::PROGRAM_INPUT) {
+                let output = outputs
+                    .iter()
+                    .find(|o| o.name == input.name || (input.location != -1 && o.location == input.location));
+                match output {
+                    None => {
+                        return Err(format!(
+                            "Failed to link: the {} shader reads `{}`, which the {} shader does not write",
+                            consumer.get_name(),
+                            input.name,
+                            producer.get_name()
+                        ))
+                    }
+                    Some(output) if output.variable_type != input.variable_type => {
+                        return Err(format!(
+                            "Failed to link: `{}` of the {} shader does not have the type of `{}` of the {} shader",
+                            input.name,
+                            consumer.get_name(),
+                            output.name,
+                            producer.get_name()
+                        ))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn uses_engine_uniforms(&self) -> bool {
+        self.uses_engine_uniforms
+    }
+
+    pub fn is_tessellated(&self) -> bool {
+        self.tessellated
+    }
+
+    pub fn get_draw_params_layout(&self) -> Option<&DrawParamsLayout> {
+        self.draw_params.as_ref()
+    }
+
+    /// Binds the `DRAW_PARAMS_BLOCK` of a stage to its binding and reads its layout, if the stage declares it
+    fn read_draw_params_layout(program_id: GLuint) -> Option<DrawParamsLayout> {
+        let block_name = CString::new(DRAW_PARAMS_BLOCK).unwrap();
+        unsafe {
+            let index = gl::GetUniformBlockIndex(program_id, block_name.as_ptr());
+            if index == gl::INVALID_INDEX {
+                return None;
+            }
+            gl::UniformBlockBinding(program_id, index, DRAW_PARAMS_BINDING);
+
+            let mut size: GLint = 0;
+            let mut count: GLint = 0;
+            gl::GetActiveUniformBlockiv(program_id, index, gl::UNIFORM_BLOCK_DATA_SIZE, &mut size);
+            gl::GetActiveUniformBlockiv(program_id, index, gl::UNIFORM_BLOCK_ACTIVE_UNIFORMS, &mut count);
+            let mut indices: Vec<GLint> = vec![0; count.max(0) as usize];
+            gl::GetActiveUniformBlockiv(
+                program_id,
+                index,
+                gl::UNIFORM_BLOCK_ACTIVE_UNIFORM_INDICES,
+                indices.as_mut_ptr(),
+            );
+            let indices: Vec<GLuint> = indices.iter().map(|&i| i as GLuint).collect();
+            let mut offsets: Vec<GLint> = vec![0; indices.len()];
+            gl::GetActiveUniformsiv(
+                program_id,
+                indices.len() as GLint,
+                indices.as_ptr(),
+                gl::UNIFORM_OFFSET,
+                offsets.as_mut_ptr(),
+            );
+
+            let mut max_len: GLint = 0;
+            gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_len);
+            let mut buf = vec![0u8; max_len.max(1) as usize];
+            let mut members = HashMap::new();
+            for (&uniform_index, &offset) in indices.iter().zip(&offsets) {
                 let mut len: GLint = 0;
-                gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1);
-                gl::GetShaderInfoLog(shader, len, ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
+                let mut array_size: GLint = 0;
+                let mut uniform_type: GLenum = 0;
+                gl::GetActiveUniform(
+                    program_id,
+                    uniform_index,
+                    buf.len() as GLint,
+                    &mut len,
+                    &mut array_size,
+                    &mut uniform_type,
+                    buf.as_mut_ptr() as *mut GLchar,
+                );
+                let name = String::from_utf8_lossy(&buf[..len as usize]).into_owned();
+                members.insert(name, (offset as usize, uniform_type));
+            }
 
-                return Err(format!("Failed to compile shader {}", String::from_utf8(buf).unwrap()));
+            Some(DrawParamsLayout {
+                size: size as usize,
+                members: members,
+            })
+        }
+    }
+
+    /// Queries the names and locations of all samplers used by the program
+    fn get_active_samplers(program_id: GLuint) -> Vec<(String, UniformLocation)> {
+        let mut result = Vec::new();
+        unsafe {
+            let mut count: GLint = 0;
+            let mut max_len: GLint = 0;
+            gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORMS, &mut count);
+            gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_len);
+
+            let mut buf = vec![0u8; max_len.max(1) as usize];
+            for i in 0..count {
+                let mut len: GLint = 0;
+                let mut size: GLint = 0;
+                let mut uniform_type: GLenum = 0;
+                gl::GetActiveUniform(
+                    program_id,
+                    i as GLuint,
+                    buf.len() as GLint,
+                    &mut len,
+                    &mut size,
+                    &mut uniform_type,
+                    buf.as_mut_ptr() as *mut GLchar,
+                );
+                if !is_sampler_type(uniform_type) {
+                    continue;
+                }
+
+                let location = gl::GetUniformLocation(program_id, buf.as_ptr() as *const GLchar);
+                let name = String::from_utf8_lossy(&buf[..len as usize]).into_owned();
+                result.push((
+                    name,
+                    UniformLocation {
+                        program_id: program_id,
+                        location: location,
+                    },
+                ));
             }
+        }
+        result
+    }
 
-            Ok(shader)
+    /// Binds the pipeline, and points all samplers to their texture unit
+    pub fn bind(&self) {
+        unsafe {
+            gl::UseProgram(0);
+            gl::BindProgramPipeline(self.pipeline_id);
+            for (sampler, unit) in &self.sampler_locations {
+                gl::ProgramUniform1i(sampler.program_id, sampler.location, *unit as GLint);
+            }
         }
     }
+
+    /// Queries the names of the uniforms used by any stage, members of uniform blocks are not included
+    ///
+    /// Arrays are listed once, by their name without the index.
+    pub fn get_active_uniforms(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        for &program_id in &self.program_ids {
+            unsafe {
+                let mut count: GLint = 0;
+                let mut max_len: GLint = 0;
+                gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORMS, &mut count);
+                gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_len);
+
+                let mut buf = vec![0u8; max_len.max(1) as usize];
+                for i in 0..count {
+                    let mut len: GLint = 0;
+                    let mut size: GLint = 0;
+                    let mut uniform_type: GLenum = 0;
+                    gl::GetActiveUniform(
+                        program_id,
+                        i as GLuint,
+                        buf.len() as GLint,
+                        &mut len,
+                        &mut size,
+                        &mut uniform_type,
+                        buf.as_mut_ptr() as *mut GLchar,
+                    );
+
+                    // Members of uniform blocks and built-in variables have no location
+                    if gl::GetUniformLocation(program_id, buf.as_ptr() as *const GLchar) == -1 {
+                        continue;
+                    }
+                    let name = String::from_utf8_lossy(&buf[..len as usize]);
+                    let name = name.trim_end_matches("[0]").to_string();
+                    if !result.contains(&name) {
+                        result.push(name);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the texture unit assigned to the sampler with the given name
+    pub fn get_texture_unit(&self, sampler_name: &str) -> Option<GLuint> {
+        self.texture_units.get(sampler_name).cloned()
+    }
+
+    /// Returns the location of the uniform in all stages which use it
+    pub fn get_uniform_locations(&self, uniform_name: &str) -> Vec<UniformLocation> {
+        // A name with a NUL byte cannot be the name of a uniform
+        let name = match CString::new(uniform_name) {
+            Ok(name) => name,
+            Err(_) => return Vec::new(),
+        };
+        self.program_ids
+            .iter()
+            .filter_map(|&program_id| {
+                let location = unsafe { gl::GetUniformLocation(program_id, name.as_ptr()) };
+                if location != -1 {
+                    Some(UniformLocation {
+                        program_id: program_id,
+                        location: location,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
 }
-impl Drop for ShaderProgram {
+impl Drop for ShaderPipeline {
     fn drop(&mut self) {
         unsafe {
-            gl::DeleteProgram(self.program_id);
+            gl::DeleteProgramPipelines(1, &self.pipeline_id);
         }
     }
 }
@@ -98,21 +704,28 @@ pub struct RenderTarget {
     fbo_handle: GLuint,
     textures: Vec<GLuint>,
     depth_buf: Option<GLuint>,
+    // All attachments of the framebuffer, including the depth buffer
+    attachments: Vec<GLenum>,
     width: u32,
     height: u32,
 }
 impl RenderTarget {
+    /// Creates a target with one texture per format
+    ///
+    /// Depth formats are attached as depth buffer, they have to come after all color formats.
     pub fn new(width: u32, height: u32, has_depth: bool, formats: &[RenderTargetFormat]) -> Result<Self, String> {
-        if formats.len() > 4 {
+        let color_buffers = formats.iter().filter(|f| !f.is_depth()).count();
+        if color_buffers > 4 {
             return Err(format!(
                 "Only up to 4 color buffers are supported, you provided {}",
-                formats.len()
+                color_buffers
             ));
         }
 
         let mut fbo_handle: GLuint = 0;
         let mut textures = Vec::new();
         let mut depth_buf: Option<GLuint> = None;
+        let mut used_attachments = Vec::new();
         unsafe {
             gl::GenFramebuffers(1, &mut fbo_handle);
             gl::BindFramebuffer(gl::FRAMEBUFFER, fbo_handle);
@@ -120,21 +733,21 @@ impl RenderTarget {
             textures.resize(formats.len(), 0);
             gl::GenTextures(formats.len() as GLint, textures.as_mut_ptr());
 
-            // Generate the color buffers
+            // Generate the color buffers, and the depth buffer if it is sampled as a texture
             for (i, fmt) in formats.iter().enumerate() {
+                let attachment = if fmt.is_depth() {
+                    gl::DEPTH_ATTACHMENT
+                } else {
+                    gl::COLOR_ATTACHMENT0 + i as GLuint
+                };
                 gl::ActiveTexture(gl::TEXTURE0 + i as GLuint);
                 gl::BindTexture(gl::TEXTURE_2D, textures[i]);
                 gl::TexStorage2D(gl::TEXTURE_2D, 1, Self::to_gl_format(*fmt), width as i32, height as i32);
                 gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
                 gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
 
-                gl::FramebufferTexture2D(
-                    gl::FRAMEBUFFER,
-                    gl::COLOR_ATTACHMENT0 + i as GLuint,
-                    gl::TEXTURE_2D,
-                    textures[i],
-                    0,
-                );
+                gl::FramebufferTexture2D(gl::FRAMEBUFFER, attachment, gl::TEXTURE_2D, textures[i], 0);
+                used_attachments.push(attachment);
             }
 
             // Optionally generate the depth stencil
@@ -145,6 +758,7 @@ impl RenderTarget {
                 gl::RenderbufferStorage(gl::RENDERBUFFER, gl::DEPTH_COMPONENT, width as i32, height as i32);
                 gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, depth_buf_id);
                 depth_buf = Some(depth_buf_id);
+                used_attachments.push(gl::DEPTH_ATTACHMENT);
             }
 
             let attachments = [
@@ -153,7 +767,7 @@ impl RenderTarget {
                 gl::COLOR_ATTACHMENT2,
                 gl::COLOR_ATTACHMENT3,
             ];
-            gl::DrawBuffers(formats.len() as i32, attachments.as_ptr());
+            gl::DrawBuffers(color_buffers as i32, attachments.as_ptr());
 
             if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
                 gl::DeleteFramebuffers(1, &mut fbo_handle);
@@ -170,30 +784,39 @@ impl RenderTarget {
             fbo_handle: fbo_handle,
             textures: textures,
             depth_buf: depth_buf,
+            attachments: used_attachments,
             width: width,
             height: height,
         })
     }
 
-    fn to_gl_format(format: RenderTargetFormat) -> GLenum {
+    pub fn to_gl_format(format: RenderTargetFormat) -> GLenum {
         match format {
             RenderTargetFormat::Srgb8 => gl::SRGB8,
             RenderTargetFormat::Srgba8 => gl::SRGB8_ALPHA8,
 
             RenderTargetFormat::R8 => gl::R8,
+            RenderTargetFormat::Rg8 => gl::RG8,
             RenderTargetFormat::Rgb8 => gl::RGB8,
             RenderTargetFormat::Rgba8 => gl::RGBA8,
 
             RenderTargetFormat::R16 => gl::R16,
             RenderTargetFormat::R16F => gl::R16F,
+            RenderTargetFormat::Rg16F => gl::RG16F,
             RenderTargetFormat::Rgb16 => gl::RGB16,
             RenderTargetFormat::Rgb16F => gl::RGB16F,
             RenderTargetFormat::Rgba16 => gl::RGBA16,
             RenderTargetFormat::Rgba16F => gl::RGBA16F,
 
             RenderTargetFormat::R32F => gl::R32F,
+            RenderTargetFormat::Rg32F => gl::RG32F,
             RenderTargetFormat::Rgb32F => gl::RGB32F,
             RenderTargetFormat::Rgba32F => gl::RGBA32F,
+
+            RenderTargetFormat::R11FG11FB10F => gl::R11F_G11F_B10F,
+            RenderTargetFormat::Rgb10A2 => gl::RGB10_A2,
+
+            RenderTargetFormat::Depth32F => gl::DEPTH_COMPONENT32F,
         }
     }
 
@@ -203,13 +826,121 @@ impl RenderTarget {
         }
     }
 
-    pub fn bind_as_texture(&self, texture_unit: GLuint, index: usize) {
+    /// Copies the first color buffer of another target, or of the screen framebuffer if `None`, into the first color
+    /// buffer of this target, leaves the target bound
+    ///
+    /// The area of the screen framebuffer which is copied starts at `screen_origin` and has the size of this target.
+    pub fn copy_from(&self, source: Option<&RenderTarget>, screen_fbo: GLuint, screen_origin: (u32, u32)) {
+        let (source_fbo, (x, y), source_width, source_height) = match source {
+            Some(source) => (source.fbo_handle, (0, 0), source.width, source.height),
+            None => (screen_fbo, screen_origin, self.width, self.height),
+        };
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, source_fbo);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.fbo_handle);
+            gl::BlitFramebuffer(
+                x as GLint,
+                y as GLint,
+                (x + source_width) as GLint,
+                (y + source_height) as GLint,
+                0,
+                0,
+                self.width as GLint,
+                self.height as GLint,
+                gl::COLOR_BUFFER_BIT,
+                gl::LINEAR,
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo_handle);
+        }
+    }
+
+    /// Copies the depth buffer of another target with the same formats into this target, scaling it to the size of
+    /// this target, leaves the target bound
+    pub fn copy_depth_from(&self, source: &RenderTarget) {
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, source.fbo_handle);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.fbo_handle);
+            gl::BlitFramebuffer(
+                0,
+                0,
+                source.width as GLint,
+                source.height as GLint,
+                0,
+                0,
+                self.width as GLint,
+                self.height as GLint,
+                gl::DEPTH_BUFFER_BIT,
+                gl::NEAREST,
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo_handle);
+        }
+    }
+
+    /// Draws only to one of the color buffers of the bound target, as the first output of shaders, or to all of them
+    /// again if `None`
+    pub fn select_draw_buffer(&self, index: Option<usize>) {
+        let color_attachments: Vec<GLenum> = self
+            .attachments
+            .iter()
+            .cloned()
+            .filter(|&a| a != gl::DEPTH_ATTACHMENT)
+            .collect();
+        let draw_buffers = match index {
+            Some(index) => vec![color_attachments[index]],
+            None => color_attachments,
+        };
+        unsafe {
+            gl::DrawBuffers(draw_buffers.len() as GLint, draw_buffers.as_ptr());
+        }
+    }
+
+    /// Binds one of the color buffers as texture
+    ///
+    /// If `srgb_decode` is false, sampling sRGB buffers returns the encoded values instead of decoding them to linear.
+    /// This has no effect on other formats.
+    pub fn bind_as_texture(&self, texture_unit: GLuint, index: usize, srgb_decode: bool) {
+        let decode = if srgb_decode { DECODE_EXT } else { SKIP_DECODE_EXT };
         unsafe {
             gl::ActiveTexture(gl::TEXTURE0 + texture_unit);
             gl::BindTexture(gl::TEXTURE_2D, self.textures[index]);
+            gl::TexParameteri(gl::TEXTURE_2D, TEXTURE_SRGB_DECODE_EXT, decode as GLint);
         }
     }
 
+    /// Binds the framebuffer for reading from one of its color buffers
+    pub fn bind_for_read(&self, index: usize) {
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo_handle);
+            gl::ReadBuffer(gl::COLOR_ATTACHMENT0 + index as GLuint);
+        }
+    }
+
+    /// Reads all pixels of one of the color buffers as RGBA, with the rows from bottom to top
+    pub fn read_pixels(&self, index: usize) -> Vec<(f32, f32, f32, f32)> {
+        let mut pixels = vec![(0.0, 0.0, 0.0, 0.0); (self.width * self.height) as usize];
+        self.bind_for_read(index);
+        unsafe {
+            gl::ReadPixels(
+                0,
+                0,
+                self.width as GLint,
+                self.height as GLint,
+                gl::RGBA,
+                gl::FLOAT,
+                pixels.as_mut_ptr() as *mut GLvoid,
+            );
+        }
+        pixels
+    }
+
+    pub fn get_attachments(&self) -> &[GLenum] {
+        &self.attachments
+    }
+
+    pub fn get_fbo_handle(&self) -> GLuint {
+        self.fbo_handle
+    }
+
     pub fn get_width(&self) -> u32 {
         self.width
     }
@@ -228,20 +959,25 @@ impl Drop for RenderTarget {
     }
 }
 
-pub struct Model {
-    vbo_handle: GLuint,
-    vao_handle: GLuint,
-    ebo_handle: GLuint,
-    trig_count: GLint,
+/// Range of the indices of a model which is drawn with the same material
+pub struct ModelGroup {
+    first_index: usize,
+    index_count: usize,
+    pub material: Option<String>,
 }
-impl Model {
-    pub fn load_obj_file(path: &Path) -> Result<Model, ()> {
-        let mut vbo = 0;
-        let mut ebo = 0;
-        let mut vao = 0;
-        let mut trig_count = 0;
 
-        let obj = wavefront_obj::obj::parse(std::fs::read_to_string(path).map_err(|_| ())?).map_err(|_| ())?;
+/// Triangles of a model before they are uploaded, see `Model::from_mesh`
+pub struct Mesh {
+    // Interleaved position/normal/uv vertices
+    buffer: Vec<GLfloat>,
+    indices: Vec<u32>,
+    groups: Vec<ModelGroup>,
+    // MTL file referenced by the mesh, relative to the mesh
+    material_library: Option<String>,
+}
+impl Mesh {
+    pub fn load_obj_file(resources: &dyn ResourceProvider, path: &Path) -> Result<Mesh, ()> {
+        let obj = wavefront_obj::obj::parse(resources.read_to_string(path).map_err(|_| ())?).map_err(|_| ())?;
 
         if obj.objects.len() != 1 {
             return Err(()); // Expected one object
@@ -251,7 +987,10 @@ impl Model {
         let mut resolved_vertices: HashMap<wavefront_obj::obj::VTNIndex, u32> = HashMap::new();
         let mut indices: Vec<u32> =
             Vec::with_capacity(obj.objects[0].geometry.iter().map(|x| x.shapes.len()).sum::<usize>() * 3);
+        let mut groups: Vec<ModelGroup> = Vec::new();
         for geometry in &obj.objects[0].geometry {
+            // Consecutive geometries with the same material are drawn together
+            let first_index = indices.len();
             for shape in &geometry.shapes {
                 if let wavefront_obj::obj::Primitive::Triangle(a, b, c) = shape.primitive {
                     for vertex in &[a, b, c] {
@@ -259,9 +998,17 @@ impl Model {
                         let vertex_idx = resolved_vertices.entry(*vertex).or_insert(next_index);
                         indices.push(*vertex_idx);
                     }
-                    trig_count += 1;
                 }
             }
+            let index_count = indices.len() - first_index;
+            match groups.last_mut() {
+                Some(group) if group.material == geometry.material_name => group.index_count += index_count,
+                _ => groups.push(ModelGroup {
+                    first_index: first_index,
+                    index_count: index_count,
+                    material: geometry.material_name.clone(),
+                }),
+            }
         }
 
         // Create an interleaved vertex buffer
@@ -289,6 +1036,161 @@ impl Model {
             buffer[resolved_index as usize * 8 + 7] = tex.v as f32;
         }
 
+        Ok(Mesh {
+            buffer: buffer,
+            indices: indices,
+            groups: groups,
+            material_library: obj.material_library.clone(),
+        })
+    }
+
+    /// Builds the grid mesh of a terrain from its heightmap, see `TerrainDef`
+    pub fn load_terrain(resources: &dyn ResourceProvider, path: &Path, terrain: &TerrainDef) -> Result<Mesh, ()> {
+        let image = RawImage::from_bytes(path, &resources.read(path).map_err(|_| ())?, false)?;
+        let heights = image.get_first_channel();
+        if heights.is_empty() {
+            return Err(());
+        }
+
+        // Bilinear sample of the heightmap, u and v go from 0 to 1 towards its right and bottom
+        let sample = |u: f32, v: f32| {
+            let x = u * (image.width - 1) as f32;
+            let y = v * (image.height - 1) as f32;
+            let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+            let (x1, y1) = ((x0 + 1).min(image.width - 1), (y0 + 1).min(image.height - 1));
+            let texel = |x: usize, y: usize| heights[y * image.width + x];
+            let top = texel(x0, y0) + (texel(x1, y0) - texel(x0, y0)) * (x - x0 as f32);
+            let bottom = texel(x0, y1) + (texel(x1, y1) - texel(x0, y1)) * (x - x0 as f32);
+            (top + (bottom - top) * (y - y0 as f32)) * terrain.max_height
+        };
+
+        // Vertices per side, and the distance between them
+        let n = terrain.resolution as usize + 1;
+        let step = terrain.size / terrain.resolution as f32;
+        let coord = |i: usize| i as f32 / (n - 1) as f32;
+        let mut grid = Vec::with_capacity(n * n);
+        for row in 0..n {
+            for col in 0..n {
+                grid.push(sample(coord(col), coord(row)));
+            }
+        }
+        let height = |col: usize, row: usize| grid[row * n + col];
+
+        let mut buffer: Vec<GLfloat> = Vec::with_capacity(n * n * 8);
+        for row in 0..n {
+            for col in 0..n {
+                // Slopes by central differences, which are one-sided at the edges
+                let (left, right) = (col.saturating_sub(1), (col + 1).min(n - 1));
+                let (up, down) = (row.saturating_sub(1), (row + 1).min(n - 1));
+                let dx = (height(right, row) - height(left, row)) / ((right - left) as f32 * step);
+                let dz = (height(col, down) - height(col, up)) / ((down - up) as f32 * step);
+                let length = (dx * dx + 1.0 + dz * dz).sqrt();
+
+                let (u, v) = (coord(col), coord(row));
+                buffer.extend_from_slice(&[(u - 0.5) * terrain.size, height(col, row), (v - 0.5) * terrain.size]);
+                buffer.extend_from_slice(&[-dx / length, 1.0 / length, -dz / length]);
+                // Textures are flipped when loaded, so the top of the image is at the top of the texture
+                buffer.extend_from_slice(&[u, 1.0 - v]);
+            }
+        }
+
+        let mut indices: Vec<u32> = Vec::with_capacity((n - 1) * (n - 1) * 6);
+        for row in 0..n - 1 {
+            for col in 0..n - 1 {
+                // Counter-clockwise when seen from above
+                let a = (row * n + col) as u32;
+                let b = a + n as u32;
+                indices.extend_from_slice(&[a, b, b + 1, a, b + 1, a + 1]);
+            }
+        }
+
+        Ok(Self::from_buffers(buffer, indices))
+    }
+
+    /// Cube spanning -1..1 on all axes, used in place of models which could not be loaded
+    pub fn unit_cube() -> Mesh {
+        let mut buffer: Vec<GLfloat> = Vec::with_capacity(6 * 4 * 8);
+        let mut indices: Vec<u32> = Vec::with_capacity(6 * 6);
+        for axis in 0..3 {
+            for &sign in &[1.0, -1.0] {
+                // Two directions spanning the face, chosen so that the triangles are counter-clockwise from outside
+                let mut normal = [0.0; 3];
+                let mut u = [0.0; 3];
+                let mut v = [0.0; 3];
+                normal[axis] = sign;
+                u[(axis + 1) % 3] = sign;
+                v[(axis + 2) % 3] = 1.0;
+
+                let base = (buffer.len() / 8) as u32;
+                for &(s, t) in &[(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                    for i in 0..3 {
+                        buffer.push(normal[i] + s * u[i] + t * v[i]);
+                    }
+                    buffer.extend_from_slice(&normal);
+                    buffer.push((s + 1.0) * 0.5);
+                    buffer.push((t + 1.0) * 0.5);
+                }
+                indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+            }
+        }
+
+        Self::from_buffers(buffer, indices)
+    }
+
+    /// Merges meshes into one mesh, with the vertices of each mesh transformed by its model matrix
+    ///
+    /// The merged mesh has a single group without a material. Normals are only rotated, which requires transforms
+    /// without non-uniform scaling.
+    pub fn merge(parts: &[(Mesh, glm::Mat4)]) -> Mesh {
+        let mut buffer: Vec<GLfloat> = Vec::with_capacity(parts.iter().map(|p| p.0.buffer.len()).sum());
+        let mut indices: Vec<u32> = Vec::with_capacity(parts.iter().map(|p| p.0.indices.len()).sum());
+        for (mesh, matrix) in parts {
+            let base = (buffer.len() / 8) as u32;
+            for vertex in mesh.buffer.chunks(8) {
+                let pos = *matrix * glm::Vec4::new(vertex[0], vertex[1], vertex[2], 1.0);
+                let normal = *matrix * glm::Vec4::new(vertex[3], vertex[4], vertex[5], 0.0);
+                let normal = glm::normalize(glm::Vec3::new(normal.x, normal.y, normal.z));
+                buffer.extend_from_slice(&[pos.x, pos.y, pos.z, normal.x, normal.y, normal.z, vertex[6], vertex[7]]);
+            }
+            indices.extend(mesh.indices.iter().map(|i| base + i));
+        }
+        Self::from_buffers(buffer, indices)
+    }
+
+    /// Mesh of a single group without a material
+    fn from_buffers(buffer: Vec<GLfloat>, indices: Vec<u32>) -> Mesh {
+        let index_count = indices.len();
+        Mesh {
+            buffer: buffer,
+            indices: indices,
+            groups: vec![ModelGroup {
+                first_index: 0,
+                index_count: index_count,
+                material: None,
+            }],
+            material_library: None,
+        }
+    }
+}
+
+pub struct Model {
+    vbo_handle: GLuint,
+    vao_handle: GLuint,
+    ebo_handle: GLuint,
+    trig_count: GLint,
+    groups: Vec<ModelGroup>,
+    // MTL file referenced by the model, relative to the model
+    material_library: Option<String>,
+}
+impl Model {
+    /// Uploads the vertices and triangles of a mesh
+    pub fn from_mesh(mesh: Mesh) -> Model {
+        let buffer = &mesh.buffer;
+        let indices = &mesh.indices;
+        let mut vbo = 0;
+        let mut ebo = 0;
+        let mut vao = 0;
+
         unsafe {
             // Create GPU buffer for vertex data
             gl::GenBuffers(1, &mut vbo);
@@ -344,21 +1246,49 @@ impl Model {
             );
         }
 
-        Ok(Model {
+        Model {
             ebo_handle: ebo,
             vao_handle: vao,
             vbo_handle: vbo,
-            trig_count: trig_count,
-        })
+            trig_count: (indices.len() / 3) as GLint,
+            groups: mesh.groups,
+            material_library: mesh.material_library,
+        }
+    }
+
+    /// Draws the triangles with the given mode, `gl::TRIANGLES` or `gl::PATCHES` of 3 vertices for tessellation
+    pub fn draw(&self, mode: GLenum) {
+        set_patch_vertices(mode);
+        unsafe {
+            gl::BindVertexArray(self.vao_handle);
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ebo_handle);
+            gl::DrawElements(mode, self.trig_count * 3, gl::UNSIGNED_INT, ptr::null());
+        }
     }
 
-    pub fn draw(&self) {
+    /// Draws only the triangles of one group, see `get_groups`
+    pub fn draw_group(&self, group: usize, mode: GLenum) {
+        let group = &self.groups[group];
+        set_patch_vertices(mode);
         unsafe {
             gl::BindVertexArray(self.vao_handle);
             gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ebo_handle);
-            gl::DrawElements(gl::TRIANGLES, self.trig_count * 3, gl::UNSIGNED_INT, ptr::null());
+            gl::DrawElements(
+                mode,
+                group.index_count as GLint,
+                gl::UNSIGNED_INT,
+                (group.first_index * mem::size_of::<u32>()) as *const GLvoid,
+            );
         }
     }
+
+    pub fn get_groups(&self) -> &[ModelGroup] {
+        &self.groups
+    }
+
+    pub fn get_material_library(&self) -> Option<&str> {
+        self.material_library.as_ref().map(|l| l.as_str())
+    }
 }
 impl Drop for Model {
     fn drop(&mut self) {
@@ -370,18 +1300,120 @@ impl Drop for Model {
     }
 }
 
+/// Models are tessellated as patches of 3 vertices, this needs to be set before drawing with `gl::PATCHES`
+fn set_patch_vertices(mode: GLenum) {
+    if mode == gl::PATCHES {
+        unsafe {
+            gl::PatchParameteri(gl::PATCH_VERTICES, 3);
+        }
+    }
+}
+
+/// Material of an MTL file, with its maps named like the entries of `draw_model` materials
+#[derive(Debug, Default)]
+pub struct MtlMaterial {
+    pub colors: Vec<(&'static str, [f32; 3])>,
+    pub values: Vec<(&'static str, f32)>,
+    pub maps: Vec<(&'static str, String)>,
+}
+
+/// Parses the materials of an MTL file by name
+///
+/// Only the statements which have a counterpart in `draw_model` materials are read, all others are skipped. Colors
+/// are given in sRGB, like in the file.
+pub fn parse_mtl(source: &str) -> HashMap<String, MtlMaterial> {
+    let mut materials = HashMap::new();
+    let mut current: Option<String> = None;
+    for line in source.lines() {
+        let mut words = line.split_whitespace();
+        let statement = match words.next() {
+            Some(statement) => statement,
+            None => continue,
+        };
+        let args: Vec<&str> = words.collect();
+        if statement == "newmtl" {
+            let name = args.join(" ");
+            materials.entry(name.clone()).or_insert_with(MtlMaterial::default);
+            current = Some(name);
+            continue;
+        }
+
+        let material = match current.as_ref().and_then(|name| materials.get_mut(name)) {
+            Some(material) => material,
+            None => continue,
+        };
+        let numbers: Vec<f32> = args.iter().filter_map(|a| a.parse().ok()).collect();
+        match (statement, numbers.as_slice()) {
+            ("Kd", &[r, g, b]) => material.colors.push(("albedo", [r, g, b])),
+            ("Ke", &[r, g, b]) => material.colors.push(("emissive", [r, g, b])),
+            ("Pr", &[v]) => material.values.push(("roughness", v)),
+            ("Pm", &[v]) => material.values.push(("metallic", v)),
+            _ => {}
+        }
+        let map = match statement {
+            "map_Kd" => "albedo",
+            "map_Ke" => "emissive",
+            "map_Bump" | "map_bump" | "bump" | "norm" => "normal",
+            "map_Pr" => "roughness",
+            "map_Pm" => "metallic",
+            "map_d" => "opacity",
+            _ => continue,
+        };
+        // Options such as `-bm 1.0` come before the file name, which is assumed not to contain spaces
+        if let Some(file) = args.last() {
+            material.maps.push((map, file.to_string()));
+        }
+    }
+    materials
+}
+
 pub struct Texture {
     handle: GLuint,
 }
 impl Texture {
-    pub fn load_file(path: &Path, srgb: bool) -> Result<Texture, ()> {
-        let mut image = RawImage::from_file(path, srgb)?;
-        image.flip_y();
+    pub fn load_file(
+        resources: &dyn ResourceProvider,
+        path: &Path,
+        srgb: bool,
+        options: &TextureImportOptions,
+    ) -> Result<Texture, ()> {
+        let mut image = RawImage::from_bytes(path, &resources.read(path).map_err(|_| ())?, srgb)?;
+        image.apply_import_options(options);
+        Ok(Self::from_image(&image))
+    }
 
+    /// Magenta and black checkerboard, used in place of textures which could not be loaded
+    pub fn checkerboard(srgb: bool) -> Texture {
+        const SIZE: usize = 8;
+        let mut pixel_data = Vec::with_capacity(SIZE * SIZE * 4);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let v = if (x + y) % 2 == 0 { 255 } else { 0 };
+                pixel_data.extend_from_slice(&[v, 0, v, 255]);
+            }
+        }
+
+        let mut texture = Self::from_image(&RawImage {
+            width: SIZE,
+            height: SIZE,
+            bytes_per_pixel: 4,
+            internal_format: if srgb { gl::SRGB8_ALPHA8 } else { gl::RGBA8 },
+            format: gl::RGBA,
+            data_type: gl::UNSIGNED_BYTE,
+            pixel_data: pixel_data.into_boxed_slice(),
+        });
+        // Keep the squares sharp
+        texture.set_nearest_filtering();
+        texture
+    }
+
+    fn from_image(image: &RawImage) -> Texture {
         let mut handle: GLuint = 0;
         unsafe {
             gl::GenTextures(1, &mut handle as *mut GLuint);
             gl::BindTexture(gl::TEXTURE_2D, handle);
+            // The rows of the image are not padded
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
             let img_ptr: *const GLvoid = image.pixel_data.as_ptr() as *const GLvoid;
             gl::TexImage2D(
                 gl::TEXTURE_2D,
@@ -395,8 +1427,8 @@ impl Texture {
                 img_ptr,
             );
 
-            // HACK: Clamp 16F textures, since they are used as LUTs
-            if image.data_type == gl::HALF_FLOAT {
+            // HACK: Clamp 16 bit textures, since they are used as LUTs and heightmaps
+            if image.data_type == gl::HALF_FLOAT || image.data_type == gl::UNSIGNED_SHORT {
                 gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
                 gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
                 gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
@@ -408,7 +1440,15 @@ impl Texture {
             }
         }
 
-        Ok(Texture { handle: handle })
+        Texture { handle: handle }
+    }
+
+    fn set_nearest_filtering(&mut self) {
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.handle);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+        }
     }
 
     pub fn bind(&self, texture_unit: GLuint) {
@@ -426,6 +1466,19 @@ impl Drop for Texture {
     }
 }
 
+/// File of an IBL folder with the spherical harmonics of the ambient light
+const IBL_SH_FILE: &str = "sh.txt";
+/// The environment cubemap has up to 9 MIP levels, each face of a level is an EXR file named by `Ibl::face_file`
+const IBL_MIP_LEVELS: usize = 9;
+const IBL_FACES: [(GLenum, &str); 6] = [
+    (gl::TEXTURE_CUBE_MAP_POSITIVE_X, "px"),
+    (gl::TEXTURE_CUBE_MAP_NEGATIVE_X, "nx"),
+    (gl::TEXTURE_CUBE_MAP_POSITIVE_Y, "py"),
+    (gl::TEXTURE_CUBE_MAP_NEGATIVE_Y, "ny"),
+    (gl::TEXTURE_CUBE_MAP_POSITIVE_Z, "pz"),
+    (gl::TEXTURE_CUBE_MAP_NEGATIVE_Z, "nz"),
+];
+
 /// Holds information about image based lighting
 ///
 /// This information consists of a pre-filtered environment cubemap, where each MIP level represents differen roughness
@@ -435,15 +1488,24 @@ pub struct Ibl {
     handle: GLuint,
 }
 impl Ibl {
-    pub fn load_folder(path: &Path) -> Result<Ibl, ()> {
+    /// Names of the files `load_folder` reads from an IBL folder, missing MIP levels are allowed
+    pub fn folder_files() -> Vec<String> {
+        let faces = (0..IBL_MIP_LEVELS).flat_map(|i| IBL_FACES.iter().map(move |(_, face)| Self::face_file(i, face)));
+        Some(IBL_SH_FILE.to_owned()).into_iter().chain(faces).collect()
+    }
+
+    fn face_file(mip_level: usize, face: &str) -> String {
+        format!("m{}_{}.exr", mip_level, face)
+    }
+
+    pub fn load_folder(resources: &dyn ResourceProvider, path: &Path) -> Result<Ibl, ()> {
         let mut irradiance_sph = [0.0; 27];
 
-        let file = File::open(path.join("sh.txt")).map_err(|_| ())?;
+        let sh = resources.read_to_string(&path.join(IBL_SH_FILE)).map_err(|_| ())?;
         let mut read_values: usize = 0;
-        for line in BufReader::new(file).lines().take(9) {
-            let line = line.map_err(|_| ())?;
+        for line in sh.lines().take(9) {
             let re = regex::Regex::new(r"-?\d+(\.\d+)?").unwrap();
-            for i in re.find_iter(&line).take(3) {
+            for i in re.find_iter(line).take(3) {
                 irradiance_sph[read_values] = i.as_str().parse().map_err(|_| ())?;
                 read_values += 1;
             }
@@ -453,20 +1515,14 @@ impl Ibl {
             return Err(());
         }
 
-        let faces = [
-            (gl::TEXTURE_CUBE_MAP_POSITIVE_X, "px"),
-            (gl::TEXTURE_CUBE_MAP_NEGATIVE_X, "nx"),
-            (gl::TEXTURE_CUBE_MAP_POSITIVE_Y, "py"),
-            (gl::TEXTURE_CUBE_MAP_NEGATIVE_Y, "ny"),
-            (gl::TEXTURE_CUBE_MAP_POSITIVE_Z, "pz"),
-            (gl::TEXTURE_CUBE_MAP_NEGATIVE_Z, "nz"),
-        ];
-
         let mut textures: Vec<(usize, GLenum, RawImage)> = Vec::new();
-        for i in 0..9 {
-            for (target, face) in faces.iter() {
-                let path = path.join(format!("m{}_{}.exr", i, face));
-                let image = RawImage::from_file(&path, false);
+        for i in 0..IBL_MIP_LEVELS {
+            for (target, face) in IBL_FACES.iter() {
+                let path = path.join(Self::face_file(i, face));
+                let image = resources
+                    .read(&path)
+                    .map_err(|_| ())
+                    .and_then(|bytes| RawImage::from_bytes(&path, &bytes, false));
                 if let Ok(image) = image {
                     textures.push((i as usize, *target, image));
                 }
@@ -522,6 +1578,43 @@ impl Ibl {
         })
     }
 
+    /// Uniformly grey environment, used in place of environments which could not be loaded
+    pub fn grey() -> Ibl {
+        // Only the constant spherical harmonics band contributes
+        let mut irradiance_sph = [0.0; 27];
+        irradiance_sph[0] = 0.5;
+        irradiance_sph[1] = 0.5;
+        irradiance_sph[2] = 0.5;
+
+        let grey: [GLfloat; 3] = [0.5, 0.5, 0.5];
+        let mut handle: GLuint = 0;
+        unsafe {
+            gl::GenTextures(1, &mut handle);
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, handle);
+            gl::TexStorage2D(gl::TEXTURE_CUBE_MAP, 1, gl::RGB16F, 1, 1);
+            for i in 0..6 {
+                gl::TexSubImage2D(
+                    gl::TEXTURE_CUBE_MAP_POSITIVE_X + i,
+                    0,
+                    0,
+                    0,
+                    1,
+                    1,
+                    gl::RGB,
+                    gl::FLOAT,
+                    grey.as_ptr() as *const GLvoid,
+                );
+            }
+            gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        }
+
+        Ibl {
+            irradiance_sph: irradiance_sph,
+            handle: handle,
+        }
+    }
+
     pub fn bind(&self, texture_unit: GLuint) {
         unsafe {
             gl::ActiveTexture(gl::TEXTURE0 + texture_unit);
@@ -540,3 +1633,405 @@ impl Drop for Ibl {
         }
     }
 }
+
+/// Number of bins of the luminance histogram, has to match `LUMINANCE_STATS_COMPUTE_SHADER`
+pub const LUMINANCE_HISTOGRAM_BINS: usize = 64;
+/// Size of the work groups of `LUMINANCE_STATS_COMPUTE_SHADER` in both directions
+const LUMINANCE_STATS_GROUP_SIZE: u32 = 16;
+
+/// Returns true once the GPU executed all commands issued before the fence, without waiting for it
+fn is_fence_signaled(fence: GLsync) -> bool {
+    let status = unsafe { gl::ClientWaitSync(fence, 0, 0) };
+    status == gl::ALREADY_SIGNALED || status == gl::CONDITION_SATISFIED
+}
+
+/// Average luminance and luminance histogram of a buffer, computed by a compute shader
+///
+/// The results are read back asynchronously: they become available once the GPU finished the computation, which is
+/// usually the next frame. Until then, the results of the previous computation are returned.
+pub struct LuminanceStats {
+    // Histogram bins (u32), followed by the luminance sum of each work group (f32)
+    buffer: GLuint,
+    group_count: usize,
+    pixel_count: u32,
+    fence: Option<GLsync>,
+    average: f32,
+    histogram: Vec<f32>,
+}
+impl LuminanceStats {
+    pub fn new() -> Self {
+        let mut buffer = 0;
+        unsafe {
+            gl::GenBuffers(1, &mut buffer);
+        }
+        LuminanceStats {
+            buffer: buffer,
+            group_count: 0,
+            pixel_count: 0,
+            fence: None,
+            average: 0.0,
+            histogram: vec![0.0; LUMINANCE_HISTOGRAM_BINS],
+        }
+    }
+
+    /// Starts computing the statistics of a width x height texture with `LUMINANCE_STATS_COMPUTE_SHADER`
+    ///
+    /// The shader and the texture have to be bound. Nothing is computed while the previous results are pending.
+    pub fn dispatch(&mut self, width: u32, height: u32) {
+        if self.fence.is_some() {
+            return;
+        }
+
+        let groups_x = (width + LUMINANCE_STATS_GROUP_SIZE - 1) / LUMINANCE_STATS_GROUP_SIZE;
+        let groups_y = (height + LUMINANCE_STATS_GROUP_SIZE - 1) / LUMINANCE_STATS_GROUP_SIZE;
+        let group_count = (groups_x * groups_y) as usize;
+        unsafe {
+            gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, self.buffer);
+            if group_count != self.group_count {
+                let size = (LUMINANCE_HISTOGRAM_BINS + group_count) * mem::size_of::<u32>();
+                gl::BufferData(gl::SHADER_STORAGE_BUFFER, size as isize, ptr::null(), gl::DYNAMIC_READ);
+                self.group_count = group_count;
+            }
+            // Without data, the bins are cleared to zero
+            gl::ClearBufferSubData(
+                gl::SHADER_STORAGE_BUFFER,
+                gl::R32UI,
+                0,
+                (LUMINANCE_HISTOGRAM_BINS * mem::size_of::<u32>()) as isize,
+                gl::RED_INTEGER,
+                gl::UNSIGNED_INT,
+                ptr::null(),
+            );
+            gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 0, self.buffer);
+            gl::DispatchCompute(groups_x, groups_y, 1);
+            gl::MemoryBarrier(gl::BUFFER_UPDATE_BARRIER_BIT);
+            self.fence = Some(gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0));
+        }
+        self.pixel_count = width * height;
+    }
+
+    /// Reads the results of the last computation if the GPU is done with it
+    pub fn poll(&mut self) {
+        let fence = match self.fence {
+            Some(fence) if is_fence_signaled(fence) => fence,
+            _ => return,
+        };
+
+        let mut data = vec![0u32; LUMINANCE_HISTOGRAM_BINS + self.group_count];
+        unsafe {
+            gl::DeleteSync(fence);
+            gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, self.buffer);
+            gl::GetBufferSubData(
+                gl::SHADER_STORAGE_BUFFER,
+                0,
+                (data.len() * mem::size_of::<u32>()) as isize,
+                data.as_mut_ptr() as *mut GLvoid,
+            );
+        }
+        self.fence = None;
+
+        let pixel_count = self.pixel_count.max(1) as f32;
+        let (bins, group_sums) = data.split_at(LUMINANCE_HISTOGRAM_BINS);
+        self.histogram = bins.iter().map(|&count| count as f32 / pixel_count).collect();
+        self.average = group_sums.iter().map(|&sum| f32::from_bits(sum)).sum::<f32>() / pixel_count;
+    }
+
+    pub fn get_average(&self) -> f32 {
+        self.average
+    }
+
+    /// Fraction of the pixels which fall into the given bin
+    pub fn get_histogram_bin(&self, bin: usize) -> Option<f32> {
+        self.histogram.get(bin).cloned()
+    }
+}
+impl Drop for LuminanceStats {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(fence) = self.fence {
+                gl::DeleteSync(fence);
+            }
+            gl::DeleteBuffers(1, &self.buffer);
+        }
+    }
+}
+
+/// Reads a pixel back from the GPU asynchronously, through a pixel buffer object
+///
+/// Like `LuminanceStats`, the value becomes available once the GPU copied it, which is usually the next frame.
+pub struct PixelReadback {
+    pbo: GLuint,
+    fence: Option<GLsync>,
+    value: Option<[f32; 4]>,
+}
+impl PixelReadback {
+    pub fn new() -> Self {
+        let mut pbo = 0;
+        unsafe {
+            gl::GenBuffers(1, &mut pbo);
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, pbo);
+            gl::BufferData(
+                gl::PIXEL_PACK_BUFFER,
+                mem::size_of::<[f32; 4]>() as isize,
+                ptr::null(),
+                gl::STREAM_READ,
+            );
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+        }
+        PixelReadback {
+            pbo: pbo,
+            fence: None,
+            value: None,
+        }
+    }
+
+    /// Starts copying the pixel at (x, y) of the buffer bound for reading, unless the previous copy is pending
+    pub fn start(&mut self, x: u32, y: u32) {
+        if self.fence.is_some() {
+            return;
+        }
+        unsafe {
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, self.pbo);
+            gl::ReadPixels(x as GLint, y as GLint, 1, 1, gl::RGBA, gl::FLOAT, ptr::null_mut());
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+            self.fence = Some(gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0));
+        }
+    }
+
+    /// Reads the copied pixel if the GPU is done with it
+    pub fn poll(&mut self) {
+        let fence = match self.fence {
+            Some(fence) if is_fence_signaled(fence) => fence,
+            _ => return,
+        };
+
+        let mut value = [0.0f32; 4];
+        unsafe {
+            gl::DeleteSync(fence);
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, self.pbo);
+            gl::GetBufferSubData(
+                gl::PIXEL_PACK_BUFFER,
+                0,
+                mem::size_of::<[f32; 4]>() as isize,
+                value.as_mut_ptr() as *mut GLvoid,
+            );
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+        }
+        self.fence = None;
+        self.value = Some(value);
+    }
+
+    /// The last pixel which was read, as stored in the buffer
+    pub fn get_value(&self) -> Option<[f32; 4]> {
+        self.value
+    }
+}
+impl Drop for PixelReadback {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(fence) = self.fence {
+                gl::DeleteSync(fence);
+            }
+            gl::DeleteBuffers(1, &self.pbo);
+        }
+    }
+}
+
+/// Number of frames a `GpuTimer` can measure before the first result is read
+const GPU_TIMER_QUERIES: usize = 4;
+
+/// Measures how long the GPU works on a frame, through timer queries
+///
+/// Like `PixelReadback`, results are only read once the GPU is done with them, a few frames after they were measured.
+pub struct GpuTimer {
+    queries: [GLuint; GPU_TIMER_QUERIES],
+    running: Option<GLuint>,
+    // Queries which were ended but not read yet, the oldest first
+    pending: VecDeque<GLuint>,
+}
+impl GpuTimer {
+    pub fn new() -> Self {
+        let mut queries = [0; GPU_TIMER_QUERIES];
+        unsafe {
+            gl::GenQueries(GPU_TIMER_QUERIES as GLint, queries.as_mut_ptr());
+        }
+        GpuTimer {
+            queries: queries,
+            running: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Starts measuring, ending the previous measurement if it is still running
+    ///
+    /// Nothing is measured while all queries are pending.
+    pub fn begin(&mut self) {
+        self.end();
+        let pending = &self.pending;
+        if let Some(&query) = self.queries.iter().find(|q| !pending.contains(q)) {
+            unsafe {
+                gl::BeginQuery(gl::TIME_ELAPSED, query);
+            }
+            self.running = Some(query);
+        }
+    }
+
+    /// Stops the running measurement, its result can be read once the GPU is done with the frame
+    pub fn end(&mut self) {
+        if let Some(query) = self.running.take() {
+            unsafe {
+                gl::EndQuery(gl::TIME_ELAPSED);
+            }
+            self.pending.push_back(query);
+        }
+    }
+
+    /// Reads the measurements the GPU is done with, and returns the time of the most recent one in seconds
+    pub fn poll(&mut self) -> Option<f32> {
+        let mut elapsed_s = None;
+        while let Some(&query) = self.pending.front() {
+            let mut available: GLint = 0;
+            let mut elapsed_ns: GLuint64 = 0;
+            unsafe {
+                gl::GetQueryObjectiv(query, gl::QUERY_RESULT_AVAILABLE, &mut available);
+                if available == 0 {
+                    break;
+                }
+                gl::GetQueryObjectui64v(query, gl::QUERY_RESULT, &mut elapsed_ns);
+            }
+            self.pending.pop_front();
+            elapsed_s = Some(elapsed_ns as f32 * 1e-9);
+        }
+        elapsed_s
+    }
+}
+impl Drop for GpuTimer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteQueries(GPU_TIMER_QUERIES as GLint, self.queries.as_ptr());
+        }
+    }
+}
+
+/// Number of frames which a `UniformRing` keeps apart, the GPU may still be drawing the previous frame
+pub const UNIFORM_RING_FRAMES: usize = 2;
+
+/// Uniform data written by the CPU every frame into buffers which stay mapped
+///
+/// Each buffer holds one region per frame. A region is only written again once the fence of the frame which used it is
+/// signaled, so writing uniforms never waits for draws which are still pending on the GPU. When a frame fills its
+/// region, it goes on in the region of the next buffer, which is created the first time it is needed.
+pub struct UniformRing {
+    // (buffer, mapping) of each buffer, all of `region_size * UNIFORM_RING_FRAMES` bytes
+    buffers: Vec<(GLuint, *mut u8)>,
+    region_size: usize,
+    alignment: usize,
+    region: usize,
+    // Buffer the current frame writes to, and the bytes written to its region
+    buffer_index: usize,
+    offset: usize,
+    fences: [Option<GLsync>; UNIFORM_RING_FRAMES],
+}
+impl UniformRing {
+    pub fn new(region_size: usize) -> Self {
+        let mut alignment: GLint = 0;
+        unsafe {
+            gl::GetIntegerv(gl::UNIFORM_BUFFER_OFFSET_ALIGNMENT, &mut alignment);
+        }
+        UniformRing {
+            buffers: vec![Self::create_buffer(region_size)],
+            region_size: region_size,
+            alignment: alignment.max(1) as usize,
+            region: 0,
+            buffer_index: 0,
+            offset: 0,
+            fences: [None; UNIFORM_RING_FRAMES],
+        }
+    }
+
+    fn create_buffer(region_size: usize) -> (GLuint, *mut u8) {
+        let size = (region_size * UNIFORM_RING_FRAMES) as isize;
+        let flags = gl::MAP_WRITE_BIT | gl::MAP_PERSISTENT_BIT | gl::MAP_COHERENT_BIT;
+        let mut buffer = 0;
+        unsafe {
+            gl::GenBuffers(1, &mut buffer);
+            gl::BindBuffer(gl::UNIFORM_BUFFER, buffer);
+            gl::BufferStorage(gl::UNIFORM_BUFFER, size, ptr::null(), flags);
+            let mapping = gl::MapBufferRange(gl::UNIFORM_BUFFER, 0, size, flags);
+            gl::BindBuffer(gl::UNIFORM_BUFFER, 0);
+            (buffer, mapping as *mut u8)
+        }
+    }
+
+    /// Starts writing to the region of the next frame, waits if the GPU did not finish the frame which used it last
+    pub fn begin_frame(&mut self) {
+        self.region = (self.region + 1) % UNIFORM_RING_FRAMES;
+        self.buffer_index = 0;
+        self.offset = 0;
+        if let Some(fence) = self.fences[self.region].take() {
+            unsafe {
+                gl::ClientWaitSync(fence, gl::SYNC_FLUSH_COMMANDS_BIT, u64::max_value());
+                gl::DeleteSync(fence);
+            }
+        }
+    }
+
+    /// Fences the region of the current frame, after the last draw which uses it
+    pub fn end_frame(&mut self) {
+        unsafe {
+            if let Some(fence) = self.fences[self.region].take() {
+                gl::DeleteSync(fence);
+            }
+            self.fences[self.region] = Some(gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0));
+        }
+    }
+
+    /// Copies the data into the region of the current frame, and binds it to the given uniform buffer binding
+    pub fn bind_data(&mut self, binding: GLuint, data: &[u8]) -> Result<(), String> {
+        if data.len() > self.region_size {
+            return Err(format!(
+                "Uniform data of {} bytes does not fit into the {} bytes of a region",
+                data.len(),
+                self.region_size
+            ));
+        }
+        let mut offset = (self.offset + self.alignment - 1) / self.alignment * self.alignment;
+        if offset + data.len() > self.region_size {
+            // The fence of the frame covers the regions of all buffers, the next buffer can be written right away
+            self.buffer_index += 1;
+            offset = 0;
+            if self.buffer_index == self.buffers.len() {
+                self.buffers.push(Self::create_buffer(self.region_size));
+            }
+        }
+        let (buffer, mapping) = self.buffers[self.buffer_index];
+        if mapping.is_null() {
+            return Err(format!("The uniform ring could not be mapped"));
+        }
+
+        let start = self.region * self.region_size + offset;
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), mapping.add(start), data.len());
+            gl::BindBufferRange(gl::UNIFORM_BUFFER, binding, buffer, start as isize, data.len() as isize);
+        }
+        self.offset = offset + data.len();
+        Ok(())
+    }
+}
+impl Drop for UniformRing {
+    fn drop(&mut self) {
+        unsafe {
+            for fence in self.fences.iter_mut() {
+                if let Some(fence) = fence.take() {
+                    gl::DeleteSync(fence);
+                }
+            }
+            for &(buffer, _) in &self.buffers {
+                gl::BindBuffer(gl::UNIFORM_BUFFER, buffer);
+                gl::UnmapBuffer(gl::UNIFORM_BUFFER);
+                gl::BindBuffer(gl::UNIFORM_BUFFER, 0);
+                gl::DeleteBuffers(1, &buffer);
+            }
+        }
+    }
+}
@@ -1,10 +1,14 @@
 use std::fs::File;
+use std::io::Cursor;
 use std::path::Path;
 
 use gl::types::GLenum;
 use half::f16;
 use image::GenericImageView;
 
+use bytecode::TextureImportOptions;
+use color::{linear_to_srgb, srgb_to_linear};
+
 pub struct RawImage {
     pub width: usize,
     pub height: usize,
@@ -15,19 +19,25 @@ pub struct RawImage {
     pub pixel_data: Box<[u8]>,
 }
 impl RawImage {
-    pub fn from_file(path: &Path, srgb_hint: bool) -> Result<Self, ()> {
+    /// Decodes the contents of an image file, the format is given by the extension of its path
+    pub fn from_bytes(path: &Path, bytes: &[u8], srgb_hint: bool) -> Result<Self, ()> {
         let extension = path.extension().ok_or(())?;
-        if extension == "png" || extension == "jpg" {
-            Self::load_using_image(path, srgb_hint)
+        if extension == "png" {
+            match Self::load_16_bit_png(bytes, srgb_hint)? {
+                Some(image) => Ok(image),
+                None => Self::load_using_image(bytes, srgb_hint),
+            }
+        } else if extension == "jpg" {
+            Self::load_using_image(bytes, srgb_hint)
         } else if extension == "exr" {
-            Self::load_using_exr(path)
+            Self::load_using_exr(bytes)
         } else {
             Err(())
         }
     }
 
-    fn load_using_image(path: &Path, srgb_hint: bool) -> Result<Self, ()> {
-        let image = image::open(path).map_err(|_| ())?;
+    fn load_using_image(bytes: &[u8], srgb_hint: bool) -> Result<Self, ()> {
+        let image = image::load_from_memory(bytes).map_err(|_| ())?;
         let width = image.width() as usize;
         let height = image.height() as usize;
         let channels = match image {
@@ -36,7 +46,7 @@ impl RawImage {
             image::DynamicImage::ImageRgba8(_) => 4,
             image::DynamicImage::ImageBgra8(_) => 4,
             image::DynamicImage::ImageLuma8(_) => 1,
-            _ => return Err(()),
+            image::DynamicImage::ImageLumaA8(_) => 2,
         };
 
         let swap_pixels = match image {
@@ -56,34 +66,81 @@ impl RawImage {
             }
         }
 
+        // Grayscale images are taken as data rather than colors
         let internal_format = match (channels, srgb_hint) {
             (1, _) => gl::R8,
+            (2, _) => gl::RG8,
             (3, true) => gl::SRGB8,
             (4, true) => gl::SRGB8_ALPHA8,
             (3, false) => gl::RGB8,
             (4, false) => gl::RGBA8,
             _ => unreachable!(),
         };
-        let format = match channels {
-            1 => gl::RED,
-            3 => gl::RGB,
-            4 => gl::RGBA,
-            _ => unreachable!(),
-        };
         Ok(RawImage {
             width: width,
             height: height,
             bytes_per_pixel: channels,
             internal_format: internal_format,
-            format: format,
+            format: pixel_format(channels),
             data_type: gl::UNSIGNED_BYTE,
             pixel_data: pixels.into_boxed_slice(),
         })
     }
 
-    pub fn load_using_exr(path: &Path) -> Result<Self, ()> {
-        let mut file = File::open(path).map_err(|_| ())?;
-        let mut exr_file = openexr::InputFile::new(&mut file).map_err(|_| ())?;
+    /// Decodes a PNG file with 16 bits per channel, which the `image` crate reduces to 8 bits
+    ///
+    /// The image is uploaded to a 16 bit normalized texture, for heightmaps and lookup tables which need the precision.
+    /// There are no 16 bit sRGB formats, so the colors of sRGB images are converted to linear instead. Returns `None`
+    /// for files with 8 bits or less per channel.
+    fn load_16_bit_png(bytes: &[u8], srgb_hint: bool) -> Result<Option<Self>, ()> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let (info, mut reader) = decoder.read_info().map_err(|_| ())?;
+        let (color_type, bit_depth) = reader.output_color_type();
+        if bit_depth != png::BitDepth::Sixteen {
+            return Ok(None);
+        }
+        let channels = match color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => return Ok(None),
+        };
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).map_err(|_| ())?;
+
+        // PNG files store the channels in big endian, OpenGL expects them in the byte order of the machine
+        let srgb = srgb_hint && channels >= 3;
+        let mut pixels = Vec::with_capacity(data.len());
+        for (i, value) in data.chunks(2).enumerate() {
+            let mut value = u16::from_be_bytes([value[0], value[1]]);
+            if srgb && i % channels < 3 {
+                value = (srgb_to_linear(value as f32 / 65535.0) * 65535.0).round() as u16;
+            }
+            pixels.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        let internal_format = match channels {
+            1 => gl::R16,
+            2 => gl::RG16,
+            3 => gl::RGB16,
+            _ => gl::RGBA16,
+        };
+        Ok(Some(RawImage {
+            width: info.width as usize,
+            height: info.height as usize,
+            bytes_per_pixel: 2 * channels,
+            internal_format: internal_format,
+            format: pixel_format(channels),
+            data_type: gl::UNSIGNED_SHORT,
+            pixel_data: pixels.into_boxed_slice(),
+        }))
+    }
+
+    pub fn load_using_exr(bytes: &[u8]) -> Result<Self, ()> {
+        let mut cursor = Cursor::new(bytes);
+        let mut exr_file = openexr::InputFile::new(&mut cursor).map_err(|_| ())?;
 
         let (width, height) = exr_file.header().data_dimensions();
         let width = width as usize;
@@ -118,6 +175,99 @@ impl RawImage {
         })
     }
 
+    /// Converts the pixels from the conventions of the file to those of the engine
+    pub fn apply_import_options(&mut self, options: &TextureImportOptions) {
+        if options.exposure != 0.0 || options.premultiply_alpha {
+            self.scale_colors(options.exposure.exp2(), options.premultiply_alpha);
+        }
+        if options.flip_y {
+            self.flip_y();
+        }
+    }
+
+    /// Multiplies the colors of all pixels by the factor, and by their alpha if `premultiply_alpha` is set
+    ///
+    /// sRGB pixels are scaled in linear space. Colors are clamped to the range of normalized formats, and alpha is left
+    /// as it is.
+    fn scale_colors(&mut self, factor: f32, premultiply_alpha: bool) {
+        let srgb = self.internal_format == gl::SRGB8 || self.internal_format == gl::SRGB8_ALPHA8;
+        let channels = self.get_channels();
+        let has_alpha = channels == 2 || channels == 4;
+        let mut values = self.get_values();
+        for pixel in values.chunks_mut(channels) {
+            let alpha = if has_alpha && premultiply_alpha {
+                pixel[channels - 1]
+            } else {
+                1.0
+            };
+            let color_channels = if has_alpha { channels - 1 } else { channels };
+            for value in &mut pixel[..color_channels] {
+                *value = if srgb {
+                    linear_to_srgb(srgb_to_linear(*value) * factor * alpha)
+                } else {
+                    *value * factor * alpha
+                };
+            }
+        }
+        self.set_values(&values);
+    }
+
+    fn get_channels(&self) -> usize {
+        match self.format {
+            gl::RED => 1,
+            gl::RG => 2,
+            gl::RGB => 3,
+            _ => 4,
+        }
+    }
+
+    /// Returns all channels of all pixels, normalized formats are mapped to [0, 1]
+    fn get_values(&self) -> Vec<f32> {
+        match self.data_type {
+            gl::UNSIGNED_BYTE => self.pixel_data.iter().map(|&value| value as f32 / 255.0).collect(),
+            gl::UNSIGNED_SHORT => self
+                .pixel_data
+                .chunks(2)
+                .map(|value| u16::from_ne_bytes([value[0], value[1]]) as f32 / 65535.0)
+                .collect(),
+            gl::HALF_FLOAT => self
+                .pixel_data
+                .chunks(2)
+                .map(|value| f16::from_bits(u16::from_le_bytes([value[0], value[1]])).to_f32())
+                .collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the first channel of all pixels, row by row as stored in the file
+    pub fn get_first_channel(&self) -> Vec<f32> {
+        self.get_values().into_iter().step_by(self.get_channels()).collect()
+    }
+
+    /// Replaces all channels of all pixels, values are clamped to the range of normalized formats
+    fn set_values(&mut self, values: &[f32]) {
+        let pixel_data = &mut self.pixel_data;
+        match self.data_type {
+            gl::UNSIGNED_BYTE => {
+                for (byte, value) in pixel_data.iter_mut().zip(values) {
+                    *byte = (value.max(0.0).min(1.0) * 255.0).round() as u8;
+                }
+            }
+            gl::UNSIGNED_SHORT => {
+                for (bytes, value) in pixel_data.chunks_mut(2).zip(values) {
+                    let value = (value.max(0.0).min(1.0) * 65535.0).round() as u16;
+                    bytes.copy_from_slice(&value.to_ne_bytes());
+                }
+            }
+            gl::HALF_FLOAT => {
+                for (bytes, &value) in pixel_data.chunks_mut(2).zip(values) {
+                    bytes.copy_from_slice(&f16::from_f32(value).to_bits().to_le_bytes());
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
     pub fn flip_y(&mut self) {
         for y in 0..self.height / 2 {
             for x in 0..(self.width * self.bytes_per_pixel) {
@@ -128,3 +278,51 @@ impl RawImage {
         }
     }
 }
+
+/// Returns the format of pixels with the given number of channels
+fn pixel_format(channels: usize) -> GLenum {
+    match channels {
+        1 => gl::RED,
+        2 => gl::RG,
+        3 => gl::RGB,
+        _ => gl::RGBA,
+    }
+}
+
+/// Writes RGBA pixels to an EXR file with 32 bit float channels, the rows go from top to bottom
+pub fn write_exr(path: &Path, width: usize, height: usize, pixels: &[(f32, f32, f32, f32)]) -> Result<(), String> {
+    let mut file = File::create(path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+    let mut header = openexr::Header::new();
+    header
+        .set_resolution(width as u32, height as u32)
+        .add_channel("R", openexr::PixelType::FLOAT)
+        .add_channel("G", openexr::PixelType::FLOAT)
+        .add_channel("B", openexr::PixelType::FLOAT)
+        .add_channel("A", openexr::PixelType::FLOAT);
+    let mut exr_file = openexr::ScanlineOutputFile::new(&mut file, &header)
+        .map_err(|e| format!("Could not write {}: {:?}", path.display(), e))?;
+
+    let mut fb = openexr::FrameBuffer::new(width as u32, height as u32);
+    fb.insert_channels(&["R", "G", "B", "A"], pixels);
+    exr_file
+        .write_pixels(&fb)
+        .map_err(|e| format!("Could not write {}: {:?}", path.display(), e))
+}
+
+/// Writes 8 bit RGBA pixels to a PNG file, the rows go from top to bottom
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) -> Result<(), String> {
+    image::save_buffer(path, pixels, width, height, image::ColorType::RGBA(8))
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
+
+/// Writes 8 bit RGBA pixels to a PNG file like `write_png`, scaled down to fit into a square of the given size
+pub fn write_png_thumbnail(path: &Path, width: u32, height: u32, pixels: &[u8], size: u32) -> Result<(), String> {
+    let image = image::RgbaImage::from_raw(width, height, pixels.to_vec())
+        .ok_or_else(|| format!("Could not write {}: the pixels do not fill the image", path.display()))?;
+    let scale = (size as f32 / width.max(height) as f32).min(1.0);
+    let thumbnail_width = ((width as f32 * scale).round() as u32).max(1);
+    let thumbnail_height = ((height as f32 * scale).round() as u32).max(1);
+    image::imageops::thumbnail(&image, thumbnail_width, thumbnail_height)
+        .save(path)
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
@@ -0,0 +1,52 @@
+extern crate gl;
+#[cfg(feature = "editor")]
+#[macro_use]
+extern crate lalrpop_util;
+extern crate bytes;
+extern crate gilrs;
+extern crate glm;
+extern crate half;
+extern crate image;
+extern crate memmap;
+extern crate openexr;
+extern crate png;
+extern crate regex;
+extern crate rodio;
+#[cfg(feature = "editor")]
+extern crate rust_rocket;
+extern crate time;
+extern crate wavefront_obj;
+extern crate zstd;
+
+mod ast;
+mod astvisitor;
+pub mod audio;
+pub mod bytecode;
+mod capabilities;
+pub mod color;
+pub mod crash;
+mod deadcode;
+pub mod demoscene;
+pub mod diagnostics;
+mod draw2d;
+mod easing;
+#[cfg(feature = "editor")]
+pub mod format;
+mod framegraph;
+pub mod gl_resources;
+mod imageio;
+#[cfg(feature = "editor")]
+mod json;
+#[cfg(feature = "editor")]
+pub mod lsp;
+pub mod pack;
+mod postfx;
+pub mod resources;
+pub mod runtime;
+mod serialize;
+pub mod sync;
+mod typecheck;
+pub mod types;
+
+#[cfg(feature = "editor")]
+lalrpop_mod!(grammar);
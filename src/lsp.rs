@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use ast::{self, SourceSlice};
+use bytecode::{CompileOptions, ProgramContainer, BUILTIN_EXPRESSIONS, BUILTIN_STATEMENTS, GLOBALS};
+use demoscene;
+use easing;
+use grammar::ProgramParser;
+use json::Json;
+use lalrpop_util::ParseError;
+
+/// `CompletionItemKind` and `DiagnosticSeverity` values of the protocol
+const COMPLETION_FUNCTION: f64 = 3.0;
+const COMPLETION_VARIABLE: f64 = 6.0;
+const SEVERITY_ERROR: f64 = 1.0;
+const SEVERITY_WARNING: f64 = 2.0;
+/// JSON-RPC error codes for messages which cannot be parsed, for requests the server does not implement, and for
+/// requests which failed
+const PARSE_ERROR: f64 = -32700.0;
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const REQUEST_FAILED: f64 = -32803.0;
+/// Command toggling a breakpoint of the running engine, its arguments are the URI of the script and the line
+const TOGGLE_BREAKPOINT: &str = "demoengine.toggleBreakpoint";
+
+/// Line and character of a byte offset, characters are counted in UTF-16 code units as the protocol requires
+fn offset_to_position(text: &str, offset: usize) -> Json {
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[..line_start].matches('\n').count();
+    let character: usize = text[line_start..offset].chars().map(|c| c.len_utf16()).sum();
+    Json::object(vec![
+        ("line", Json::Number(line as f64)),
+        ("character", Json::Number(character as f64)),
+    ])
+}
+
+/// Byte offset of a position of the protocol, positions past the end of a line are moved to its end
+fn position_to_offset(text: &str, position: &Json) -> Option<usize> {
+    let line = position.get("line")?.as_f64()? as usize;
+    let character = position.get("character")?.as_f64()? as usize;
+    let mut line_start = 0;
+    for _ in 0..line {
+        line_start += text[line_start..].find('\n')? + 1;
+    }
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+/// Path of a `file://` URI, with the percent-encoded bytes decoded
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?;
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = encoded
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+fn slice_to_range(text: &str, slice: SourceSlice) -> Json {
+    Json::object(vec![
+        ("start", offset_to_position(text, slice.begin)),
+        ("end", offset_to_position(text, slice.end)),
+    ])
+}
+
+fn diagnostic(text: &str, slice: SourceSlice, severity: f64, message: String) -> Json {
+    Json::object(vec![
+        ("range", slice_to_range(text, slice)),
+        ("severity", Json::Number(severity)),
+        ("source", Json::str("demoengine")),
+        ("message", Json::Str(message)),
+    ])
+}
+
+/// Parses and compiles a script like the engine does, returning the problems found as diagnostics of the protocol
+fn check_script(text: &str) -> Vec<Json> {
+    let ast = match ProgramParser::new().parse(text) {
+        Ok(ast) => ast,
+        Err(e) => {
+            let (slice, message) = match e {
+                ParseError::InvalidToken { location } => {
+                    (SourceSlice::new(location, location), format!("Invalid token"))
+                }
+                ParseError::UnrecognizedToken { token, .. } => (
+                    SourceSlice::new(token.0, token.2),
+                    format!("Unexpected token `{}`", &text[token.0..token.2]),
+                ),
+                e => (SourceSlice::new(text.len(), text.len()), format!("{:?}", e)),
+            };
+            return vec![diagnostic(text, slice, SEVERITY_ERROR, message)];
+        }
+    };
+
+    match ProgramContainer::from_ast(text, &ast, CompileOptions::default()) {
+        Ok(bytecode) => bytecode
+            .get_warnings()
+            .iter()
+            .map(|w| diagnostic(text, w.get_slice(), SEVERITY_WARNING, w.get_message().to_owned()))
+            .collect(),
+        Err(e) => {
+            let mut message = e.get_message().to_owned();
+            for note in e.get_notes() {
+                message += &format!("\nnote: {}", note);
+            }
+            vec![diagnostic(text, e.get_slice(), SEVERITY_ERROR, message)]
+        }
+    }
+}
+
+/// The identifier the given offset is in or right after
+fn word_at(text: &str, offset: usize) -> Option<&str> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let offset = offset.min(text.len());
+    if !text.is_char_boundary(offset) {
+        return None;
+    }
+    let begin = text[..offset].rfind(|c| !is_word(c)).map_or(0, |i| i + 1);
+    let end = text[offset..].find(|c| !is_word(c)).map_or(text.len(), |i| offset + i);
+    if begin < end {
+        Some(&text[begin..end])
+    } else {
+        None
+    }
+}
+
+/// Where a function, render target, scene object, batch, light or sync track with the given name is defined
+fn find_definition(text: &str, program: &ast::Program, name: &str) -> Option<SourceSlice> {
+    let mut names = program
+        .functions
+        .iter()
+        .map(|f| f.name)
+        .chain(program.render_targets.iter().map(|rt| rt.name))
+        .chain(program.objects.iter().map(|o| o.name))
+        .chain(program.batches.iter().map(|b| b.name))
+        .chain(program.lights.iter().map(|l| l.name))
+        .chain(program.tracks.iter().map(|t| t.name));
+    names.find(|slice| slice.to_slice(text).trim_matches('"') == name)
+}
+
+/// Built-ins, user functions and globals, the script is only used if it parses
+fn completion_items(text: &str) -> Vec<Json> {
+    let item = |label: &str, kind: f64, detail: &str| {
+        Json::object(vec![
+            ("label", Json::str(label)),
+            ("kind", Json::Number(kind)),
+            ("detail", Json::str(detail)),
+        ])
+    };
+    let mut items: Vec<Json> = BUILTIN_STATEMENTS
+        .iter()
+        .map(|name| item(name, COMPLETION_FUNCTION, "built-in statement"))
+        .chain(
+            BUILTIN_EXPRESSIONS
+                .iter()
+                .chain(easing::FUNCTIONS.iter().map(|f| &f.0))
+                .map(|name| item(name, COMPLETION_FUNCTION, "built-in function")),
+        )
+        .chain(GLOBALS.iter().map(|name| item(name, COMPLETION_VARIABLE, "global")))
+        .collect();
+    if let Ok(program) = ProgramParser::new().parse(text) {
+        for function in &program.functions {
+            items.push(item(function.name.to_slice(text), COMPLETION_FUNCTION, "function"));
+        }
+    }
+    items
+}
+
+/// Serves diagnostics, go-to-definition and completion for scripts over the Language Server Protocol
+///
+/// Messages are exchanged on stdin and stdout. Documents are synchronized in full, and are checked whenever they are
+/// opened or changed.
+struct Server {
+    documents: HashMap<String, String>,
+    output: io::Stdout,
+}
+impl Server {
+    fn send(&mut self, message: Json) -> Result<(), String> {
+        let body = format!("{}", message);
+        let mut output = self.output.lock();
+        write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|_| output.flush())
+            .map_err(|e| format!("Could not write to stdout: {}", e))
+    }
+
+    fn respond(&mut self, id: Json, result: Json) -> Result<(), String> {
+        self.send(Json::object(vec![
+            ("jsonrpc", Json::str("2.0")),
+            ("id", id),
+            ("result", result),
+        ]))
+    }
+
+    fn respond_error(&mut self, id: Json, code: f64, message: String) -> Result<(), String> {
+        let error = Json::object(vec![("code", Json::Number(code)), ("message", Json::Str(message))]);
+        self.send(Json::object(vec![
+            ("jsonrpc", Json::str("2.0")),
+            ("id", id),
+            ("error", error),
+        ]))
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> Result<(), String> {
+        let diagnostics = self.documents.get(uri).map_or(Vec::new(), |text| check_script(text));
+        self.send(Json::object(vec![
+            ("jsonrpc", Json::str("2.0")),
+            ("method", Json::str("textDocument/publishDiagnostics")),
+            (
+                "params",
+                Json::object(vec![("uri", Json::str(uri)), ("diagnostics", Json::Array(diagnostics))]),
+            ),
+        ]))
+    }
+
+    fn definition(&self, params: &Json) -> Option<Json> {
+        let uri = params.path(&["textDocument", "uri"])?.as_str()?;
+        let text = self.documents.get(uri)?;
+        let offset = position_to_offset(text, params.get("position")?)?;
+        let program = ProgramParser::new().parse(text).ok()?;
+        let slice = find_definition(text, &program, word_at(text, offset)?)?;
+        Some(Json::object(vec![
+            ("uri", Json::str(uri)),
+            ("range", slice_to_range(text, slice)),
+        ]))
+    }
+
+    /// Toggles a breakpoint in the file the engine reads them from, and returns the lines of all breakpoints
+    ///
+    /// Lines are counted from 0 like everywhere in the protocol, the file counts them from 1.
+    fn toggle_breakpoint(&self, params: &Json) -> Result<Json, String> {
+        let args = params.get("arguments").and_then(|a| a.as_array()).unwrap_or(&[]);
+        let (uri, line) = match args {
+            [uri, line] => (uri.as_str(), line.as_f64()),
+            _ => (None, None),
+        };
+        let (uri, line) = uri
+            .zip(line)
+            .ok_or_else(|| format!("{} expects the URI of a script and a line", TOGGLE_BREAKPOINT))?;
+        let path = uri_to_path(uri).ok_or_else(|| format!("Not a file: {}", uri))?;
+        let line = line as usize + 1;
+        let mut breakpoints = demoscene::read_breakpoints(&path);
+        if !breakpoints.remove(&line) {
+            breakpoints.insert(line);
+        }
+        demoscene::write_breakpoints(&path, &breakpoints)?;
+        Ok(Json::Array(
+            breakpoints.iter().map(|line| Json::Number((line - 1) as f64)).collect(),
+        ))
+    }
+
+    /// Handles a message, returns false once the client asks the server to exit
+    fn handle(&mut self, message: &Json) -> Result<bool, String> {
+        let method = message.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let id = message.get("id").cloned();
+
+        match method {
+            "initialize" => {
+                let capabilities = Json::object(vec![
+                    ("textDocumentSync", Json::Number(1.0)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("completionProvider", Json::object(vec![])),
+                    (
+                        "executeCommandProvider",
+                        Json::object(vec![("commands", Json::Array(vec![Json::str(TOGGLE_BREAKPOINT)]))]),
+                    ),
+                ]);
+                let result = Json::object(vec![
+                    ("capabilities", capabilities),
+                    ("serverInfo", Json::object(vec![("name", Json::str("demoengine"))])),
+                ]);
+                self.respond(id.unwrap_or(Json::Null), result)?;
+            }
+            "textDocument/didOpen" => {
+                let document = params.get("textDocument");
+                let uri = document.and_then(|d| d.get("uri")).and_then(|u| u.as_str());
+                let text = document.and_then(|d| d.get("text")).and_then(|t| t.as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.documents.insert(uri.to_owned(), text.to_owned());
+                    self.publish_diagnostics(uri)?;
+                }
+            }
+            "textDocument/didChange" => {
+                let uri = params.path(&["textDocument", "uri"]).and_then(|u| u.as_str());
+                let changes = params.get("contentChanges").and_then(|c| c.as_array());
+                let text = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c.get("text"))
+                    .and_then(|t| t.as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.documents.insert(uri.to_owned(), text.to_owned());
+                    self.publish_diagnostics(uri)?;
+                }
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params.path(&["textDocument", "uri"]).and_then(|u| u.as_str()) {
+                    self.documents.remove(uri);
+                    self.publish_diagnostics(uri)?;
+                }
+            }
+            "textDocument/definition" => {
+                let result = self.definition(&params).unwrap_or(Json::Null);
+                self.respond(id.unwrap_or(Json::Null), result)?;
+            }
+            "textDocument/completion" => {
+                let uri = params.path(&["textDocument", "uri"]).and_then(|u| u.as_str());
+                let text = uri.and_then(|uri| self.documents.get(uri)).cloned().unwrap_or_default();
+                self.respond(id.unwrap_or(Json::Null), Json::Array(completion_items(&text)))?;
+            }
+            "workspace/executeCommand" if params.get("command").and_then(|c| c.as_str()) == Some(TOGGLE_BREAKPOINT) => {
+                match self.toggle_breakpoint(&params) {
+                    Ok(result) => self.respond(id.unwrap_or(Json::Null), result)?,
+                    Err(err) => self.respond_error(id.unwrap_or(Json::Null), REQUEST_FAILED, err)?,
+                }
+            }
+            "shutdown" => self.respond(id.unwrap_or(Json::Null), Json::Null)?,
+            "exit" => return Ok(false),
+            _ => {
+                // Notifications which are not supported are ignored, requests have to be answered
+                if let Some(id) = id {
+                    self.respond_error(id, METHOD_NOT_FOUND, format!("Unsupported method {}", method))?;
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Reads the next message, `None` once the input is closed
+///
+/// Only failing to read the input is an error, a message which cannot be decoded is returned as `Some(Err(..))` so
+/// that the server can report it and go on with the next one.
+fn read_message(input: &mut dyn BufRead) -> Result<Option<Result<Json, String>>, String> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        let read = input
+            .read_line(&mut header)
+            .map_err(|e| format!("Could not read from stdin: {}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if header.to_ascii_lowercase().starts_with("content-length:") {
+            content_length = header["content-length:".len()..].trim().parse().ok();
+        }
+    }
+
+    let content_length: usize = match content_length {
+        Some(content_length) => content_length,
+        None => return Ok(Some(Err(format!("Message without Content-Length")))),
+    };
+    let mut body = vec![0; content_length];
+    input
+        .read_exact(&mut body)
+        .map_err(|e| format!("Could not read from stdin: {}", e))?;
+    let message = String::from_utf8(body)
+        .map_err(|e| format!("Message is not valid UTF-8: {}", e))
+        .and_then(|body| Json::parse(&body));
+    Ok(Some(message))
+}
+
+/// Runs the language server until the client exits, see `Server`
+pub fn run() -> Result<(), String> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut server = Server {
+        documents: HashMap::new(),
+        output: io::stdout(),
+    };
+    // A broken message or a failed request is reported to the client, only losing stdin or stdout stops the server
+    while let Some(message) = read_message(&mut input)? {
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("Invalid message: {}", err);
+                // The id of a message which cannot be parsed is unknown
+                server.respond_error(Json::Null, PARSE_ERROR, err)?;
+                continue;
+            }
+        };
+        match server.handle(&message) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                eprintln!("Request failed: {}", err);
+                if let Some(id) = message.get("id").cloned() {
+                    server.respond_error(id, REQUEST_FAILED, err)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
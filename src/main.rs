@@ -1,55 +1,559 @@
+extern crate demoengine;
 extern crate gl;
 extern crate glutin;
-#[macro_use]
-extern crate lalrpop_util;
-extern crate bytes;
-extern crate glm;
-extern crate half;
-extern crate image;
 extern crate notify;
-extern crate openexr;
-extern crate regex;
-extern crate rust_rocket;
 extern crate time;
-extern crate wavefront_obj;
 
+use std::collections::{BTreeSet, HashSet};
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
-use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
-mod ast;
-mod astvisitor;
-mod bytecode;
-mod color;
-mod demoscene;
-mod gl_resources;
-mod imageio;
-mod runtime;
-mod sync;
-mod types;
+use demoengine::sync::SyncTracker;
+use demoengine::{bytecode, crash, demoscene, diagnostics, format, gl_resources, lsp, pack, runtime, sync, types};
 
-lalrpop_mod!(grammar);
-
-use sync::SyncTracker;
+/// Settings passed on the command line
+struct Options {
+    filename: String,
+    compile_options: bytecode::CompileOptions,
+    execution_options: runtime::ExecutionOptions,
+    // Section rendered by `--capture-loop`, and the frame rate of the capture and of `--render`
+    capture_loop: Option<(f32, f32)>,
+    capture_fps: f32,
+    // Render target buffers written as EXR files along with the frames of `--render` and `--capture-loop`
+    capture_rts: Vec<String>,
+    // Folder or video file the demo is rendered to by `--render`, instead of running it, and the size of the frames
+    render: Option<String>,
+    render_size: (u32, u32),
+    // File the demo is packed into by `--pack`, instead of running it
+    pack: Option<String>,
+    // Print the compiled ops with `--disasm`, instead of running the demo
+    disassemble: bool,
+    // Save a thumbnail of the first frame after each reload with `--history`
+    history: bool,
+}
 
-fn try_load_demo(path: &Path) -> Option<demoscene::DemoScene> {
-    demoscene::DemoScene::from_file(&path)
+fn try_load_demo(
+    path: &Path,
+    options: &Options,
+    resource_cache: gl_resources::ResourceCache,
+) -> Option<demoscene::DemoScene> {
+    demoscene::DemoScene::from_file(&path, options.compile_options, resource_cache)
         .map_err(|e| println!("Error while loading demo:\n{}", e))
         .ok()
 }
 
+/// Reloads the demo after a file change
+///
+/// If only the script itself changed, the new bytecode is swapped into the running scene whenever possible, which
+/// skips recompiling shaders and reloading all other resources.
+fn reload_demo(path: &Path, options: &Options, demo: &mut Option<demoscene::DemoScene>, script_only: bool) {
+    if script_only {
+        if let Some(scene) = demo.as_mut() {
+            let bytecode = demoscene::DemoScene::compile_file(path, options.compile_options);
+            match bytecode {
+                Ok(bytecode) => match scene.swap_bytecode(bytecode) {
+                    Ok(()) => {
+                        println!("Swapped bytecode, resources are unchanged");
+                        return;
+                    }
+                    Err(bytecode) => {
+                        let resource_cache = demo.take().unwrap().into_resource_cache();
+                        *demo = demoscene::DemoScene::from_bytecode(path, bytecode, resource_cache)
+                            .map_err(|e| println!("Error while loading demo:\n{}", e))
+                            .ok();
+                        return;
+                    }
+                },
+                Err(e) => {
+                    println!("Error while loading demo:\n{}", e);
+                    demo.take();
+                    return;
+                }
+            }
+        }
+    }
+
+    let resource_cache = demo.take().map(|scene| scene.into_resource_cache()).unwrap_or_default();
+    *demo = try_load_demo(path, options, resource_cache);
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
 fn create_sync_tracks(sync_tracker: &mut dyn sync::SyncTracker, scene: &demoscene::DemoScene) {
     scene
         .get_bytecode()
         .get_sync_tracks()
         .iter()
         .for_each(|track| sync_tracker.require_track(track));
+    scene
+        .get_bytecode()
+        .get_track_hints()
+        .iter()
+        .for_each(|hint| sync_tracker.set_track_hint(hint));
+}
+
+/// Canonical path of the music of the demo, see `demoscene::DemoScene::load_soundtrack`
+fn soundtrack_path(script_path: &Path, scene: Option<&demoscene::DemoScene>) -> Option<PathBuf> {
+    let soundtrack = scene?.get_bytecode().get_metadata().soundtrack.as_ref()?;
+    Some(canonical(&script_path.parent()?.join(soundtrack)))
+}
+
+/// Plays the music of the demo along with the sync tracker, the demo stays silent if the music cannot be played
+fn play_soundtrack(sync_tracker: &mut dyn sync::SyncTracker, scene: Option<&demoscene::DemoScene>) {
+    let soundtrack = match scene.map(|scene| scene.load_soundtrack()) {
+        Some(Ok(soundtrack)) => soundtrack,
+        Some(Err(err)) => {
+            println!("Error while loading the soundtrack: {}", err);
+            None
+        }
+        None => None,
+    };
+    sync_tracker.set_soundtrack(soundtrack);
+}
+
+/// Solos and mutes scene objects and passes, to debug the demo without editing the script
+///
+/// `[` and `]` select an object or pass, `M` mutes it, `S` solos it and backspace shows everything again.
+fn handle_visibility_key(
+    key: glutin::VirtualKeyCode,
+    scene: &demoscene::DemoScene,
+    selected: &mut usize,
+    execution_options: &mut runtime::ExecutionOptions,
+) {
+    let bytecode = scene.get_bytecode();
+    let names: Vec<&str> = bytecode
+        .get_scene_objects()
+        .iter()
+        .map(|o| o.name.as_str())
+        .chain(bytecode.get_passes())
+        .collect();
+    if names.is_empty() {
+        return;
+    }
+    *selected = (*selected).min(names.len() - 1);
+
+    match key {
+        glutin::VirtualKeyCode::LBracket => *selected = (*selected + names.len() - 1) % names.len(),
+        glutin::VirtualKeyCode::RBracket => *selected = (*selected + 1) % names.len(),
+        glutin::VirtualKeyCode::M => {
+            let name = names[*selected].to_owned();
+            if !execution_options.muted.remove(&name) {
+                execution_options.muted.insert(name);
+            }
+        }
+        glutin::VirtualKeyCode::S => {
+            let name = names[*selected].to_owned();
+            execution_options.solo = match execution_options.solo.take() {
+                Some(ref solo) if *solo == name => None,
+                _ => Some(name),
+            };
+        }
+        glutin::VirtualKeyCode::Back => {
+            execution_options.muted.clear();
+            execution_options.solo = None;
+        }
+        _ => return,
+    }
+
+    let name = names[*selected];
+    let state = if execution_options.solo.as_ref().map_or(false, |s| s == name) {
+        " (solo)"
+    } else if execution_options.muted.contains(name) {
+        " (muted)"
+    } else {
+        ""
+    };
+    println!("Selected {}{}", name, state);
+}
+
+/// The folders watched for changes, and which of the changes reload the demo
+struct WatchPlan {
+    roots: Vec<(PathBuf, RecursiveMode)>,
+    // Files which reload the demo, any file under a root does if this is `None`
+    files: Option<HashSet<PathBuf>>,
+    excluded: Vec<PathBuf>,
+}
+impl WatchPlan {
+    /// Watches the files used by the demo, or the folders given by `watch` in its metadata
+    ///
+    /// Without a demo, the folder of the script is watched recursively.
+    fn new(path: &Path, scene: Option<&demoscene::DemoScene>) -> Self {
+        let dir = path.parent().unwrap();
+        let bytecode = match scene {
+            Some(scene) => scene.get_bytecode(),
+            None => {
+                return WatchPlan {
+                    roots: vec![(dir.to_owned(), RecursiveMode::Recursive)],
+                    files: None,
+                    excluded: Vec::new(),
+                }
+            }
+        };
+        let metadata = bytecode.get_metadata();
+        let excluded = metadata.watch_exclude.iter().map(|p| canonical(&dir.join(p))).collect();
+        if !metadata.watch.is_empty() {
+            let mut roots: Vec<(PathBuf, RecursiveMode)> = metadata
+                .watch
+                .iter()
+                .map(|p| (dir.join(p), RecursiveMode::Recursive))
+                .collect();
+            // The script itself is always watched
+            if !metadata.watch.iter().any(|p| canonical(&dir.join(p)) == canonical(dir)) {
+                roots.push((dir.to_owned(), RecursiveMode::NonRecursive));
+            }
+            return WatchPlan {
+                roots: roots,
+                files: None,
+                excluded: excluded,
+            };
+        }
+
+        // Folders are watched rather than the files themselves, editors often save by replacing the file
+        let mut files = HashSet::new();
+        files.insert(canonical(path));
+        for resource in bytecode.get_external_resources() {
+            let resource = dir.join(resource);
+            // Materials of models are loaded from the MTL file next to them
+            files.insert(canonical(&resource.with_extension("mtl")));
+            files.insert(canonical(&resource));
+        }
+        let mut roots: Vec<(PathBuf, RecursiveMode)> = Vec::new();
+        for file in &files {
+            if let Some(parent) = file.parent() {
+                if !roots.iter().any(|r| r.0 == parent) {
+                    roots.push((parent.to_owned(), RecursiveMode::NonRecursive));
+                }
+            }
+        }
+        for ibl in bytecode.get_ibl_defs() {
+            let folder = canonical(&dir.join(&ibl.folder));
+            roots.push((folder.clone(), RecursiveMode::NonRecursive));
+            files.insert(folder);
+        }
+        WatchPlan {
+            roots: roots,
+            files: Some(files),
+            excluded: excluded,
+        }
+    }
+
+    fn watch(&self, watcher: &mut RecommendedWatcher) {
+        for (root, mode) in &self.roots {
+            if let Err(err) = watcher.watch(root, *mode) {
+                println!("Warning: cannot watch {}: {:?}", root.display(), err);
+            }
+        }
+    }
+
+    fn unwatch(&self, watcher: &mut RecommendedWatcher) {
+        for (root, _) in &self.roots {
+            let _ = watcher.unwatch(root);
+        }
+    }
+
+    /// Whether a change of the given file reloads the demo, files of IBL folders stand for their folder
+    fn is_relevant(&self, changed_path: &Path) -> bool {
+        let changed_path = canonical(changed_path);
+        if self.excluded.iter().any(|e| changed_path.starts_with(e)) {
+            return false;
+        }
+        match &self.files {
+            Some(files) => files.contains(&changed_path) || changed_path.parent().map_or(false, |p| files.contains(p)),
+            None => true,
+        }
+    }
+}
+
+/// The path with symbolic links and relative components resolved, if the file exists
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+/// Lists the probes from top to bottom, in the order their graphs are drawn
+fn print_probes(scene: &demoscene::DemoScene) {
+    if scene.get_probes().is_empty() {
+        println!("No probes were evaluated");
+    }
+    for (index, probe) in scene.get_probes().iter().enumerate() {
+        let (min, max) = probe.get_range();
+        let value = probe.get_values().back().cloned().unwrap_or(0.0);
+        let label = probe.get_label();
+        println!("Probe {} {}: {} ({} to {})", index + 1, label, value, min, max);
+    }
+}
+
+/// Applies the title and cursor visibility set by the script, the window keeps its defaults for the others
+fn apply_window_settings(window: &glutin::Window, settings: &runtime::WindowSettings) {
+    let title = settings.title.as_ref().map(String::as_str);
+    window.set_title(title.unwrap_or("Demoengine"));
+    window.hide_cursor(!settings.show_cursor.unwrap_or(true));
+}
+
+/// Lists what changed between the frames rendered before and after a reload
+fn print_frame_diff(before: &runtime::FrameRecord, after: &runtime::FrameRecord) {
+    let changes = before.diff(after);
+    if changes.is_empty() {
+        println!("The frame runs the same passes, ops and uniform values as before");
+    } else {
+        println!("Changes in the frame:");
+    }
+    for change in changes {
+        println!(" ~ {}", change);
+    }
+}
+
+/// Renders the frames of a section of the demo as PNG files, for videos and GIFs which loop without a seam
+///
+/// The section is rendered twice with the time wrapping around at its end. Only the second pass is saved, the first
+/// fills history and feedback targets so the first frame continues the last one.
+struct LoopCapture {
+    start_s: f32,
+    length_s: f32,
+    frame_count: u32,
+    // Frame rendered next, counting both passes
+    frame: u32,
+    folder: PathBuf,
+    // Render target buffers saved with each frame, see `capture_targets`
+    target_buffers: Vec<String>,
+}
+impl LoopCapture {
+    fn new(script_path: &Path, (start_s, end_s): (f32, f32), fps: f32, target_buffers: &[String]) -> Self {
+        let length_s = end_s - start_s;
+        LoopCapture {
+            start_s: start_s,
+            length_s: length_s,
+            frame_count: ((length_s * fps).round() as u32).max(1),
+            frame: 0,
+            folder: script_path.with_file_name("capture"),
+            target_buffers: target_buffers.to_vec(),
+        }
+    }
+
+    /// Time of the next frame, the frames cover [start, end) so the last one leads back into the first
+    fn get_time(&self) -> f32 {
+        let index = self.frame % self.frame_count;
+        self.start_s + self.length_s * index as f32 / self.frame_count as f32
+    }
+
+    /// Saves the frame which was just rendered if it belongs to the second pass, returns false after the last frame
+    fn save_frame(&mut self, demo: &mut demoscene::DemoScene) -> bool {
+        if self.frame >= self.frame_count {
+            let index = self.frame - self.frame_count;
+            let path = self.folder.join(format!("frame_{:05}.png", index));
+            let result = demo
+                .capture_screen(&path)
+                .and_then(|_| capture_targets(demo, &self.target_buffers, &self.folder, index));
+            if let Err(err) = result {
+                println!("Error while capturing the frame: {}", err);
+            }
+        }
+        self.frame += 1;
+        self.frame < 2 * self.frame_count
+    }
+}
+
+/// Writes the given render target buffers as left by the last frame to EXR files, named like `gbuffer.normals_00001.exr`
+fn capture_targets(
+    demo: &mut demoscene::DemoScene,
+    target_buffers: &[String],
+    folder: &Path,
+    index: u32,
+) -> Result<(), String> {
+    for buffer in target_buffers {
+        demo.capture_target(buffer, &folder.join(format!("{}_{:05}.exr", buffer, index)))?;
+    }
+    Ok(())
+}
+
+/// Extensions of the outputs of `--render` which are encoded as videos, all other outputs are folders of PNG files
+const VIDEO_EXTENSIONS: [&str; 4] = ["mp4", "mkv", "mov", "webm"];
+
+fn is_video(output: &Path) -> bool {
+    let extension = output.extension().and_then(|extension| extension.to_str());
+    extension.map_or(false, |extension| VIDEO_EXTENSIONS.contains(&extension))
+}
+
+/// Where `--render` writes the frames to
+enum RenderOutput {
+    Frames(PathBuf),
+    // The frames are piped into ffmpeg as raw RGBA pixels
+    Video(process::Child),
+}
+impl RenderOutput {
+    fn new(output: &Path, (width, height): (u32, u32), fps: f32, soundtrack: Option<&Path>) -> Result<Self, String> {
+        if !is_video(output) {
+            fs::create_dir_all(output).map_err(|e| format!("Could not create {}: {}", output.display(), e))?;
+            return Ok(RenderOutput::Frames(output.to_owned()));
+        }
+
+        let mut ffmpeg = process::Command::new("ffmpeg");
+        ffmpeg
+            .args(&["-y", "-loglevel", "error", "-f", "rawvideo", "-pix_fmt", "rgba"])
+            .arg("-s")
+            .arg(format!("{}x{}", width, height))
+            .arg("-r")
+            .arg(fps.to_string())
+            .args(&["-i", "-"]);
+        if let Some(soundtrack) = soundtrack {
+            ffmpeg.arg("-i").arg(soundtrack).arg("-shortest");
+        }
+        ffmpeg
+            .args(&["-pix_fmt", "yuv420p"])
+            .arg(output)
+            .stdin(process::Stdio::piped());
+        let ffmpeg = ffmpeg
+            .spawn()
+            .map_err(|e| format!("Could not run ffmpeg, which encodes the video: {}", e))?;
+        Ok(RenderOutput::Video(ffmpeg))
+    }
+
+    /// Writes the frame which was just rendered
+    fn write_frame(&mut self, index: u32, demo: &demoscene::DemoScene) -> Result<(), String> {
+        match self {
+            RenderOutput::Frames(folder) => demo.capture_screen(&folder.join(format!("frame_{:05}.png", index))),
+            RenderOutput::Video(ffmpeg) => {
+                let (_, _, pixels) = demo.read_screen();
+                let input = ffmpeg.stdin.as_mut().expect("The input of ffmpeg is piped");
+                input
+                    .write_all(&pixels)
+                    .map_err(|e| format!("Could not pass the frame to ffmpeg: {}", e))
+            }
+        }
+    }
+
+    /// Waits until the video is encoded
+    fn finish(self) -> Result<(), String> {
+        match self {
+            RenderOutput::Frames(_) => Ok(()),
+            RenderOutput::Video(mut ffmpeg) => {
+                // Closing the input ends the video
+                ffmpeg.stdin.take();
+                let status = ffmpeg.wait().map_err(|e| format!("ffmpeg did not finish: {}", e))?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(format!("ffmpeg failed with {}", status))
+                }
+            }
+        }
+    }
+}
+
+/// Renders the whole demo for `--render`, at a fixed frame rate into a framebuffer of a fixed size
+///
+/// Each frame takes as long as it needs, so that the result does not depend on the speed of the machine. No window is
+/// opened, and the music is not played but added to videos. Sync tracks are baked from the editor before the first
+/// frame, gamepad tracks stay at 0.
+fn render_demo(options: &Options, output_path: &Path) -> Result<(), String> {
+    let (width, height) = options.render_size;
+    let events_loop = glutin::EventsLoop::new();
+    let size = glutin::dpi::PhysicalSize::new(width as f64, height as f64);
+    let context = glutin::ContextBuilder::new()
+        .with_gl_profile(glutin::GlProfile::Core)
+        .build_headless(&events_loop, size)
+        .map_err(|e| format!("Could not create the GL context: {}", e))?;
+    let context = unsafe {
+        context
+            .make_current()
+            .map_err(|(_, e)| format!("Could not make the GL context current: {}", e))?
+    };
+    gl::load_with(|symbol| context.get_proc_address(symbol) as *const _);
+
+    let path = Path::new(&options.filename);
+    let mut demo = demoscene::DemoScene::from_file(path, options.compile_options, Default::default())
+        .map_err(|e| format!("Error while loading demo:\n{}", e))?;
+    let duration = demo
+        .get_bytecode()
+        .get_metadata()
+        .duration
+        .ok_or_else(|| format!("The demo has no `duration`, it has to be given to render the demo"))?;
+    // Tracks are baked before rendering like for a pack, the editor is only needed if the demo has any
+    let tracks = bake_tracks(demo.get_bytecode())?.unwrap_or_default();
+    let mut sync = sync::BakedSyncTracker::new(tracks);
+
+    let target = gl_resources::RenderTarget::new(width, height, true, &[types::RenderTargetFormat::Srgba8])?;
+    demo.set_screen_target(Some(&target));
+    let soundtrack = soundtrack_path(path, Some(&demo));
+    let soundtrack = soundtrack.as_ref().map(|soundtrack| soundtrack.as_path());
+    let mut output = RenderOutput::new(output_path, options.render_size, options.capture_fps, soundtrack)?;
+    // Buffers of `--capture-rt` go next to the frames, or into a folder named like the video
+    let targets_folder = if is_video(output_path) {
+        output_path.with_extension("")
+    } else {
+        output_path.to_owned()
+    };
+    if !options.capture_rts.is_empty() {
+        fs::create_dir_all(&targets_folder)
+            .map_err(|e| format!("Could not create {}: {}", targets_folder.display(), e))?;
+    }
+
+    let frame_count = ((duration * options.capture_fps).ceil() as u32).max(1);
+    for frame in 0..frame_count {
+        let time = frame as f32 / options.capture_fps;
+        sync.seek(time as f64);
+        demo.draw(width as f32, height as f32, time, &sync, &options.execution_options)?;
+        output.write_frame(frame, &demo)?;
+        capture_targets(&mut demo, &options.capture_rts, &targets_folder, frame)?;
+        print!("\rRendered frame {}/{}", frame + 1, frame_count);
+        std::io::stdout().flush().unwrap_or(());
+    }
+    println!();
+    output.finish()
+}
+
+/// Saves a thumbnail of the frame which was just rendered to the `.history` folder next to the script, named by the
+/// time it was saved at, for a visual log of the changes to the demo
+fn save_history_frame(script_path: &Path, demo: &demoscene::DemoScene) -> Result<PathBuf, String> {
+    let folder = script_path.with_file_name(".history");
+    fs::create_dir_all(&folder).map_err(|e| format!("Could not create {}: {}", folder.display(), e))?;
+    let stem = script_path.file_stem().unwrap_or_default().to_string_lossy();
+    let timestamp = time::strftime("%Y-%m-%d_%H-%M-%S", &time::now()).map_err(|e| e.to_string())?;
+    let path = folder.join(format!("{}_{}.png", stem, timestamp));
+    demo.capture_thumbnail(&path, 256)?;
+    Ok(path)
+}
+
+/// Samples the sync tracks of a demo from the editor, so that a packed demo plays and `--render` renders without it
+///
+/// Tracks are baked up to the `duration` of the demo, the gamepad tracks stay live.
+fn bake_tracks(bytecode: &bytecode::ProgramContainer) -> Result<Option<sync::BakedTracks>, String> {
+    let mut tracks: Vec<String> = bytecode
+        .get_sync_tracks()
+        .iter()
+        .filter(|track| !track.starts_with(sync::GAMEPAD_TRACK_PREFIX))
+        .cloned()
+        .collect();
+    if tracks.is_empty() {
+        return Ok(None);
+    }
+    tracks.sort();
+    let duration = bytecode
+        .get_metadata()
+        .duration
+        .ok_or_else(|| format!("The demo uses sync tracks, its `duration` has to be given to bake them"))?;
+
+    let mut rocket =
+        sync::RocketSyncTracker::new(24.0).map_err(|e| format!("Could not connect to the editor: {}", e))?;
+    for hint in bytecode.get_track_hints() {
+        rocket.set_track_hint(hint);
+    }
+    println!(" ~ Baking {} tracks", tracks.len());
+    Ok(Some(rocket.bake(&tracks, duration)))
 }
 
-fn run_demo(filename: &str, size: (u32, u32)) {
+fn run_demo(options: &Options, size: (u32, u32)) {
     let mut size = glutin::dpi::LogicalSize::new(size.0 as f64, size.1 as f64);
     let mut events_loop = glutin::EventsLoop::new();
     let window = glutin::WindowBuilder::new()
@@ -59,29 +563,75 @@ fn run_demo(filename: &str, size: (u32, u32)) {
         .with_vsync(true)
         .with_gl_profile(glutin::GlProfile::Core)
         .build_windowed(window, &events_loop)
-        .unwrap();
+        .expect("Failed to create the window");
 
     let mut dpi_factor = window_context.window().get_hidpi_factor();
 
-    let window_context = unsafe { window_context.make_current().unwrap() };
+    let window_context = unsafe {
+        window_context
+            .make_current()
+            .expect("Failed to make the GL context current")
+    };
 
     unsafe {
         gl::load_with(|symbol| window_context.get_proc_address(symbol) as *const _);
         gl::ClearColor(0.0, 0.0, 0.0, 1.0);
     }
 
-    let path = Path::new(filename);
-    let mut demo = try_load_demo(path);
-    let mut sync = sync::RocketSyncTracker::new(24.0).expect("Expected a running sync tracker");
+    let path = Path::new(&options.filename);
+    let mut demo = try_load_demo(path, options, Default::default());
+    let rocket = sync::RocketSyncTracker::new(24.0).expect("Expected a running sync tracker");
+    let mut sync = sync::GamepadSyncTracker::new(rocket);
     demo.as_ref().map(|demo| create_sync_tracks(&mut sync, demo));
+    let mut soundtrack = soundtrack_path(path, demo.as_ref());
+    play_soundtrack(&mut sync, demo.as_ref());
 
-    // Watch the directory for changes
+    // Watch the files of the demo for changes
     let (tx, rx) = channel();
     let mut watcher = watcher(tx, Duration::from_millis(100)).unwrap();
-    watcher.watch(path.parent().unwrap(), RecursiveMode::Recursive).unwrap();
+    let mut watch_plan = WatchPlan::new(path, demo.as_ref());
+    watch_plan.watch(&mut watcher);
+
+    let mut execution_options = options.execution_options.clone();
+    let mut selected = 0;
+
+    // Breakpoints are given on the command line, and by editors in a file next to the script
+    let breakpoints_file = canonical(&demoscene::breakpoints_path(&canonical(path)));
+    let read_breakpoints = || -> BTreeSet<usize> {
+        let breakpoints = &options.execution_options.debugger.breakpoints;
+        breakpoints.union(&demoscene::read_breakpoints(path)).cloned().collect()
+    };
+    execution_options.debugger.breakpoints = read_breakpoints();
+    // The op at which the last frame stopped, to only print the state when the debugger stops somewhere else
+    let mut last_stop = None;
+    // Whether the next frame is the first one after a reload, which `--history` saves
+    let mut save_history = false;
+    // The scrub bar seeks from a click on it until the button is released, see `ScrubBar`
+    let mut cursor_position = (0.0, 0.0);
+    let mut scrubbing = false;
+    // What the script set the window to, see `runtime::WindowSettings`
+    let mut window_settings = runtime::WindowSettings::default();
+
+    let mut capture = options
+        .capture_loop
+        .map(|section| LoopCapture::new(path, section, options.capture_fps, &options.capture_rts));
+    if let Some(capture) = capture.as_ref() {
+        if let Err(err) = std::fs::create_dir_all(&capture.folder) {
+            println!("Could not create {}: {}", capture.folder.display(), err);
+            return;
+        }
+        println!(
+            "Capturing {} frames to {}",
+            capture.frame_count,
+            capture.folder.display()
+        );
+        demo.as_mut().map(|demo| demo.set_loop_length(Some(capture.length_s)));
+    }
 
     let mut running = true;
     while running {
+        let mut pressed_keys = Vec::new();
+        let mut left_button = None;
         events_loop.poll_events(|event| match event {
             glutin::Event::WindowEvent { event, .. } => match event {
                 glutin::WindowEvent::CloseRequested => running = false,
@@ -90,52 +640,428 @@ fn run_demo(filename: &str, size: (u32, u32)) {
                     window_context.resize(logical_size.to_physical(dpi_factor));
                     size = logical_size;
                 }
+                glutin::WindowEvent::KeyboardInput {
+                    input:
+                        glutin::KeyboardInput {
+                            state: glutin::ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => pressed_keys.push(key),
+                glutin::WindowEvent::CursorMoved { position, .. } => {
+                    let position = position.to_physical(dpi_factor);
+                    cursor_position = (position.x as f32, position.y as f32);
+                }
+                glutin::WindowEvent::MouseInput {
+                    state,
+                    button: glutin::MouseButton::Left,
+                    ..
+                } => left_button = Some(state == glutin::ElementState::Pressed),
                 _ => (),
             },
             _ => (),
         });
+        if let Some(demo) = demo.as_ref() {
+            for key in pressed_keys {
+                if key == glutin::VirtualKeyCode::P {
+                    execution_options.show_probes = !execution_options.show_probes;
+                    if execution_options.show_probes {
+                        print_probes(demo);
+                    }
+                } else if key == glutin::VirtualKeyCode::F10 {
+                    execution_options.debugger.step();
+                } else if key == glutin::VirtualKeyCode::F5 {
+                    execution_options.debugger.resume();
+                } else if key == glutin::VirtualKeyCode::T {
+                    execution_options.show_scrub_bar = !execution_options.show_scrub_bar;
+                } else {
+                    handle_visibility_key(key, demo, &mut selected, &mut execution_options);
+                }
+            }
+
+            if execution_options.show_scrub_bar && capture.is_none() {
+                let window_height = size.to_physical(dpi_factor).height as f32;
+                let time = sync.get_time() as f32;
+                let scrub_time = |dragging| demo.get_scrub_time(window_height, cursor_position, time, dragging);
+                match left_button {
+                    Some(true) => scrubbing = scrub_time(false).is_some(),
+                    Some(false) => scrubbing = false,
+                    None => {}
+                }
+                if let Some(time) = scrub_time(true).filter(|_| scrubbing) {
+                    sync.seek(time as f64);
+                }
+            }
+        }
 
         if let Some(demo) = demo.as_mut() {
-            sync.update();
+            match capture.as_ref() {
+                Some(capture) => sync.seek(capture.get_time() as f64),
+                // The time stands still while paused, so that every frame stops in the same state
+                None if execution_options.debugger.is_paused() => {}
+                None => sync.update(),
+            }
             let time = sync.get_time();
 
             let physical_size = size.to_physical(dpi_factor);
-            if let Err(err) = demo.draw(
+            match demo.draw(
                 physical_size.width as f32,
                 physical_size.height as f32,
                 time as f32,
                 &sync,
+                &execution_options,
             ) {
-                println!("Error while rendering scene: \n{}", err);
+                Ok(()) if save_history => match save_history_frame(path, demo) {
+                    Ok(history_path) => println!("Saved {}", history_path.display()),
+                    Err(err) => println!("Error while saving the frame to the history: {}", err),
+                },
+                Ok(()) => {}
+                Err(err) => println!("Error while rendering scene: \n{}", err),
+            }
+            save_history = false;
+            let stop = demo.get_debug_stop();
+            if stop.map(|s| s.op) != last_stop {
+                match stop {
+                    Some(stop) => {
+                        let source = demo.get_bytecode().get_source();
+                        println!("{}", stop.render(source, diagnostics::use_color()))
+                    }
+                    None => println!("Continuing"),
+                }
+                last_stop = stop.map(|s| s.op);
+            }
+            execution_options.debugger.frame_done(stop);
+            if *demo.get_window_settings() != window_settings {
+                window_settings = demo.get_window_settings().clone();
+                apply_window_settings(window_context.window(), &window_settings);
+            }
+            if let Some(capture) = capture.as_mut() {
+                running &= capture.save_frame(demo);
             }
         }
 
-        window_context.swap_buffers().unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(16));
+        match window_context.swap_buffers() {
+            Ok(()) => {}
+            Err(glutin::ContextError::ContextLost) => {
+                println!("The GL context was lost");
+                running = false;
+            }
+            Err(err) => println!("Error while presenting the frame: {}", err),
+        }
+        if capture.is_none() {
+            std::thread::sleep(std::time::Duration::from_millis(16));
+        }
 
         // Look if any files have changed
         let mut recreate_scene = false;
+        let mut script_only = true;
+        let mut breakpoints_changed = false;
+        let mut soundtrack_changed = false;
         for event in rx.try_iter() {
-            if let DebouncedEvent::Write(_) = event {
-                recreate_scene = true;
+            match event {
+                DebouncedEvent::Create(ref changed_path)
+                | DebouncedEvent::Write(ref changed_path)
+                | DebouncedEvent::Remove(ref changed_path)
+                    if canonical(changed_path) == breakpoints_file =>
+                {
+                    breakpoints_changed = true
+                }
+                DebouncedEvent::Write(changed_path) => {
+                    if watch_plan.is_relevant(&changed_path) {
+                        recreate_scene = true;
+                        script_only &= is_same_file(&changed_path, path);
+                        soundtrack_changed |= soundtrack == Some(canonical(&changed_path));
+                    }
+                }
+                _ => (),
             }
         }
+        if breakpoints_changed {
+            execution_options.debugger.breakpoints = read_breakpoints();
+            println!("Breakpoints at lines {:?}", execution_options.debugger.breakpoints);
+        }
         if recreate_scene {
+            // The frame is rendered before and after the reload at the same time, to show what the edit changed
+            let physical_size = size.to_physical(dpi_factor);
+            let (width, height, time) = (
+                physical_size.width as f32,
+                physical_size.height as f32,
+                sync.get_time() as f32,
+            );
+            let record_frame = |demo: &mut Option<demoscene::DemoScene>| {
+                let demo = demo.as_mut()?;
+                demo.record_frame(width, height, time, &sync, &execution_options).ok()
+            };
+            let before = record_frame(&mut demo);
+
             println!("Reloading...");
-            demo.take();
-            demo = try_load_demo(&path);
+            reload_demo(&path, options, &mut demo, script_only);
+            if let (Some(before), Some(after)) = (before, record_frame(&mut demo)) {
+                print_frame_diff(&before, &after);
+            }
+            save_history = options.history && demo.is_some();
+            // The ops are numbered differently in the new script
+            execution_options.debugger.frame_done(None);
             demo.as_ref().map(|demo| create_sync_tracks(&mut sync, demo));
+            // Decoding takes a while, the music is only loaded again if it changed
+            if soundtrack_changed || soundtrack_path(path, demo.as_ref()) != soundtrack {
+                soundtrack = soundtrack_path(path, demo.as_ref());
+                play_soundtrack(&mut sync, demo.as_ref());
+            }
+            // Frames rendered before the reload no longer match, the capture starts over
+            if let Some(capture) = capture.as_mut() {
+                capture.frame = 0;
+                demo.as_mut().map(|demo| demo.set_loop_length(Some(capture.length_s)));
+            }
+
+            // The files used by the demo may have changed with the script
+            watch_plan.unwatch(&mut watcher);
+            watch_plan = WatchPlan::new(path, demo.as_ref());
+            watch_plan.watch(&mut watcher);
+        }
+    }
+}
+
+fn print_usage() {
+    println!("Usage: ./demoengine [OPTIONS] SCRIPT");
+    println!("       ./demoengine --lsp");
+    println!("       ./demoengine fmt [--check] SCRIPT...");
+    println!();
+    println!("Options:");
+    println!("    --asserts=MODE        How failed asserts are handled: halt (default), warn or off");
+    println!("    --break=LINE          Pause frames at a line of the script, F10 steps and F5 continues");
+    println!("    --capture-fps=N       Frame rate of --capture-loop and --render, 30 by default");
+    println!("    --capture-loop=A:B    Render the seconds A to B as a seamless loop of PNG files, then exit");
+    println!("    --capture-rt=RT       Also write a render target buffer like gbuffer.normals to EXR files with");
+    println!("                          --render and --capture-loop, may be given several times");
+    println!("    --check-nan           Abort the frame when an expression evaluates to NaN or infinity");
+    println!("    --disasm              Print the compiled ops with the lines of the script they come from, then exit");
+    println!("    --history             Save a thumbnail of the first frame after each reload to the .history folder");
+    println!("    --lsp                 Run a language server for editors on stdin and stdout, without a script");
+    println!("    --max-call-depth=N    Abort the frame when functions calls are nested deeper than N");
+    println!("    --max-ops=N           Abort the frame when more than N ops are executed");
+    println!("    --pack=FILE           Pack the demo without asserts and all files it uses into FILE, then exit");
+    println!("    --render=OUTPUT       Render the demo frame by frame into a folder of PNG files, then exit. Outputs");
+    println!("                          ending with .mp4, .mkv, .mov or .webm are encoded as videos with ffmpeg");
+    println!("    --render-size=WxH     Size of the frames of --render, 1920x1080 by default");
+}
+
+/// Rewrites scripts with canonical formatting, or only lists the ones which differ from it with `--check`
+///
+/// Returns false if a script could not be formatted, or if a checked script is not formatted.
+fn format_scripts(args: &[String]) -> bool {
+    let check = args.iter().any(|arg| arg == "--check");
+    let scripts: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    if scripts.is_empty() || args.iter().any(|arg| arg.starts_with("--") && arg != "--check") {
+        print_usage();
+        return false;
+    }
+
+    let mut success = true;
+    for script in scripts {
+        let result = fs::read_to_string(script)
+            .map_err(|e| format!("Could not read {}: {}", script, e))
+            .and_then(|source| Ok((format::format_source(&source)?, source)))
+            .and_then(|(formatted, source)| {
+                if formatted == source {
+                    Ok(())
+                } else if check {
+                    Err(format!("{} is not formatted", script))
+                } else {
+                    fs::write(script, formatted).map_err(|e| format!("Could not write {}: {}", script, e))
+                }
+            });
+        if let Err(err) = result {
+            println!("{}", err);
+            success = false;
+        }
+    }
+    success
+}
+
+fn parse_options() -> Option<Options> {
+    let mut filename = None;
+    let mut compile_options = bytecode::CompileOptions::default();
+    let mut execution_options = runtime::ExecutionOptions::default();
+    let mut capture_loop = None;
+    let mut capture_fps = 30.0;
+    let mut capture_rts = Vec::new();
+    let mut render = None;
+    let mut render_size = (1920, 1080);
+    let mut pack = None;
+    let mut disassemble = false;
+    let mut history = false;
+    for arg in env::args().skip(1) {
+        let valid = if arg.starts_with("--asserts=") {
+            types::AssertMode::from_str(&arg["--asserts=".len()..])
+                .map(|v| compile_options.assert_mode = v)
+                .is_some()
+        } else if arg.starts_with("--break=") {
+            arg["--break=".len()..]
+                .parse()
+                .ok()
+                .filter(|&line: &usize| line > 0)
+                .map(|line| execution_options.debugger.breakpoints.insert(line))
+                .is_some()
+        } else if arg.starts_with("--capture-fps=") {
+            arg["--capture-fps=".len()..]
+                .parse()
+                .ok()
+                .filter(|&v: &f32| v > 0.0)
+                .map(|v| capture_fps = v)
+                .is_some()
+        } else if arg.starts_with("--capture-loop=") {
+            parse_section(&arg["--capture-loop=".len()..])
+                .map(|v| capture_loop = Some(v))
+                .is_some()
+        } else if arg.starts_with("--capture-rt=") {
+            Some(arg["--capture-rt=".len()..].to_owned())
+                .filter(|buffer| !buffer.is_empty())
+                .map(|buffer| capture_rts.push(buffer))
+                .is_some()
+        } else if arg == "--check-nan" {
+            execution_options.check_non_finite = true;
+            true
+        } else if arg == "--disasm" {
+            disassemble = true;
+            true
+        } else if arg == "--history" {
+            history = true;
+            true
+        } else if arg.starts_with("--max-call-depth=") {
+            arg["--max-call-depth=".len()..]
+                .parse()
+                .map(|v| execution_options.max_call_depth = v)
+                .is_ok()
+        } else if arg.starts_with("--max-ops=") {
+            arg["--max-ops=".len()..]
+                .parse()
+                .map(|v| execution_options.max_ops_per_frame = v)
+                .is_ok()
+        } else if arg.starts_with("--pack=") {
+            pack = Some(arg["--pack=".len()..].to_owned());
+            true
+        } else if arg.starts_with("--render=") {
+            render = Some(arg["--render=".len()..].to_owned());
+            true
+        } else if arg.starts_with("--render-size=") {
+            parse_size(&arg["--render-size=".len()..])
+                .map(|v| render_size = v)
+                .is_some()
+        } else if filename.is_none() && !arg.starts_with("--") {
+            filename = Some(arg);
+            true
+        } else {
+            false
+        };
+
+        if !valid {
+            return None;
         }
     }
+
+    filename.map(|filename| Options {
+        filename: filename,
+        compile_options: compile_options,
+        execution_options: execution_options,
+        capture_loop: capture_loop,
+        capture_fps: capture_fps,
+        capture_rts: capture_rts,
+        render: render,
+        render_size: render_size,
+        pack: pack,
+        disassemble: disassemble,
+        history: history,
+    })
+}
+
+/// Parses a section of the demo given as `START:END` in seconds
+fn parse_section(text: &str) -> Option<(f32, f32)> {
+    let mut parts = text.splitn(2, ':');
+    let start: f32 = parts.next()?.parse().ok()?;
+    let end: f32 = parts.next()?.parse().ok()?;
+    if start >= 0.0 && end > start {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Parses a size given as `WIDTHxHEIGHT` in pixels
+fn parse_size(text: &str) -> Option<(u32, u32)> {
+    let mut parts = text.splitn(2, 'x');
+    let width: u32 = parts.next()?.parse().ok()?;
+    let height: u32 = parts.next()?.parse().ok()?;
+    if width > 0 && height > 0 {
+        Some((width, height))
+    } else {
+        None
+    }
 }
 
 fn main() {
-    if env::args().len() != 2 {
-        println!("Usage: ./demoengine SCRIPT");
+    if env::args().skip(1).eq(Some("--lsp".to_owned())) {
+        if let Err(err) = lsp::run() {
+            eprintln!("Language server stopped: {}", err);
+        }
+        return;
+    }
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map_or(false, |arg| arg == "fmt") {
+        if !format_scripts(&args[1..]) {
+            process::exit(1);
+        }
         return;
     }
-    let filename = env::args().skip(1).next().unwrap();
+
+    let options = match parse_options() {
+        Some(options) => options,
+        None => {
+            print_usage();
+            return;
+        }
+    };
     let initial_size = (1024, 768);
+    crash::install_panic_hook(Path::new(&options.filename).with_file_name("crash.log"));
 
-    run_demo(&filename, initial_size);
+    if options.disassemble {
+        match demoscene::DemoScene::compile_file(Path::new(&options.filename), options.compile_options) {
+            Ok(bytecode) => print!("{}", bytecode.disassemble()),
+            Err(err) => println!("{}", err),
+        }
+        return;
+    }
+    if let Some(pack_path) = options.pack.as_ref() {
+        let path = Path::new(&options.filename);
+        // Packs are release builds, asserts are stripped whatever `--asserts` says
+        let compile_options = bytecode::CompileOptions {
+            assert_mode: types::AssertMode::Off,
+            ..options.compile_options
+        };
+        let result = demoscene::DemoScene::compile_file(path, compile_options).and_then(|bytecode| {
+            let tracks = bake_tracks(&bytecode)?;
+            pack::pack_demo(path, &bytecode, tracks.as_ref(), Path::new(pack_path))
+        });
+        match result {
+            Ok(()) => {
+                println!("Packed the demo into {}", pack_path);
+                println!(" ~ To build a player embedding it, run:");
+                println!(
+                    "   DEMO_PACK={} cargo build --profile player --no-default-features --features player --bin player",
+                    pack_path
+                );
+            }
+            Err(err) => println!("Error while packing the demo:\n{}", err),
+        }
+        return;
+    }
+    if let Some(output) = options.render.as_ref() {
+        match render_demo(&options, Path::new(output)) {
+            Ok(()) => println!("Rendered the demo to {}", output),
+            Err(err) => println!("Error while rendering the demo:\n{}", err),
+        }
+        return;
+    }
+    run_demo(&options, initial_size);
 }
@@ -0,0 +1,287 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use memmap::Mmap;
+use zstd;
+
+use bytecode::ProgramContainer;
+use gl_resources::{self, Ibl};
+use resources::{DiskProvider, ResourceProvider};
+use serialize::{Reader, Writer};
+use sync::BakedTracks;
+
+/// Identifies packed demos, the version has to be increased whenever the layout changes
+const PACK_MAGIC: &[u8; 4] = b"DEPK";
+const PACK_VERSION: u32 = 1;
+/// Packing happens once per release, so the slowest levels are worth it
+const PACK_COMPRESSION_LEVEL: i32 = 19;
+/// Size of the footer: offset, compressed size and size of the index, then the magic
+const PACK_FOOTER_SIZE: usize = 8 + 8 + 8 + 4;
+/// Largest size of an entry or the index, sizes read from a corrupted pack must not allocate arbitrary amounts of memory
+const PACK_MAX_ENTRY_SIZE: u64 = 1 << 30;
+
+/// Entry holding the compiled script, next to the files the script references by their path
+pub const BYTECODE_ENTRY: &str = "<bytecode>";
+/// Entry holding the baked sync tracks, only present if the demo uses sync tracks
+pub const TRACKS_ENTRY: &str = "<tracks>";
+
+/// Location of a file in a pack, offsets are from the start of the pack
+#[derive(Debug, Clone)]
+pub struct PackEntry {
+    pub path: String,
+    pub offset: u64,
+    pub compressed_size: u64,
+    pub size: u64,
+}
+
+/// Writes a pack with the given (path, contents) entries
+///
+/// The layout is:
+/// - the magic and the version
+/// - the entries, each compressed as its own zstd frame, so that any entry can be read without the others
+/// - the index, a zstd frame with the `PackEntry` of each entry
+/// - the footer, with the offset, compressed size and size of the index as u64, then the magic again
+///
+/// All numbers are stored in little endian.
+pub fn write_pack(path: &Path, entries: &[(String, Vec<u8>)]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+    let mut file = BufWriter::new(file);
+    let write_error = |e| format!("Could not write {}: {}", path.display(), e);
+
+    let mut header = Writer::new();
+    header.write_bytes(PACK_MAGIC);
+    header.write(&PACK_VERSION);
+    let header = header.into_bytes();
+    file.write_all(&header).map_err(write_error)?;
+
+    let mut offset = header.len() as u64;
+    let mut index = Vec::new();
+    for (entry_path, contents) in entries {
+        if contents.len() as u64 > PACK_MAX_ENTRY_SIZE {
+            return Err(format!(
+                "{} is larger than the limit of {} bytes for packed files",
+                entry_path, PACK_MAX_ENTRY_SIZE
+            ));
+        }
+        let compressed = zstd::block::compress(contents, PACK_COMPRESSION_LEVEL).map_err(write_error)?;
+        file.write_all(&compressed).map_err(write_error)?;
+        index.push(PackEntry {
+            path: entry_path.clone(),
+            offset: offset,
+            compressed_size: compressed.len() as u64,
+            size: contents.len() as u64,
+        });
+        offset += compressed.len() as u64;
+    }
+
+    let mut index_writer = Writer::new();
+    index_writer.write(&index);
+    let index_bytes = index_writer.into_bytes();
+    let compressed_index = zstd::block::compress(&index_bytes, PACK_COMPRESSION_LEVEL).map_err(write_error)?;
+    file.write_all(&compressed_index).map_err(write_error)?;
+
+    let mut footer = Writer::new();
+    footer.write(&offset);
+    footer.write(&(compressed_index.len() as u64));
+    footer.write(&(index_bytes.len() as u64));
+    footer.write_bytes(PACK_MAGIC);
+    file.write_all(&footer.into_bytes()).map_err(write_error)?;
+    file.flush().map_err(write_error)
+}
+
+/// Packs a compiled demo with all files it uses into a single file
+pub fn pack_demo(
+    script_path: &Path,
+    bytecode: &ProgramContainer,
+    tracks: Option<&BakedTracks>,
+    path: &Path,
+) -> Result<(), String> {
+    let resources = DiskProvider::new(script_path.parent().unwrap_or(Path::new("")));
+    let mut entries = vec![(BYTECODE_ENTRY.to_owned(), bytecode.to_bytes())];
+    if let Some(tracks) = tracks {
+        let mut writer = Writer::new();
+        writer.write(tracks);
+        entries.push((TRACKS_ENTRY.to_owned(), writer.into_bytes()));
+    }
+    for file in demo_files(&resources, bytecode) {
+        let contents = resources.read(Path::new(&file))?;
+        entries.push((file, contents));
+    }
+    println!(" ~ Packed files:    {:?}", entries.len() - 1 - tracks.map_or(0, |_| 1));
+    write_pack(path, &entries)
+}
+
+/// Paths of the files a demo loads, relative to the folder of its script and with `/` as separator
+///
+/// Besides the resources named by the script, these are the material libraries of models with their maps, and the
+/// files of IBL folders which exist.
+pub fn demo_files(resources: &dyn ResourceProvider, bytecode: &ProgramContainer) -> BTreeSet<String> {
+    let mut files: BTreeSet<String> = bytecode.get_external_resources().iter().cloned().collect();
+    for model in bytecode.get_model_defs().iter().filter(|m| m.terrain.is_none()) {
+        let obj = resources.read_to_string(Path::new(&model.path)).ok();
+        let library = match obj.as_ref().and_then(|obj| material_library(obj)) {
+            Some(library) => join_path(parent_path(&model.path), &library),
+            None => continue,
+        };
+        if let Ok(mtl) = resources.read_to_string(Path::new(&library)) {
+            for material in gl_resources::parse_mtl(&mtl).values() {
+                for (_, map) in &material.maps {
+                    files.insert(join_path(parent_path(&library), map));
+                }
+            }
+            files.insert(library);
+        }
+    }
+    for ibl in bytecode.get_ibl_defs() {
+        for file in Ibl::folder_files() {
+            let path = join_path(&ibl.folder, &file);
+            if resources.read(Path::new(&path)).is_ok() {
+                files.insert(path);
+            }
+        }
+    }
+    files.into_iter().map(|f| f.replace('\\', "/")).collect()
+}
+
+/// The file named by the `mtllib` statement of an OBJ file
+fn material_library(obj: &str) -> Option<String> {
+    obj.lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("mtllib "))
+        .map(|line| line["mtllib ".len()..].trim().to_owned())
+}
+
+fn parent_path(path: &str) -> &str {
+    match path.rfind(|c| c == '/' || c == '\\') {
+        Some(i) => &path[..i],
+        None => "",
+    }
+}
+
+fn join_path(dir: &str, file: &str) -> String {
+    if dir.is_empty() {
+        file.to_owned()
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), file)
+    }
+}
+
+/// Where the bytes of a pack are kept
+enum PackData {
+    Mapped(Mmap),
+    // Packs embedded into the executable with `include_bytes!`
+    Embedded(&'static [u8]),
+}
+impl PackData {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            PackData::Mapped(map) => map,
+            PackData::Embedded(bytes) => bytes,
+        }
+    }
+}
+
+/// A pack written by `write_pack`, which is memory-mapped and decompresses entries as they are read
+pub struct PackedDemo {
+    data: PackData,
+    entries: HashMap<String, PackEntry>,
+}
+impl PackedDemo {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        // The pack must not be modified while it is mapped, which is the case for release builds of a demo
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| format!("Could not map {}: {}", path.display(), e))?;
+        let entries = Self::read_index(&map).map_err(|e| format!("Invalid pack {}: {}", path.display(), e))?;
+        Ok(PackedDemo {
+            data: PackData::Mapped(map),
+            entries: entries,
+        })
+    }
+
+    /// Reads a pack which is part of the executable
+    pub fn from_static(bytes: &'static [u8]) -> Result<Self, String> {
+        let entries = Self::read_index(bytes).map_err(|e| format!("Invalid embedded pack: {}", e))?;
+        Ok(PackedDemo {
+            data: PackData::Embedded(bytes),
+            entries: entries,
+        })
+    }
+
+    fn read_index(bytes: &[u8]) -> Result<HashMap<String, PackEntry>, String> {
+        let mut header = Reader::new(bytes);
+        if header.read_bytes(PACK_MAGIC.len())? != PACK_MAGIC {
+            return Err(format!("Data is not a packed demo"));
+        }
+        let version: u32 = header.read()?;
+        if version != PACK_VERSION {
+            return Err(format!(
+                "Pack version {} is not supported, expected version {}",
+                version, PACK_VERSION
+            ));
+        }
+        if bytes.len() < PACK_FOOTER_SIZE {
+            return Err(format!("The footer is missing"));
+        }
+
+        let mut footer = Reader::new(&bytes[bytes.len() - PACK_FOOTER_SIZE..]);
+        let index_offset: u64 = footer.read()?;
+        let index_compressed_size: u64 = footer.read()?;
+        let index_size: u64 = footer.read()?;
+        if footer.read_bytes(PACK_MAGIC.len())? != PACK_MAGIC {
+            return Err(format!("The footer is corrupted"));
+        }
+        let compressed_index = Self::slice(bytes, index_offset, index_compressed_size)?;
+        let index_bytes = Self::decompress(compressed_index, index_size)
+            .map_err(|e| format!("Could not decompress the index: {}", e))?;
+        let index: Vec<PackEntry> = Reader::new(&index_bytes).read()?;
+        Ok(index.into_iter().map(|entry| (entry.path.clone(), entry)).collect())
+    }
+
+    /// The bytes of the pack from `offset` to `offset + size`
+    fn slice(bytes: &[u8], offset: u64, size: u64) -> Result<&[u8], String> {
+        let end = offset.checked_add(size).filter(|&end| end <= bytes.len() as u64);
+        match end {
+            Some(end) => Ok(&bytes[offset as usize..end as usize]),
+            None => Err(format!("Data at {} extends past the end of the pack", offset)),
+        }
+    }
+
+    /// Decompresses an entry or the index, which is the given size once decompressed
+    fn decompress(compressed: &[u8], size: u64) -> Result<Vec<u8>, String> {
+        if size > PACK_MAX_ENTRY_SIZE {
+            return Err(format!(
+                "Size of {} bytes exceeds the limit of {} bytes",
+                size, PACK_MAX_ENTRY_SIZE
+            ));
+        }
+        zstd::block::decompress(compressed, size as usize).map_err(|e| e.to_string())
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    /// Decompresses the entry with the given path, relative to the folder of the script
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        let entry = self
+            .entries
+            .get(path)
+            .ok_or_else(|| format!("{} is not part of the pack", path))?;
+        let compressed = Self::slice(self.data.bytes(), entry.offset, entry.compressed_size)?;
+        Self::decompress(compressed, entry.size).map_err(|e| format!("Could not decompress {}: {}", path, e))
+    }
+
+    pub fn read_bytecode(&self) -> Result<ProgramContainer, String> {
+        ProgramContainer::from_bytes(&self.read(BYTECODE_ENTRY)?)
+    }
+
+    /// The baked sync tracks, if the demo uses any
+    pub fn read_tracks(&self) -> Result<Option<BakedTracks>, String> {
+        if !self.contains(TRACKS_ENTRY) {
+            return Ok(None);
+        }
+        Reader::new(&self.read(TRACKS_ENTRY)?).read().map(Some)
+    }
+}
@@ -0,0 +1,113 @@
+extern crate demoengine;
+extern crate gl;
+extern crate glutin;
+
+use std::path::Path;
+
+use demoengine::sync::SyncTracker;
+use demoengine::{crash, demoscene, pack, runtime, sync};
+
+// `DEMO_PACK`, the demo played by this executable, written by the build script
+include!(concat!(env!("OUT_DIR"), "/demo_pack.rs"));
+
+fn load_demo() -> Result<(demoscene::DemoScene, sync::BakedTracks), String> {
+    let packed = pack::PackedDemo::from_static(DEMO_PACK)?;
+    let tracks = packed.read_tracks()?.unwrap_or_default();
+    let scene = demoscene::DemoScene::from_pack(packed, Default::default())?;
+    Ok((scene, tracks))
+}
+
+fn main() {
+    crash::install_panic_hook(Path::new("crash.log").to_owned());
+
+    let mut events_loop = glutin::EventsLoop::new();
+    let monitor = events_loop.get_primary_monitor();
+    let window = glutin::WindowBuilder::new()
+        .with_title("Demoengine")
+        .with_fullscreen(Some(monitor));
+    let window_context = glutin::ContextBuilder::new()
+        .with_vsync(true)
+        .with_gl_profile(glutin::GlProfile::Core)
+        .build_windowed(window, &events_loop)
+        .expect("Failed to create the window");
+    let window_context = unsafe {
+        window_context
+            .make_current()
+            .expect("Failed to make the GL context current")
+    };
+    // The cursor is hidden over the fullscreen window, unless the script shows it
+    window_context.window().hide_cursor(true);
+    let mut window_settings = runtime::WindowSettings::default();
+
+    unsafe {
+        gl::load_with(|symbol| window_context.get_proc_address(symbol) as *const _);
+        gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+    }
+
+    let (mut demo, tracks) = match load_demo() {
+        Ok(demo) => demo,
+        Err(err) => {
+            println!("Error while loading demo:\n{}", err);
+            return;
+        }
+    };
+    let duration = demo.get_bytecode().get_metadata().duration;
+    let execution_options = runtime::ExecutionOptions::default();
+    let mut sync = sync::GamepadSyncTracker::new(sync::BakedSyncTracker::new(tracks));
+    match demo.load_soundtrack() {
+        Ok(soundtrack) => sync.set_soundtrack(soundtrack),
+        Err(err) => println!("Error while loading the soundtrack: {}", err),
+    }
+
+    let mut running = true;
+    while running {
+        events_loop.poll_events(|event| match event {
+            glutin::Event::WindowEvent { event, .. } => match event {
+                glutin::WindowEvent::CloseRequested => running = false,
+                glutin::WindowEvent::KeyboardInput {
+                    input:
+                        glutin::KeyboardInput {
+                            state: glutin::ElementState::Pressed,
+                            virtual_keycode: Some(glutin::VirtualKeyCode::Escape),
+                            ..
+                        },
+                    ..
+                } => running = false,
+                _ => (),
+            },
+            _ => (),
+        });
+
+        sync.update();
+        let time = sync.get_time();
+        if duration.map_or(false, |duration| time >= duration as f64) {
+            break;
+        }
+
+        let window = window_context.window();
+        let size = window
+            .get_inner_size()
+            .map(|size| size.to_physical(window.get_hidpi_factor()))
+            .unwrap_or(glutin::dpi::PhysicalSize::new(1.0, 1.0));
+        if let Err(err) = demo.draw(
+            size.width as f32,
+            size.height as f32,
+            time as f32,
+            &sync,
+            &execution_options,
+        ) {
+            println!("Error while rendering scene: \n{}", err);
+        }
+        if *demo.get_window_settings() != window_settings {
+            window_settings = demo.get_window_settings().clone();
+            let title = window_settings.title.as_ref().map(String::as_str);
+            window.set_title(title.unwrap_or("Demoengine"));
+            window.hide_cursor(!window_settings.show_cursor.unwrap_or(false));
+        }
+
+        if let Err(err) = window_context.swap_buffers() {
+            println!("Error while presenting the frame: {}", err);
+            running = false;
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use glm;
+
+use types::LensEffect;
+
+/// Vertex shader shared by the built-in fullscreen passes
+pub const FULLSCREEN_VERTEX_SHADER: &str = "#version 440
+layout(location=0) in vec2 position;
+out vec2 v_uv;
+void main() {
+  v_uv = position * 0.5 + 0.5;
+  gl_Position = vec4(position, 0.0, 1.0);
+}";
+
+/// Draws a fullscreen quad, and passes the near and far points of the ray of each corner in model space
+pub const RAYMARCH_VERTEX_SHADER: &str = "#version 440
+layout(location=0) in vec2 position;
+out vec4 v_near;
+out vec4 v_far;
+uniform mat4 u_ModelViewProjectionMatrix;
+uniform bool u_ReversedZ;
+void main() {
+  mat4 inv_mvp = inverse(u_ModelViewProjectionMatrix);
+  v_near = inv_mvp * vec4(position, u_ReversedZ ? 1.0 : -1.0, 1.0);
+  v_far = inv_mvp * vec4(position, u_ReversedZ ? 0.0 : 1.0, 1.0);
+  gl_Position = vec4(position, 0.0, 1.0);
+}";
+
+/// Declarations which precede the SDF file of a raymarch program
+///
+/// The file implements `map`, the signed distance to the closest surface of the scene at a point in model space, and
+/// `shade`, the color of the surface at a point given its normal and the direction of the ray. It can declare its own
+/// uniforms, and the usual uniforms of the engine are set as for any other program.
+pub const RAYMARCH_FRAGMENT_HEADER: &str = "#version 440
+in vec4 v_near;
+in vec4 v_far;
+layout(location=0) out vec4 color;
+uniform mat4 u_ModelViewProjectionMatrix;
+uniform bool u_ReversedZ;
+float map(vec3 p);
+vec4 shade(vec3 p, vec3 normal, vec3 ray_direction);
+#line 1
+";
+
+/// Marches the rays of the pixels through the SDF, and writes the depth of the hits to compose with rasterized geometry
+///
+/// Pixels whose ray misses the SDF before the far plane are discarded.
+pub const RAYMARCH_FRAGMENT_MAIN: &str = "
+const int RAYMARCH_MAX_STEPS = 128;
+const float RAYMARCH_HIT_DISTANCE = 1e-3;
+vec3 raymarch_normal(vec3 p) {
+  const vec2 k = vec2(1.0, -1.0) * RAYMARCH_HIT_DISTANCE;
+  return normalize(k.xyy * map(p + k.xyy) + k.yyx * map(p + k.yyx) + k.yxy * map(p + k.yxy) + k.xxx * map(p + k.xxx));
+}
+void main() {
+  vec3 origin = v_near.xyz / v_near.w;
+  vec3 end = v_far.xyz / v_far.w;
+  float max_distance = length(end - origin);
+  vec3 direction = (end - origin) / max_distance;
+
+  float t = 0.0;
+  bool hit = false;
+  for (int i = 0; i < RAYMARCH_MAX_STEPS && t < max_distance; ++i) {
+    float d = map(origin + direction * t);
+    if (d < RAYMARCH_HIT_DISTANCE) {
+      hit = true;
+      break;
+    }
+    t += d;
+  }
+  if (!hit) {
+    discard;
+  }
+
+  vec3 p = origin + direction * t;
+  vec4 clip = u_ModelViewProjectionMatrix * vec4(p, 1.0);
+  gl_FragDepth = u_ReversedZ ? clip.z / clip.w : clip.z / clip.w * 0.5 + 0.5;
+  color = shade(p, raymarch_normal(p), direction);
+}";
+
+/// Source of the fragment shader of a raymarch program, given the contents of its SDF file
+pub fn raymarch_shader(map_source: &str) -> String {
+    format!("{}{}\n{}", RAYMARCH_FRAGMENT_HEADER, map_source, RAYMARCH_FRAGMENT_MAIN)
+}
+
+/// Draws a fullscreen quad just in front of the far plane, and passes the view direction of each corner
+///
+/// `u_InvViewProjection` is the inverse of the projection multiplied by the rotation of the view, so that the sky stays
+/// at infinite distance. The quad is drawn at `SKYBOX_DEPTH` rather than at the far plane, so that it passes both
+/// the `less` and `less_equal` depth tests against a cleared depth buffer, or their reversed counterparts.
+pub const SKYBOX_VERTEX_SHADER: &str = "#version 440
+layout(location=0) in vec2 position;
+out vec3 v_direction;
+uniform mat4 u_InvViewProjection;
+uniform bool u_ReversedZ;
+const float SKYBOX_DEPTH = 0.99999;
+void main() {
+  vec4 near = u_InvViewProjection * vec4(position, u_ReversedZ ? 1.0 : -1.0, 1.0);
+  vec4 far = u_InvViewProjection * vec4(position, u_ReversedZ ? 0.0 : 1.0, 1.0);
+  v_direction = far.xyz / far.w - near.xyz / near.w;
+  gl_Position = vec4(position, u_ReversedZ ? 1.0 - SKYBOX_DEPTH : SKYBOX_DEPTH, 1.0);
+}";
+
+/// Samples the sharpest level of an environment cubemap in the view direction
+pub const SKYBOX_FRAGMENT_SHADER: &str = "#version 440
+in vec3 v_direction;
+layout(location=0) out vec4 color;
+uniform samplerCube t_Environment;
+void main() {
+  color = vec4(textureLod(t_Environment, normalize(v_direction), 0.0).rgb, 1.0);
+}";
+
+/// Resolves temporal anti-aliasing
+///
+/// The history is reprojected using the motion of the closest surface in the 3x3 neighborhood, and clamped to the
+/// colors of that neighborhood to reject stale samples. Motion vectors hold the offset in uv space from the position in
+/// the previous frame to the one in the current frame.
+pub const TAA_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Color;
+uniform sampler2D t_Depth;
+uniform sampler2D t_Motion;
+uniform sampler2D t_History;
+uniform bool u_ReversedZ;
+void main() {
+  vec2 texel = 1.0 / vec2(textureSize(t_Color, 0));
+  vec3 current = texture(t_Color, v_uv).rgb;
+  vec3 color_min = current;
+  vec3 color_max = current;
+  float closest_depth = 1.0;
+  vec2 closest_uv = v_uv;
+  for (int y = -1; y <= 1; ++y) {
+    for (int x = -1; x <= 1; ++x) {
+      vec2 uv = v_uv + vec2(x, y) * texel;
+      vec3 c = texture(t_Color, uv).rgb;
+      color_min = min(color_min, c);
+      color_max = max(color_max, c);
+      float depth = texture(t_Depth, uv).r;
+      depth = u_ReversedZ ? 1.0 - depth : depth;
+      if (depth < closest_depth) {
+        closest_depth = depth;
+        closest_uv = uv;
+      }
+    }
+  }
+
+  vec2 history_uv = v_uv - texture(t_Motion, closest_uv).rg;
+  vec3 history = clamp(texture(t_History, history_uv).rgb, color_min, color_max);
+  bool offscreen = any(lessThan(history_uv, vec2(0.0))) || any(greaterThan(history_uv, vec2(1.0)));
+  color = vec4(mix(current, history, offscreen ? 0.0 : 0.9), 1.0);
+}";
+
+/// Computes the exposure of an HDR image from its average luminance, and adapts it to the previous exposure
+///
+/// The log-average luminance is taken over a grid of samples, and mapped to middle grey. `u_Adaptation` is the weight
+/// of the new exposure, between 0 (keep the previous one) and 1 (no adaptation).
+pub const AUTO_EXPOSURE_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 exposure;
+uniform sampler2D t_Source;
+uniform sampler2D t_Previous;
+uniform float u_Adaptation;
+const int GRID_SIZE = 16;
+const float MIDDLE_GREY = 0.18;
+void main() {
+  float log_sum = 0.0;
+  for (int y = 0; y < GRID_SIZE; ++y) {
+    for (int x = 0; x < GRID_SIZE; ++x) {
+      vec2 uv = (vec2(x, y) + 0.5) / float(GRID_SIZE);
+      vec3 c = textureLod(t_Source, uv, 0.0).rgb;
+      float luminance = dot(c, vec3(0.2126, 0.7152, 0.0722));
+      log_sum += log(max(luminance, 1e-4));
+    }
+  }
+  float average = exp(log_sum / float(GRID_SIZE * GRID_SIZE));
+  float target = MIDDLE_GREY / average;
+  float previous = texture(t_Previous, vec2(0.5)).r;
+  exposure = vec4(mix(previous, target, u_Adaptation), 0.0, 0.0, 1.0);
+}";
+
+/// Raymarches the fog in front of each pixel, lit by the given lights
+///
+/// The ray goes from the camera to the surface reconstructed from the depth buffer, with a per-pixel offset to hide the
+/// banding of the samples. The fog scatters the same amount of light in all directions. Lights are not occluded, light
+/// shafts are only shaped by the cones of spot lights. The output holds the in-scattered light in rgb and the
+/// transmittance of the fog in alpha, so that it is composited as `color * a + rgb`.
+pub const VOLUMETRICS_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 fog;
+uniform sampler2D t_Depth;
+uniform mat4 u_InvProjectionMatrix;
+uniform bool u_ReversedZ;
+uniform float u_Density;
+uniform int u_LightCount;
+uniform vec4 u_LightPosition[16];
+uniform vec4 u_LightDirection[16];
+uniform vec4 u_LightColor[16];
+const int STEPS = 32;
+const float PI = 3.14159265;
+vec3 incoming_light(vec3 p) {
+  vec3 result = vec3(0.0);
+  for (int i = 0; i < u_LightCount; ++i) {
+    if (u_LightPosition[i].w == 0.0) {
+      result += u_LightColor[i].rgb;
+      continue;
+    }
+    vec3 to_light = u_LightPosition[i].xyz - p;
+    float distance_sq = max(dot(to_light, to_light), 1e-4);
+    float attenuation = 1.0 / distance_sq;
+    if (u_LightPosition[i].w == 2.0) {
+      float cos_angle = dot(-to_light * inversesqrt(distance_sq), u_LightDirection[i].xyz);
+      float cos_outer = u_LightDirection[i].w;
+      attenuation *= smoothstep(cos_outer, mix(cos_outer, 1.0, 0.1), cos_angle);
+    }
+    result += u_LightColor[i].rgb * attenuation;
+  }
+  return result;
+}
+void main() {
+  float depth = texture(t_Depth, v_uv).r;
+  vec3 ndc = vec3(v_uv * 2.0 - 1.0, u_ReversedZ ? depth : depth * 2.0 - 1.0);
+  vec4 surface = u_InvProjectionMatrix * vec4(ndc, 1.0);
+  vec3 end = surface.xyz / surface.w;
+  float step_length = length(end) / float(STEPS);
+  float offset = fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715))));
+
+  vec3 inscattered = vec3(0.0);
+  float transmittance = 1.0;
+  float step_transmittance = exp(-u_Density * step_length);
+  for (int i = 0; i < STEPS; ++i) {
+    vec3 p = end * ((float(i) + offset) / float(STEPS));
+    inscattered += transmittance * u_Density * step_length * incoming_light(p) / (4.0 * PI);
+    transmittance *= step_transmittance;
+  }
+  fog = vec4(inscattered, transmittance);
+}";
+
+/// Composites the transparent surfaces accumulated in an OIT target
+///
+/// The output is the weighted average color of the surfaces in rgb, and their coverage in alpha, to be alpha blended
+/// over the opaque surfaces. Pixels without transparent surfaces are discarded.
+pub const OIT_RESOLVE_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Accum;
+uniform sampler2D t_Revealage;
+void main() {
+  float revealage = texture(t_Revealage, v_uv).r;
+  if (revealage >= 1.0) {
+    discard;
+  }
+  vec4 accum = texture(t_Accum, v_uv);
+  color = vec4(accum.rgb / clamp(accum.a, 1e-4, 5e4), 1.0 - revealage);
+}";
+
+/// Draws a color buffer which was rendered at a lower resolution over the bound target, as premultiplied alpha
+pub const UPSAMPLE_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Source;
+void main() {
+  color = texture(t_Source, v_uv);
+}";
+
+/// Adds ghosts and a halo of the bright parts of the image
+///
+/// Ghosts are mirrored through the center of the screen, as reflections between the lens elements would be. Only the
+/// light above 1.0 contributes, `u_Amount` scales the flare.
+pub const LENSFLARE_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Source;
+uniform float u_Amount;
+const int GHOSTS = 4;
+vec3 bright(vec2 uv) {
+  return max(texture(t_Source, uv).rgb - 1.0, vec3(0.0));
+}
+void main() {
+  vec2 flipped = 1.0 - v_uv;
+  vec2 ghost_step = (0.5 - flipped) * 0.4;
+  vec3 flare = vec3(0.0);
+  for (int i = 0; i < GHOSTS; ++i) {
+    vec2 uv = fract(flipped + ghost_step * float(i));
+    float weight = pow(1.0 - min(length(0.5 - uv) / 0.7071, 1.0), 10.0);
+    flare += bright(uv) * weight;
+  }
+  vec2 halo_uv = flipped + normalize(ghost_step + 1e-6) * 0.45;
+  float halo_weight = pow(1.0 - min(length(0.5 - fract(halo_uv)) / 0.7071, 1.0), 5.0);
+  flare += bright(halo_uv) * halo_weight;
+  color = vec4(texture(t_Source, v_uv).rgb + flare * u_Amount, 1.0);
+}";
+
+/// Shifts the red and blue channels apart towards the edges of the screen
+///
+/// `u_Amount` is the offset in uv space at the corners, where the effect is strongest.
+pub const CHROMATIC_ABERRATION_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Source;
+uniform float u_Amount;
+void main() {
+  vec2 offset = (v_uv - 0.5) * 1.4142 * u_Amount;
+  float r = texture(t_Source, v_uv + offset).r;
+  float g = texture(t_Source, v_uv).g;
+  float b = texture(t_Source, v_uv - offset).b;
+  color = vec4(r, g, b, 1.0);
+}";
+
+/// Darkens the corners of the screen, `u_Amount` is the darkening in the corners between 0 and 1
+pub const VIGNETTE_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Source;
+uniform float u_Amount;
+void main() {
+  vec2 d = v_uv - 0.5;
+  float falloff = smoothstep(0.0, 1.0, dot(d, d) * 2.0);
+  color = vec4(texture(t_Source, v_uv).rgb * (1.0 - u_Amount * falloff), 1.0);
+}";
+
+/// Adds film grain, which is stronger in the midtones
+///
+/// The noise pattern changes with `u_Seed`, passing the time animates the grain.
+pub const GRAIN_FRAGMENT_SHADER: &str = "#version 440
+in vec2 v_uv;
+layout(location=0) out vec4 color;
+uniform sampler2D t_Source;
+uniform float u_Seed;
+const float STRENGTH = 0.06;
+float hash(vec3 p) {
+  p = fract(p * 0.1031);
+  p += dot(p, p.zyx + 31.32);
+  return fract((p.x + p.y) * p.z);
+}
+void main() {
+  vec3 source = texture(t_Source, v_uv).rgb;
+  float noise = hash(vec3(gl_FragCoord.xy, fract(u_Seed * 0.01) * 1000.0)) - 0.5;
+  float luminance = dot(source, vec3(0.2126, 0.7152, 0.0722));
+  float midtones = 1.0 - abs(clamp(luminance, 0.0, 1.0) * 2.0 - 1.0);
+  color = vec4(source + noise * STRENGTH * (0.5 + midtones), 1.0);
+}";
+
+/// Name, fragment shader and parameter uniform of the pass applying a lens effect
+pub fn lens_effect(effect: LensEffect) -> (&'static str, &'static str, &'static str) {
+    match effect {
+        LensEffect::Flare => ("lensflare", LENSFLARE_FRAGMENT_SHADER, "u_Amount"),
+        LensEffect::ChromaticAberration => ("chromatic_aberration", CHROMATIC_ABERRATION_FRAGMENT_SHADER, "u_Amount"),
+        LensEffect::Vignette => ("vignette", VIGNETTE_FRAGMENT_SHADER, "u_Amount"),
+        LensEffect::Grain => ("grain", GRAIN_FRAGMENT_SHADER, "u_Seed"),
+    }
+}
+
+/// Computes the luminance histogram and the luminance sum of each 16x16 work group of a buffer
+///
+/// The 64 histogram bins evenly cover log2 luminances from -10 to 6, darker and brighter pixels are counted in the first
+/// and last bin.
+pub const LUMINANCE_STATS_COMPUTE_SHADER: &str = "#version 440
+layout(local_size_x = 16, local_size_y = 16) in;
+uniform sampler2D t_Source;
+layout(std430, binding = 0) buffer Stats {
+  uint bins[64];
+  float group_sums[];
+};
+const float MIN_LOG2 = -10.0;
+const float MAX_LOG2 = 6.0;
+shared float sums[256];
+void main() {
+  ivec2 size = textureSize(t_Source, 0);
+  ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+  float luminance = 0.0;
+  if (pixel.x < size.x && pixel.y < size.y) {
+    luminance = dot(texelFetch(t_Source, pixel, 0).rgb, vec3(0.2126, 0.7152, 0.0722));
+    float t = (log2(max(luminance, 1e-6)) - MIN_LOG2) / (MAX_LOG2 - MIN_LOG2);
+    atomicAdd(bins[min(uint(clamp(t, 0.0, 1.0) * 64.0), 63u)], 1u);
+  }
+
+  uint index = gl_LocalInvocationIndex;
+  sums[index] = luminance;
+  barrier();
+  for (uint stride = 128u; stride > 0u; stride >>= 1) {
+    if (index < stride) {
+      sums[index] += sums[index + stride];
+    }
+    barrier();
+  }
+  if (index == 0u) {
+    group_sums[gl_WorkGroupID.y * gl_NumWorkGroups.x + gl_WorkGroupID.x] = sums[0];
+  }
+}";
+
+/// Weight of the new exposure after `delta_s` seconds, for an adaptation speed in 1/s
+///
+/// Without a previous frame to adapt from, or with a speed of 0, the new exposure is used as is.
+pub fn exposure_adaptation(delta_s: Option<f32>, speed: f32) -> f32 {
+    match delta_s {
+        Some(delta_s) if speed > 0.0 => 1.0 - (-delta_s * speed).exp(),
+        _ => 1.0,
+    }
+}
+
+/// Number of distinct sub-pixel offsets used for TAA before the sequence repeats
+const TAA_JITTER_SAMPLES: u64 = 8;
+
+fn halton(mut index: u64, base: u64) -> f32 {
+    let mut result = 0.0;
+    let mut f = 1.0;
+    while index > 0 {
+        f /= base as f32;
+        result += f * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// Sub-pixel offset of the given frame in pixels, between -0.5 and 0.5
+pub fn taa_jitter(frame_index: u64) -> (f32, f32) {
+    let index = frame_index % TAA_JITTER_SAMPLES + 1;
+    (halton(index, 2) - 0.5, halton(index, 3) - 0.5)
+}
+
+/// Maps the depth of a projection from [-1, 1] to [1, 0], for reversed-Z with a [0, 1] clip space depth
+pub fn reverse_depth(projection: &glm::Mat4) -> glm::Mat4 {
+    let reversal = glm::Mat4::new(
+        glm::Vec4::new(1.0, 0.0, 0.0, 0.0),
+        glm::Vec4::new(0.0, 1.0, 0.0, 0.0),
+        glm::Vec4::new(0.0, 0.0, -0.5, 0.0),
+        glm::Vec4::new(0.0, 0.0, 0.5, 1.0),
+    );
+    reversal * *projection
+}
+
+/// Offsets the projection by the given amount of pixels
+pub fn jitter_projection(projection: &glm::Mat4, jitter: (f32, f32), width: f32, height: f32) -> glm::Mat4 {
+    let translation = glm::Mat4::new(
+        glm::Vec4::new(1.0, 0.0, 0.0, 0.0),
+        glm::Vec4::new(0.0, 1.0, 0.0, 0.0),
+        glm::Vec4::new(0.0, 0.0, 1.0, 0.0),
+        glm::Vec4::new(2.0 * jitter.0 / width, 2.0 * jitter.1 / height, 0.0, 1.0),
+    );
+    translation * *projection
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pack::PackedDemo;
+
+/// Source of the files a demo loads, such as shaders, models, textures and IBL folders
+///
+/// Paths are relative to the folder of the script. The loaders only read files through this trait, so that demos can
+/// be loaded from a folder, from a pack or from files created in memory.
+pub trait ResourceProvider {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String>;
+
+    fn read_to_string(&self, path: &Path) -> Result<String, String> {
+        String::from_utf8(self.read(path)?).map_err(|e| format!("{} is not valid UTF-8: {}", path.display(), e))
+    }
+}
+
+/// The path of a file as it is stored in packs and in memory, with `/` as separator
+pub fn normalize_path(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Reads the files from a folder on disk, usually the folder of the script
+pub struct DiskProvider {
+    folder: PathBuf,
+}
+impl DiskProvider {
+    pub fn new(folder: &Path) -> Self {
+        DiskProvider {
+            folder: folder.to_owned(),
+        }
+    }
+}
+impl ResourceProvider for DiskProvider {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        fs::read(self.folder.join(path)).map_err(|e| format!("Could not read {}: {}", path.display(), e))
+    }
+}
+
+impl ResourceProvider for PackedDemo {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        PackedDemo::read(self, &normalize_path(path))
+    }
+}
+
+/// Files held in memory, e.g. assets generated by a tool or synthetic assets for tests
+#[derive(Default)]
+pub struct MemoryProvider {
+    files: HashMap<String, Vec<u8>>,
+}
+impl MemoryProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, path: &str, contents: Vec<u8>) {
+        self.files.insert(normalize_path(Path::new(path)), contents);
+    }
+}
+impl ResourceProvider for MemoryProvider {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        self.files
+            .get(&normalize_path(path))
+            .cloned()
+            .ok_or_else(|| format!("{} does not exist", path.display()))
+    }
+}
@@ -1,43 +1,357 @@
 use crate::bytecode;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::mem;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::thread;
+use std::time::Duration;
 
 use gl;
 use gl::types::{GLboolean, GLfloat, GLint, GLenum, GLsizeiptr, GLuint};
 use glm::{GenMat, GenSquareMat};
 
-use ast;
-use bytecode::{BytecodeOp, ProgramContainer, ValueExpr};
-use color::LinearRGBA;
-use gl_resources::{Ibl, Model, RenderTarget, ShaderProgram, Texture};
+use ast::{self, SourceSlice};
+use bytecode::{BytecodeOp, ProgramContainer, SourceSnippet, ValueExpr};
+use color::{LinearRGBA, SrgbRGBA};
+use crash;
+use diagnostics::{self, Severity};
+use draw2d;
+use easing;
+use gl_resources::{
+    self, GpuTimer, Ibl, LuminanceStats, Mesh, Model, PixelReadback, RenderTarget, ResourceCache, ShaderPipeline,
+    ShaderStage, Texture, UniformLocation, UniformRing, UniformValue,
+};
+use postfx;
+use resources::ResourceProvider;
 use sync::SyncTracker;
-use types::{BinaryOperator, BlendMode, RenderTargetFormat, ZTestMode, CullingMode};
+use types::{BinaryOperator, BlendMode, LensEffect, LightType, RenderTargetFormat, StereoMode, ZTestMode, CullingMode};
 
 static VERTEX_DATA: [GLfloat; 8] = [-1., 1., -1., -1., 1., -1., 1., 1.];
 
+/// Shader stages used in place of programs which fail to compile, drawing everything in magenta
+const ERROR_VERTEX_SHADER: &str = "#version 440
+layout(location=0) in vec3 position;
+uniform mat4 u_ModelViewProjectionMatrix;
+void main() {
+  gl_Position = u_ModelViewProjectionMatrix * vec4(position, 1.0);
+}";
+const ERROR_FRAGMENT_SHADER: &str = "#version 440
+layout(location=0) out vec4 color;
+void main() {
+  color = vec4(1.0, 0.0, 1.0, 1.0);
+}";
+
+/// Maximum number of lines `print` and `trace` output per frame, so that the console stays readable
+const MAX_PRINTS_PER_FRAME: u32 = 32;
+/// Render targets which were not used for this many frames are released, and created again once they are used
+const RENDER_TARGET_RELEASE_FRAMES: u64 = 120;
+/// Distance between the eyes in stereo, unless the demo declares `eye_separation` in its metadata
+const DEFAULT_EYE_SEPARATION: f32 = 0.1;
+/// Bytes of each buffer of the uniform ring per frame, each update of the matrices takes 192 bytes plus alignment
+const UNIFORM_RING_REGION_SIZE: usize = 1 << 20;
+/// Smallest fraction of the size of their target passes are rendered at, see `RenderContext::bind_scaled_render_target`
+const MIN_PASS_SCALE: f32 = 0.125;
+/// Dynamic resolution scales passes down to this fraction of their declared scale at most
+const MIN_DYNAMIC_SCALE: f32 = 0.5;
+/// Part of the frame time which dynamic resolution aims at, so that small spikes do not drop frames
+const DYNAMIC_RESOLUTION_HEADROOM: f32 = 0.9;
+/// Colors of the probe graphs, see `RenderContext::draw_probes`
+const PROBE_COLORS: [(f32, f32, f32); 4] = [(1.0, 0.8, 0.1), (0.2, 0.8, 1.0), (1.0, 0.3, 0.5), (0.4, 1.0, 0.4)];
+/// Size of the scrub bar and its distance to the edges of the screen in pixels, see `ScrubBar`
+const SCRUB_BAR_HEIGHT: f32 = 16.0;
+const SCRUB_BAR_MARGIN: f32 = 8.0;
+/// Uniforms of script programs which the engine sets itself, see `RenderContext::warn_unused_uniforms`
+const ENGINE_SET_UNIFORMS: [&str; 11] = [
+    "u_ModelViewProjectionMatrix",
+    "u_ModelViewMatrix",
+    "u_ModelViewInvTranspMatrix",
+    "u_ClipPlanes",
+    "u_ReversedZ",
+    "u_LightCount",
+    "u_LightPosition",
+    "u_LightDirection",
+    "u_LightColor",
+    "u_IblIrrandianceSph",
+    "t_IblRadianceMap",
+];
+
+/// Material of a group of a model, loaded from the MTL file of the model
+struct ModelMaterial {
+    // By the uniform they are bound to
+    textures: Vec<(String, Rc<Texture>)>,
+    colors: Vec<(String, LinearRGBA)>,
+    values: Vec<(String, f32)>,
+}
+impl ModelMaterial {
+    /// Binds the material to the uniforms of the given shader, uniforms which the shader does not use are skipped
+    fn bind(&self, shader: &ShaderPipeline) {
+        for (uniform, texture) in &self.textures {
+            if let Some(texture_unit) = shader.get_texture_unit(uniform) {
+                texture.bind(texture_unit);
+            }
+        }
+        for (uniform, color) in &self.colors {
+            for location in shader.get_uniform_locations(uniform) {
+                unsafe {
+                    gl::ProgramUniform4f(
+                        location.program_id,
+                        location.location,
+                        color.r,
+                        color.g,
+                        color.b,
+                        color.a,
+                    );
+                }
+            }
+        }
+        for (uniform, value) in &self.values {
+            for location in shader.get_uniform_locations(uniform) {
+                unsafe {
+                    gl::ProgramUniform1f(location.program_id, location.location, *value);
+                }
+            }
+        }
+    }
+}
+
+/// Size and formats a render target is created with, once it is used
+#[derive(PartialEq)]
+struct RenderTargetSpec {
+    width: u32,
+    height: u32,
+    has_depth: bool,
+    formats: Vec<RenderTargetFormat>,
+}
+
 pub struct RenderContext {
-    parent_dir: PathBuf,
+    resources: Box<dyn ResourceProvider>,
 
-    shaders: Vec<ShaderProgram>,
+    resource_cache: ResourceCache,
+
+    // Stages are shared between shaders, they are identified by their file
+    shader_stages: Vec<Rc<ShaderStage>>,
+    shader_stage_files: HashMap<String, usize>,
+    shaders: Vec<ShaderPipeline>,
+    // Shaders which failed to compile are replaced by the error shader, the error is kept here
+    shader_errors: Vec<Option<String>>,
+    // Identifies the program of each shader across reloads, see `program_key`
+    shader_keys: Vec<String>,
     current_shader: Option<u32>,
-    next_free_texture_unit: u32,
+    // Names of the uniforms set by the script, and their locations in each shader: `uniform_locations[shader][uniform]`
+    uniform_names: Vec<String>,
+    uniform_locations: Vec<Vec<Vec<UniformLocation>>>,
+    // Texture unit of the uniforms which are samplers: `uniform_texture_units[shader][uniform]`
+    uniform_texture_units: Vec<Vec<Option<GLuint>>>,
 
+    // Targets are only created when they are used, and released when they were not used for a while
+    render_target_specs: HashMap<u32, RenderTargetSpec>,
     render_targets: HashMap<u32, RenderTarget>,
+    render_target_last_use: HashMap<u32, u64>,
     current_render_target: Option<u32>,
-    targets_with_blending: HashSet<u32>,
+    // Target of the current pass if it is drawn into a smaller copy of the target, see `bind_scaled_render_target`
+    scaled_pass: Option<u32>,
+    // Smaller copies of the targets of scaled passes, reused as long as the scale does not change
+    scaled_targets: HashMap<u32, RenderTarget>,
+    // Blending of the color buffers of the bound target, buffers without blending are not included
+    blend_modes: HashMap<u32, BlendMode>,
+    // (x, y, width, height), reset to the size of the target whenever one is bound
+    viewport: (u32, u32, u32, u32),
+    // Targets which were drawn to with a viewport larger than them, to only warn once
+    oversized_viewport_targets: HashSet<Option<u32>>,
+    // Area of the window the demo is rendered to, the rest is covered by black bars to keep the aspect ratio
+    screen_origin: (u32, u32),
+    screen_size: (u32, u32),
+    letterboxed: bool,
+    // Eye which is being rendered in stereo, state carried over from previous frames is kept apart for each eye
+    eye_index: usize,
+    // Targets which hold the history of TAA, and their copies of the eyes other than the current one by (eye, target),
+    // see `set_eye_rect`
+    history_targets: HashSet<u32>,
+    eye_history_targets: HashMap<(usize, u32), RenderTarget>,
+    // Framebuffer drawn to in place of the window, for frames which are rendered offline
+    screen_framebuffer: GLuint,
+    // Depth goes from 1 at the near plane to 0 at the far plane, see `set_reversed_z`
+    reversed_z: bool,
+    // Planes of the enabled clip distances in world space, reset whenever a target is bound
+    clip_planes: [Option<glm::Vec4>; bytecode::MAX_CLIP_PLANES as usize],
+    frame_index: u64,
+    frame_time_s: Option<f32>,
+    // Time since the previous frame, unless the time went backwards
+    frame_delta_s: Option<f32>,
+    // Length of the section which is played in a loop, see `set_loop_length`
+    loop_length_s: Option<f32>,
+    // The projection is jittered once TAA is used
+    taa_enabled: bool,
+    builtin_shaders: HashMap<&'static str, ShaderPipeline>,
+    // Copies of the screen made by `grab_screen`, by the eye and the uniform they are bound to
+    grabbed_screens: HashMap<(usize, u32), RenderTarget>,
+    // Exposure of the previous frame, by the eye and the target `post_auto_exposure` writes to
+    exposure_history: HashMap<(usize, u32), RenderTarget>,
+    // Statistics of (target, buffer), and the ones which were used this frame and are computed at its end
+    luminance_stats: HashMap<(u32, u32), LuminanceStats>,
+    requested_luminance_stats: HashSet<(u32, u32)>,
+    // Pixels read back from (target, buffer, x, y), and the ones which were used this frame and are read at its end
+    pixel_readbacks: HashMap<(u32, u32, u32, u32), PixelReadback>,
+    requested_pixel_readbacks: HashSet<(u32, u32, u32, u32)>,
+    // Envelopes by the sync track which triggers them, and the frame they were last updated in
+    envelopes: HashMap<String, (easing::Envelope, u64)>,
+    // Values of the `probe` calls, in the order they were first evaluated
+    probes: Vec<Probe>,
+    // Per-draw data of the `gl_resources::ENGINE_UNIFORMS_BLOCK` and the `gl_resources::DRAW_PARAMS_BLOCK`
+    uniform_ring: UniformRing,
+    // Members of the `gl_resources::DRAW_PARAMS_BLOCK` of the current program which the script set like uniforms, they
+    // are written to the ring before the next draw, unless the draw passes its own parameters
+    pass_params: Vec<u8>,
+    pass_params_pending: bool,
+    draw_params_bound: bool,
+    // Frame time dynamic resolution aims at, and the factor it currently applies to the scale of passes
+    target_frame_time_s: Option<f32>,
+    dynamic_scale: f32,
+    gpu_timer: GpuTimer,
 
     fullscreen_quad_vao: GLuint,
-    models: Vec<Model>,
-    textures: Vec<Texture>,
+    models: Vec<Rc<Model>>,
+    // Merged meshes of the batches
+    batches: Vec<Model>,
+    // Materials of the groups of each model, from the MTL file of the model
+    model_materials: Vec<Vec<Option<Rc<ModelMaterial>>>>,
+    textures: Vec<Rc<Texture>>,
     ibls: Vec<Ibl>,
 
     model_matrix: glm::Mat4,
     view_matrix: glm::Mat4,
     projection_matrix: glm::Mat4,
+    // (model, view, projection) matrices of the 3D scene, kept while drawing in 2D
+    saved_3d_matrices: Option<(glm::Mat4, glm::Mat4, glm::Mat4)>,
+    eye: Eye,
+    // Lights of the current frame, bound to every program
+    lights: Vec<FrameLight>,
+    // Where the debugger stopped the current frame
+    debug_stop: Option<DebugStop>,
+    recording: Option<FrameRecord>,
+    window_settings: WindowSettings,
+}
+
+/// State of the window set by the script with `set_title` and `show_cursor`, which the host applies after each frame
+///
+/// The settings stay until the script changes them again, `None` keeps the default of the host.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WindowSettings {
+    pub title: Option<String>,
+    pub show_cursor: Option<bool>,
+}
+
+/// Identifies a program by its files, which stay the same when the files are edited
+fn program_key(program: &bytecode::ProgramDef) -> String {
+    match &program.raymarch {
+        Some(map_file) => format!("raymarch:{}", map_file),
+        None => format!(
+            "{:?}:{:?}:{:?}:{:?}:{:?}",
+            program.vert, program.tess_ctrl, program.tess_eval, program.geom, program.frag
+        ),
+    }
+}
+
+/// How the camera of the script is placed into the view which is rendered, see `RenderContext::set_eye`
+#[derive(Debug, Clone, Copy)]
+struct Eye {
+    // Applied after the view of the camera, like the offset of an eye in stereo
+    view_matrix: glm::Mat4,
+    // Aspect ratio of `camera_perspective`
+    aspect: f32,
+    // Size in pixels, which the jitter of TAA depends on
+    width: f32,
+    height: f32,
+}
+
+/// A light evaluated for the current frame, in world space
+pub struct FrameLight {
+    pub light_type: LightType,
+    pub position: glm::Vec3,
+    pub direction: glm::Vec3,
+    pub color: LinearRGBA, // multiplied by the intensity
+    pub angle: f32,
+}
+
+/// Time line at the bottom of the screen, which the editor uses to seek
+///
+/// The bar spans the `duration` of the demo, or whole minutes when the duration is not known. The start of each of the
+/// `scenes` declared in the metadata is marked on it.
+#[derive(Debug, Clone)]
+pub struct ScrubBar {
+    time_s: f32,
+    length_s: f32,
+    markers: Vec<f32>,
+}
+impl ScrubBar {
+    pub fn new(metadata: &bytecode::Metadata, time_s: f32) -> Self {
+        let last_s = metadata.scenes.last().cloned().unwrap_or(0.0).max(time_s);
+        ScrubBar {
+            time_s: time_s,
+            length_s: metadata.duration.unwrap_or((last_s / 60.0).floor() * 60.0 + 60.0),
+            markers: metadata.scenes.clone(),
+        }
+    }
+
+    /// Area of the bar as (x, y, width, height) in pixels, from the top left corner of a screen of the given size
+    fn get_rect(screen_size: (u32, u32)) -> (f32, f32, f32, f32) {
+        let (width, height) = (screen_size.0 as f32, screen_size.1 as f32);
+        let bar_width = (width - 2.0 * SCRUB_BAR_MARGIN).max(1.0);
+        let y = height - SCRUB_BAR_MARGIN - SCRUB_BAR_HEIGHT;
+        (SCRUB_BAR_MARGIN, y, bar_width, SCRUB_BAR_HEIGHT)
+    }
+
+    /// Horizontal position of a time on the bar, in pixels
+    fn get_x(&self, rect: (f32, f32, f32, f32), time_s: f32) -> f32 {
+        let fraction = (time_s / self.length_s).max(0.0).min(1.0);
+        rect.0 + rect.2 * fraction
+    }
+
+    /// Time under a position given in pixels from the top left corner of the screen, if the position is on the bar
+    ///
+    /// With `dragging`, any position gives the time at the nearest point of the bar.
+    pub fn time_at(&self, screen_size: (u32, u32), position: (f32, f32), dragging: bool) -> Option<f32> {
+        let (x, y, width, height) = ScrubBar::get_rect(screen_size);
+        let on_bar = position.0 >= x && position.0 <= x + width && position.1 >= y && position.1 <= y + height;
+        if on_bar || dragging {
+            Some(((position.0 - x) / width).max(0.0).min(1.0) * self.length_s)
+        } else {
+            None
+        }
+    }
+}
+
+/// Number of frames for which the values of a probe are kept
+pub const PROBE_HISTORY: usize = 240;
+
+/// The values an expression marked with `probe("label", expr)` took in the last frames, oldest first
+pub struct Probe {
+    label: String,
+    values: VecDeque<f32>,
+    // Value of the current frame, the last one wins when the probe is evaluated several times
+    current: Option<f32>,
+}
+impl Probe {
+    pub fn get_label(&self) -> &str {
+        &self.label
+    }
+    pub fn get_values(&self) -> &VecDeque<f32> {
+        &self.values
+    }
+    /// (min, max) of the values which are kept
+    pub fn get_range(&self) -> (f32, f32) {
+        self.values
+            .iter()
+            .fold((std::f32::MAX, std::f32::MIN), |(min, max), &v| {
+                (min.min(v), max.max(v))
+            })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +359,10 @@ pub enum Value {
     Void,
     Float32(f32),
     LinColor(LinearRGBA),
+    Vec2(glm::Vec2),
+    Vec3(glm::Vec3),
+    Vec4(glm::Vec4),
+    Mat4(glm::Mat4),
     Str(String),
 }
 impl Value {
@@ -62,45 +380,339 @@ impl Value {
         }
     }
 
+    /// Returns the floats of colors, vectors and matrices in the order of GLSL, or `None` for other values
+    pub fn as_components(&self) -> Option<Vec<f32>> {
+        match self {
+            Value::Float32(v) => Some(vec![*v]),
+            Value::LinColor(c) => Some(vec![c.r, c.g, c.b, c.a]),
+            Value::Vec2(v) => Some(vec![v.x, v.y]),
+            Value::Vec3(v) => Some(vec![v.x, v.y, v.z]),
+            Value::Vec4(v) => Some(vec![v.x, v.y, v.z, v.w]),
+            Value::Mat4(m) => Some(mat4_components(m).to_vec()),
+            Value::Void | Value::Str(_) => None,
+        }
+    }
+
+    pub fn as_vec3(&self) -> Result<glm::Vec3, String> {
+        match self {
+            Value::Vec3(v) => Ok(*v),
+            _ => Err(format!("Cannot convert {:?} to vec3", self)),
+        }
+    }
+
+    pub fn as_mat4(&self) -> Result<glm::Mat4, String> {
+        match self {
+            Value::Mat4(m) => Ok(*m),
+            _ => Err(format!("Cannot convert {:?} to mat4", self)),
+        }
+    }
+
+    /// Returns false if the value contains a NaN or an infinite component
+    pub fn is_finite(&self) -> bool {
+        match self {
+            Value::Float32(v) => v.is_finite(),
+            Value::LinColor(c) => c.r.is_finite() && c.g.is_finite() && c.b.is_finite() && c.a.is_finite(),
+            Value::Vec2(v) => v.x.is_finite() && v.y.is_finite(),
+            Value::Vec3(v) => v.x.is_finite() && v.y.is_finite() && v.z.is_finite(),
+            Value::Vec4(v) => v.x.is_finite() && v.y.is_finite() && v.z.is_finite() && v.w.is_finite(),
+            Value::Mat4(m) => mat4_components(m).iter().all(|v| v.is_finite()),
+            Value::Void | Value::Str(_) => true,
+        }
+    }
+
     pub fn value_type(&self) -> ast::Type {
         match self {
             Value::Void => ast::Type::Void,
             Value::Float32(_) => ast::Type::Float32,
             Value::LinColor(_) => ast::Type::LinColor,
+            Value::Vec2(_) => ast::Type::Vec2,
+            Value::Vec3(_) => ast::Type::Vec3,
+            Value::Vec4(_) => ast::Type::Vec4,
+            Value::Mat4(_) => ast::Type::Mat4,
             Value::Str(_) => ast::Type::Str,
         }
     }
 }
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Void => write!(f, "void"),
+            Value::Float32(v) => write!(f, "{}", v),
+            Value::LinColor(c) => write!(f, "LinColor({}, {}, {}, {})", c.r, c.g, c.b, c.a),
+            Value::Vec2(v) => write!(f, "Vec2({}, {})", v.x, v.y),
+            Value::Vec3(v) => write!(f, "Vec3({}, {}, {})", v.x, v.y, v.z),
+            Value::Vec4(v) => write!(f, "Vec4({}, {}, {}, {})", v.x, v.y, v.z, v.w),
+            Value::Mat4(m) => {
+                let columns: Vec<String> = [m.c0, m.c1, m.c2, m.c3]
+                    .iter()
+                    .map(|c| format!("({}, {}, {}, {})", c.x, c.y, c.z, c.w))
+                    .collect();
+                write!(f, "Mat4({})", columns.join(", "))
+            }
+            Value::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
 
-pub struct FunctionContext<'a> {
-    pub program: &'a ProgramContainer,
-    pub sync_track: &'a dyn SyncTracker,
-    pub globals: &'a HashMap<String, Value>,
-    pub locals: HashMap<String, Value>,
-}
-impl<'a> FunctionContext<'a> {
-    pub fn get_prop(&self, name: &str, props: &[String]) -> Result<Value, String> {
-        if name == "sync" {
-            let track = props.join(":");
-            self.sync_track
-                .get_value(&track)
-                .map(|v| Value::Float32(v))
-                .ok_or_else(|| format!("Could not get value for sync track \"{}\"", track))
-        } else {
-            if !props.is_empty() {
-                return Err("Right now `.` is only supported for sync expressions".to_owned());
+/// Components of a matrix in column-major order, as OpenGL expects them
+pub fn mat4_components(m: &glm::Mat4) -> [f32; 16] {
+    let mut result = [0.0; 16];
+    for (i, c) in [m.c0, m.c1, m.c2, m.c3].iter().enumerate() {
+        result[i * 4..i * 4 + 4].copy_from_slice(&[c.x, c.y, c.z, c.w]);
+    }
+    result
+}
+
+/// Options which control how the interpreter executes the program
+///
+/// The limits protect the engine against runaway scripts: when a limit is exceeded the current frame is aborted with an
+/// error, instead of hanging or overflowing the stack.
+#[derive(Debug, Clone)]
+pub struct ExecutionOptions {
+    pub max_call_depth: u32,
+    pub max_ops_per_frame: u32,
+    /// Abort the frame as soon as any expression evaluates to NaN or infinity (debugging aid)
+    pub check_non_finite: bool,
+    /// Scene objects and passes which are skipped (debugging aid)
+    ///
+    /// Passes are the functions called as statements by the function which renders the frame, usually `main`.
+    pub muted: HashSet<String>,
+    /// The only scene object or pass which is drawn, objects are still drawn when a pass is soloed and vice versa
+    pub solo: Option<String>,
+    /// Draw the values of the `probe` calls over the frame, see `RenderContext::draw_probes`
+    pub show_probes: bool,
+    /// Stops frames at breakpoints of the script (debugging aid)
+    pub debugger: Debugger,
+    /// Draw a time line over the frame, see `ScrubBar`
+    pub show_scrub_bar: bool,
+}
+impl Default for ExecutionOptions {
+    fn default() -> Self {
+        ExecutionOptions {
+            max_call_depth: 128,
+            max_ops_per_frame: 1_000_000,
+            check_non_finite: false,
+            muted: HashSet::new(),
+            solo: None,
+            show_probes: false,
+            debugger: Debugger::default(),
+            show_scrub_bar: false,
+        }
+    }
+}
+impl ExecutionOptions {
+    /// Whether a scene object or pass is drawn, `is_same_kind` tells if the soloed name is of the same kind
+    fn is_shown<F: Fn(&str) -> bool>(&self, name: &str, is_same_kind: F) -> bool {
+        if self.muted.contains(name) {
+            return false;
+        }
+        match &self.solo {
+            Some(solo) => solo == name || !is_same_kind(solo),
+            None => true,
+        }
+    }
+}
+
+/// Pauses frames at lines of the script, to inspect the state of the program at that point
+///
+/// The interpreter cannot suspend a frame halfway, so while paused every frame is stopped before the same op and only
+/// what was drawn up to it is shown. Ops are numbered in the order they are executed in a frame, like
+/// `FrameCounters::ops`, stepping moves the stop to the next op.
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    /// Lines of the script counted from 1, frames stop before the first op of every statement starting on them
+    pub breakpoints: BTreeSet<usize>,
+    // Op frames stop at while paused
+    paused_at: Option<u32>,
+    // After continuing, breakpoints are ignored up to this op of the frame
+    resume_after: u32,
+}
+impl Debugger {
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Stops at the next op, or runs to the end of the frame if the paused op was the last one
+    pub fn step(&mut self) {
+        self.paused_at = self.paused_at.map(|op| op + 1);
+    }
+
+    /// Runs to the next breakpoint, which may be in the next frame
+    pub fn resume(&mut self) {
+        if let Some(op) = self.paused_at.take() {
+            self.resume_after = op;
+        }
+    }
+
+    /// Takes over where the last frame stopped, `None` if it ran to the end
+    pub fn frame_done(&mut self, stop: Option<&DebugStop>) {
+        self.paused_at = stop.map(|stop| stop.op);
+        self.resume_after = 0;
+    }
+
+    /// Whether the frame stops before the given op, `statement_line` is only called to check breakpoints
+    fn should_stop<F: FnOnce() -> Option<usize>>(&self, op: u32, statement_line: F) -> bool {
+        match self.paused_at {
+            Some(paused_at) => op == paused_at,
+            None if self.breakpoints.is_empty() || op <= self.resume_after => false,
+            None => statement_line().map_or(false, |line| self.breakpoints.contains(&line)),
+        }
+    }
+}
+
+/// Where the debugger stopped a frame, with the values and the GL state at that point
+#[derive(Debug, Clone)]
+pub struct DebugStop {
+    pub op: u32,
+    pub function: String,
+    // The statement of the op which was not executed anymore
+    pub slice: SourceSlice,
+    pub locals: Vec<(String, Value)>,
+    pub globals: Vec<(String, Value)>,
+    pub gl_state: Vec<String>,
+}
+impl DebugStop {
+    /// Formats the stop like a diagnostic, with the values and the GL state as notes
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let values = |values: &[(String, Value)]| {
+            let values: Vec<String> = values
+                .iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect();
+            values.join(", ")
+        };
+        let mut notes = Vec::new();
+        if !self.locals.is_empty() {
+            notes.push(format!("locals: {}", values(&self.locals)));
+        }
+        notes.push(format!("globals: {}", values(&self.globals)));
+        notes.extend(self.gl_state.iter().cloned());
+        let message = format!("Paused in `{}` before op {}", self.function, self.op);
+        let snippet = SourceSnippet::new(self.slice, source);
+        diagnostics::render(Severity::Note, &message, Some(snippet), &notes, color)
+    }
+}
+
+/// What a frame did, to compare the frames before and after a reload, see `RenderContext::start_recording`
+#[derive(Debug, Clone, Default)]
+pub struct FrameRecord {
+    // Functions called by the function which renders the frame, in the order they ran
+    passes: Vec<String>,
+    // Number of ops each function executed
+    op_counts: BTreeMap<String, u32>,
+    // Last value of the uniforms, by (program, uniform), see `program_key`
+    uniforms: BTreeMap<(String, String), String>,
+}
+impl FrameRecord {
+    /// Describes what a later frame did differently, one line for each difference
+    pub fn diff(&self, after: &FrameRecord) -> Vec<String> {
+        let mut result = Vec::new();
+        if self.passes != after.passes {
+            result.push(format!(
+                "passes: {} -> {}",
+                self.passes.join(", "),
+                after.passes.join(", ")
+            ));
+        }
+
+        let functions: BTreeSet<&String> = self.op_counts.keys().chain(after.op_counts.keys()).collect();
+        for function in functions {
+            let before_count = self.op_counts.get(function).cloned().unwrap_or(0);
+            let after_count = after.op_counts.get(function).cloned().unwrap_or(0);
+            if before_count != after_count {
+                result.push(format!(
+                    "`{}` executed {} ops, now {}",
+                    function, before_count, after_count
+                ));
+            }
+        }
+
+        let uniforms: BTreeSet<&(String, String)> = self.uniforms.keys().chain(after.uniforms.keys()).collect();
+        let not_set = format!("not set");
+        for uniform in uniforms {
+            let before_value = self.uniforms.get(uniform).unwrap_or(&not_set);
+            let after_value = after.uniforms.get(uniform).unwrap_or(&not_set);
+            if before_value != after_value {
+                result.push(format!(
+                    "`{}` of {}: {} -> {}",
+                    uniform.1, uniform.0, before_value, after_value
+                ));
             }
+        }
+        result
+    }
+}
+
+/// Error which occurred while executing the program
+///
+/// If known, the error holds the location of the script which caused it.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    slice: Option<SourceSlice>,
+    error: String,
+}
+impl RuntimeError {
+    /// Attaches the location to the error, unless a more precise location is already known
+    pub fn with_fallback_slice(self, slice: SourceSlice) -> Self {
+        RuntimeError {
+            slice: self.slice.or(Some(slice)),
+            error: self.error,
+        }
+    }
+
+    pub fn source_snippet<'a>(&self, source: &'a str) -> Option<SourceSnippet<'a>> {
+        self.slice.map(|slice| SourceSnippet::new(slice, source))
+    }
+
+    /// Formats the error with its location, see `diagnostics::render`
+    pub fn render(&self, source: &str, color: bool) -> String {
+        diagnostics::render(Severity::Error, &self.error, self.source_snippet(source), &[], color)
+    }
+}
+impl From<String> for RuntimeError {
+    fn from(error: String) -> Self {
+        RuntimeError {
+            slice: None,
+            error: error,
+        }
+    }
+}
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Runtime Error: {}", self.error)
+    }
+}
 
-            let value = self
-                .locals
-                .get(name)
-                .or_else(|| self.globals.get(name))
-                .map(|v| v.clone());
-            value.ok_or_else(|| format!("Unknown variable {}", name))
+/// Statistics about the frame which is currently being executed
+#[derive(Debug, Default)]
+pub struct FrameCounters {
+    pub ops: Cell<u32>,
+    pub prints: Cell<u32>,
+}
+impl FrameCounters {
+    /// Counts a line of script output, returns false once the output of this frame is suppressed
+    fn allow_print(&self) -> bool {
+        let print_count = self.prints.get() + 1;
+        self.prints.set(print_count);
+        if print_count == MAX_PRINTS_PER_FRAME + 1 {
+            println!("... further output of this frame is suppressed");
         }
+        print_count <= MAX_PRINTS_PER_FRAME
     }
 }
 
+pub struct FunctionContext<'a> {
+    pub program: &'a ProgramContainer,
+    pub function: &'a str,
+    pub sync_track: &'a dyn SyncTracker,
+    pub globals: &'a [Value],
+    // The parameters, followed by the locals of `let` statements in scope, by slot
+    pub locals: RefCell<Vec<(String, Value)>>,
+
+    pub options: &'a ExecutionOptions,
+    pub call_depth: u32,
+    pub counters: &'a FrameCounters,
+}
 fn identity_4() -> glm::Mat4 {
     glm::Mat4::new(
         glm::Vec4::new(1.0, 0.0, 0.0, 0.0),
@@ -111,10 +723,11 @@ fn identity_4() -> glm::Mat4 {
 }
 
 impl RenderContext {
-    pub fn new(path: &Path) -> Self {
+    pub fn new(resources: Box<dyn ResourceProvider>, resource_cache: ResourceCache) -> Self {
         let mut quad_vao = 0;
         unsafe {
-            // Enable linear color output for shaders
+            // Enable linear color output for shaders: writes to sRGB targets are always encoded, and sampling them
+            // decodes back to linear unless `srgb_decode: 0` is passed to `uniform_rtt`
             gl::Enable(gl::FRAMEBUFFER_SRGB);
             gl::Enable(gl::DEPTH_TEST);
             gl::Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
@@ -139,218 +752,1823 @@ impl RenderContext {
         }
 
         Self {
-            parent_dir: path.to_owned(),
+            resources: resources,
+            resource_cache: resource_cache,
+            shader_stages: Vec::new(),
+            shader_stage_files: HashMap::new(),
             shaders: Vec::new(),
+            shader_errors: Vec::new(),
+            shader_keys: Vec::new(),
             current_shader: None,
-            next_free_texture_unit: 0,
+            uniform_names: Vec::new(),
+            uniform_locations: Vec::new(),
+            uniform_texture_units: Vec::new(),
 
+            render_target_specs: HashMap::new(),
             render_targets: HashMap::new(),
+            render_target_last_use: HashMap::new(),
             current_render_target: None,
-            targets_with_blending: HashSet::new(),
+            scaled_pass: None,
+            scaled_targets: HashMap::new(),
+            blend_modes: HashMap::new(),
+            viewport: (0, 0, 0, 0),
+            oversized_viewport_targets: HashSet::new(),
+            screen_origin: (0, 0),
+            screen_size: (0, 0),
+            letterboxed: false,
+            eye_index: 0,
+            history_targets: HashSet::new(),
+            eye_history_targets: HashMap::new(),
+            screen_framebuffer: 0,
+            reversed_z: false,
+            clip_planes: [None; bytecode::MAX_CLIP_PLANES as usize],
+            frame_index: 0,
+            frame_time_s: None,
+            frame_delta_s: None,
+            loop_length_s: None,
+            taa_enabled: false,
+            builtin_shaders: HashMap::new(),
+            grabbed_screens: HashMap::new(),
+            exposure_history: HashMap::new(),
+            luminance_stats: HashMap::new(),
+            requested_luminance_stats: HashSet::new(),
+            pixel_readbacks: HashMap::new(),
+            requested_pixel_readbacks: HashSet::new(),
+            envelopes: HashMap::new(),
+            probes: Vec::new(),
+            uniform_ring: UniformRing::new(UNIFORM_RING_REGION_SIZE),
+            pass_params: Vec::new(),
+            pass_params_pending: false,
+            draw_params_bound: false,
+            target_frame_time_s: None,
+            dynamic_scale: 1.0,
+            gpu_timer: GpuTimer::new(),
 
             fullscreen_quad_vao: quad_vao,
             models: Vec::new(),
+            batches: Vec::new(),
+            model_materials: Vec::new(),
             textures: Vec::new(),
             ibls: Vec::new(),
 
             model_matrix: identity_4(),
             view_matrix: identity_4(),
             projection_matrix: identity_4(),
+            saved_3d_matrices: None,
+            eye: Eye {
+                view_matrix: identity_4(),
+                aspect: 1.0,
+                width: 1.0,
+                height: 1.0,
+            },
+            lights: Vec::new(),
+            debug_stop: None,
+            recording: None,
+            window_settings: WindowSettings::default(),
         }
     }
 
-    pub fn make_target(
+    /// Declares the size and formats of a render target, the target is created the first time it is used
+    ///
+    /// If the target already exists with a different size or different formats, it is released.
+    pub fn declare_target(
         &mut self,
         idx: u32,
         width: u32,
         height: u32,
         has_depth: bool,
         formats: &[(String, RenderTargetFormat)],
-    ) -> Result<(), String> {
-        let mut recreate_render_target = false;
-        {
-            let value = self.render_targets.get(&idx);
-            match value {
-                Some(render_target) => {
-                    if render_target.get_width() != width || render_target.get_height() != height {
-                        recreate_render_target = true;
-                    } else {
-                        render_target.bind();
-                    }
-                }
-                None => {
-                    recreate_render_target = true;
-                }
-            };
+    ) {
+        let spec = RenderTargetSpec {
+            width: width,
+            height: height,
+            has_depth: has_depth,
+            formats: formats.iter().map(|x| x.1).collect(),
+        };
+        if self.render_target_specs.get(&idx) != Some(&spec) {
+            self.render_targets.remove(&idx);
+            self.scaled_targets.remove(&idx);
+            self.eye_history_targets.retain(|&(_, target), _| target != idx);
+            self.render_target_specs.insert(idx, spec);
         }
+    }
 
-        let formats: Vec<RenderTargetFormat> = formats.iter().map(|x| x.1).collect();
-
-        if recreate_render_target {
-            let render_target = RenderTarget::new(width, height, has_depth, &formats)?;
-            render_target.bind();
-            self.render_targets.remove(&idx);
-            self.render_targets.insert(idx, render_target);
+    /// Creates a declared render target unless it exists, and marks it as used in this frame
+    fn ensure_target(&mut self, idx: u32) -> Result<(), String> {
+        self.render_target_last_use.insert(idx, self.frame_index);
+        if self.render_targets.contains_key(&idx) {
+            return Ok(());
         }
 
+        let spec = self
+            .render_target_specs
+            .get(&idx)
+            .ok_or_else(|| format!("Unknown render target at index {}", idx))?;
+        let render_target = RenderTarget::new(spec.width, spec.height, spec.has_depth, &spec.formats)?;
+        self.render_targets.insert(idx, render_target);
         Ok(())
     }
 
-    pub fn bind_render_target(&mut self, target: Option<u32>) -> Result<(), String> {
-        if let Some(target) = target {
-            if let Some(render_target) = self.render_targets.get(&target) {
-                render_target.bind();
-                self.current_render_target = Some(target);
-            } else {
-                return Err(format!("Unknown render target: {}", target));
+    /// Releases the render targets which were not used recently
+    fn release_unused_targets(&mut self) {
+        let frame_index = self.frame_index;
+        let last_use = &self.render_target_last_use;
+        self.render_targets.retain(|idx, _| match last_use.get(idx) {
+            Some(&used) => frame_index - used < RENDER_TARGET_RELEASE_FRAMES,
+            None => false,
+        });
+        let render_targets = &self.render_targets;
+        self.scaled_targets.retain(|idx, _| render_targets.contains_key(idx));
+        self.eye_history_targets
+            .retain(|&(_, idx), _| render_targets.contains_key(&idx));
+    }
+
+    /// Starts a new frame at the given time, rendering to the given (x, y, width, height) area of the window
+    ///
+    /// If the area does not cover the whole window, the rest of it is cleared to black.
+    pub fn begin_frame(&mut self, screen_rect: (u32, u32, u32, u32), time_s: f32) {
+        let (x, y, width, height) = screen_rect;
+        self.screen_origin = (x, y);
+        self.screen_size = (width, height);
+        self.letterboxed = x > 0 || y > 0;
+        self.uniform_ring.begin_frame();
+        self.gpu_timer.begin();
+        self.debug_stop = None;
+        // A scaled pass is left open when the previous frame failed, its contents are dropped
+        self.scaled_pass = None;
+        if self.saved_3d_matrices.take().is_some() {
+            unsafe {
+                gl::Enable(gl::DEPTH_TEST);
             }
-        } else {
+        }
+        if self.letterboxed {
             unsafe {
-                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                gl::BindFramebuffer(gl::FRAMEBUFFER, self.screen_framebuffer);
+                gl::Disable(gl::SCISSOR_TEST);
+                gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
             }
-            self.current_render_target = None;
         }
-        Ok(())
+        self.frame_index += 1;
+        let wrapped_delta_s = match (self.frame_time_s, self.loop_length_s) {
+            (Some(previous_s), Some(length_s)) if time_s < previous_s && time_s + length_s >= previous_s => {
+                Some(time_s + length_s - previous_s)
+            }
+            _ => None,
+        };
+        self.frame_delta_s = match self.frame_time_s {
+            Some(previous_s) if time_s >= previous_s => Some(time_s - previous_s),
+            _ => wrapped_delta_s,
+        };
+        self.frame_time_s = Some(time_s);
+        // Envelopes carry on from the end of a loop as if the time had kept going
+        if let (Some(_), Some(length_s)) = (wrapped_delta_s, self.loop_length_s) {
+            for &mut (ref mut envelope, _) in self.envelopes.values_mut() {
+                envelope.shift_time(-length_s);
+            }
+        }
+        // Envelopes start over when the time jumps back
+        if self.frame_delta_s.is_none() {
+            self.envelopes.clear();
+        }
     }
 
-    pub fn viewport_rect(&mut self, x: u32, y: u32, width: u32, height: u32) {
-        unsafe {
-            gl::Viewport(x as GLint, y as GLint, width as GLint, height as GLint);
-        }
+    /// Treats the time jumping back by the given length as a continuation, for sections which are played in a loop
+    ///
+    /// Frame deltas and envelopes then run on across the wrap instead of starting over, so the loop has no seam.
+    pub fn set_loop_length(&mut self, loop_length_s: Option<f32>) {
+        self.loop_length_s = loop_length_s;
     }
 
-    pub fn clear(&mut self, linear: LinearRGBA) {
-        unsafe {
-            gl::ClearColor(linear.r, linear.g, linear.b, linear.a);
-            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+    /// Adapts the scale of the passes which have one to the time the GPU takes for a frame, so that the given frame rate
+    /// is held, see `bind_scaled_render_target`
+    pub fn set_dynamic_resolution(&mut self, target_fps: Option<f32>) {
+        self.target_frame_time_s = target_fps.map(|fps| 1.0 / fps);
+        if self.target_frame_time_s.is_none() {
+            self.dynamic_scale = 1.0;
         }
     }
 
-    pub fn set_blending(&mut self, buffer: u32, mode: BlendMode) {
-        unsafe {
-            match mode {
-                BlendMode::None => {
-                    gl::BlendFunci(buffer, gl::ONE, gl::ZERO);
-                    self.targets_with_blending.remove(&buffer);
-                    if self.targets_with_blending.is_empty() {
-                        gl::Disable(gl::BLEND);
-                    }
-                }
-                BlendMode::Add => {
-                    if self.targets_with_blending.is_empty() {
-                        gl::Enable(gl::BLEND);
-                    }
-                    self.targets_with_blending.insert(buffer);
-                    gl::BlendFunci(buffer, gl::ONE, gl::ONE);
-                }
-                BlendMode::AlphaBlend => {
-                    if self.targets_with_blending.is_empty() {
-                        gl::Enable(gl::BLEND);
-                    }
-                    self.targets_with_blending.insert(buffer);
-                    gl::BlendFunci(buffer, gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
-                }
-                BlendMode::OitCoverageBlend => {
-                    if self.targets_with_blending.is_empty() {
-                        gl::Enable(gl::BLEND);
-                    }
-                    self.targets_with_blending.insert(buffer);
-                    gl::BlendFunci(buffer, gl::ZERO, gl::ONE_MINUS_SRC_ALPHA);
-                }
-            }
+    /// Restricts drawing to the screen to the given (x, y, width, height) area of the window, used for the eyes in stereo
+    ///
+    /// The history of TAA, the adapted exposure and the grabbed screens are kept apart for each eye, so that an eye
+    /// never reads what another eye rendered in the previous frame.
+    pub fn set_eye_rect(&mut self, eye_index: usize, eye_rect: (u32, u32, u32, u32)) {
+        let (x, y, width, height) = eye_rect;
+        self.screen_origin = (x, y);
+        self.screen_size = (width, height);
+        self.letterboxed = true;
+        if eye_index == self.eye_index {
+            return;
         }
-    }
 
-    pub fn set_write_mask(&mut self, write_color: bool, write_depth: bool) {
-        unsafe {
-            gl::ColorMask(
-                write_color as u8,
-                write_color as u8,
-                write_color as u8,
-                write_color as u8,
-            );
-            gl::DepthMask(write_depth as u8);
+        // History targets are swapped in and out, so that the script keeps reading them by their index
+        for &idx in &self.history_targets {
+            if let Some(target) = self.render_targets.remove(&idx) {
+                self.eye_history_targets.insert((self.eye_index, idx), target);
+            }
+            if let Some(target) = self.eye_history_targets.remove(&(eye_index, idx)) {
+                self.render_targets.insert(idx, target);
+            }
         }
+        self.eye_index = eye_index;
     }
 
-    pub fn set_z_test(&mut self, mode: ZTestMode) {
-        let mode = match mode {
-            ZTestMode::LessEqual => gl::LEQUAL,
-            ZTestMode::Equal => gl::EQUAL,
-            ZTestMode::Always => gl::ALWAYS,
-        };
+    /// Sets the framebuffer drawn to in place of the window, 0 draws to the window again
+    pub fn set_screen_framebuffer(&mut self, framebuffer: GLuint) {
+        self.screen_framebuffer = framebuffer;
+    }
 
+    /// Switches between the default depth convention and reversed-Z
+    ///
+    /// With reversed-Z, clip space depth goes from 0 to 1 and projections map the near plane to 1, the depth tests and
+    /// depth clears are flipped to match. Scripts keep using depths from 0 at the near plane to 1 at the far plane.
+    pub fn set_reversed_z(&mut self, reversed_z: bool) {
+        if reversed_z == self.reversed_z {
+            return;
+        }
+        self.reversed_z = reversed_z;
         unsafe {
-            gl::DepthFunc(mode);
+            if reversed_z {
+                gl::ClipControl(gl::LOWER_LEFT, gl::ZERO_TO_ONE);
+                gl::ClearDepth(0.0);
+            } else {
+                gl::ClipControl(gl::LOWER_LEFT, gl::NEGATIVE_ONE_TO_ONE);
+                gl::ClearDepth(1.0);
+            }
         }
     }
 
-    pub fn set_culling(&mut self, mode: CullingMode) {
-        let mode: Option<GLenum> = match mode {
-            CullingMode::Front => Some(gl::FRONT),
-            CullingMode::Back => Some(gl::BACK),
-            CullingMode::None => None
-        };
+    /// Converts a depth from 0 at the near plane to 1 at the far plane to the value stored in depth buffers
+    fn stored_depth(&self, depth: f32) -> f32 {
+        if self.reversed_z {
+            1.0 - depth
+        } else {
+            depth
+        }
+    }
 
-        unsafe {
-            if let Some(mode) = mode {
-                gl::Enable(gl::CULL_FACE);
-                gl::CullFace(mode);
-            } else {
-                gl::Disable(gl::CULL_FACE);
+    /// Tells programs which depth convention is used, through the `u_ReversedZ` uniform
+    fn set_reversed_z_uniform(&self, shader: &ShaderPipeline) {
+        for location in shader.get_uniform_locations("u_ReversedZ") {
+            unsafe {
+                gl::ProgramUniform1i(location.program_id, location.location, self.reversed_z as GLint);
             }
         }
-
     }
 
-    pub fn push_new_shader(&mut self, vert_file: &str, frag_file: &str) -> Result<(), String> {
-        let path: &PathBuf = &self.parent_dir;
+    /// Reads a color buffer of a target as it was left by the last frame, with the rows from top to bottom
+    ///
+    /// Returns the width, height and RGBA pixels of the buffer.
+    pub fn read_target_buffer(
+        &mut self,
+        target: u32,
+        buffer: u32,
+    ) -> Result<(u32, u32, Vec<(f32, f32, f32, f32)>), String> {
+        let render_target = self
+            .render_targets
+            .get(&target)
+            .ok_or_else(|| format!("Render target {} was not used by the last frame", target))?;
+        let width = render_target.get_width();
+        let height = render_target.get_height();
+        let mut pixels = render_target.read_pixels(buffer as usize);
+        for y in 0..height / 2 {
+            for x in 0..width {
+                pixels.swap((y * width + x) as usize, ((height - 1 - y) * width + x) as usize);
+            }
+        }
+        Ok((width, height, pixels))
+    }
 
-        let vs_src = Self::load_shader(&path.join(vert_file))?;
-        let fs_src = Self::load_shader(&path.join(frag_file))?;
-        let shader = ShaderProgram::from_vert_frag(&vs_src, &fs_src)?;
-        self.shaders.push(shader);
-        Ok(())
+    /// Reads the area of the screen the last frame was rendered to, with the rows from top to bottom
+    ///
+    /// Returns the width, height and 8 bit RGBA pixels of the area.
+    pub fn read_screen(&self) -> (u32, u32, Vec<u8>) {
+        let (x, y) = self.screen_origin;
+        let (width, height) = self.screen_size;
+        let row_size = width as usize * 4;
+        let mut pixels = vec![0u8; row_size * height as usize];
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.screen_framebuffer);
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl::ReadPixels(
+                x as GLint,
+                y as GLint,
+                width as GLint,
+                height as GLint,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                pixels.as_mut_ptr() as *mut _,
+            );
+        }
+        let flipped = pixels
+            .chunks(row_size)
+            .rev()
+            .flat_map(|row| row.iter().cloned())
+            .collect();
+        (width, height, flipped)
     }
 
-    pub fn push_new_model(&mut self, model_file: &str) -> Result<(), String> {
-        let path: &PathBuf = &self.parent_dir;
+    /// Finishes the frame by starting the computations which were requested during it
+    pub fn end_frame(&mut self) -> Result<(), String> {
+        self.finish_scaled_pass()?;
+        self.gpu_timer.end();
+        self.update_dynamic_scale();
+        self.release_unused_targets();
+        self.update_probes();
+        self.uniform_ring.end_frame();
+        self.start_pixel_readbacks()?;
+        if self.requested_luminance_stats.is_empty() {
+            return Ok(());
+        }
 
-        let model = Model::load_obj_file(&path.join(model_file))
-            .map_err(|_| format!("Could not load model {:?}", model_file))?;
+        self.ensure_builtin_shader(
+            "luminance_stats",
+            &[(
+                "<luminance stats compute shader>",
+                postfx::LUMINANCE_STATS_COMPUTE_SHADER,
+                gl::COMPUTE_SHADER,
+            )],
+        )?;
+        let shader = &self.builtin_shaders["luminance_stats"];
+        shader.bind();
+        self.current_shader = None;
+        for (target, buffer) in self.requested_luminance_stats.drain() {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            if let Some(texture_unit) = shader.get_texture_unit("t_Source") {
+                render_target.bind_as_texture(texture_unit, buffer as usize, true);
+            }
+            self.luminance_stats
+                .entry((target, buffer))
+                .or_insert_with(LuminanceStats::new)
+                .dispatch(render_target.get_width(), render_target.get_height());
+        }
+        Ok(())
+    }
+
+    /// Starts reading back the pixels requested this frame, and forgets the ones which were not requested
+    fn start_pixel_readbacks(&mut self) -> Result<(), String> {
+        let requested = &self.requested_pixel_readbacks;
+        self.pixel_readbacks.retain(|key, _| requested.contains(key));
+        for &(target, buffer, x, y) in &self.requested_pixel_readbacks {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            render_target.bind_for_read(buffer as usize);
+            self.pixel_readbacks
+                .entry((target, buffer, x, y))
+                .or_insert_with(PixelReadback::new)
+                .start(x, y);
+        }
+        self.requested_pixel_readbacks.clear();
+        Ok(())
+    }
+
+    /// Pixel of a color buffer as of the end of a previous frame, as stored in the buffer
+    ///
+    /// Returns `None` until the first readback of the pixel completed, which is usually in the next frame. Pixels are
+    /// only read again at the end of frames in which they are used.
+    pub fn get_pixel_readback(&mut self, target: u32, buffer: u32, x: u32, y: u32) -> Result<Option<[f32; 4]>, String> {
+        self.ensure_target(target)?;
+        let (width, height) = {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            (render_target.get_width(), render_target.get_height())
+        };
+        if x >= width || y >= height {
+            return Err(format!(
+                "Pixel ({}, {}) is outside of the render target, which is {}x{}",
+                x, y, width, height
+            ));
+        }
+
+        self.requested_pixel_readbacks.insert((target, buffer, x, y));
+        let readback = self
+            .pixel_readbacks
+            .entry((target, buffer, x, y))
+            .or_insert_with(PixelReadback::new);
+        readback.poll();
+        Ok(readback.get_value())
+    }
+
+    /// Value of the envelope triggered by the given sync track, see `easing::Envelope`
+    ///
+    /// Envelopes advance once per frame, later evaluations in the same frame, such as the ones of the other eye in
+    /// stereo, return the value of the first one.
+    pub fn update_envelope(&mut self, track: &str, gate: bool, adsr: (f32, f32, f32, f32)) -> f32 {
+        let time_s = self.frame_time_s.unwrap_or(0.0);
+        let (attack, decay, sustain, release) = adsr;
+        let frame_index = self.frame_index;
+        let &mut (ref mut envelope, ref mut updated_frame) = self
+            .envelopes
+            .entry(track.to_owned())
+            .or_insert_with(|| (easing::Envelope::default(), 0));
+        if *updated_frame == frame_index {
+            return envelope.get_value();
+        }
+        *updated_frame = frame_index;
+        envelope.update(time_s, gate, attack, decay, sustain, release)
+    }
+
+    /// Remembers the value of a probe for the current frame
+    pub fn record_probe(&mut self, label: &str, value: f32) {
+        match self.probes.iter_mut().find(|p| p.label == label) {
+            Some(probe) => probe.current = Some(value),
+            None => self.probes.push(Probe {
+                label: label.to_owned(),
+                values: VecDeque::with_capacity(PROBE_HISTORY),
+                current: Some(value),
+            }),
+        }
+    }
+
+    /// Adds the values of this frame to the history of the probes, and forgets the probes which were not evaluated
+    fn update_probes(&mut self) {
+        self.probes.retain(|p| p.current.is_some());
+        for probe in &mut self.probes {
+            if probe.values.len() == PROBE_HISTORY {
+                probe.values.pop_front();
+            }
+            probe.values.extend(probe.current.take());
+        }
+    }
+
+    pub fn get_probes(&self) -> &[Probe] {
+        &self.probes
+    }
+
+    /// Where the debugger stopped the last frame, see `Debugger`
+    pub fn get_debug_stop(&self) -> Option<&DebugStop> {
+        self.debug_stop.as_ref()
+    }
+
+    pub fn get_window_settings(&self) -> &WindowSettings {
+        &self.window_settings
+    }
+
+    /// Records what the following frames do, until the record is taken with `take_recording`
+    pub fn start_recording(&mut self) {
+        self.recording = Some(FrameRecord::default());
+    }
+
+    pub fn take_recording(&mut self) -> Option<FrameRecord> {
+        self.recording.take()
+    }
+
+    /// Summary of the state set by the script, shown by the debugger
+    fn describe_state(&self, program: &ProgramContainer) -> Vec<String> {
+        let target = match self.current_render_target {
+            Some(target) => format!("`{}`", program.get_target_defs()[target as usize].name),
+            None => format!("the screen"),
+        };
+        let shader = match self.current_shader {
+            Some(shader) => self.shader_keys[shader as usize].as_str(),
+            None => "none",
+        };
+        let mut blend_modes: Vec<(&u32, &BlendMode)> = self.blend_modes.iter().collect();
+        blend_modes.sort_by_key(|b| b.0);
+        vec![
+            format!("target: {}, viewport: {:?}", target, self.viewport),
+            format!("program: {}", shader),
+            format!("blending: {:?}", blend_modes),
+        ]
+    }
+
+    /// Draws the history of every probe as a bar graph over the top left corner of the screen
+    ///
+    /// Each graph is scaled to the range of its values. The graphs are drawn in the order of `get_probes`.
+    pub fn draw_probes(&mut self) -> Result<(), String> {
+        if self.probes.is_empty() {
+            return Ok(());
+        }
+        self.bind_render_target(None)?;
+        self.set_blending(0, BlendMode::AlphaBlend);
+        self.begin_2d()?;
+
+        let graph_height = 48.0;
+        let graphs: Vec<(f32, f32, Vec<f32>)> = self
+            .probes
+            .iter()
+            .map(|p| {
+                let (min, max) = p.get_range();
+                (min, max, p.values.iter().cloned().collect())
+            })
+            .collect();
+        for (index, (min, max, values)) in graphs.into_iter().enumerate() {
+            let y = 8.0 + index as f32 * (graph_height + 8.0);
+            let background = LinearRGBA::from_f32(0.0, 0.0, 0.0, 0.6);
+            self.draw_rect((8.0, y, PROBE_HISTORY as f32, graph_height), background)?;
+            let color = PROBE_COLORS[index % PROBE_COLORS.len()];
+            let color = LinearRGBA::from_f32(color.0, color.1, color.2, 1.0);
+            for (x, value) in values.into_iter().enumerate() {
+                // Constant probes are drawn at half height
+                let fraction = if max > min { (value - min) / (max - min) } else { 0.5 };
+                let bar_height = (fraction * graph_height).max(1.0);
+                let bar = (8.0 + x as f32, y + graph_height - bar_height, 1.0, bar_height);
+                self.draw_rect(bar, color)?;
+            }
+        }
+
+        self.end_2d()?;
+        self.set_blending(0, BlendMode::None);
+        Ok(())
+    }
+
+    /// Draws the scrub bar over the screen, with a line at the current time and the start of each scene
+    pub fn draw_scrub_bar(&mut self, scrub_bar: &ScrubBar) -> Result<(), String> {
+        self.bind_render_target(None)?;
+        self.set_blending(0, BlendMode::AlphaBlend);
+        self.begin_2d()?;
+
+        let rect = ScrubBar::get_rect(self.screen_size);
+        let (x, y, _, height) = rect;
+        let time_x = scrub_bar.get_x(rect, scrub_bar.time_s);
+        self.draw_rect(rect, LinearRGBA::from_f32(0.0, 0.0, 0.0, 0.6))?;
+        self.draw_rect((x, y, time_x - x, height), LinearRGBA::from_f32(1.0, 1.0, 1.0, 0.25))?;
+        let (r, g, b) = PROBE_COLORS[0];
+        for &marker_s in &scrub_bar.markers {
+            let marker_x = scrub_bar.get_x(rect, marker_s);
+            self.draw_rect((marker_x - 1.0, y, 2.0, height), LinearRGBA::from_f32(r, g, b, 1.0))?;
+        }
+        let white = LinearRGBA::from_f32(1.0, 1.0, 1.0, 1.0);
+        self.draw_rect((time_x - 1.0, y - 4.0, 2.0, height + 8.0), white)?;
+
+        self.end_2d()?;
+        self.set_blending(0, BlendMode::None);
+        Ok(())
+    }
+
+    /// Area of the window the last frame was drawn to as (x, y, width, height), y goes up from the bottom like in GL
+    pub fn get_screen_rect(&self) -> (u32, u32, u32, u32) {
+        let ((x, y), (width, height)) = (self.screen_origin, self.screen_size);
+        (x, y, width, height)
+    }
+
+    /// Luminance statistics of a buffer as of the end of a previous frame, see `LuminanceStats`
+    ///
+    /// The statistics are computed again at the end of every frame in which they are used.
+    pub fn get_luminance_stats(&mut self, target: u32, buffer: u32) -> Result<&LuminanceStats, String> {
+        self.ensure_target(target)?;
+        self.requested_luminance_stats.insert((target, buffer));
+        let stats = self
+            .luminance_stats
+            .entry((target, buffer))
+            .or_insert_with(LuminanceStats::new);
+        stats.poll();
+        Ok(stats)
+    }
+
+    /// Sub-pixel offset to apply to the projection of the current frame, if TAA is used
+    pub fn taa_jitter(&self) -> Option<(f32, f32)> {
+        if self.taa_enabled {
+            Some(postfx::taa_jitter(self.frame_index))
+        } else {
+            None
+        }
+    }
+
+    /// Resolves temporal anti-aliasing into the bound target, and copies the result into the history target
+    ///
+    /// Buffers are given as (target, buffer). The current program has to be bound again afterwards.
+    pub fn post_taa(
+        &mut self,
+        color: (u32, u32),
+        depth: (u32, u32),
+        motion: (u32, u32),
+        history: u32,
+    ) -> Result<(), String> {
+        for &target in &[color.0, depth.0, motion.0, history] {
+            self.ensure_target(target)?;
+        }
+        self.taa_enabled = true;
+        self.history_targets.insert(history);
+        self.begin_internal_pass();
+        self.ensure_builtin_shader(
+            "taa",
+            &[
+                (
+                    "<fullscreen vertex shader>",
+                    postfx::FULLSCREEN_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                ),
+                (
+                    "<taa fragment shader>",
+                    postfx::TAA_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+
+        let shader = &self.builtin_shaders["taa"];
+        shader.bind();
+        self.current_shader = None;
+        self.set_reversed_z_uniform(shader);
+        for &(sampler, (target, buffer)) in &[
+            ("t_Color", color),
+            ("t_Depth", depth),
+            ("t_Motion", motion),
+            ("t_History", (history, 0)),
+        ] {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            if let Some(texture_unit) = shader.get_texture_unit(sampler) {
+                render_target.bind_as_texture(texture_unit, buffer as usize, true);
+            }
+        }
+        self.render_fullscreen_quad();
+
+        let source = match self.current_render_target {
+            Some(target) => Some(
+                self.render_targets
+                    .get(&target)
+                    .ok_or_else(|| format!("Unknown render target: {}", target))?,
+            ),
+            None => None,
+        };
+        self.render_targets
+            .get(&history)
+            .ok_or_else(|| format!("Unknown render target at index {}", history))?
+            .copy_from(source, self.screen_framebuffer, self.screen_origin);
+
+        self.restore_render_target()
+    }
+
+    /// Writes the exposure of the given HDR buffer into the first buffer of the target
+    ///
+    /// The exposure maps the average luminance of the source to middle grey, and moves towards it at the given speed
+    /// (in 1/s) to adapt to brightness changes over time. The current program has to be bound again afterwards.
+    pub fn post_auto_exposure(&mut self, source: (u32, u32), target: u32, speed: f32) -> Result<(), String> {
+        self.ensure_target(source.0)?;
+        self.ensure_target(target)?;
+        self.ensure_builtin_shader(
+            "auto_exposure",
+            &[
+                (
+                    "<fullscreen vertex shader>",
+                    postfx::FULLSCREEN_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                ),
+                (
+                    "<auto exposure fragment shader>",
+                    postfx::AUTO_EXPOSURE_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+
+        self.begin_internal_pass();
+        let mut adaptation = postfx::exposure_adaptation(self.frame_delta_s, speed);
+        let history_key = (self.eye_index, target);
+        if !self.exposure_history.contains_key(&history_key) {
+            let history = RenderTarget::new(1, 1, false, &[RenderTargetFormat::R32F])?;
+            self.exposure_history.insert(history_key, history);
+            adaptation = 1.0;
+        }
+
+        let (width, height) = {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            render_target.bind();
+            (render_target.get_width(), render_target.get_height())
+        };
+        unsafe {
+            gl::Viewport(0, 0, width as GLint, height as GLint);
+        }
+
+        let shader = &self.builtin_shaders["auto_exposure"];
+        shader.bind();
+        self.current_shader = None;
+        let source_target = self
+            .render_targets
+            .get(&source.0)
+            .ok_or_else(|| format!("Unknown render target at index {}", source.0))?;
+        if let Some(texture_unit) = shader.get_texture_unit("t_Source") {
+            source_target.bind_as_texture(texture_unit, source.1 as usize, true);
+        }
+        let history = &self.exposure_history[&history_key];
+        if let Some(texture_unit) = shader.get_texture_unit("t_Previous") {
+            history.bind_as_texture(texture_unit, 0, true);
+        }
+        for location in shader.get_uniform_locations("u_Adaptation") {
+            unsafe {
+                gl::ProgramUniform1f(location.program_id, location.location, adaptation);
+            }
+        }
+        self.render_fullscreen_quad();
+
+        self.exposure_history[&history_key].copy_from(Some(&self.render_targets[&target]), 0, (0, 0));
+        self.restore_render_target()
+    }
+
+    /// Writes the fog in front of the surfaces of the depth buffer into the first buffer of the target
+    ///
+    /// The fog is lit by the given lights, see `postfx::VOLUMETRICS_FRAGMENT_SHADER`. The current program has to be
+    /// bound again afterwards.
+    pub fn post_volumetrics(
+        &mut self,
+        depth: (u32, u32),
+        lights: &[u32],
+        target: u32,
+        density: f32,
+    ) -> Result<(), String> {
+        self.ensure_target(depth.0)?;
+        self.ensure_target(target)?;
+        self.ensure_builtin_shader(
+            "volumetrics",
+            &[
+                (
+                    "<fullscreen vertex shader>",
+                    postfx::FULLSCREEN_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                ),
+                (
+                    "<volumetrics fragment shader>",
+                    postfx::VOLUMETRICS_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+        let inv_projection = self
+            .projection_matrix
+            .inverse()
+            .ok_or_else(|| format!("Projection matrix is non-invertible"))?;
+
+        self.begin_internal_pass();
+        let (width, height) = {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            render_target.bind();
+            (render_target.get_width(), render_target.get_height())
+        };
+        unsafe {
+            gl::Viewport(0, 0, width as GLint, height as GLint);
+        }
+
+        let shader = &self.builtin_shaders["volumetrics"];
+        shader.bind();
+        self.current_shader = None;
+        self.set_reversed_z_uniform(shader);
+        let depth_target = self
+            .render_targets
+            .get(&depth.0)
+            .ok_or_else(|| format!("Unknown render target at index {}", depth.0))?;
+        if let Some(texture_unit) = shader.get_texture_unit("t_Depth") {
+            depth_target.bind_as_texture(texture_unit, depth.1 as usize, true);
+        }
+        for location in shader.get_uniform_locations("u_InvProjectionMatrix") {
+            unsafe {
+                gl::ProgramUniformMatrix4fv(
+                    location.program_id,
+                    location.location,
+                    1,
+                    gl::FALSE,
+                    mem::transmute(&inv_projection),
+                );
+            }
+        }
+        for location in shader.get_uniform_locations("u_Density") {
+            unsafe {
+                gl::ProgramUniform1f(location.program_id, location.location, density);
+            }
+        }
+        let lights: Vec<&FrameLight> = lights.iter().map(|&l| &self.lights[l as usize]).collect();
+        self.bind_lights(shader, &lights);
+        self.render_fullscreen_quad();
+
+        self.restore_render_target()
+    }
+
+    /// Composites the transparent surfaces accumulated in an OIT target over the first buffer of the target
+    ///
+    /// Buffers are given as (target, buffer). The current program has to be bound again afterwards.
+    pub fn post_oit_resolve(&mut self, accum: (u32, u32), revealage: (u32, u32), target: u32) -> Result<(), String> {
+        for &target in &[accum.0, revealage.0, target] {
+            self.ensure_target(target)?;
+        }
+        self.ensure_builtin_shader(
+            "oit_resolve",
+            &[
+                (
+                    "<fullscreen vertex shader>",
+                    postfx::FULLSCREEN_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                ),
+                (
+                    "<oit resolve fragment shader>",
+                    postfx::OIT_RESOLVE_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+
+        self.begin_internal_pass();
+        let (width, height) = {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            render_target.bind();
+            (render_target.get_width(), render_target.get_height())
+        };
+        unsafe {
+            gl::Viewport(0, 0, width as GLint, height as GLint);
+        }
+        let previous_blending = self.blend_modes.get(&0).cloned().unwrap_or(BlendMode::None);
+        self.set_blending(0, BlendMode::AlphaBlend);
+
+        let shader = &self.builtin_shaders["oit_resolve"];
+        shader.bind();
+        self.current_shader = None;
+        for &(sampler, (target, buffer)) in &[("t_Accum", accum), ("t_Revealage", revealage)] {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            if let Some(texture_unit) = shader.get_texture_unit(sampler) {
+                render_target.bind_as_texture(texture_unit, buffer as usize, false);
+            }
+        }
+        self.render_fullscreen_quad();
+
+        self.set_blending(0, previous_blending);
+        self.restore_render_target()
+    }
+
+    /// Applies a lens effect to the given buffer, and draws the result into the bound target
+    ///
+    /// `param` is the strength of the effect, or the seed of the grain, see `postfx::lens_effect`. The current program
+    /// has to be bound again afterwards.
+    pub fn post_lens(&mut self, effect: LensEffect, source: (u32, u32), param: f32) -> Result<(), String> {
+        self.ensure_target(source.0)?;
+        let (name, fragment_shader, param_uniform) = postfx::lens_effect(effect);
+        let label = format!("<{} fragment shader>", name);
+        self.ensure_builtin_shader(
+            name,
+            &[
+                (
+                    "<fullscreen vertex shader>",
+                    postfx::FULLSCREEN_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                ),
+                (&label, fragment_shader, gl::FRAGMENT_SHADER),
+            ],
+        )?;
+
+        self.begin_internal_pass();
+        let shader = &self.builtin_shaders[name];
+        shader.bind();
+        self.current_shader = None;
+        let source_target = self
+            .render_targets
+            .get(&source.0)
+            .ok_or_else(|| format!("Unknown render target at index {}", source.0))?;
+        if let Some(texture_unit) = shader.get_texture_unit("t_Source") {
+            source_target.bind_as_texture(texture_unit, source.1 as usize, true);
+        }
+        for location in shader.get_uniform_locations(param_uniform) {
+            unsafe {
+                gl::ProgramUniform1f(location.program_id, location.location, param);
+            }
+        }
+        self.render_fullscreen_quad();
+
+        self.restore_render_target()
+    }
+
+    /// Copies the current contents of the screen into a texture
+    ///
+    /// The texture is bound to the given uniform by every program which is used afterwards, including in the following
+    /// frames until the screen is grabbed again.
+    pub fn grab_screen(&mut self, uniform_id: u32) -> Result<(), String> {
+        let (width, height) = self.screen_size;
+        let key = (self.eye_index, uniform_id);
+        let recreate = match self.grabbed_screens.get(&key) {
+            Some(target) => target.get_width() != width || target.get_height() != height,
+            None => true,
+        };
+        if recreate {
+            let target = RenderTarget::new(width, height, false, &[RenderTargetFormat::Srgba8])?;
+            self.grabbed_screens.insert(key, target);
+        }
+
+        self.begin_internal_pass();
+        self.grabbed_screens[&key].copy_from(None, self.screen_framebuffer, self.screen_origin);
+        self.restore_render_target()
+    }
+
+    /// Binds the grabbed screens to the current program, if it uses them
+    fn bind_grabbed_screens(&self, shader_id: u32) {
+        for (&(eye_index, uniform_id), target) in &self.grabbed_screens {
+            if eye_index != self.eye_index {
+                continue;
+            }
+            if let Some(texture_unit) = self.uniform_texture_units[shader_id as usize][uniform_id as usize] {
+                target.bind_as_texture(texture_unit, 0, true);
+            }
+        }
+    }
+
+    /// Binds a target for a new pass
+    ///
+    /// The viewport is reset to cover the whole target, and the pipeline state to its defaults, so that no state leaks
+    /// from the previous pass.
+    pub fn bind_render_target(&mut self, target: Option<u32>) -> Result<(), String> {
+        self.finish_scaled_pass()?;
+        if let Some(target) = target {
+            self.ensure_target(target)?;
+            if let Some(render_target) = self.render_targets.get(&target) {
+                render_target.bind();
+                self.current_render_target = Some(target);
+            } else {
+                return Err(format!("Unknown render target: {}", target));
+            }
+        } else {
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, self.screen_framebuffer);
+            }
+            self.current_render_target = None;
+        }
+
+        let (width, height) = self.current_target_size();
+        self.viewport_rect(0, 0, width, height);
+        self.reset_pipeline_state();
+        self.apply_screen_scissor();
+        Ok(())
+    }
+
+    /// Binds a target for a new pass which is drawn at a fraction of the size of the target
+    ///
+    /// The pass is drawn into a smaller copy of the target, which starts out transparent black with the depth of the
+    /// target. When the pass ends, the copy is upsampled and blended over the target as premultiplied alpha. Viewports
+    /// are still given in pixels of the target.
+    pub fn bind_scaled_render_target(&mut self, target: u32, scale: f32) -> Result<(), String> {
+        self.finish_scaled_pass()?;
+        self.ensure_target(target)?;
+        let scale = (scale * self.dynamic_scale).max(MIN_PASS_SCALE).min(1.0);
+        let has_depth = {
+            let spec = &self.render_target_specs[&target];
+            let width = ((spec.width as f32 * scale).round() as u32).max(1);
+            let height = ((spec.height as f32 * scale).round() as u32).max(1);
+            let reusable = self
+                .scaled_targets
+                .get(&target)
+                .map_or(false, |t| t.get_width() == width && t.get_height() == height);
+            if !reusable {
+                let scaled_target = RenderTarget::new(width, height, spec.has_depth, &spec.formats)?;
+                self.scaled_targets.insert(target, scaled_target);
+            }
+            spec.has_depth || spec.formats.iter().any(|f| f.is_depth())
+        };
+
+        self.scaled_targets[&target].bind();
+        self.current_render_target = Some(target);
+        self.scaled_pass = Some(target);
+        self.reset_pipeline_state();
+        self.apply_screen_scissor();
+        unsafe {
+            gl::ClearColor(0.0, 0.0, 0.0, 0.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+        }
+        if has_depth {
+            self.scaled_targets[&target].copy_depth_from(&self.render_targets[&target]);
+        }
+        let (width, height) = self.current_target_size();
+        self.viewport_rect(0, 0, width, height);
+        Ok(())
+    }
+
+    /// Ends the current scaled pass by blending the smaller copy of its target over the target
+    fn finish_scaled_pass(&mut self) -> Result<(), String> {
+        let target = match self.scaled_pass.take() {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        self.ensure_builtin_shader(
+            "upsample",
+            &[
+                (
+                    "<fullscreen vertex shader>",
+                    postfx::FULLSCREEN_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                ),
+                (
+                    "<upsample fragment shader>",
+                    postfx::UPSAMPLE_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+
+        self.begin_internal_pass();
+        self.reset_pipeline_state();
+        let (width, height) = {
+            let render_target = self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target at index {}", target))?;
+            render_target.bind();
+            (render_target.get_width(), render_target.get_height())
+        };
+        let color_buffers = self.render_target_specs[&target]
+            .formats
+            .iter()
+            .filter(|f| !f.is_depth())
+            .count();
+        let shader = &self.builtin_shaders["upsample"];
+        shader.bind();
+        self.current_shader = None;
+        let texture_unit = shader.get_texture_unit("t_Source");
+        unsafe {
+            gl::Viewport(0, 0, width as GLint, height as GLint);
+            gl::Disable(gl::DEPTH_TEST);
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+        }
+        for buffer in 0..color_buffers {
+            self.render_targets[&target].select_draw_buffer(Some(buffer));
+            if let Some(texture_unit) = texture_unit {
+                self.scaled_targets[&target].bind_as_texture(texture_unit, buffer, true);
+            }
+            self.render_fullscreen_quad();
+        }
+        self.render_targets[&target].select_draw_buffer(None);
+        unsafe {
+            gl::BlendFunc(gl::ONE, gl::ZERO);
+            gl::Disable(gl::BLEND);
+            gl::Enable(gl::DEPTH_TEST);
+        }
+        Ok(())
+    }
+
+    /// Ratio of the size of the smaller copy to the size of the target during a scaled pass
+    fn scaled_pass_ratio(&self) -> Option<(f32, f32)> {
+        let target = self.scaled_pass?;
+        let scaled_target = self.scaled_targets.get(&target)?;
+        let render_target = self.render_targets.get(&target)?;
+        Some((
+            scaled_target.get_width() as f32 / render_target.get_width() as f32,
+            scaled_target.get_height() as f32 / render_target.get_height() as f32,
+        ))
+    }
+
+    /// Moves the scale of passes towards the one which fits the GPU time of the last frames into the frame time
+    fn update_dynamic_scale(&mut self) {
+        let elapsed_s = match self.gpu_timer.poll() {
+            Some(elapsed_s) => elapsed_s,
+            None => return,
+        };
+        if let Some(frame_time_s) = self.target_frame_time_s {
+            // Only the area of the scaled passes shrinks with the scale, hence the small steps
+            let budget_s = frame_time_s * DYNAMIC_RESOLUTION_HEADROOM;
+            let step = (budget_s / elapsed_s.max(1e-6)).sqrt().max(0.9).min(1.05);
+            self.dynamic_scale = (self.dynamic_scale * step).max(MIN_DYNAMIC_SCALE).min(1.0);
+        }
+    }
+
+    /// Binds the current target and viewport again after an internal pass, keeping the state of the current pass
+    fn restore_render_target(&mut self) -> Result<(), String> {
+        match self.current_render_target {
+            Some(target) if self.scaled_pass == Some(target) => self.scaled_targets[&target].bind(),
+            Some(target) => self
+                .render_targets
+                .get(&target)
+                .ok_or_else(|| format!("Unknown render target: {}", target))?
+                .bind(),
+            None => unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, self.screen_framebuffer);
+            },
+        }
+        let (x, y, width, height) = self.viewport;
+        self.viewport_rect(x, y, width, height);
+        self.apply_screen_scissor();
+        self.enable_clip_distances(true);
+        Ok(())
+    }
+
+    /// Prepares the state for a pass which is not part of the script, see `restore_render_target`
+    fn begin_internal_pass(&self) {
+        unsafe {
+            gl::Disable(gl::SCISSOR_TEST);
+        }
+        self.enable_clip_distances(false);
+    }
+
+    /// Enables the clip distances of the clip planes which are set, or disables all of them
+    fn enable_clip_distances(&self, enable: bool) {
+        for (index, plane) in self.clip_planes.iter().enumerate() {
+            if plane.is_some() {
+                unsafe {
+                    if enable {
+                        gl::Enable(gl::CLIP_DISTANCE0 + index as GLenum);
+                    } else {
+                        gl::Disable(gl::CLIP_DISTANCE0 + index as GLenum);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keeps clears of the screen from drawing over the black bars
+    fn apply_screen_scissor(&self) {
+        let (x, y) = self.screen_origin;
+        let (width, height) = self.screen_size;
+        unsafe {
+            if self.letterboxed && self.current_render_target.is_none() {
+                gl::Enable(gl::SCISSOR_TEST);
+                gl::Scissor(x as GLint, y as GLint, width as GLint, height as GLint);
+            } else {
+                gl::Disable(gl::SCISSOR_TEST);
+            }
+        }
+    }
+
+    /// Size of the bound target in pixels
+    fn current_target_size(&self) -> (u32, u32) {
+        let render_target = self
+            .current_render_target
+            .and_then(|target| self.render_targets.get(&target));
+        match render_target {
+            Some(render_target) => (render_target.get_width(), render_target.get_height()),
+            None => self.screen_size,
+        }
+    }
+
+    /// Restores the pipeline state which is set up when the context is created
+    fn reset_pipeline_state(&mut self) {
+        let buffers: Vec<u32> = self.blend_modes.keys().cloned().collect();
+        for buffer in buffers {
+            self.set_blending(buffer, BlendMode::None);
+        }
+        self.set_write_mask(true, true);
+        self.set_culling(CullingMode::Back);
+        self.enable_clip_distances(false);
+        self.clip_planes = [None; bytecode::MAX_CLIP_PLANES as usize];
+        unsafe {
+            gl::DepthFunc(if self.reversed_z { gl::GREATER } else { gl::LESS });
+        }
+    }
+
+    /// Sets the viewport of the bound target, viewports of the screen are relative to the area the demo is rendered to
+    pub fn viewport_rect(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        self.viewport = (x, y, width, height);
+        let (x, y, width, height) = match self.scaled_pass_ratio() {
+            Some((ratio_x, ratio_y)) => (
+                (x as f32 * ratio_x).round() as u32,
+                (y as f32 * ratio_y).round() as u32,
+                (width as f32 * ratio_x).round() as u32,
+                (height as f32 * ratio_y).round() as u32,
+            ),
+            None => (x, y, width, height),
+        };
+        let (origin_x, origin_y) = match self.current_render_target {
+            Some(_) => (0, 0),
+            None => self.screen_origin,
+        };
+        unsafe {
+            gl::Viewport(
+                (origin_x + x) as GLint,
+                (origin_y + y) as GLint,
+                width as GLint,
+                height as GLint,
+            );
+        }
+    }
+
+    /// Warns once per target when drawing with a viewport which extends past the bound target
+    pub fn validate_viewport(&mut self) {
+        let (x, y, width, height) = self.viewport;
+        let (target_width, target_height) = self.current_target_size();
+        if x + width <= target_width && y + height <= target_height {
+            return;
+        }
+        if self.oversized_viewport_targets.insert(self.current_render_target) {
+            let target = match self.current_render_target {
+                Some(target) => format!("render target {}", target),
+                None => format!("the screen"),
+            };
+            println!(
+                "Warning: the viewport ({}, {}, {}, {}) extends past {} ({}x{})",
+                x, y, width, height, target, target_width, target_height
+            );
+        }
+    }
+
+    /// Tells the driver that the contents of the bound target are not needed anymore
+    pub fn invalidate_render_target(&mut self) {
+        // Scaled passes are blended over the contents of their target, which are kept
+        if self.scaled_pass.is_some() {
+            return;
+        }
+        let attachments: Vec<GLenum> = match self.current_render_target {
+            Some(target) => match self.render_targets.get(&target) {
+                Some(render_target) => render_target.get_attachments().to_vec(),
+                None => return,
+            },
+            None => vec![gl::COLOR, gl::DEPTH],
+        };
+        unsafe {
+            gl::InvalidateFramebuffer(gl::FRAMEBUFFER, attachments.len() as GLint, attachments.as_ptr());
+        }
+    }
+
+    pub fn clear(&mut self, linear: LinearRGBA) {
+        unsafe {
+            gl::ClearColor(linear.r, linear.g, linear.b, linear.a);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+    }
+
+    /// Clears the given color buffers of the bound target to the given (buffer, color), and the depth buffer if set
+    ///
+    /// Buffers which are not given keep their contents.
+    pub fn clear_buffers(&mut self, colors: &[(u32, LinearRGBA)], depth: Option<f32>) {
+        unsafe {
+            for (buffer, color) in colors {
+                let color: [GLfloat; 4] = [color.r, color.g, color.b, color.a];
+                gl::ClearBufferfv(gl::COLOR, *buffer as GLint, color.as_ptr());
+            }
+            if let Some(depth) = depth {
+                gl::ClearBufferfv(gl::DEPTH, 0, &self.stored_depth(depth));
+            }
+        }
+    }
+
+    /// Clears the buffers of an OIT target to their initial values, see `bytecode::OIT_BUFFERS`
+    pub fn clear_oit(&mut self) {
+        let accum: [GLfloat; 4] = [0.0, 0.0, 0.0, 0.0];
+        let revealage: [GLfloat; 4] = [1.0, 1.0, 1.0, 1.0];
+        let depth: GLfloat = self.stored_depth(1.0);
+        unsafe {
+            gl::ClearBufferfv(gl::COLOR, 0, accum.as_ptr());
+            gl::ClearBufferfv(gl::COLOR, 1, revealage.as_ptr());
+            gl::ClearBufferfv(gl::DEPTH, 0, &depth);
+        }
+    }
+
+    pub fn set_blending(&mut self, buffer: u32, mode: BlendMode) {
+        unsafe {
+            match mode {
+                BlendMode::None => {
+                    gl::BlendFunci(buffer, gl::ONE, gl::ZERO);
+                    self.blend_modes.remove(&buffer);
+                    if self.blend_modes.is_empty() {
+                        gl::Disable(gl::BLEND);
+                    }
+                }
+                BlendMode::Add => {
+                    if self.blend_modes.is_empty() {
+                        gl::Enable(gl::BLEND);
+                    }
+                    self.blend_modes.insert(buffer, mode);
+                    gl::BlendFunci(buffer, gl::ONE, gl::ONE);
+                }
+                BlendMode::AlphaBlend => {
+                    if self.blend_modes.is_empty() {
+                        gl::Enable(gl::BLEND);
+                    }
+                    self.blend_modes.insert(buffer, mode);
+                    gl::BlendFunci(buffer, gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+                }
+                BlendMode::OitCoverageBlend => {
+                    if self.blend_modes.is_empty() {
+                        gl::Enable(gl::BLEND);
+                    }
+                    self.blend_modes.insert(buffer, mode);
+                    gl::BlendFunci(buffer, gl::ZERO, gl::ONE_MINUS_SRC_ALPHA);
+                }
+            }
+        }
+    }
+
+    pub fn set_write_mask(&mut self, write_color: bool, write_depth: bool) {
+        unsafe {
+            gl::ColorMask(
+                write_color as u8,
+                write_color as u8,
+                write_color as u8,
+                write_color as u8,
+            );
+            gl::DepthMask(write_depth as u8);
+        }
+    }
+
+    pub fn set_z_test(&mut self, mode: ZTestMode) {
+        let mode = match mode {
+            ZTestMode::LessEqual if self.reversed_z => gl::GEQUAL,
+            ZTestMode::LessEqual => gl::LEQUAL,
+            ZTestMode::Equal => gl::EQUAL,
+            ZTestMode::Always => gl::ALWAYS,
+        };
+
+        unsafe {
+            gl::DepthFunc(mode);
+        }
+    }
+
+    /// Enables a clip distance, which discards the geometry behind the given plane in world space
+    ///
+    /// Programs write the distances to `gl_ClipDistance` themselves, with the planes in view space from
+    /// `u_ClipPlanes[index]`, e.g. `dot(u_ClipPlanes[0], u_ModelViewMatrix * vec4(position, 1.0))`.
+    pub fn set_clip_plane(&mut self, index: u32, plane: glm::Vec4) {
+        self.clip_planes[index as usize] = Some(plane);
+        unsafe {
+            gl::Enable(gl::CLIP_DISTANCE0 + index);
+        }
+        self.set_clip_plane_uniforms();
+    }
+
+    /// Packs the clip planes into `u_ClipPlanes` of the current program, planes which are not set are zero
+    fn set_clip_plane_uniforms(&self) {
+        let shader = match self.current_shader {
+            Some(shader_id) => &self.shaders[shader_id as usize],
+            None => return,
+        };
+        let view_inv_transp = match self.view_matrix.inverse() {
+            Some(m) => m.transpose(),
+            None => return,
+        };
+        let mut planes = Vec::with_capacity(self.clip_planes.len() * 4);
+        for plane in &self.clip_planes {
+            let plane = view_inv_transp * plane.unwrap_or(glm::Vec4::new(0.0, 0.0, 0.0, 0.0));
+            planes.extend_from_slice(&[plane.x, plane.y, plane.z, plane.w]);
+        }
+        for location in shader.get_uniform_locations("u_ClipPlanes") {
+            unsafe {
+                gl::ProgramUniform4fv(
+                    location.program_id,
+                    location.location,
+                    self.clip_planes.len() as GLint,
+                    planes.as_ptr(),
+                );
+            }
+        }
+    }
+
+    pub fn set_culling(&mut self, mode: CullingMode) {
+        let mode: Option<GLenum> = match mode {
+            CullingMode::Front => Some(gl::FRONT),
+            CullingMode::Back => Some(gl::BACK),
+            CullingMode::None => None
+        };
+
+        unsafe {
+            if let Some(mode) = mode {
+                gl::Enable(gl::CULL_FACE);
+                gl::CullFace(mode);
+            } else {
+                gl::Disable(gl::CULL_FACE);
+            }
+        }
+
+    }
+
+    /// Compiles the shaders of all given programs
+    ///
+    /// All stages are submitted before waiting for any of them, so that drivers supporting
+    /// KHR_parallel_shader_compile can compile them concurrently. Programs which fail to compile or link are replaced
+    /// by the error shader, so that the rest of the demo keeps running.
+    pub fn push_new_shaders(&mut self, programs: &[bytecode::ProgramDef]) -> Result<(), String> {
+        let mut pipelines = Vec::new();
+        for program in programs {
+            if let Some(map_file) = &program.raymarch {
+                let vs = self.get_or_push_shader_stage(
+                    "<raymarch vertex shader>",
+                    postfx::RAYMARCH_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                )?;
+                let fs = self.get_or_compile_raymarch_stage(map_file)?;
+                pipelines.push(vec![vs, fs]);
+                continue;
+            }
+            let vert_file = program.vert.as_ref().ok_or_else(|| format!("Missing vertex shader"))?;
+            let frag_file = program
+                .frag
+                .as_ref()
+                .ok_or_else(|| format!("Missing fragment shader"))?;
+            let optional_stages = [
+                (&program.tess_ctrl, gl::TESS_CONTROL_SHADER),
+                (&program.tess_eval, gl::TESS_EVALUATION_SHADER),
+                (&program.geom, gl::GEOMETRY_SHADER),
+            ];
+            let mut stages = vec![self.get_or_compile_shader_stage(vert_file, gl::VERTEX_SHADER)?];
+            for (file, stage) in optional_stages.iter() {
+                if let Some(file) = file {
+                    stages.push(self.get_or_compile_shader_stage(file, *stage)?);
+                }
+            }
+            stages.push(self.get_or_compile_shader_stage(frag_file, gl::FRAGMENT_SHADER)?);
+            pipelines.push(stages);
+        }
+        self.shader_keys.extend(programs.iter().map(program_key));
+
+        let stage_errors = self.finish_shader_stages();
+        for stages in pipelines {
+            let errors: Vec<&str> = stages
+                .iter()
+                .filter_map(|idx| stage_errors.get(idx).map(|e| e.as_str()))
+                .collect();
+            let shader = if errors.is_empty() {
+                let stages: Vec<&ShaderStage> = stages.iter().map(|&idx| &*self.shader_stages[idx]).collect();
+                ShaderPipeline::from_stages(&stages)
+            } else {
+                Err(errors.join("\n"))
+            };
+
+            match shader {
+                Ok(shader) => {
+                    self.shaders.push(shader);
+                    self.shader_errors.push(None);
+                }
+                Err(e) => {
+                    println!("{}\n ~ Using the error shader instead", e);
+                    let shader = self.make_error_shader()?;
+                    self.shaders.push(shader);
+                    self.shader_errors.push(Some(e));
+                }
+            }
+        }
+        self.restore_uniform_values();
+        Ok(())
+    }
+
+    /// Sets the last values the script gave to the uniforms of each program, which survive reloads in the cache
+    ///
+    /// Values set in programs which were not recompiled are still there, this matters for the recompiled ones.
+    fn restore_uniform_values(&self) {
+        for ((key, uniform_name), value) in &self.resource_cache.uniform_values {
+            let shaders = self.shader_keys.iter().zip(&self.shaders);
+            for (_, shader) in shaders.filter(|(shader_key, _)| *shader_key == key) {
+                for uniform in shader.get_uniform_locations(uniform_name) {
+                    value.apply(&uniform);
+                }
+            }
+        }
+    }
+
+    /// Remembers the value of a uniform set by the script in the current program, see `restore_uniform_values`
+    fn remember_uniform_value(&mut self, uniform_id: u32, value: UniformValue) {
+        if let Some(shader_id) = self.current_shader {
+            let key = self.shader_keys[shader_id as usize].clone();
+            let uniform_name = self.uniform_names[uniform_id as usize].clone();
+            if let Some(recording) = self.recording.as_mut() {
+                let uniform = (key.clone(), uniform_name.clone());
+                recording.uniforms.insert(uniform, format!("{}", value));
+            }
+            self.resource_cache.uniform_values.insert((key, uniform_name), value);
+        }
+    }
+
+    /// Returns the index of the stage compiled from the given file, the file is only compiled the first time
+    fn get_or_compile_shader_stage(&mut self, file: &str, stage: GLenum) -> Result<usize, String> {
+        if let Some(&idx) = self.shader_stage_files.get(file) {
+            return Ok(idx);
+        }
+
+        let src = self.load_shader(Path::new(file))?;
+        self.push_shader_stage(file, &src, stage)
+    }
+
+    /// Returns the index of the raymarching stage built around the SDF of the given file, see `postfx::raymarch_shader`
+    fn get_or_compile_raymarch_stage(&mut self, file: &str) -> Result<usize, String> {
+        let name = format!("{} <raymarch>", file);
+        if let Some(&idx) = self.shader_stage_files.get(&name) {
+            return Ok(idx);
+        }
+
+        let src = postfx::raymarch_shader(&self.load_shader(Path::new(file))?);
+        self.push_shader_stage(&name, &src, gl::FRAGMENT_SHADER)
+    }
+
+    /// Returns the index of the stage with the given name, the source is only compiled the first time
+    fn get_or_push_shader_stage(&mut self, name: &str, src: &str, stage: GLenum) -> Result<usize, String> {
+        match self.shader_stage_files.get(name) {
+            Some(&idx) => Ok(idx),
+            None => self.push_shader_stage(name, src, stage),
+        }
+    }
+
+    fn push_shader_stage(&mut self, name: &str, src: &str, stage: GLenum) -> Result<usize, String> {
+        let mut hasher = DefaultHasher::new();
+        src.hash(&mut hasher);
+        let key = (hasher.finish(), stage);
+
+        let shader_stage = match self.resource_cache.shader_stages.get(&key) {
+            Some(shader_stage) => shader_stage.clone(),
+            None => {
+                let shader_stage =
+                    Rc::new(ShaderStage::start_compile(src, stage).map_err(|e| format!("{}: {}", name, e))?);
+                self.resource_cache.shader_stages.insert(key, shader_stage.clone());
+                shader_stage
+            }
+        };
+        self.shader_stages.push(shader_stage);
+        let idx = self.shader_stages.len() - 1;
+        self.shader_stage_files.insert(name.to_owned(), idx);
+        Ok(idx)
+    }
+
+    /// Waits until all shader stages are compiled, and returns the compile errors by stage index
+    fn finish_shader_stages(&self) -> HashMap<usize, String> {
+        if gl_resources::supports_parallel_shader_compile() {
+            while !self.shader_stages.iter().all(|s| s.is_compile_complete()) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        self.shader_stage_files
+            .iter()
+            .filter_map(|(file, &idx)| {
+                self.shader_stages[idx]
+                    .finish_compile()
+                    .err()
+                    .map(|e| (idx, format!("{}: {}", file, e)))
+            })
+            .collect()
+    }
+
+    /// Creates a pipeline from the built-in error shader stages
+    fn make_error_shader(&mut self) -> Result<ShaderPipeline, String> {
+        self.make_builtin_shader(&[
+            ("<error vertex shader>", ERROR_VERTEX_SHADER, gl::VERTEX_SHADER),
+            ("<error fragment shader>", ERROR_FRAGMENT_SHADER, gl::FRAGMENT_SHADER),
+        ])
+    }
+
+    /// Creates the built-in shader with the given key from (name, source, stage) stages, unless it already exists
+    fn ensure_builtin_shader(&mut self, key: &'static str, stages: &[(&str, &str, GLenum)]) -> Result<(), String> {
+        if !self.builtin_shaders.contains_key(key) {
+            let shader = self.make_builtin_shader(stages)?;
+            self.builtin_shaders.insert(key, shader);
+        }
+        Ok(())
+    }
+
+    /// Creates a pipeline from built-in (name, source, stage) stages, which are compiled the first time they are needed
+    fn make_builtin_shader(&mut self, builtin_stages: &[(&str, &str, GLenum)]) -> Result<ShaderPipeline, String> {
+        let mut stages = Vec::new();
+        for &(name, src, stage) in builtin_stages {
+            let idx = match self.shader_stage_files.get(name) {
+                Some(&idx) => idx,
+                None => {
+                    let idx = self.push_shader_stage(name, src, stage)?;
+                    self.shader_stages[idx].finish_compile()?;
+                    idx
+                }
+            };
+            stages.push(idx);
+        }
+        let stages: Vec<&ShaderStage> = stages.iter().map(|&idx| &*self.shader_stages[idx]).collect();
+        ShaderPipeline::from_stages(&stages)
+    }
+
+    pub fn push_new_model(&mut self, model_def: &bytecode::ModelDef) -> Result<(), String> {
+        let path = Path::new(&model_def.path);
+        // Terrains are cached by their heightmap and their parameters
+        let hash = self.hash_file(path).map(|hash| match model_def.terrain {
+            Some(terrain) => {
+                let mut hasher = DefaultHasher::new();
+                let terrain_key = (terrain.size.to_bits(), terrain.max_height.to_bits(), terrain.resolution);
+                (hash, terrain_key).hash(&mut hasher);
+                hasher.finish()
+            }
+            None => hash,
+        });
+        if let Some(model) = hash.and_then(|hash| self.resource_cache.models.get(&hash)).cloned() {
+            let materials = self.load_model_materials(path, &model);
+            self.model_materials.push(materials);
+            self.models.push(model);
+            return Ok(());
+        }
 
+        let model = match self.load_mesh(model_def) {
+            Ok(mesh) => {
+                let model = Rc::new(Model::from_mesh(mesh));
+                if let Some(hash) = hash {
+                    self.resource_cache.models.insert(hash, model.clone());
+                }
+                model
+            }
+            Err(_) => {
+                println!(
+                    "Warning: Could not load model {:?}, using a cube instead",
+                    model_def.path
+                );
+                Rc::new(Model::from_mesh(Mesh::unit_cube()))
+            }
+        };
+
+        let materials = self.load_model_materials(path, &model);
+        self.model_materials.push(materials);
         self.models.push(model);
         Ok(())
     }
 
-    pub fn push_new_texture(&mut self, texture_file: &str, srgb: bool) -> Result<(), String> {
-        let path: &PathBuf = &self.parent_dir;
+    /// Loads the parts of a batch and uploads them merged into one model, parts which fail to load become cubes
+    pub fn push_new_batch(&mut self, batch_def: &bytecode::BatchDef) -> Result<(), String> {
+        let mut parts = Vec::new();
+        for part in &batch_def.parts {
+            let mesh = self.load_mesh(&part.model).unwrap_or_else(|_| {
+                println!(
+                    "Warning: Could not load model {:?} of batch `{}`, using a cube instead",
+                    part.model.path, batch_def.name
+                );
+                Mesh::unit_cube()
+            });
+            let transform: Vec<(&str, f32)> = part.transform.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+            let matrix = transform_matrix(&identity_4(), &transform)
+                .map_err(|key| format!("Unknown transform `{}` of batch `{}`", key, batch_def.name))?;
+            parts.push((mesh, matrix));
+        }
+        self.batches.push(Model::from_mesh(Mesh::merge(&parts)));
+        Ok(())
+    }
+
+    /// Reads the mesh of a model file or of a terrain
+    fn load_mesh(&self, model_def: &bytecode::ModelDef) -> Result<Mesh, ()> {
+        let path = Path::new(&model_def.path);
+        match &model_def.terrain {
+            Some(terrain) => Mesh::load_terrain(&*self.resources, path, terrain),
+            None => Mesh::load_obj_file(&*self.resources, path),
+        }
+    }
+
+    /// Loads the materials of the groups of a model from its MTL file, groups without a material get `None`
+    fn load_model_materials(&mut self, model_path: &Path, model: &Model) -> Vec<Option<Rc<ModelMaterial>>> {
+        let mut result = vec![None; model.get_groups().len()];
+        let library = match model.get_material_library() {
+            Some(library) => model_path.parent().unwrap_or(Path::new("")).join(library),
+            None => return result,
+        };
+        let mtl = match self.resources.read_to_string(&library) {
+            Ok(source) => gl_resources::parse_mtl(&source),
+            Err(_) => {
+                println!("Warning: Could not load material library {:?}", library);
+                return result;
+            }
+        };
+
+        let mut loaded: HashMap<&str, Rc<ModelMaterial>> = HashMap::new();
+        for (group, material) in model.get_groups().iter().enumerate() {
+            let name = match &material.material {
+                Some(name) => name.as_str(),
+                None => continue,
+            };
+            if let Some(material) = loaded.get(name) {
+                result[group] = Some(material.clone());
+                continue;
+            }
+            let mtl_material = match mtl.get(name) {
+                Some(mtl_material) => mtl_material,
+                None => {
+                    println!("Warning: Material {:?} is missing in {:?}", name, library);
+                    continue;
+                }
+            };
+
+            let mut textures = Vec::new();
+            for (map, file) in &mtl_material.maps {
+                let srgb = bytecode::is_srgb_material_map(map);
+                let path = library.parent().unwrap_or(Path::new("")).join(file);
+                let options = bytecode::TextureImportOptions::default();
+                let texture = self.load_texture(&path, srgb, options).unwrap_or_else(|| {
+                    println!(
+                        "Warning: Could not load texture {:?}, using a checkerboard instead",
+                        path
+                    );
+                    Rc::new(Texture::checkerboard(srgb))
+                });
+                textures.push((bytecode::material_uniform_name(map, true), texture));
+            }
+            let material = Rc::new(ModelMaterial {
+                textures: textures,
+                colors: mtl_material
+                    .colors
+                    .iter()
+                    .map(|&(key, [r, g, b])| {
+                        let color = SrgbRGBA::from_f32(r, g, b, 1.0).into();
+                        (bytecode::material_uniform_name(key, false), color)
+                    })
+                    .collect(),
+                values: mtl_material
+                    .values
+                    .iter()
+                    .map(|&(key, value)| (bytecode::material_uniform_name(key, false), value))
+                    .collect(),
+            });
+            loaded.insert(name, material.clone());
+            result[group] = Some(material);
+        }
+        result
+    }
 
-        let texture = Texture::load_file(&path.join(texture_file), srgb)
-            .map_err(|_| format!("Could not load texture {:?}", texture_file))?;
+    pub fn push_new_texture(&mut self, texture_def: &bytecode::TextureDef) -> Result<(), String> {
+        let path = Path::new(&texture_def.path);
+        let srgb = texture_def.srgb;
+        let texture = self.load_texture(path, srgb, texture_def.options).unwrap_or_else(|| {
+            println!(
+                "Warning: Could not load texture {:?}, using a checkerboard instead",
+                texture_def.path
+            );
+            Rc::new(Texture::checkerboard(srgb))
+        });
 
         self.textures.push(texture);
         Ok(())
     }
 
-    pub fn push_new_ibl(&mut self, ibl_folder: &str) -> Result<(), String> {
-        let path: &PathBuf = &self.parent_dir;
+    /// Loads a texture, or takes it from the resource cache if neither the file nor the import options changed
+    fn load_texture(
+        &mut self,
+        path: &Path,
+        srgb: bool,
+        options: bytecode::TextureImportOptions,
+    ) -> Option<Rc<Texture>> {
+        let key = self.hash_file(path).map(|hash| {
+            let mut hasher = DefaultHasher::new();
+            let options_key = (options.exposure.to_bits(), options.premultiply_alpha, options.flip_y);
+            (hash, options_key).hash(&mut hasher);
+            (hasher.finish(), srgb)
+        });
+        if let Some(texture) = key.and_then(|key| self.resource_cache.textures.get(&key)) {
+            return Some(texture.clone());
+        }
+
+        let texture = Rc::new(Texture::load_file(&*self.resources, path, srgb, &options).ok()?);
+        if let Some(key) = key {
+            self.resource_cache.textures.insert(key, texture.clone());
+        }
+        Some(texture)
+    }
 
-        let ibl = Ibl::load_folder(&path.join(ibl_folder))
-            .map_err(|_| format!("Could not load ibl folder: {:?}", ibl_folder))?;
+    pub fn push_new_ibl(&mut self, ibl_folder: &str) -> Result<(), String> {
+        let ibl = Ibl::load_folder(&*self.resources, Path::new(ibl_folder)).unwrap_or_else(|_| {
+            println!(
+                "Warning: Could not load ibl folder {:?}, using a grey environment instead",
+                ibl_folder
+            );
+            Ibl::grey()
+        });
 
         self.ibls.push(ibl);
         Ok(())
     }
 
+    /// Hash of the contents of a file, used to share resources created from identical files
+    fn hash_file(&self, path: &Path) -> Option<u64> {
+        let bytes = self.resources.read(path).ok()?;
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Drops cached resources which are not used by this context, once all resources are loaded
+    pub fn prune_resource_cache(&mut self) {
+        self.resource_cache.prune();
+    }
+
+    /// Releases the context, keeping the resources in the cache so that they can be shared with the next context
+    pub fn into_resource_cache(self) -> ResourceCache {
+        self.resource_cache
+    }
+
     pub fn use_shaders(&mut self, shader_id: u32) -> Result<(), String> {
         let shader = &self.shaders[shader_id as usize];
         shader.bind();
 
         self.current_shader = Some(shader_id);
-        self.next_free_texture_unit = 0;
+        self.pass_params = match self.shaders[shader_id as usize].get_draw_params_layout() {
+            Some(layout) => vec![0; layout.size],
+            None => Vec::new(),
+        };
+        self.pass_params_pending = !self.pass_params.is_empty();
+        self.draw_params_bound = false;
+
+        self.set_matrix_uniforms()?;
+        self.set_light_uniforms();
+        self.set_clip_plane_uniforms();
+        self.bind_grabbed_screens(shader_id);
+
+        Ok(())
+    }
+
+    /// Sets the matrix uniforms of the current program
+    fn set_matrix_uniforms(&mut self) -> Result<(), String> {
+        if self.current_shader.is_none() {
+            return Ok(());
+        }
 
-        // Set uniforms
         let mv = self.view_matrix * self.model_matrix;
         let mvp = self.projection_matrix * mv;
         let mv_it = &mv
@@ -360,117 +2578,562 @@ impl RenderContext {
         let _ = self.set_uniform_mat4("u_ModelViewProjectionMatrix", &mvp);
         let _ = self.set_uniform_mat4("u_ModelViewMatrix", &mv);
         let _ = self.set_uniform_mat4("u_ModelViewInvTranspMatrix", &mv_it);
+        if let Some(shader_id) = self.current_shader {
+            self.set_reversed_z_uniform(&self.shaders[shader_id as usize]);
+            if self.shaders[shader_id as usize].uses_engine_uniforms() {
+                // In the order of the members of the block, std140 lays out mat4 like glm
+                let matrices = [mvp, mv, *mv_it];
+                let data =
+                    unsafe { slice::from_raw_parts(matrices.as_ptr() as *const u8, mem::size_of_val(&matrices)) };
+                self.uniform_ring
+                    .bind_data(gl_resources::ENGINE_UNIFORMS_BINDING, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes values to the `gl_resources::DRAW_PARAMS_BLOCK` of the current program, for the next draw
+    ///
+    /// Like uniforms, values which the program does not use are skipped.
+    pub fn bind_draw_params(&mut self, params: &[(&str, Value)]) -> Result<(), String> {
+        let data = {
+            let layout = match self.current_shader {
+                Some(shader_id) => match self.shaders[shader_id as usize].get_draw_params_layout() {
+                    Some(layout) => layout,
+                    None => return Ok(()),
+                },
+                None => return Err(format!("Draw parameters need a bound program")),
+            };
+            // Members without a parameter keep the values of the pass
+            let mut data = self.pass_params.clone();
+            for (name, value) in params {
+                let components = value.as_components().unwrap_or_default();
+                layout
+                    .write(&mut data, name, &components)
+                    .map_err(|e| format!("Draw parameter {}", e))?;
+            }
+            data
+        };
+        self.uniform_ring.bind_data(gl_resources::DRAW_PARAMS_BINDING, &data)?;
+        self.draw_params_bound = true;
+        Ok(())
+    }
+
+    /// Writes a uniform of the script to the pass parameters, if the current program has it in its
+    /// `gl_resources::DRAW_PARAMS_BLOCK`, returns false otherwise
+    fn stage_pass_param(&mut self, uniform_id: u32, value: &UniformValue) -> Result<bool, String> {
+        let layout = match self.current_shader {
+            Some(shader_id) => match self.shaders[shader_id as usize].get_draw_params_layout() {
+                Some(layout) => layout,
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        let name = &self.uniform_names[uniform_id as usize];
+        let staged = layout
+            .write(&mut self.pass_params, name, value.components())
+            .map_err(|e| format!("Uniform {}", e))?;
+        self.pass_params_pending |= staged;
+        Ok(staged)
+    }
+
+    /// Writes the pass parameters to the ring before a draw with a program of the script, see `stage_pass_param`
+    pub fn upload_pass_params(&mut self) -> Result<(), String> {
+        if self.draw_params_bound {
+            // Parameters passed to a draw only apply to that draw
+            self.draw_params_bound = false;
+            self.pass_params_pending = !self.pass_params.is_empty();
+        } else if self.pass_params_pending {
+            self.pass_params_pending = false;
+            self.uniform_ring
+                .bind_data(gl_resources::DRAW_PARAMS_BINDING, &self.pass_params)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_lights(&mut self, lights: Vec<FrameLight>) {
+        self.lights = lights;
+    }
+
+    /// Packs the lights into the light uniforms of the current program, see `bytecode::LightDef`
+    fn set_light_uniforms(&self) {
+        if let Some(shader_id) = self.current_shader {
+            let lights: Vec<&FrameLight> = self.lights.iter().collect();
+            self.bind_lights(&self.shaders[shader_id as usize], &lights);
+        }
+    }
+
+    fn bind_lights(&self, shader: &ShaderPipeline, lights: &[&FrameLight]) {
+        let mut positions = Vec::with_capacity(lights.len() * 4);
+        let mut directions = Vec::with_capacity(lights.len() * 4);
+        let mut colors = Vec::with_capacity(lights.len() * 4);
+        for light in lights {
+            let light_type = match light.light_type {
+                LightType::Directional => 0.0,
+                LightType::Point => 1.0,
+                LightType::Spot => 2.0,
+            };
+            let p = light.position;
+            let position = self.view_matrix * glm::Vec4::new(p.x, p.y, p.z, 1.0);
+            positions.extend_from_slice(&[position.x, position.y, position.z, light_type]);
+            let d = light.direction;
+            let direction = glm::normalize(self.view_matrix * glm::Vec4::new(d.x, d.y, d.z, 0.0));
+            directions.extend_from_slice(&[direction.x, direction.y, direction.z, light.angle.cos()]);
+            colors.extend_from_slice(&[light.color.r, light.color.g, light.color.b, light.color.a]);
+        }
+
+        let count = lights.len() as GLint;
+        for location in shader.get_uniform_locations("u_LightCount") {
+            unsafe {
+                gl::ProgramUniform1i(location.program_id, location.location, count);
+            }
+        }
+        if lights.is_empty() {
+            return;
+        }
+        for (name, values) in &[
+            ("u_LightPosition", positions),
+            ("u_LightDirection", directions),
+            ("u_LightColor", colors),
+        ] {
+            for location in shader.get_uniform_locations(name) {
+                unsafe {
+                    gl::ProgramUniform4fv(location.program_id, location.location, count, values.as_ptr());
+                }
+            }
+        }
+    }
+
+    /// Switches to drawing in 2D
+    ///
+    /// Positions are then given in pixels of the viewport, from its top left corner, and depth testing is disabled
+    /// until `end_2d` is called.
+    pub fn begin_2d(&mut self) -> Result<(), String> {
+        if self.saved_3d_matrices.is_some() {
+            return Err(format!("`begin_2d` was called again before `end_2d`"));
+        }
+        self.saved_3d_matrices = Some((self.model_matrix, self.view_matrix, self.projection_matrix));
+
+        let (_, _, width, height) = self.viewport;
+        self.model_matrix = identity_4();
+        self.view_matrix = identity_4();
+        self.projection_matrix = draw2d::pixel_projection(width as f32, height as f32);
+        unsafe {
+            gl::Disable(gl::DEPTH_TEST);
+        }
+        self.set_matrix_uniforms()
+    }
+
+    pub fn end_2d(&mut self) -> Result<(), String> {
+        let (model, view, projection) = self
+            .saved_3d_matrices
+            .take()
+            .ok_or_else(|| format!("`end_2d` was called without `begin_2d`"))?;
+        self.model_matrix = model;
+        self.view_matrix = view;
+        self.projection_matrix = projection;
+        unsafe {
+            gl::Enable(gl::DEPTH_TEST);
+        }
+        self.set_matrix_uniforms()
+    }
+
+    /// Draws a rectangle with a solid color, transformed by the current matrices
+    pub fn draw_rect(&mut self, rect: (f32, f32, f32, f32), color: LinearRGBA) -> Result<(), String> {
+        self.ensure_builtin_shader(
+            "rect",
+            &[
+                ("<rect vertex shader>", draw2d::RECT_VERTEX_SHADER, gl::VERTEX_SHADER),
+                (
+                    "<rect fragment shader>",
+                    draw2d::RECT_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+
+        let shader = &self.builtin_shaders["rect"];
+        shader.bind();
+        self.set_rect_transform(shader, rect);
+        for location in shader.get_uniform_locations("u_Color") {
+            unsafe {
+                gl::ProgramUniform4f(
+                    location.program_id,
+                    location.location,
+                    color.r,
+                    color.g,
+                    color.b,
+                    color.a,
+                );
+            }
+        }
+        self.render_fullscreen_quad();
+        self.rebind_current_shader();
+        Ok(())
+    }
+
+    /// Draws a rectangle showing a texture, transformed by the current matrices
+    ///
+    /// The texture is bound to a texture unit used by the current program, its textures have to be set again.
+    pub fn draw_textured_quad(&mut self, rect: (f32, f32, f32, f32), texture_index: u32) -> Result<(), String> {
+        self.ensure_builtin_shader(
+            "textured_rect",
+            &[
+                ("<rect vertex shader>", draw2d::RECT_VERTEX_SHADER, gl::VERTEX_SHADER),
+                (
+                    "<textured rect fragment shader>",
+                    draw2d::TEXTURED_RECT_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+
+        let shader = &self.builtin_shaders["textured_rect"];
+        shader.bind();
+        self.set_rect_transform(shader, rect);
+        if let Some(texture_unit) = shader.get_texture_unit("t_Texture") {
+            self.textures[texture_index as usize].bind(texture_unit);
+        }
+        self.render_fullscreen_quad();
+        self.rebind_current_shader();
+        Ok(())
+    }
+
+    /// Sets the matrix of a built-in rect shader, so that it draws the given (x, y, width, height) rectangle
+    fn set_rect_transform(&self, shader: &ShaderPipeline, rect: (f32, f32, f32, f32)) {
+        let (x, y, width, height) = rect;
+        let model_matrix = self.model_matrix * draw2d::rect_transform(x, y, width, height);
+        let mvp = self.projection_matrix * self.view_matrix * model_matrix;
+        for location in shader.get_uniform_locations("u_ModelViewProjectionMatrix") {
+            unsafe {
+                gl::ProgramUniformMatrix4fv(
+                    location.program_id,
+                    location.location,
+                    1,
+                    gl::FALSE,
+                    mem::transmute(&mvp),
+                );
+            }
+        }
+    }
+
+    /// Binds the current program again after a built-in shader was used, its uniforms are kept
+    fn rebind_current_shader(&self) {
+        if let Some(shader_id) = self.current_shader {
+            self.shaders[shader_id as usize].bind();
+        }
+    }
+
+    /// Reads a file of the demo, relative to the script
+    pub fn read_resource(&self, path: &Path) -> Result<Vec<u8>, String> {
+        self.resources.read(path)
+    }
+
+    fn load_shader(&self, filename: &Path) -> Result<String, String> {
+        self.resources
+            .read_to_string(filename)
+            .map_err(|e| format!("Failed to load shader file {:?}, {}", filename, e))
+    }
+
+    /// Draws a quad covering the viewport with the current program
+    ///
+    /// Only models are drawn as patches, hence the program must not tessellate.
+    pub fn draw_quad(&mut self) -> Result<(), String> {
+        if let Some(shader_id) = self.current_shader {
+            if self.shaders[shader_id as usize].is_tessellated() {
+                return Err(format!(
+                    "`draw_fullscreenquad` cannot be used with a program which tessellates, only models can"
+                ));
+            }
+        }
+        self.render_fullscreen_quad();
+        Ok(())
+    }
+
+    pub fn render_fullscreen_quad(&mut self) {
+        unsafe {
+            gl::BindVertexArray(self.fullscreen_quad_vao);
+            gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+        }
+    }
+
+    /// Draws the environment of an IBL behind everything which has been drawn into the bound target
+    ///
+    /// Only the rotation of the view is applied. The current program has to be bound again afterwards.
+    pub fn draw_skybox(&mut self, ibl_index: u32) -> Result<(), String> {
+        self.ensure_builtin_shader(
+            "skybox",
+            &[
+                (
+                    "<skybox vertex shader>",
+                    postfx::SKYBOX_VERTEX_SHADER,
+                    gl::VERTEX_SHADER,
+                ),
+                (
+                    "<skybox fragment shader>",
+                    postfx::SKYBOX_FRAGMENT_SHADER,
+                    gl::FRAGMENT_SHADER,
+                ),
+            ],
+        )?;
+        let mut view_rotation = self.view_matrix;
+        view_rotation.c3 = glm::Vec4::new(0.0, 0.0, 0.0, 1.0);
+        let inv_view_projection = (self.projection_matrix * view_rotation)
+            .inverse()
+            .ok_or_else(|| format!("View-Projection matrix is non-invertible"))?;
+
+        let shader = &self.builtin_shaders["skybox"];
+        shader.bind();
+        self.current_shader = None;
+        self.set_reversed_z_uniform(shader);
+        for location in shader.get_uniform_locations("u_InvViewProjection") {
+            unsafe {
+                gl::ProgramUniformMatrix4fv(
+                    location.program_id,
+                    location.location,
+                    1,
+                    gl::FALSE,
+                    mem::transmute(&inv_view_projection),
+                );
+            }
+        }
+        if let Some(texture_unit) = shader.get_texture_unit("t_Environment") {
+            self.ibls[ibl_index as usize].bind(texture_unit);
+        }
+        self.render_fullscreen_quad();
+        Ok(())
+    }
+
+    /// Draws a model, if `bind_materials` is set the materials from its MTL file are bound to the current program
+    pub fn render_model(&mut self, model_id: u32, bind_materials: bool) {
+        let mode = self.model_draw_mode();
+        let model = &self.models[model_id as usize];
+        let materials = &self.model_materials[model_id as usize];
+        let shader = match self.current_shader {
+            Some(shader_id) if bind_materials && materials.iter().any(|m| m.is_some()) => {
+                &self.shaders[shader_id as usize]
+            }
+            _ => return model.draw(mode),
+        };
+        for (group, material) in materials.iter().enumerate() {
+            if let Some(material) = material {
+                material.bind(shader);
+            }
+            model.draw_group(group, mode);
+        }
+    }
 
-        Ok(())
+    pub fn render_batch(&self, batch_id: u32) {
+        self.batches[batch_id as usize].draw(self.model_draw_mode());
     }
 
-    fn load_shader(filename: &Path) -> Result<String, String> {
-        let mut file = File::open(filename).map_err(|e| format!("Failed to load shader file {:?}, {}", filename, e))?;
+    /// Models are drawn as triangles, or as patches of 3 vertices if the current program tessellates them
+    fn model_draw_mode(&self) -> GLenum {
+        match self.current_shader {
+            Some(shader_id) if self.shaders[shader_id as usize].is_tessellated() => gl::PATCHES,
+            _ => gl::TRIANGLES,
+        }
+    }
 
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .map_err(|e| format!("Failed to read shader file: {:?}, {}", filename, e))?;
-        Ok(contents)
+    /// Looks up the location of every uniform used by the script in all loaded shaders
+    ///
+    /// This needs to be called whenever the shaders or the bytecode change, uniforms are then set by their index into
+    /// `uniform_names`.
+    pub fn resolve_uniform_locations(&mut self, uniform_names: &[String]) {
+        // Grabbed screens are identified by their uniform
+        self.grabbed_screens.clear();
+        self.uniform_names = uniform_names.to_vec();
+        self.uniform_locations = self
+            .shaders
+            .iter()
+            .map(|shader| {
+                uniform_names
+                    .iter()
+                    .map(|name| shader.get_uniform_locations(name))
+                    .collect()
+            })
+            .collect();
+        self.uniform_texture_units = self
+            .shaders
+            .iter()
+            .map(|shader| uniform_names.iter().map(|name| shader.get_texture_unit(name)).collect())
+            .collect();
     }
 
-    pub fn render_fullscreen_quad(&mut self) {
-        unsafe {
-            gl::BindVertexArray(self.fullscreen_quad_vao);
-            gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+    /// Warns about the uniforms of each program which are neither set by the script nor by the engine
+    ///
+    /// This catches uniforms which were renamed in the shader but not in the script, which is otherwise only noticed
+    /// when setting the old name fails. The materials of loaded models count as set by the script, programs which
+    /// failed to compile are skipped.
+    pub fn warn_unused_uniforms(&self, programs: &[bytecode::ProgramDef]) {
+        let mut set_uniforms: HashSet<&str> = self.uniform_names.iter().map(|name| name.as_str()).collect();
+        set_uniforms.extend(ENGINE_SET_UNIFORMS.iter().cloned());
+        for material in self.model_materials.iter().flatten().flatten() {
+            set_uniforms.extend(material.textures.iter().map(|(name, _)| name.as_str()));
+            set_uniforms.extend(material.colors.iter().map(|(name, _)| name.as_str()));
+            set_uniforms.extend(material.values.iter().map(|(name, _)| name.as_str()));
+        }
+
+        for ((program, shader), error) in programs.iter().zip(&self.shaders).zip(&self.shader_errors) {
+            if error.is_some() {
+                continue;
+            }
+            let file = program
+                .raymarch
+                .as_ref()
+                .or(program.frag.as_ref())
+                .or(program.vert.as_ref());
+            for uniform in shader.get_active_uniforms() {
+                if !set_uniforms.contains(uniform.as_str()) {
+                    println!(
+                        "Warning: Uniform '{}' of the program {:?} is never set by the script",
+                        uniform,
+                        file.map(|file| file.as_str()).unwrap_or("")
+                    );
+                }
+            }
         }
     }
 
-    pub fn render_model(&mut self, model_id: u32) {
-        let model = &self.models[model_id as usize];
-        model.draw();
+    fn get_current_program_uniform_id_locations(&self, uniform_id: u32) -> Result<&[UniformLocation], String> {
+        let uniform_name = &self.uniform_names[uniform_id as usize];
+        let shader_id = self
+            .current_shader
+            .ok_or_else(|| format!("Current shader is invalid (while setting uniform '{}')", uniform_name))?;
+
+        let locations = &self.uniform_locations[shader_id as usize][uniform_id as usize];
+        if locations.is_empty() && self.shader_errors[shader_id as usize].is_none() {
+            return Err(format!("Trying to set unknown uniform '{}'", uniform_name));
+        }
+        Ok(locations)
     }
 
-    fn get_current_program_uniform_location(&self, uniform_name: &str) -> Result<GLint, String> {
-        let shader = self
+    fn get_current_program_uniform_locations(&self, uniform_name: &str) -> Result<Vec<UniformLocation>, String> {
+        let shader_id = self
             .current_shader
-            .as_ref()
-            .map(|id| &self.shaders[*id as usize])
             .ok_or_else(|| format!("Current shader is invalid (while setting uniform '{}')", uniform_name))?;
 
-        shader
-            .get_uniform_location(uniform_name)
-            .ok_or_else(|| format!("Trying to set unknown uniform '{}'", uniform_name))
+        // The error shader does not use the uniforms of the shader it replaces
+        let locations = self.shaders[shader_id as usize].get_uniform_locations(uniform_name);
+        if locations.is_empty() && self.shader_errors[shader_id as usize].is_none() {
+            return Err(format!("Trying to set unknown uniform '{}'", uniform_name));
+        }
+        Ok(locations)
     }
 
-    pub fn set_uniform_f32(&mut self, uniform_name: &str, value: f32) -> Result<(), String> {
-        let location = self.get_current_program_uniform_location(uniform_name)?;
-        unsafe {
-            gl::Uniform1f(location, value);
+    /// Returns the texture unit of a sampler in the current shader, `None` if the error shader is used
+    fn get_current_program_texture_unit(&self, uniform_id: u32) -> Result<Option<GLuint>, String> {
+        let uniform_name = &self.uniform_names[uniform_id as usize];
+        let shader_id = self
+            .current_shader
+            .ok_or_else(|| format!("Current shader is invalid (while setting uniform '{}')", uniform_name))?;
+
+        if self.shader_errors[shader_id as usize].is_some() {
+            return Ok(None);
+        }
+        match self.uniform_texture_units[shader_id as usize][uniform_id as usize] {
+            Some(texture_unit) => Ok(Some(texture_unit)),
+            None => Err(format!("Trying to set unknown sampler '{}'", uniform_name)),
         }
-        Ok(())
     }
 
-    pub fn set_uniform_color(&mut self, uniform_name: &str, value: LinearRGBA) -> Result<(), String> {
-        let location = self.get_current_program_uniform_location(uniform_name)?;
-        unsafe {
-            gl::Uniform4f(location, value.r, value.g, value.b, value.a);
+    pub fn set_uniform_f32(&mut self, uniform_id: u32, value: f32) -> Result<(), String> {
+        self.set_uniform_value(uniform_id, UniformValue::Float(value))
+    }
+
+    pub fn set_uniform_color(&mut self, uniform_id: u32, value: LinearRGBA) -> Result<(), String> {
+        self.set_uniform_value(uniform_id, UniformValue::Vec4([value.r, value.g, value.b, value.a]))
+    }
+
+    pub fn set_uniform_vec3(&mut self, uniform_id: u32, value: glm::Vec3) -> Result<(), String> {
+        self.set_uniform_value(uniform_id, UniformValue::Vec3([value.x, value.y, value.z]))
+    }
+
+    /// Sets a matrix uniform of the script, unlike `set_uniform_mat4` which sets the matrices of the engine by name
+    pub fn set_uniform_matrix(&mut self, uniform_id: u32, value: &glm::Mat4) -> Result<(), String> {
+        self.set_uniform_value(uniform_id, UniformValue::Mat4(mat4_components(value)))
+    }
+
+    /// Sets a uniform of the script in every stage of the current program, or stages it as a pass parameter
+    fn set_uniform_value(&mut self, uniform_id: u32, value: UniformValue) -> Result<(), String> {
+        if !self.stage_pass_param(uniform_id, &value)? {
+            for uniform in self.get_current_program_uniform_id_locations(uniform_id)? {
+                value.apply(&uniform);
+            }
         }
+        self.remember_uniform_value(uniform_id, value);
         Ok(())
     }
 
     pub fn set_uniform_mat4(&mut self, uniform_name: &str, value: &glm::Mat4) -> Result<(), String> {
-        let location = self.get_current_program_uniform_location(uniform_name)?;
-        unsafe {
-            gl::UniformMatrix4fv(location, 1, gl::FALSE, mem::transmute(value));
+        for uniform in self.get_current_program_uniform_locations(uniform_name)? {
+            unsafe {
+                gl::ProgramUniformMatrix4fv(
+                    uniform.program_id,
+                    uniform.location,
+                    1,
+                    gl::FALSE,
+                    mem::transmute(value),
+                );
+            }
         }
         Ok(())
     }
 
-    pub fn set_uniform_texture_srgb(&mut self, uniform_name: &str, texture_index: u32) -> Result<(), String> {
-        let location = self.get_current_program_uniform_location(uniform_name)?;
-        let texture = &self.textures[texture_index as usize];
-
-        unsafe {
-            gl::Uniform1i(location, self.next_free_texture_unit as GLint);
+    pub fn set_uniform_texture_srgb(&mut self, uniform_id: u32, texture_index: u32) -> Result<(), String> {
+        if let Some(texture_unit) = self.get_current_program_texture_unit(uniform_id)? {
+            self.textures[texture_index as usize].bind(texture_unit);
         }
-        texture.bind(self.next_free_texture_unit);
-        self.next_free_texture_unit += 1;
-
         Ok(())
     }
 
     pub fn set_uniform_ibl(&mut self, ibl_index: u32) -> Result<(), String> {
-        let sph_location = self.get_current_program_uniform_location("u_IblIrrandianceSph")?;
-        let texture_location = self.get_current_program_uniform_location("t_IblRadianceMap")?;
+        let sph_locations = self.get_current_program_uniform_locations("u_IblIrrandianceSph")?;
+        let texture_unit = self
+            .current_shader
+            .and_then(|shader_id| self.shaders[shader_id as usize].get_texture_unit("t_IblRadianceMap"));
         let ibl = &self.ibls[ibl_index as usize];
 
-        unsafe {
-            gl::Uniform3fv(sph_location, 9, ibl.irradiance_sph() as *const f32);
-            gl::Uniform1i(texture_location, self.next_free_texture_unit as GLint);
+        for uniform in sph_locations {
+            unsafe {
+                gl::ProgramUniform3fv(
+                    uniform.program_id,
+                    uniform.location,
+                    9,
+                    ibl.irradiance_sph() as *const f32,
+                );
+            }
+        }
+        if let Some(texture_unit) = texture_unit {
+            ibl.bind(texture_unit);
         }
-
-        ibl.bind(self.next_free_texture_unit);
-        self.next_free_texture_unit += 1;
 
         Ok(())
     }
 
     pub fn set_uniform_render_target_texture(
         &mut self,
-        uniform_name: &str,
+        uniform_id: u32,
         target_index: u32,
         buffer_index: u32,
+        srgb_decode: bool,
     ) -> Result<(), String> {
-        let location = self.get_current_program_uniform_location(uniform_name)?;
+        self.ensure_target(target_index)?;
         let render_target = self
             .render_targets
             .get(&target_index)
             .ok_or_else(|| format!("Unknown render target at index {}", target_index))?;
 
-        unsafe {
-            gl::Uniform1i(location, self.next_free_texture_unit as GLint);
+        if let Some(texture_unit) = self.get_current_program_texture_unit(uniform_id)? {
+            render_target.bind_as_texture(texture_unit, buffer_index as usize, srgb_decode);
         }
-        render_target.bind_as_texture(self.next_free_texture_unit, buffer_index as usize);
-        self.next_free_texture_unit += 1;
 
         Ok(())
     }
 
+    pub fn get_model_matrix(&self) -> glm::Mat4 {
+        self.model_matrix
+    }
     pub fn set_model_matrix(&mut self, m: &glm::Mat4) {
         self.model_matrix = *m;
     }
@@ -480,119 +3143,418 @@ impl RenderContext {
     pub fn set_projection_matrix(&mut self, m: &glm::Mat4) {
         self.projection_matrix = *m;
     }
+
+    /// Renders the following draws into the view of an eye, with the default camera of the demo
+    fn set_eye(&mut self, eye: Eye, projection: &glm::Mat4) {
+        self.eye = eye;
+        self.view_matrix = eye.view_matrix * default_view_matrix();
+        self.projection_matrix = self.eye_projection(projection);
+    }
+
+    /// Applies reversed-Z and the jitter of TAA to a projection of the camera
+    fn eye_projection(&self, projection: &glm::Mat4) -> glm::Mat4 {
+        let mut projection = *projection;
+        if self.reversed_z {
+            projection = postfx::reverse_depth(&projection);
+        }
+        if let Some(jitter) = self.taa_jitter() {
+            projection = postfx::jitter_projection(&projection, jitter, self.eye.width, self.eye.height);
+        }
+        projection
+    }
+
+    /// Moves the camera to `eye`, looking at `center`, for `camera_lookat`
+    pub fn set_camera_lookat(&mut self, eye: glm::Vec3, center: glm::Vec3, up: glm::Vec3) -> Result<(), String> {
+        self.expect_3d("camera")?;
+        self.view_matrix = self.eye.view_matrix * glm::ext::look_at(eye, center, up);
+        // Lights and clip planes are bound in view space
+        self.set_matrix_uniforms()?;
+        self.set_light_uniforms();
+        self.set_clip_plane_uniforms();
+        Ok(())
+    }
+
+    /// Sets a perspective projection with the aspect ratio of the eye, for `camera_perspective`
+    pub fn set_camera_perspective(&mut self, fov_y: f32, near: f32, far: f32) -> Result<(), String> {
+        self.expect_3d("camera")?;
+        let projection = glm::ext::perspective(fov_y, self.eye.aspect, near, far);
+        self.projection_matrix = self.eye_projection(&projection);
+        self.set_matrix_uniforms()
+    }
+
+    /// Replaces the model matrix, for `set_model_transform`
+    pub fn set_model_transform(&mut self, m: &glm::Mat4) -> Result<(), String> {
+        self.expect_3d("model transform")?;
+        self.model_matrix = *m;
+        self.set_matrix_uniforms()
+    }
+
+    /// The matrices of the 3D scene are replaced while drawing in 2D, see `begin_2d`
+    fn expect_3d(&self, what: &str) -> Result<(), String> {
+        match self.saved_3d_matrices {
+            Some(_) => Err(format!("The {} cannot be changed in 2D, after `begin_2d`", what)),
+            None => Ok(()),
+        }
+    }
 }
 
 pub fn evaluate_expression(
     render_ctx: &mut RenderContext,
     function_ctx: &FunctionContext,
     expr: &ValueExpr,
-) -> Result<Value, String> {
-    match expr {
+) -> Result<Value, RuntimeError> {
+    let value = match expr {
         ValueExpr::FunctionCall(function_call) => execute_function_call(render_ctx, function_ctx, function_call),
-        ValueExpr::Var(name, props) => function_ctx.get_prop(&name, &props),
+        ValueExpr::Var(name) => Err(format!("Unknown variable {}", name).into()),
+        ValueExpr::Local(slot, _) => Ok(function_ctx.locals.borrow()[*slot].1.clone()),
+        ValueExpr::Global(slot, _) => Ok(function_ctx.globals[*slot].clone()),
+        ValueExpr::SyncTrack(track) => Ok(function_ctx
+            .sync_track
+            .get_value(track)
+            .map(|v| Value::Float32(v))
+            .ok_or_else(|| format!("Could not get value for sync track \"{}\"", track))?),
 
         ValueExpr::ConstFloat(val) => Ok(Value::Float32(*val)),
         ValueExpr::ConstLinColor(val) => Ok(Value::LinColor(*val)),
         ValueExpr::ConstString(val) => Ok(Value::Str(val.clone())),
-        ValueExpr::ConstDict(_val) => Err(format!("Const dict not supported")),
+        ValueExpr::ConstDict(_val) => Err(format!("Const dict not supported").into()),
 
-        // Only implemented for floats for now
         ValueExpr::BinaryOp(operand, e1, e2) => {
             let e1 = evaluate_expression(render_ctx, function_ctx, e1)?;
             let e2 = evaluate_expression(render_ctx, function_ctx, e2)?;
-            let e1 = e1.as_f32()?;
-            let e2 = e2.as_f32()?;
+            evaluate_binary_op(operand, &e1, &e2)
+        }
+    }?;
 
-            match operand {
-                &BinaryOperator::Add => Ok(Value::Float32(e1 + e2)),
-                &BinaryOperator::Sub => Ok(Value::Float32(e1 - e2)),
-                &BinaryOperator::Mul => Ok(Value::Float32(e1 * e2)),
-                &BinaryOperator::Div => Ok(Value::Float32(e1 / e2)),
+    // Sub-expressions are checked first, hence the error names the innermost expression which produced the value
+    if function_ctx.options.check_non_finite && !value.is_finite() {
+        return Err(format!("Expression `{}` evaluated to {}", expr, value).into());
+    }
+    Ok(value)
+}
 
-                &BinaryOperator::Lt => Ok(Value::Float32(if e1 < e2 { 1.0 } else { 0.0 })),
-                &BinaryOperator::Le => Ok(Value::Float32(if e1 <= e2 { 1.0 } else { 0.0 })),
-                &BinaryOperator::Gt => Ok(Value::Float32(if e1 > e2 { 1.0 } else { 0.0 })),
-                &BinaryOperator::Ge => Ok(Value::Float32(if e1 >= e2 { 1.0 } else { 0.0 })),
-                &BinaryOperator::Eq => Ok(Value::Float32(if e1 == e2 { 1.0 } else { 0.0 })),
-                &BinaryOperator::Ne => Ok(Value::Float32(if e1 != e2 { 1.0 } else { 0.0 })),
-            }
-        }
+/// Applies a binary operator to floats, or to the vectors and matrices accepted by the type checker
+fn evaluate_binary_op(operand: &BinaryOperator, e1: &Value, e2: &Value) -> Result<Value, RuntimeError> {
+    let bool_value = |b: bool| Value::Float32(if b { 1.0 } else { 0.0 });
+    match (operand, e1, e2) {
+        (_, Value::Float32(e1), Value::Float32(e2)) => Ok(match operand {
+            BinaryOperator::Add => Value::Float32(e1 + e2),
+            BinaryOperator::Sub => Value::Float32(e1 - e2),
+            BinaryOperator::Mul => Value::Float32(e1 * e2),
+            BinaryOperator::Div => Value::Float32(e1 / e2),
+
+            BinaryOperator::Lt => bool_value(e1 < e2),
+            BinaryOperator::Le => bool_value(e1 <= e2),
+            BinaryOperator::Gt => bool_value(e1 > e2),
+            BinaryOperator::Ge => bool_value(e1 >= e2),
+            BinaryOperator::Eq => bool_value(e1 == e2),
+            BinaryOperator::Ne => bool_value(e1 != e2),
+        }),
+
+        (BinaryOperator::Add, Value::Vec2(a), Value::Vec2(b)) => Ok(Value::Vec2(*a + *b)),
+        (BinaryOperator::Add, Value::Vec3(a), Value::Vec3(b)) => Ok(Value::Vec3(*a + *b)),
+        (BinaryOperator::Add, Value::Vec4(a), Value::Vec4(b)) => Ok(Value::Vec4(*a + *b)),
+        (BinaryOperator::Sub, Value::Vec2(a), Value::Vec2(b)) => Ok(Value::Vec2(*a - *b)),
+        (BinaryOperator::Sub, Value::Vec3(a), Value::Vec3(b)) => Ok(Value::Vec3(*a - *b)),
+        (BinaryOperator::Sub, Value::Vec4(a), Value::Vec4(b)) => Ok(Value::Vec4(*a - *b)),
+
+        // Scaling vectors, from either side
+        (BinaryOperator::Mul, Value::Vec2(v), Value::Float32(s))
+        | (BinaryOperator::Mul, Value::Float32(s), Value::Vec2(v)) => Ok(Value::Vec2(*v * *s)),
+        (BinaryOperator::Mul, Value::Vec3(v), Value::Float32(s))
+        | (BinaryOperator::Mul, Value::Float32(s), Value::Vec3(v)) => Ok(Value::Vec3(*v * *s)),
+        (BinaryOperator::Mul, Value::Vec4(v), Value::Float32(s))
+        | (BinaryOperator::Mul, Value::Float32(s), Value::Vec4(v)) => Ok(Value::Vec4(*v * *s)),
+        (BinaryOperator::Div, Value::Vec2(v), Value::Float32(s)) => Ok(Value::Vec2(*v / *s)),
+        (BinaryOperator::Div, Value::Vec3(v), Value::Float32(s)) => Ok(Value::Vec3(*v / *s)),
+        (BinaryOperator::Div, Value::Vec4(v), Value::Float32(s)) => Ok(Value::Vec4(*v / *s)),
+
+        (BinaryOperator::Mul, Value::Mat4(a), Value::Mat4(b)) => Ok(Value::Mat4(*a * *b)),
+        (BinaryOperator::Mul, Value::Mat4(m), Value::Vec4(v)) => Ok(Value::Vec4(*m * *v)),
+
+        _ => Err(format!(
+            "Cannot apply `{}` to {:?} and {:?}",
+            operand,
+            e1.value_type(),
+            e2.value_type()
+        )
+        .into()),
     }
 }
 
+/// Returns the largest (x, y, width, height) area of the window with the given aspect ratio, centered in the window
+fn letterbox(window_width: f32, window_height: f32, aspect: Option<(f32, f32)>) -> (u32, u32, u32, u32) {
+    let (width, height) = match aspect {
+        Some((aspect_width, aspect_height)) => {
+            let width = window_width.min(window_height * aspect_width / aspect_height).round();
+            let height = window_height.min(window_width * aspect_height / aspect_width).round();
+            (width, height)
+        }
+        None => (window_width.round(), window_height.round()),
+    };
+    let x = ((window_width.round() - width) / 2.0).floor().max(0.0);
+    let y = ((window_height.round() - height) / 2.0).floor().max(0.0);
+    (x as u32, y as u32, width as u32, height as u32)
+}
+
+/// Renders a frame of the program to a window of the given size
 pub fn execute(
     render_ctx: &mut RenderContext,
     program: &ProgramContainer,
-    width: f32,
-    height: f32,
+    window_width: f32,
+    window_height: f32,
+    time_s: f32,
+    sync_track: &dyn SyncTracker,
+    options: &ExecutionOptions,
+) -> Result<(), RuntimeError> {
+    evaluate(
+        render_ctx,
+        program,
+        "main",
+        Vec::new(),
+        window_width,
+        window_height,
+        time_s,
+        sync_track,
+        options,
+    )
+    .map(|_| ())
+}
+
+/// Renders a frame of the program like `execute`, but calls the given function instead of `main`
+///
+/// The arguments have to match the parameters of the function. The value returned by the function is passed back.
+pub fn evaluate(
+    render_ctx: &mut RenderContext,
+    program: &ProgramContainer,
+    function: &str,
+    args: Vec<Value>,
+    window_width: f32,
+    window_height: f32,
+    time_s: f32,
+    sync_track: &dyn SyncTracker,
+    options: &ExecutionOptions,
+) -> Result<Value, RuntimeError> {
+    let called_fn = program
+        .get_function(function)
+        .ok_or_else(|| format!("Function {} is not defined", function))?;
+    let param_types: Vec<ast::Type> = called_fn.params.iter().map(|p| p.1).collect();
+    let arg_types: Vec<ast::Type> = args.iter().map(|a| a.value_type()).collect();
+    if param_types != arg_types {
+        return Err(format!(
+            "Function {} expects arguments of types {:?}, but was called with {:?}",
+            function, param_types, arg_types
+        )
+        .into());
+    }
+
+    // The demo is letterboxed when it declares an aspect ratio
+    let metadata = program.get_metadata();
+    let screen_rect = letterbox(window_width, window_height, metadata.aspect);
+    let aspect = screen_rect.2 as f32 / screen_rect.3 as f32;
+
+    // (area of the window, offset of the camera along its x axis) of each eye
+    let eyes = match metadata.stereo_mode {
+        Some(StereoMode::SideBySide) => {
+            let (x, y, width, height) = screen_rect;
+            let offset = metadata.eye_separation.unwrap_or(DEFAULT_EYE_SEPARATION) / 2.0;
+            let (left_width, right_width) = (width / 2, width - width / 2);
+            vec![
+                ((x, y, left_width, height), -offset),
+                ((x + left_width, y, right_width, height), offset),
+            ]
+        }
+        None => vec![(screen_rect, 0.0)],
+    };
+    // Render targets are declared with the size of the widest eye, with an odd width the eyes would otherwise release
+    // and recreate them every frame
+    let target_size = eyes
+        .iter()
+        .fold((0, 0), |size, eye| (size.0.max((eye.0).2), size.1.max((eye.0).3)));
+
+    let counters = FrameCounters::default();
+    render_ctx.set_reversed_z(metadata.reversed_z);
+    render_ctx.set_dynamic_resolution(metadata.dynamic_resolution);
+    render_ctx.begin_frame(screen_rect, time_s);
+    let mut value = Value::Void;
+    for (eye_index, (eye_rect, eye_offset)) in eyes.into_iter().enumerate() {
+        if metadata.stereo_mode.is_some() {
+            render_ctx.set_eye_rect(eye_index, eye_rect);
+        }
+        // In stereo, the area of each eye is squeezed horizontally, hence the projection uses the aspect ratio of the
+        // whole screen rather than the one of the area
+        let eye_translation = glm::Vec3::new(-eye_offset, 0.0, 0.0);
+        let view_matrix = glm::ext::translate(&identity_4(), eye_translation);
+        let proj_matrix = glm::ext::perspective(0.5, aspect, 0.01, 20.0);
+        let result = render_eye(
+            render_ctx,
+            program,
+            function,
+            args.clone(),
+            eye_rect,
+            target_size,
+            &view_matrix,
+            &proj_matrix,
+            aspect,
+            time_s,
+            sync_track,
+            options,
+            &counters,
+        );
+        value = match result {
+            // What was drawn up to the stop is shown, the remaining eyes are skipped
+            Err(_) if render_ctx.debug_stop.is_some() => break,
+            result => result?,
+        };
+    }
+    render_ctx.end_frame()?;
+    if options.show_probes {
+        render_ctx.draw_probes()?;
+    }
+    if options.show_scrub_bar {
+        // In stereo, the bar spans both eyes
+        render_ctx.set_eye_rect(0, screen_rect);
+        render_ctx.draw_scrub_bar(&ScrubBar::new(metadata, time_s))?;
+    }
+    Ok(value)
+}
+
+/// Camera of the demo, looking at the origin from the front
+fn default_view_matrix() -> glm::Mat4 {
+    let eye = glm::Vec3::new(0.0, 0.0, 5.0);
+    let center = glm::Vec3::new(0.0, 0.0, 0.0);
+    let up = glm::Vec3::new(0.0, 1.0, 0.0);
+    glm::ext::look_at(eye, center, up)
+}
+
+/// Renders the view of an eye into the given (x, y, width, height) area of the screen, see `evaluate`
+///
+/// The view matrix is applied after the camera of the script, the projection is the one of the default camera until
+/// the script replaces it. Render targets are declared as if the screen had the given (width, height).
+fn render_eye(
+    render_ctx: &mut RenderContext,
+    program: &ProgramContainer,
+    function: &str,
+    args: Vec<Value>,
+    eye_rect: (u32, u32, u32, u32),
+    target_size: (u32, u32),
+    view_matrix: &glm::Mat4,
+    proj_matrix: &glm::Mat4,
+    aspect: f32,
     time_s: f32,
     sync_track: &dyn SyncTracker,
-) -> Result<(), String> {
+    options: &ExecutionOptions,
+    counters: &FrameCounters,
+) -> Result<Value, RuntimeError> {
+    let width = eye_rect.2 as f32;
+    let height = eye_rect.3 as f32;
+
     // Initialize context
-    let mut globals: HashMap<String, Value> = HashMap::new();
-    globals.insert("width".into(), Value::Float32(width));
-    globals.insert("height".into(), Value::Float32(height));
-    globals.insert("time".into(), Value::Float32(time_s));
+    // In the same order as `bytecode::GLOBALS`
+    let globals = [Value::Float32(width), Value::Float32(height), Value::Float32(time_s)];
     let function_ctx = FunctionContext {
         program: program,
+        function: function,
         sync_track: sync_track,
         globals: &globals,
-        locals: HashMap::new(),
+        locals: RefCell::new(Vec::new()),
+
+        options: options,
+        call_depth: 0,
+        counters: counters,
     };
 
-    // Evaluate render targets
+    // Declare render targets, they are only created once they are used
+    let target_globals = [
+        Value::Float32(target_size.0 as f32),
+        Value::Float32(target_size.1 as f32),
+        Value::Float32(time_s),
+    ];
+    let target_ctx = FunctionContext {
+        globals: &target_globals,
+        locals: RefCell::new(Vec::new()),
+        ..function_ctx
+    };
     for (idx, rt) in program.get_target_defs().iter().enumerate() {
-        let width = evaluate_expression(render_ctx, &function_ctx, &rt.width)?
+        let width = evaluate_expression(render_ctx, &target_ctx, &rt.width)?
             .as_f32()?
             .round() as u32;
-        let height = evaluate_expression(render_ctx, &function_ctx, &rt.height)?
+        let height = evaluate_expression(render_ctx, &target_ctx, &rt.height)?
             .as_f32()?
             .round() as u32;
-        render_ctx.make_target(idx as u32, width, height, rt.has_depth, &rt.formats)?;
+        render_ctx.declare_target(idx as u32, width, height, rt.has_depth, &rt.formats);
     }
+    // Every frame starts on the screen with the default state
+    render_ctx.bind_render_target(None)?;
 
-    // Compute camera transfomration
-    let eye = glm::Vec3::new(0.0, 0.0, 5.0);
-    let center = glm::Vec3::new(0.0, 0.0, 0.0);
-    let up = glm::Vec3::new(0.0, 1.0, 0.0);
-    let view_matrix = glm::ext::look_at(eye, center, up);
-    let proj_matrix = glm::ext::perspective(0.5, width / height, 0.01, 20.0);
-
-    render_ctx.set_view_matrix(&view_matrix);
-    render_ctx.set_projection_matrix(&proj_matrix);
+    // Every eye starts with the default camera, which the script may move
+    let eye = Eye {
+        view_matrix: *view_matrix,
+        aspect: aspect,
+        width: width,
+        height: height,
+    };
+    render_ctx.set_eye(eye, proj_matrix);
     let rotation_axis = glm::Vec3::new(0.0, 1.0, 0.0);
     render_ctx.set_model_matrix(&glm::ext::rotate(&identity_4(), time_s * 0.5, rotation_axis));
+    let lights = evaluate_lights(render_ctx, &function_ctx)?;
+    render_ctx.set_lights(lights);
 
-    call_function(render_ctx, &function_ctx, "main", HashMap::new()).map(|_| {})
+    call_function(render_ctx, &function_ctx, function, args)
 }
 
 fn call_function(
     render_ctx: &mut RenderContext,
     function_ctx: &FunctionContext,
     function: &str,
-    args: HashMap<String, Value>,
-) -> Result<Value, String> {
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
     let called_fn = function_ctx
         .program
         .get_ops(&function)
         .ok_or_else(|| format!("Function {} is not defined", function))?;
 
-    // Create new frame
+    if function_ctx.call_depth >= function_ctx.options.max_call_depth {
+        return Err(format!(
+            "Exceeded the maximum call depth of {} when calling \"{}\", is there an infinite recursion?",
+            function_ctx.options.max_call_depth, function
+        )
+        .into());
+    }
+
+    // The functions called by the function which renders the frame are its passes
+    if function_ctx.call_depth == 1 {
+        if let Some(recording) = render_ctx.recording.as_mut() {
+            recording.passes.push(function.to_owned());
+        }
+    }
+
+    // Create new frame, the arguments are the first locals
+    let params = function_ctx
+        .program
+        .get_function(function)
+        .map_or(&[][..], |f| &f.params[..]);
+    let locals = params.iter().map(|p| p.0.clone()).zip(args).collect();
     let new_frame_ctx = FunctionContext {
         program: function_ctx.program,
+        function: function,
         sync_track: function_ctx.sync_track,
         globals: function_ctx.globals,
-        locals: args,
+        locals: RefCell::new(locals),
+
+        options: function_ctx.options,
+        call_depth: function_ctx.call_depth + 1,
+        counters: function_ctx.counters,
     };
 
-    execute_block(render_ctx, &new_frame_ctx, called_fn)
+    execute_block(render_ctx, &new_frame_ctx, called_fn).map(|v| v.unwrap_or(Value::Void))
 }
 
 fn execute_function_call(
     render_ctx: &mut RenderContext,
     function_ctx: &FunctionContext,
     function_call: &bytecode::FunctionCall,
-) -> Result<Value, String> {
+) -> Result<Value, RuntimeError> {
     if function_call.function == "LinColor" {
         // TODO: Bounds checking
         let r = evaluate_expression(render_ctx, function_ctx, &function_call.args[0])?.as_f32()?;
@@ -601,6 +3563,110 @@ fn execute_function_call(
         let a = evaluate_expression(render_ctx, function_ctx, &function_call.args[3])?.as_f32()?;
         return Ok(Value::LinColor(LinearRGBA::from_f32(r, g, b, a)));
     }
+    if function_call.function == "Vec2" || function_call.function == "Vec3" || function_call.function == "Vec4" {
+        let mut v = [0.0; 4];
+        for (value, arg) in v.iter_mut().zip(&function_call.args) {
+            *value = evaluate_expression(render_ctx, function_ctx, arg)?.as_f32()?;
+        }
+        return Ok(match function_call.args.len() {
+            2 => Value::Vec2(glm::Vec2::new(v[0], v[1])),
+            3 => Value::Vec3(glm::Vec3::new(v[0], v[1], v[2])),
+            _ => Value::Vec4(glm::Vec4::new(v[0], v[1], v[2], v[3])),
+        });
+    }
+    if function_call.function == "translate" {
+        let v = evaluate_expression(render_ctx, function_ctx, &function_call.args[0])?.as_vec3()?;
+        return Ok(Value::Mat4(glm::ext::translate(&identity_4(), v)));
+    }
+    if function_call.function == "rotate" {
+        let angle = evaluate_expression(render_ctx, function_ctx, &function_call.args[0])?.as_f32()?;
+        let axis = evaluate_expression(render_ctx, function_ctx, &function_call.args[1])?.as_vec3()?;
+        return Ok(Value::Mat4(glm::ext::rotate(&identity_4(), angle, axis)));
+    }
+    if function_call.function == "perspective" {
+        let mut args = [0.0; 4];
+        for (value, arg) in args.iter_mut().zip(&function_call.args) {
+            *value = evaluate_expression(render_ctx, function_ctx, arg)?.as_f32()?;
+        }
+        let [fov_y, aspect, near, far] = args;
+        return Ok(Value::Mat4(glm::ext::perspective(fov_y, aspect, near, far)));
+    }
+    if function_call.function == "-" {
+        let v = evaluate_expression(render_ctx, function_ctx, &function_call.args[0])?.as_f32()?;
+        return Ok(Value::Float32(-v));
+    }
+    if function_call.function == "probe" {
+        let label = match &function_call.args[0] {
+            ValueExpr::ConstString(label) => label,
+            _ => return Err(format!("The label of `probe` has to be a string literal").into()),
+        };
+        let value = evaluate_expression(render_ctx, function_ctx, &function_call.args[1])?.as_f32()?;
+        render_ctx.record_probe(label, value);
+        return Ok(Value::Float32(value));
+    }
+    if function_call.function == "reduce_luminance" {
+        let (target, buffer) = evaluate_target_buffer(render_ctx, function_ctx, &function_call.args[0])?;
+        let average = render_ctx.get_luminance_stats(target, buffer)?.get_average();
+        return Ok(Value::Float32(average));
+    }
+    if function_call.function == "histogram" {
+        let (target, buffer) = evaluate_target_buffer(render_ctx, function_ctx, &function_call.args[0])?;
+        let bin = evaluate_expression(render_ctx, function_ctx, &function_call.args[1])?.as_f32()?;
+        let bin = if bin >= 0.0 { bin as usize } else { usize::max_value() };
+        let fraction = render_ctx
+            .get_luminance_stats(target, buffer)?
+            .get_histogram_bin(bin)
+            .ok_or_else(|| {
+                format!(
+                    "Histogram bin {} is out of range, there are {} bins",
+                    bin,
+                    gl_resources::LUMINANCE_HISTOGRAM_BINS
+                )
+            })?;
+        return Ok(Value::Float32(fraction));
+    }
+    if function_call.function == "envelope" {
+        let track = match &function_call.args[0] {
+            ValueExpr::SyncTrack(track) => track,
+            _ => return Err(format!("The trigger of `envelope` has to be a sync track").into()),
+        };
+        // The track acts as a gate, which is open while it is at least 0.5
+        let gate = evaluate_expression(render_ctx, function_ctx, &function_call.args[0])?.as_f32()? >= 0.5;
+        let mut adsr = [0.0; 4];
+        for (value, arg) in adsr.iter_mut().zip(&function_call.args[1..]) {
+            *value = evaluate_expression(render_ctx, function_ctx, arg)?.as_f32()?;
+        }
+        let value = render_ctx.update_envelope(track, gate, (adsr[0], adsr[1], adsr[2], adsr[3]));
+        return Ok(Value::Float32(value));
+    }
+    if easing::arg_count(&function_call.function).is_some() {
+        let args = function_call
+            .args
+            .iter()
+            .map(|arg| Ok(evaluate_expression(render_ctx, function_ctx, arg)?.as_f32()?))
+            .collect::<Result<Vec<f32>, RuntimeError>>()?;
+        let value = easing::evaluate(&function_call.function, &args)
+            .ok_or_else(|| format!("Missing function {}", function_call.function))?;
+        return Ok(Value::Float32(value));
+    }
+    if function_call.function == "readback" {
+        let (target, buffer) = evaluate_target_buffer(render_ctx, function_ctx, &function_call.args[0])?;
+        let format = function_ctx.program.get_target_defs()[target as usize].formats[buffer as usize].1;
+        if format.is_depth() {
+            return Err(format!("Cannot read back depth buffers").into());
+        }
+        let x = evaluate_expression(render_ctx, function_ctx, &function_call.args[1])?.as_f32()?;
+        let y = evaluate_expression(render_ctx, function_ctx, &function_call.args[2])?.as_f32()?;
+        if x < 0.0 || y < 0.0 {
+            return Err(format!("Pixel ({}, {}) is outside of the render target", x, y).into());
+        }
+        let color = match render_ctx.get_pixel_readback(target, buffer, x as u32, y as u32)? {
+            Some([r, g, b, a]) if format.is_srgb() => SrgbRGBA::from_f32(r, g, b, a).into(),
+            Some([r, g, b, a]) => LinearRGBA::from_f32(r, g, b, a),
+            None => LinearRGBA::from_f32(0.0, 0.0, 0.0, 0.0),
+        };
+        return Ok(Value::LinColor(color));
+    }
 
     let function = function_ctx
         .program
@@ -614,106 +3680,489 @@ fn execute_function_call(
             function.params.len(),
             function_call.function,
             function_call.args.len()
-        ));
+        )
+        .into());
     }
 
-    let mut locals = HashMap::new();
+    let mut locals = Vec::with_capacity(function.params.len());
     for (p, a) in function.params.iter().zip(function_call.args.iter()) {
         let v = evaluate_expression(render_ctx, function_ctx, a)?;
         if v.value_type() != p.1 {
             return Err(format!(
                 "Expected argument \"{}\" for call to \"{}\", to have type {:?}",
                 p.0, function_call.function, p.1
-            ));
+            )
+            .into());
         }
-        locals.insert(p.0.clone(), v);
+        locals.push(v);
     }
 
     call_function(render_ctx, function_ctx, &function_call.function, locals)
 }
 
+/// Where execution continues after an op
+enum ControlFlow {
+    Next,
+    Jump(isize),
+    Return(Value),
+}
+
+/// Executes the ops of a block
+///
+/// Returns the value of the first `return` which was executed, or `None` if the block ran to the end.
 fn execute_block(
     render_ctx: &mut RenderContext,
     function_ctx: &FunctionContext,
     block: &bytecode::BlockBytecode,
-) -> Result<Value, String> {
-    for op in block.get_bytecode() {
-        match op {
-            BytecodeOp::BindRt(rt_id) => render_ctx.bind_render_target(Some(*rt_id))?,
-            BytecodeOp::BindScreenRt => render_ctx.bind_render_target(None)?,
-            BytecodeOp::BindProgram(program_id) => {
-                render_ctx.use_shaders(*program_id)?;
-            }
+) -> Result<Option<Value>, RuntimeError> {
+    let ops = block.get_bytecode();
+    let slices = block.get_source_slices();
+    let mut pc = 0;
+    while pc < ops.len() {
+        crash::set_current_op(Some(slices[pc]));
+        let op_number = function_ctx.counters.ops.get() + 1;
+        let statement_line = || {
+            // Statements can generate several ops, only the first one stops at a breakpoint
+            let is_first = pc == 0 || slices[pc - 1] != slices[pc];
+            let source = function_ctx.program.get_source();
+            Some(source[..slices[pc].begin].matches('\n').count() + 1).filter(|_| is_first)
+        };
+        if function_ctx.options.debugger.should_stop(op_number, statement_line) {
+            render_ctx.debug_stop = Some(debug_stop(render_ctx, function_ctx, op_number, slices[pc]));
+            return Err(format!("Paused by the debugger").into());
+        }
+        if let Some(recording) = render_ctx.recording.as_mut() {
+            *recording.op_counts.entry(function_ctx.function.to_owned()).or_insert(0) += 1;
+        }
+        let flow = execute_op(render_ctx, function_ctx, &ops[pc]).map_err(|e| e.with_fallback_slice(slices[pc]))?;
+        match flow {
+            ControlFlow::Next => pc += 1,
+            ControlFlow::Jump(offset) => pc = (pc as isize + offset) as usize,
+            ControlFlow::Return(value) => return Ok(Some(value)),
+        }
+    }
+    Ok(None)
+}
 
-            BytecodeOp::Viewport(x, y, width, height) => {
-                let x = evaluate_expression(render_ctx, function_ctx, &x)?.as_f32()?.round() as u32;
-                let y = evaluate_expression(render_ctx, function_ctx, &y)?.as_f32()?.round() as u32;
-                let width = evaluate_expression(render_ctx, function_ctx, &width)?.as_f32()?.round() as u32;
-                let height = evaluate_expression(render_ctx, function_ctx, &height)?
-                    .as_f32()?
-                    .round() as u32;
-                render_ctx.viewport_rect(x, y, width, height);
-            }
-            BytecodeOp::Clear(linear) => {
-                let linear = evaluate_expression(render_ctx, function_ctx, linear)?.as_linear_color()?;
-                render_ctx.clear(linear);
-            }
+/// Captures the state at which the debugger stops a frame
+fn debug_stop(render_ctx: &RenderContext, function_ctx: &FunctionContext, op: u32, slice: SourceSlice) -> DebugStop {
+    let globals = bytecode::GLOBALS
+        .iter()
+        .map(|g| g.to_string())
+        .zip(function_ctx.globals.iter().cloned());
+    DebugStop {
+        op: op,
+        function: function_ctx.function.to_owned(),
+        slice: slice,
+        locals: function_ctx.locals.borrow().clone(),
+        globals: globals.collect(),
+        gl_state: render_ctx.describe_state(function_ctx.program),
+    }
+}
 
-            BytecodeOp::PipelineSetBlending(buffer, mode) => {
-                render_ctx.set_blending(*buffer, *mode);
-            }
-            BytecodeOp::PipelineSetWriteMask(write_color, write_depth) => {
-                let write_color = evaluate_expression(render_ctx, function_ctx, write_color)?.as_f32()? > 0.0;
-                let write_depth = evaluate_expression(render_ctx, function_ctx, write_depth)?.as_f32()? > 0.0;
-                render_ctx.set_write_mask(write_color, write_depth);
-            }
-            BytecodeOp::PipelineSetZTest(mode) => {
-                render_ctx.set_z_test(*mode);
-            }
-            BytecodeOp::PipelineSetCulling(mode) => {
-                render_ctx.set_culling(*mode);
+/// Evaluates the lights of the program for the current frame
+fn evaluate_lights(
+    render_ctx: &mut RenderContext,
+    function_ctx: &FunctionContext,
+) -> Result<Vec<FrameLight>, RuntimeError> {
+    let mut lights = Vec::new();
+    for light in function_ctx.program.get_lights() {
+        let mut vector = |exprs: &[ValueExpr]| -> Result<glm::Vec3, RuntimeError> {
+            let mut vector = glm::Vec3::new(0.0, 0.0, 0.0);
+            for (i, expr) in exprs.iter().enumerate() {
+                let value = evaluate_expression(render_ctx, function_ctx, expr)?.as_f32()?;
+                match i {
+                    0 => vector.x = value,
+                    1 => vector.y = value,
+                    _ => vector.z = value,
+                }
             }
+            Ok(vector)
+        };
+        let position = vector(&light.position)?;
+        let direction = vector(&light.direction)?;
 
-            BytecodeOp::UniformFloat(uniform_name, value) => {
-                let value = evaluate_expression(render_ctx, function_ctx, &value)?.as_f32()?;
-                render_ctx.set_uniform_f32(&uniform_name, value)?;
-            }
-            BytecodeOp::UniformColor(uniform_name, value) => {
-                let value = evaluate_expression(render_ctx, function_ctx, &value)?.as_linear_color()?;
-                render_ctx.set_uniform_color(&uniform_name, value)?;
-            }
-            BytecodeOp::UniformTexture(uniform_name, texture_id) => {
-                render_ctx.set_uniform_texture_srgb(uniform_name, *texture_id)?;
+        let color = evaluate_expression(render_ctx, function_ctx, &light.color)?.as_linear_color()?;
+        let intensity = evaluate_expression(render_ctx, function_ctx, &light.intensity)?.as_f32()?;
+        let angle = evaluate_expression(render_ctx, function_ctx, &light.angle)?.as_f32()?;
+        lights.push(FrameLight {
+            light_type: light.light_type,
+            position: position,
+            direction: direction,
+            color: LinearRGBA::from_f32(color.r * intensity, color.g * intensity, color.b * intensity, color.a),
+            angle: angle,
+        });
+    }
+    Ok(lights)
+}
+
+/// Applies a transform given by the keys in `TRANSFORM_KEYS` to a parent matrix, or returns the first unknown key
+fn transform_matrix<'a>(parent: &glm::Mat4, transform: &[(&'a str, f32)]) -> Result<glm::Mat4, &'a str> {
+    let mut translation = glm::Vec3::new(0.0, 0.0, 0.0);
+    let mut rotation = glm::Vec3::new(0.0, 0.0, 0.0);
+    let mut scale = 1.0;
+    for &(key, value) in transform {
+        match key {
+            "translate_x" => translation.x = value,
+            "translate_y" => translation.y = value,
+            "translate_z" => translation.z = value,
+            "rotate_x" => rotation.x = value,
+            "rotate_y" => rotation.y = value,
+            "rotate_z" => rotation.z = value,
+            "scale" => scale = value,
+            _ => return Err(key),
+        }
+    }
+
+    // Scaled first, then rotated around x, y and z, then translated
+    let mut matrix = glm::ext::translate(parent, translation);
+    matrix = glm::ext::rotate(&matrix, rotation.z, glm::Vec3::new(0.0, 0.0, 1.0));
+    matrix = glm::ext::rotate(&matrix, rotation.y, glm::Vec3::new(0.0, 1.0, 0.0));
+    matrix = glm::ext::rotate(&matrix, rotation.x, glm::Vec3::new(1.0, 0.0, 0.0));
+    Ok(glm::ext::scale(&matrix, glm::Vec3::new(scale, scale, scale)))
+}
+
+/// Draws the visible objects of the scene, their transforms are relative to the current model matrix
+fn draw_scene(render_ctx: &mut RenderContext, function_ctx: &FunctionContext) -> Result<(), RuntimeError> {
+    let scene_matrix = render_ctx.get_model_matrix();
+    let objects = function_ctx.program.get_scene_objects();
+    let is_object = |name: &str| objects.iter().any(|o| o.name == name);
+    for object in objects {
+        if !function_ctx.options.is_shown(&object.name, is_object) {
+            continue;
+        }
+        if let Some(visible) = &object.visible {
+            if !(evaluate_expression(render_ctx, function_ctx, visible)?.as_f32()? > 0.0) {
+                continue;
             }
-            BytecodeOp::UniformIbl(ibl_id) => {
-                render_ctx.set_uniform_ibl(*ibl_id)?;
+        }
+
+        let mut transform = Vec::new();
+        for (key, expr) in &object.transform {
+            let value = evaluate_expression(render_ctx, function_ctx, expr)?.as_f32()?;
+            transform.push((key.as_str(), value));
+        }
+        let model_matrix = transform_matrix(&scene_matrix, &transform)
+            .map_err(|key| format!("Unknown transform `{}` of object `{}`", key, object.name))?;
+        render_ctx.set_model_matrix(&model_matrix);
+        render_ctx.set_matrix_uniforms()?;
+
+        execute_block(render_ctx, function_ctx, &object.bytecode)?;
+    }
+
+    render_ctx.set_model_matrix(&scene_matrix);
+    render_ctx.set_matrix_uniforms()?;
+    Ok(())
+}
+
+/// Evaluates a `target.buffer` string to the index of the target and of the buffer
+fn evaluate_target_buffer(
+    render_ctx: &mut RenderContext,
+    function_ctx: &FunctionContext,
+    expr: &ValueExpr,
+) -> Result<(u32, u32), RuntimeError> {
+    let name = match evaluate_expression(render_ctx, function_ctx, expr)? {
+        Value::Str(name) => name,
+        _ => return Err(format!("Expected a `target.buffer` string").into()),
+    };
+    let parts: Vec<&str> = name.split('.').collect();
+    let target_defs = function_ctx.program.get_target_defs();
+    let target = match parts.as_slice() {
+        [target, _] => target_defs.iter().position(|t| t.name == *target),
+        _ => None,
+    };
+    let buffer = target.and_then(|target| target_defs[target].formats.iter().position(|f| f.0 == parts[1]));
+    match (target, buffer) {
+        (Some(target), Some(buffer)) => Ok((target as u32, buffer as u32)),
+        _ => Err(format!("Unknown render target buffer {:?}", name).into()),
+    }
+}
+
+/// Evaluates the (x, y, width, height) of a rectangle
+fn evaluate_rect(
+    render_ctx: &mut RenderContext,
+    function_ctx: &FunctionContext,
+    x: &ValueExpr,
+    y: &ValueExpr,
+    width: &ValueExpr,
+    height: &ValueExpr,
+) -> Result<(f32, f32, f32, f32), RuntimeError> {
+    Ok((
+        evaluate_expression(render_ctx, function_ctx, x)?.as_f32()?,
+        evaluate_expression(render_ctx, function_ctx, y)?.as_f32()?,
+        evaluate_expression(render_ctx, function_ctx, width)?.as_f32()?,
+        evaluate_expression(render_ctx, function_ctx, height)?.as_f32()?,
+    ))
+}
+
+/// Executes a single op
+fn execute_op(
+    render_ctx: &mut RenderContext,
+    function_ctx: &FunctionContext,
+    op: &BytecodeOp,
+) -> Result<ControlFlow, RuntimeError> {
+    let op_count = function_ctx.counters.ops.get() + 1;
+    if op_count > function_ctx.options.max_ops_per_frame {
+        return Err(format!(
+            "Exceeded the budget of {} ops per frame, aborting frame",
+            function_ctx.options.max_ops_per_frame
+        )
+        .into());
+    }
+    function_ctx.counters.ops.set(op_count);
+
+    match op {
+        BytecodeOp::BindRt(rt_id) => render_ctx.bind_render_target(Some(*rt_id))?,
+        BytecodeOp::BindScaledRt(rt_id, scale) => {
+            let scale = evaluate_expression(render_ctx, function_ctx, scale)?.as_f32()?;
+            render_ctx.bind_scaled_render_target(*rt_id, scale)?;
+        }
+        BytecodeOp::BindScreenRt => render_ctx.bind_render_target(None)?,
+        BytecodeOp::InvalidateRt => render_ctx.invalidate_render_target(),
+        BytecodeOp::BindProgram(program_id) => {
+            render_ctx.use_shaders(*program_id)?;
+        }
+
+        BytecodeOp::Viewport(x, y, width, height) => {
+            let x = evaluate_expression(render_ctx, function_ctx, &x)?.as_f32()?.round() as u32;
+            let y = evaluate_expression(render_ctx, function_ctx, &y)?.as_f32()?.round() as u32;
+            let width = evaluate_expression(render_ctx, function_ctx, &width)?.as_f32()?.round() as u32;
+            let height = evaluate_expression(render_ctx, function_ctx, &height)?
+                .as_f32()?
+                .round() as u32;
+            render_ctx.viewport_rect(x, y, width, height);
+        }
+        BytecodeOp::Clear(linear) => {
+            let linear = evaluate_expression(render_ctx, function_ctx, linear)?.as_linear_color()?;
+            render_ctx.clear(linear);
+        }
+        BytecodeOp::ClearOit => render_ctx.clear_oit(),
+        BytecodeOp::ClearBuffers { colors, depth } => {
+            let mut linear_colors = Vec::new();
+            for (buffer, color) in colors {
+                let color = evaluate_expression(render_ctx, function_ctx, color)?.as_linear_color()?;
+                linear_colors.push((*buffer, color));
             }
-            BytecodeOp::UniformRt(uniform_name, target_id, buffer_id) => {
-                render_ctx.set_uniform_render_target_texture(uniform_name, *target_id, *buffer_id)?;
+            let depth = match depth {
+                Some(depth) => Some(evaluate_expression(render_ctx, function_ctx, depth)?.as_f32()?),
+                None => None,
+            };
+            render_ctx.clear_buffers(&linear_colors, depth);
+        }
+
+        BytecodeOp::PipelineSetBlending(buffer, mode) => {
+            render_ctx.set_blending(*buffer, *mode);
+        }
+        BytecodeOp::PipelineSetWriteMask(write_color, write_depth) => {
+            let write_color = evaluate_expression(render_ctx, function_ctx, write_color)?.as_f32()? > 0.0;
+            let write_depth = evaluate_expression(render_ctx, function_ctx, write_depth)?.as_f32()? > 0.0;
+            render_ctx.set_write_mask(write_color, write_depth);
+        }
+        BytecodeOp::PipelineSetZTest(mode) => {
+            render_ctx.set_z_test(*mode);
+        }
+        BytecodeOp::PipelineSetCulling(mode) => {
+            render_ctx.set_culling(*mode);
+        }
+        BytecodeOp::PipelineSetClipPlane(index, a, b, c, d) => {
+            let a = evaluate_expression(render_ctx, function_ctx, a)?.as_f32()?;
+            let b = evaluate_expression(render_ctx, function_ctx, b)?.as_f32()?;
+            let c = evaluate_expression(render_ctx, function_ctx, c)?.as_f32()?;
+            let d = evaluate_expression(render_ctx, function_ctx, d)?.as_f32()?;
+            render_ctx.set_clip_plane(*index, glm::Vec4::new(a, b, c, d));
+        }
+
+        BytecodeOp::UniformFloat(uniform_id, value) => {
+            let value = evaluate_expression(render_ctx, function_ctx, &value)?.as_f32()?;
+            render_ctx.set_uniform_f32(*uniform_id, value)?;
+        }
+        BytecodeOp::UniformColor(uniform_id, value) => {
+            let value = evaluate_expression(render_ctx, function_ctx, &value)?.as_linear_color()?;
+            render_ctx.set_uniform_color(*uniform_id, value)?;
+        }
+        BytecodeOp::UniformVec3(uniform_id, value) => {
+            let value = evaluate_expression(render_ctx, function_ctx, &value)?.as_vec3()?;
+            render_ctx.set_uniform_vec3(*uniform_id, value)?;
+        }
+        BytecodeOp::UniformMat4(uniform_id, value) => {
+            let value = evaluate_expression(render_ctx, function_ctx, &value)?.as_mat4()?;
+            render_ctx.set_uniform_matrix(*uniform_id, &value)?;
+        }
+        BytecodeOp::UniformTexture(uniform_id, texture_id) => {
+            render_ctx.set_uniform_texture_srgb(*uniform_id, *texture_id)?;
+        }
+        BytecodeOp::UniformIbl(ibl_id) => {
+            render_ctx.set_uniform_ibl(*ibl_id)?;
+        }
+        BytecodeOp::UniformRt(uniform_id, target_id, buffer_id, srgb_decode) => {
+            render_ctx.set_uniform_render_target_texture(*uniform_id, *target_id, *buffer_id, *srgb_decode)?;
+        }
+        BytecodeOp::DrawQuad => {
+            render_ctx.validate_viewport();
+            render_ctx.upload_pass_params()?;
+            render_ctx.draw_quad()?;
+        }
+        BytecodeOp::DrawModel(model_id, bind_mtl) => {
+            render_ctx.validate_viewport();
+            render_ctx.upload_pass_params()?;
+            render_ctx.render_model(*model_id, *bind_mtl);
+        }
+        BytecodeOp::BindDrawParams(params) => {
+            let mut values = Vec::with_capacity(params.len());
+            for (name, value) in params {
+                values.push((name.as_str(), evaluate_expression(render_ctx, function_ctx, value)?));
             }
-            BytecodeOp::DrawQuad => {
-                render_ctx.render_fullscreen_quad();
+            render_ctx.bind_draw_params(&values)?;
+        }
+        BytecodeOp::DrawScene => draw_scene(render_ctx, function_ctx)?,
+        BytecodeOp::CameraLookAt { eye, center, up } => {
+            let eye = evaluate_expression(render_ctx, function_ctx, eye)?.as_vec3()?;
+            let center = evaluate_expression(render_ctx, function_ctx, center)?.as_vec3()?;
+            let up = evaluate_expression(render_ctx, function_ctx, up)?.as_vec3()?;
+            render_ctx.set_camera_lookat(eye, center, up)?;
+        }
+        BytecodeOp::CameraPerspective { fov_y, near, far } => {
+            let fov_y = evaluate_expression(render_ctx, function_ctx, fov_y)?.as_f32()?;
+            let near = evaluate_expression(render_ctx, function_ctx, near)?.as_f32()?;
+            let far = evaluate_expression(render_ctx, function_ctx, far)?.as_f32()?;
+            render_ctx.set_camera_perspective(fov_y, near, far)?;
+        }
+        BytecodeOp::SetModelTransform(matrix) => {
+            let matrix = evaluate_expression(render_ctx, function_ctx, matrix)?.as_mat4()?;
+            render_ctx.set_model_transform(&matrix)?;
+        }
+        BytecodeOp::DrawBatch(batch_id) => {
+            render_ctx.validate_viewport();
+            render_ctx.upload_pass_params()?;
+            render_ctx.render_batch(*batch_id);
+        }
+        BytecodeOp::DrawSkybox(ibl_id) => render_ctx.draw_skybox(*ibl_id)?,
+        BytecodeOp::Begin2d => render_ctx.begin_2d()?,
+        BytecodeOp::End2d => render_ctx.end_2d()?,
+        BytecodeOp::DrawRect {
+            x,
+            y,
+            width,
+            height,
+            color,
+        } => {
+            let rect = evaluate_rect(render_ctx, function_ctx, x, y, width, height)?;
+            let color = evaluate_expression(render_ctx, function_ctx, color)?.as_linear_color()?;
+            render_ctx.validate_viewport();
+            render_ctx.draw_rect(rect, color)?;
+        }
+        BytecodeOp::DrawTexturedQuad {
+            x,
+            y,
+            width,
+            height,
+            texture,
+        } => {
+            let rect = evaluate_rect(render_ctx, function_ctx, x, y, width, height)?;
+            render_ctx.validate_viewport();
+            render_ctx.draw_textured_quad(rect, *texture)?;
+        }
+        BytecodeOp::GrabScreen(uniform_id) => {
+            render_ctx.grab_screen(*uniform_id)?;
+        }
+        BytecodeOp::PostTaa {
+            color,
+            depth,
+            motion,
+            history,
+        } => {
+            render_ctx.post_taa(*color, *depth, *motion, *history)?;
+        }
+        BytecodeOp::PostAutoExposure { source, target, speed } => {
+            let speed = evaluate_expression(render_ctx, function_ctx, &speed)?.as_f32()?;
+            render_ctx.post_auto_exposure(*source, *target, speed)?;
+        }
+        BytecodeOp::PostVolumetrics {
+            depth,
+            lights,
+            target,
+            density,
+        } => {
+            let density = evaluate_expression(render_ctx, function_ctx, &density)?.as_f32()?;
+            render_ctx.post_volumetrics(*depth, lights, *target, density)?;
+        }
+        BytecodeOp::PostOitResolve {
+            accum,
+            revealage,
+            target,
+        } => {
+            render_ctx.post_oit_resolve(*accum, *revealage, *target)?;
+        }
+        BytecodeOp::PostLens { effect, source, param } => {
+            let param = evaluate_expression(render_ctx, function_ctx, &param)?.as_f32()?;
+            render_ctx.post_lens(*effect, *source, param)?;
+        }
+        BytecodeOp::Print { args, line } => {
+            if function_ctx.counters.allow_print() {
+                let mut output = match line {
+                    Some(line) => format!("[line {}]", line),
+                    None => String::new(),
+                };
+                for arg in args {
+                    let value = evaluate_expression(render_ctx, function_ctx, arg)?;
+                    if !output.is_empty() {
+                        output.push(' ');
+                    }
+                    output += &value.to_string();
+                }
+                println!("{}", output);
             }
-            BytecodeOp::DrawModel(model_id) => {
-                render_ctx.render_model(*model_id);
+        }
+        BytecodeOp::Assert {
+            condition,
+            message,
+            line,
+            halt,
+        } => {
+            let value = evaluate_expression(render_ctx, function_ctx, condition)?.as_f32()?;
+            if !(value > 0.0) {
+                if *halt {
+                    return Err(format!("Assertion failed: {}", message).into());
+                } else if function_ctx.counters.allow_print() {
+                    println!("Warning: assertion failed on line {}: {}", line, message);
+                }
             }
-            BytecodeOp::FunctionCall(function_call) => {
+        }
+        BytecodeOp::SetTitle(title) => {
+            let title = evaluate_expression(render_ctx, function_ctx, title)?.to_string();
+            render_ctx.window_settings.title = Some(title);
+        }
+        BytecodeOp::ShowCursor(show) => {
+            let show = evaluate_expression(render_ctx, function_ctx, show)?.as_f32()?;
+            render_ctx.window_settings.show_cursor = Some(show > 0.0);
+        }
+        BytecodeOp::FunctionCall(function_call) => {
+            let program = function_ctx.program;
+            let is_function = |name: &str| program.get_function(name).is_some();
+            // Passes are only skipped when called by the function which renders the frame
+            let is_pass = function_ctx.call_depth == 1;
+            if !is_pass || function_ctx.options.is_shown(&function_call.function, is_function) {
                 execute_function_call(render_ctx, function_ctx, function_call)?;
             }
-            BytecodeOp::Return { expr } => {
-                return Ok(evaluate_expression(render_ctx, function_ctx, expr)?);
-            }
-            BytecodeOp::Conditional { condition, a, b } => {
-                let value = evaluate_expression(render_ctx, function_ctx, condition)?
-                    .as_f32()
-                    .unwrap();
-                if value > 0.0 {
-                    execute_block(render_ctx, function_ctx, a)?;
-                } else if let Some(b) = b {
-                    execute_block(render_ctx, function_ctx, b)?;
-                }
+        }
+        BytecodeOp::Return { expr } => {
+            let value = evaluate_expression(render_ctx, function_ctx, expr)?;
+            return Ok(ControlFlow::Return(value));
+        }
+        BytecodeOp::Let { slot, name, expr } => {
+            let value = evaluate_expression(render_ctx, function_ctx, expr)?;
+            // Slots above belong to scopes which already ended
+            let mut locals = function_ctx.locals.borrow_mut();
+            locals.truncate(*slot);
+            locals.push((name.clone(), value));
+        }
+        BytecodeOp::Jump { offset } => return Ok(ControlFlow::Jump(*offset)),
+        BytecodeOp::JumpIfNot { condition, offset } => {
+            let value = evaluate_expression(render_ctx, function_ctx, condition)?.as_f32()?;
+            if !(value > 0.0) {
+                return Ok(ControlFlow::Jump(*offset));
             }
         }
     }
-    Ok(Value::Void)
+    Ok(ControlFlow::Next)
 }
@@ -0,0 +1,1327 @@
+use std::collections::{HashMap, HashSet};
+
+use ast::{SourceSlice, Type};
+use bytecode::{
+    BatchDef, BatchPartDef, BytecodeOp, CompileOptions, Function, FunctionCall, FunctionSignature, IblDef, LightDef,
+    Metadata, ModelDef, ProgramDef, RenderTargetDef, SceneObjectDef, TerrainDef, TextureDef, TextureImportOptions,
+    TrackHint, ValueExpr,
+};
+use color::LinearRGBA;
+use pack::PackEntry;
+use sync::BakedTracks;
+use types::{
+    AssertMode, BinaryOperator, BlendMode, CullingMode, LensEffect, LightType, RenderTargetFormat, StereoMode,
+    TrackInterpolation, ZTestMode,
+};
+
+/// Compact binary encoding of a value, used for caching compiled programs
+///
+/// The encoding is not self-describing: values have to be read back in the same order and with the same types as they
+/// were written. All numbers are stored in little endian.
+pub trait Serialize {
+    fn serialize(&self, writer: &mut Writer);
+}
+
+pub trait Deserialize: Sized {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String>;
+}
+
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+impl Writer {
+    pub fn new() -> Self {
+        Writer { bytes: Vec::new() }
+    }
+
+    pub fn write<T: Serialize + ?Sized>(&mut self, value: &T) {
+        value.serialize(self);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes: bytes, pos: 0 }
+    }
+
+    pub fn read<T: Deserialize>(&mut self) -> Result<T, String> {
+        T::deserialize(self)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < len {
+            return Err(format!("Unexpected end of data at byte {}", self.pos));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
+
+fn invalid_tag<T>(type_name: &str, tag: u8) -> Result<T, String> {
+    Err(format!("Invalid tag {} for {}", tag, type_name))
+}
+
+macro_rules! serialize_number {
+    ($t:ty, $size:expr) => {
+        impl Serialize for $t {
+            fn serialize(&self, writer: &mut Writer) {
+                writer.write_bytes(&self.to_le_bytes());
+            }
+        }
+        impl Deserialize for $t {
+            fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+                let mut bytes = [0; $size];
+                bytes.copy_from_slice(reader.read_bytes($size)?);
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+        }
+    };
+}
+serialize_number!(u8, 1);
+serialize_number!(u32, 4);
+serialize_number!(u64, 8);
+serialize_number!(i64, 8);
+
+impl Serialize for f32 {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.to_bits());
+    }
+}
+impl Deserialize for f32 {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(f32::from_bits(reader.read()?))
+    }
+}
+
+impl Serialize for usize {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&(*self as u64));
+    }
+}
+impl Deserialize for usize {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(reader.read::<u64>()? as usize)
+    }
+}
+
+impl Serialize for isize {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&(*self as i64));
+    }
+}
+impl Deserialize for isize {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(reader.read::<i64>()? as isize)
+    }
+}
+
+impl Serialize for bool {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&(*self as u8));
+    }
+}
+impl Deserialize for bool {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        match reader.read::<u8>()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => invalid_tag("bool", tag),
+        }
+    }
+}
+
+impl Serialize for str {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.len());
+        writer.write_bytes(self.as_bytes());
+    }
+}
+impl Serialize for String {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(self.as_str());
+    }
+}
+impl Deserialize for String {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let len = reader.read()?;
+        let bytes = reader.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| format!("Invalid string: {}", e))
+    }
+}
+
+impl<T: Serialize> Serialize for Box<T> {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&**self);
+    }
+}
+impl<T: Deserialize> Deserialize for Box<T> {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(Box::new(reader.read()?))
+    }
+}
+
+impl<T: Serialize> Serialize for Option<T> {
+    fn serialize(&self, writer: &mut Writer) {
+        match self {
+            None => writer.write(&0u8),
+            Some(value) => {
+                writer.write(&1u8);
+                writer.write(value);
+            }
+        }
+    }
+}
+impl<T: Deserialize> Deserialize for Option<T> {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        match reader.read::<u8>()? {
+            0 => Ok(None),
+            1 => Ok(Some(reader.read()?)),
+            tag => invalid_tag("Option", tag),
+        }
+    }
+}
+
+impl<A: Serialize, B: Serialize> Serialize for (A, B) {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.0);
+        writer.write(&self.1);
+    }
+}
+impl<A: Deserialize, B: Deserialize> Deserialize for (A, B) {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let a = reader.read()?;
+        let b = reader.read()?;
+        Ok((a, b))
+    }
+}
+
+impl<T: Serialize> Serialize for [T] {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.len());
+        for item in self {
+            writer.write(item);
+        }
+    }
+}
+impl<T: Serialize> Serialize for Vec<T> {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(self.as_slice());
+    }
+}
+impl<T: Deserialize> Deserialize for Vec<T> {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let len: usize = reader.read()?;
+        let mut result = Vec::new();
+        for _ in 0..len {
+            result.push(reader.read()?);
+        }
+        Ok(result)
+    }
+}
+
+// Maps and sets are written in sorted order, so that the output is deterministic
+impl<T: Serialize> Serialize for HashMap<String, T> {
+    fn serialize(&self, writer: &mut Writer) {
+        let mut entries: Vec<(&String, &T)> = self.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        writer.write(&entries.len());
+        for (key, value) in entries {
+            writer.write(key);
+            writer.write(value);
+        }
+    }
+}
+impl<T: Deserialize> Deserialize for HashMap<String, T> {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let entries: Vec<(String, T)> = reader.read()?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+impl Serialize for HashSet<String> {
+    fn serialize(&self, writer: &mut Writer) {
+        let mut entries: Vec<&String> = self.iter().collect();
+        entries.sort();
+        writer.write(&entries.len());
+        for entry in entries {
+            writer.write(entry);
+        }
+    }
+}
+impl Deserialize for HashSet<String> {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let entries: Vec<String> = reader.read()?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+/// Implements serialization for enums without any fields, each variant is stored as a one byte tag
+macro_rules! serialize_unit_enum {
+    ($t:ident { $($variant:ident = $tag:expr),* $(,)* }) => {
+        impl Serialize for $t {
+            fn serialize(&self, writer: &mut Writer) {
+                let tag: u8 = match self {
+                    $($t::$variant => $tag,)*
+                };
+                writer.write(&tag);
+            }
+        }
+        impl Deserialize for $t {
+            fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+                match reader.read::<u8>()? {
+                    $($tag => Ok($t::$variant),)*
+                    tag => invalid_tag(stringify!($t), tag),
+                }
+            }
+        }
+    };
+}
+
+serialize_unit_enum!(BinaryOperator {
+    Add = 0,
+    Sub = 1,
+    Mul = 2,
+    Div = 3,
+    Lt = 4,
+    Le = 5,
+    Gt = 6,
+    Ge = 7,
+    Eq = 8,
+    Ne = 9,
+});
+serialize_unit_enum!(RenderTargetFormat {
+    Srgb8 = 0,
+    Srgba8 = 1,
+    R8 = 2,
+    Rgb8 = 3,
+    Rgba8 = 4,
+    R16 = 5,
+    R16F = 6,
+    Rgb16 = 7,
+    Rgb16F = 8,
+    Rgba16 = 9,
+    Rgba16F = 10,
+    R32F = 11,
+    Rgb32F = 12,
+    Rgba32F = 13,
+    Rg8 = 14,
+    Rg16F = 15,
+    Rg32F = 16,
+    R11FG11FB10F = 17,
+    Rgb10A2 = 18,
+    Depth32F = 19,
+});
+serialize_unit_enum!(BlendMode {
+    None = 0,
+    Add = 1,
+    AlphaBlend = 2,
+    OitCoverageBlend = 3,
+});
+serialize_unit_enum!(ZTestMode {
+    LessEqual = 0,
+    Equal = 1,
+    Always = 2,
+});
+serialize_unit_enum!(CullingMode {
+    Front = 0,
+    Back = 1,
+    None = 2,
+});
+serialize_unit_enum!(LightType {
+    Directional = 0,
+    Point = 1,
+    Spot = 2,
+});
+serialize_unit_enum!(StereoMode {
+    SideBySide = 0,
+});
+serialize_unit_enum!(TrackInterpolation {
+    Step = 0,
+    Linear = 1,
+    Smooth = 2,
+    Ramp = 3,
+});
+serialize_unit_enum!(LensEffect {
+    Flare = 0,
+    ChromaticAberration = 1,
+    Vignette = 2,
+    Grain = 3,
+});
+serialize_unit_enum!(AssertMode {
+    Halt = 0,
+    Warn = 1,
+    Off = 2,
+});
+
+serialize_unit_enum!(Type {
+    Float32 = 0,
+    LinColor = 1,
+    Str = 2,
+    Void = 3,
+    Vec2 = 4,
+    Vec3 = 5,
+    Vec4 = 6,
+    Mat4 = 7,
+});
+
+impl Serialize for LinearRGBA {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.r);
+        writer.write(&self.g);
+        writer.write(&self.b);
+        writer.write(&self.a);
+    }
+}
+impl Deserialize for LinearRGBA {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let r = reader.read()?;
+        let g = reader.read()?;
+        let b = reader.read()?;
+        let a = reader.read()?;
+        Ok(LinearRGBA::from_f32(r, g, b, a))
+    }
+}
+
+impl Serialize for SourceSlice {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.begin);
+        writer.write(&self.end);
+    }
+}
+impl Deserialize for SourceSlice {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let begin = reader.read()?;
+        let end = reader.read()?;
+        Ok(SourceSlice::new(begin, end))
+    }
+}
+
+impl Serialize for CompileOptions {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.assert_mode);
+        writer.write(&self.keep_unused_functions);
+    }
+}
+impl Deserialize for CompileOptions {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(CompileOptions {
+            assert_mode: reader.read()?,
+            keep_unused_functions: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for Metadata {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.aspect);
+        writer.write(&self.stereo_mode);
+        writer.write(&self.eye_separation);
+        writer.write(&self.reversed_z);
+        writer.write(&self.watch);
+        writer.write(&self.watch_exclude);
+        writer.write(&self.dynamic_resolution);
+        writer.write(&self.duration);
+        writer.write(&self.scenes);
+        writer.write(&self.soundtrack);
+    }
+}
+impl Deserialize for Metadata {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        let aspect = reader.read()?;
+        let stereo_mode = reader.read()?;
+        Ok(Metadata {
+            aspect: aspect,
+            stereo_mode: stereo_mode,
+            eye_separation: reader.read()?,
+            reversed_z: reader.read()?,
+            watch: reader.read()?,
+            watch_exclude: reader.read()?,
+            dynamic_resolution: reader.read()?,
+            duration: reader.read()?,
+            scenes: reader.read()?,
+            soundtrack: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for TrackHint {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.name);
+        writer.write(&self.range);
+        writer.write(&self.interpolation);
+    }
+}
+impl Deserialize for TrackHint {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(TrackHint {
+            name: reader.read()?,
+            range: reader.read()?,
+            interpolation: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for PackEntry {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.path);
+        writer.write(&self.offset);
+        writer.write(&self.compressed_size);
+        writer.write(&self.size);
+    }
+}
+impl Deserialize for PackEntry {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(PackEntry {
+            path: reader.read()?,
+            offset: reader.read()?,
+            compressed_size: reader.read()?,
+            size: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for BakedTracks {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.rows_per_second);
+        writer.write(&self.tracks);
+    }
+}
+impl Deserialize for BakedTracks {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(BakedTracks {
+            rows_per_second: reader.read()?,
+            tracks: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for FunctionCall {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.function);
+        writer.write(&self.args);
+    }
+}
+impl Deserialize for FunctionCall {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(FunctionCall {
+            function: reader.read()?,
+            args: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for ValueExpr {
+    fn serialize(&self, writer: &mut Writer) {
+        match self {
+            ValueExpr::FunctionCall(function_call) => {
+                writer.write(&0u8);
+                writer.write(function_call);
+            }
+            ValueExpr::Var(name) => {
+                writer.write(&1u8);
+                writer.write(name);
+            }
+            ValueExpr::Local(slot, name) => {
+                writer.write(&2u8);
+                writer.write(slot);
+                writer.write(name);
+            }
+            ValueExpr::Global(slot, name) => {
+                writer.write(&3u8);
+                writer.write(slot);
+                writer.write(name);
+            }
+            ValueExpr::SyncTrack(track) => {
+                writer.write(&4u8);
+                writer.write(track);
+            }
+            ValueExpr::ConstFloat(value) => {
+                writer.write(&5u8);
+                writer.write(value);
+            }
+            ValueExpr::ConstLinColor(color) => {
+                writer.write(&6u8);
+                writer.write(color);
+            }
+            ValueExpr::ConstString(string) => {
+                writer.write(&7u8);
+                writer.write(string);
+            }
+            ValueExpr::ConstDict(entries) => {
+                writer.write(&8u8);
+                writer.write(entries);
+            }
+            ValueExpr::BinaryOp(op, l, r) => {
+                writer.write(&9u8);
+                writer.write(op);
+                writer.write(l);
+                writer.write(r);
+            }
+        }
+    }
+}
+impl Deserialize for ValueExpr {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        match reader.read::<u8>()? {
+            0 => Ok(ValueExpr::FunctionCall(reader.read()?)),
+            1 => Ok(ValueExpr::Var(reader.read()?)),
+            2 => {
+                let slot = reader.read()?;
+                Ok(ValueExpr::Local(slot, reader.read()?))
+            }
+            3 => {
+                let slot = reader.read()?;
+                Ok(ValueExpr::Global(slot, reader.read()?))
+            }
+            4 => Ok(ValueExpr::SyncTrack(reader.read()?)),
+            5 => Ok(ValueExpr::ConstFloat(reader.read()?)),
+            6 => Ok(ValueExpr::ConstLinColor(reader.read()?)),
+            7 => Ok(ValueExpr::ConstString(reader.read()?)),
+            8 => Ok(ValueExpr::ConstDict(reader.read()?)),
+            9 => {
+                let op = reader.read()?;
+                let l = reader.read()?;
+                let r = reader.read()?;
+                Ok(ValueExpr::BinaryOp(op, l, r))
+            }
+            tag => invalid_tag("ValueExpr", tag),
+        }
+    }
+}
+
+impl Serialize for BytecodeOp {
+    fn serialize(&self, writer: &mut Writer) {
+        match self {
+            BytecodeOp::BindRt(target) => {
+                writer.write(&0u8);
+                writer.write(target);
+            }
+            BytecodeOp::BindScreenRt => writer.write(&1u8),
+            BytecodeOp::InvalidateRt => writer.write(&25u8),
+            BytecodeOp::BindProgram(program) => {
+                writer.write(&2u8);
+                writer.write(program);
+            }
+            BytecodeOp::Viewport(x, y, width, height) => {
+                writer.write(&3u8);
+                writer.write(x);
+                writer.write(y);
+                writer.write(width);
+                writer.write(height);
+            }
+            BytecodeOp::Clear(color) => {
+                writer.write(&4u8);
+                writer.write(color);
+            }
+            BytecodeOp::PipelineSetBlending(buffer, mode) => {
+                writer.write(&5u8);
+                writer.write(buffer);
+                writer.write(mode);
+            }
+            BytecodeOp::PipelineSetWriteMask(write_color, write_depth) => {
+                writer.write(&6u8);
+                writer.write(write_color);
+                writer.write(write_depth);
+            }
+            BytecodeOp::PipelineSetZTest(mode) => {
+                writer.write(&7u8);
+                writer.write(mode);
+            }
+            BytecodeOp::PipelineSetCulling(mode) => {
+                writer.write(&8u8);
+                writer.write(mode);
+            }
+            BytecodeOp::UniformFloat(uniform, value) => {
+                writer.write(&9u8);
+                writer.write(uniform);
+                writer.write(value);
+            }
+            BytecodeOp::UniformColor(uniform, value) => {
+                writer.write(&10u8);
+                writer.write(uniform);
+                writer.write(value);
+            }
+            BytecodeOp::UniformTexture(uniform, texture) => {
+                writer.write(&11u8);
+                writer.write(uniform);
+                writer.write(texture);
+            }
+            BytecodeOp::UniformIbl(ibl) => {
+                writer.write(&12u8);
+                writer.write(ibl);
+            }
+            BytecodeOp::UniformRt(uniform, target, buffer, srgb_decode) => {
+                writer.write(&13u8);
+                writer.write(uniform);
+                writer.write(target);
+                writer.write(buffer);
+                writer.write(srgb_decode);
+            }
+            BytecodeOp::DrawQuad => writer.write(&14u8),
+            BytecodeOp::DrawModel(model, bind_mtl) => {
+                writer.write(&15u8);
+                writer.write(model);
+                writer.write(bind_mtl);
+            }
+            BytecodeOp::Print { args, line } => {
+                writer.write(&16u8);
+                writer.write(args);
+                writer.write(line);
+            }
+            BytecodeOp::Assert {
+                condition,
+                message,
+                line,
+                halt,
+            } => {
+                writer.write(&17u8);
+                writer.write(condition);
+                writer.write(message);
+                writer.write(line);
+                writer.write(halt);
+            }
+            BytecodeOp::FunctionCall(function_call) => {
+                writer.write(&18u8);
+                writer.write(function_call);
+            }
+            BytecodeOp::Return { expr } => {
+                writer.write(&19u8);
+                writer.write(expr);
+            }
+            BytecodeOp::Jump { offset } => {
+                writer.write(&20u8);
+                writer.write(offset);
+            }
+            BytecodeOp::JumpIfNot { condition, offset } => {
+                writer.write(&21u8);
+                writer.write(condition);
+                writer.write(offset);
+            }
+            BytecodeOp::GrabScreen(uniform) => {
+                writer.write(&22u8);
+                writer.write(uniform);
+            }
+            BytecodeOp::PostTaa {
+                color,
+                depth,
+                motion,
+                history,
+            } => {
+                writer.write(&23u8);
+                writer.write(color);
+                writer.write(depth);
+                writer.write(motion);
+                writer.write(history);
+            }
+            BytecodeOp::PostAutoExposure { source, target, speed } => {
+                writer.write(&24u8);
+                writer.write(source);
+                writer.write(target);
+                writer.write(speed);
+            }
+            BytecodeOp::PostVolumetrics {
+                depth,
+                lights,
+                target,
+                density,
+            } => {
+                writer.write(&31u8);
+                writer.write(depth);
+                writer.write(lights);
+                writer.write(target);
+                writer.write(density);
+            }
+            BytecodeOp::PostLens { effect, source, param } => {
+                writer.write(&32u8);
+                writer.write(effect);
+                writer.write(source);
+                writer.write(param);
+            }
+            BytecodeOp::ClearOit => writer.write(&33u8),
+            BytecodeOp::ClearBuffers { colors, depth } => {
+                writer.write(&36u8);
+                writer.write(colors);
+                writer.write(depth);
+            }
+            BytecodeOp::DrawSkybox(ibl) => {
+                writer.write(&35u8);
+                writer.write(ibl);
+            }
+            BytecodeOp::BindScaledRt(target, scale) => {
+                writer.write(&38u8);
+                writer.write(target);
+                writer.write(scale);
+            }
+            BytecodeOp::PipelineSetClipPlane(index, a, b, c, d) => {
+                writer.write(&37u8);
+                writer.write(index);
+                writer.write(a);
+                writer.write(b);
+                writer.write(c);
+                writer.write(d);
+            }
+            BytecodeOp::PostOitResolve {
+                accum,
+                revealage,
+                target,
+            } => {
+                writer.write(&34u8);
+                writer.write(accum);
+                writer.write(revealage);
+                writer.write(target);
+            }
+            BytecodeOp::Begin2d => writer.write(&26u8),
+            BytecodeOp::End2d => writer.write(&27u8),
+            BytecodeOp::DrawRect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => {
+                writer.write(&28u8);
+                writer.write(x);
+                writer.write(y);
+                writer.write(width);
+                writer.write(height);
+                writer.write(color);
+            }
+            BytecodeOp::DrawTexturedQuad {
+                x,
+                y,
+                width,
+                height,
+                texture,
+            } => {
+                writer.write(&29u8);
+                writer.write(x);
+                writer.write(y);
+                writer.write(width);
+                writer.write(height);
+                writer.write(texture);
+            }
+            BytecodeOp::DrawScene => writer.write(&30u8),
+            BytecodeOp::SetTitle(title) => {
+                writer.write(&39u8);
+                writer.write(title);
+            }
+            BytecodeOp::ShowCursor(show) => {
+                writer.write(&40u8);
+                writer.write(show);
+            }
+            BytecodeOp::Let { slot, name, expr } => {
+                writer.write(&41u8);
+                writer.write(slot);
+                writer.write(name);
+                writer.write(expr);
+            }
+            BytecodeOp::DrawBatch(batch) => {
+                writer.write(&42u8);
+                writer.write(batch);
+            }
+            BytecodeOp::UniformVec3(uniform, value) => {
+                writer.write(&43u8);
+                writer.write(uniform);
+                writer.write(value);
+            }
+            BytecodeOp::UniformMat4(uniform, value) => {
+                writer.write(&44u8);
+                writer.write(uniform);
+                writer.write(value);
+            }
+            BytecodeOp::BindDrawParams(params) => {
+                writer.write(&45u8);
+                writer.write(params);
+            }
+            BytecodeOp::CameraLookAt { eye, center, up } => {
+                writer.write(&46u8);
+                writer.write(eye);
+                writer.write(center);
+                writer.write(up);
+            }
+            BytecodeOp::CameraPerspective { fov_y, near, far } => {
+                writer.write(&47u8);
+                writer.write(fov_y);
+                writer.write(near);
+                writer.write(far);
+            }
+            BytecodeOp::SetModelTransform(matrix) => {
+                writer.write(&48u8);
+                writer.write(matrix);
+            }
+        }
+    }
+}
+impl Deserialize for BytecodeOp {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        match reader.read::<u8>()? {
+            0 => Ok(BytecodeOp::BindRt(reader.read()?)),
+            1 => Ok(BytecodeOp::BindScreenRt),
+            2 => Ok(BytecodeOp::BindProgram(reader.read()?)),
+            3 => {
+                let x = reader.read()?;
+                let y = reader.read()?;
+                let width = reader.read()?;
+                let height = reader.read()?;
+                Ok(BytecodeOp::Viewport(x, y, width, height))
+            }
+            4 => Ok(BytecodeOp::Clear(reader.read()?)),
+            5 => {
+                let buffer = reader.read()?;
+                Ok(BytecodeOp::PipelineSetBlending(buffer, reader.read()?))
+            }
+            6 => {
+                let write_color = reader.read()?;
+                Ok(BytecodeOp::PipelineSetWriteMask(write_color, reader.read()?))
+            }
+            7 => Ok(BytecodeOp::PipelineSetZTest(reader.read()?)),
+            8 => Ok(BytecodeOp::PipelineSetCulling(reader.read()?)),
+            9 => {
+                let uniform = reader.read()?;
+                Ok(BytecodeOp::UniformFloat(uniform, reader.read()?))
+            }
+            10 => {
+                let uniform = reader.read()?;
+                Ok(BytecodeOp::UniformColor(uniform, reader.read()?))
+            }
+            11 => {
+                let uniform = reader.read()?;
+                Ok(BytecodeOp::UniformTexture(uniform, reader.read()?))
+            }
+            12 => Ok(BytecodeOp::UniformIbl(reader.read()?)),
+            13 => {
+                let uniform = reader.read()?;
+                let target = reader.read()?;
+                let buffer = reader.read()?;
+                Ok(BytecodeOp::UniformRt(uniform, target, buffer, reader.read()?))
+            }
+            14 => Ok(BytecodeOp::DrawQuad),
+            15 => {
+                let model = reader.read()?;
+                Ok(BytecodeOp::DrawModel(model, reader.read()?))
+            }
+            16 => {
+                let args = reader.read()?;
+                Ok(BytecodeOp::Print {
+                    args: args,
+                    line: reader.read()?,
+                })
+            }
+            17 => {
+                let condition = reader.read()?;
+                let message = reader.read()?;
+                let line = reader.read()?;
+                Ok(BytecodeOp::Assert {
+                    condition: condition,
+                    message: message,
+                    line: line,
+                    halt: reader.read()?,
+                })
+            }
+            18 => Ok(BytecodeOp::FunctionCall(reader.read()?)),
+            19 => Ok(BytecodeOp::Return { expr: reader.read()? }),
+            20 => Ok(BytecodeOp::Jump { offset: reader.read()? }),
+            21 => {
+                let condition = reader.read()?;
+                Ok(BytecodeOp::JumpIfNot {
+                    condition: condition,
+                    offset: reader.read()?,
+                })
+            }
+            22 => Ok(BytecodeOp::GrabScreen(reader.read()?)),
+            23 => {
+                let color = reader.read()?;
+                let depth = reader.read()?;
+                let motion = reader.read()?;
+                Ok(BytecodeOp::PostTaa {
+                    color: color,
+                    depth: depth,
+                    motion: motion,
+                    history: reader.read()?,
+                })
+            }
+            24 => {
+                let source = reader.read()?;
+                let target = reader.read()?;
+                Ok(BytecodeOp::PostAutoExposure {
+                    source: source,
+                    target: target,
+                    speed: reader.read()?,
+                })
+            }
+            25 => Ok(BytecodeOp::InvalidateRt),
+            26 => Ok(BytecodeOp::Begin2d),
+            27 => Ok(BytecodeOp::End2d),
+            28 => {
+                let x = reader.read()?;
+                let y = reader.read()?;
+                let width = reader.read()?;
+                let height = reader.read()?;
+                Ok(BytecodeOp::DrawRect {
+                    x: x,
+                    y: y,
+                    width: width,
+                    height: height,
+                    color: reader.read()?,
+                })
+            }
+            29 => {
+                let x = reader.read()?;
+                let y = reader.read()?;
+                let width = reader.read()?;
+                let height = reader.read()?;
+                Ok(BytecodeOp::DrawTexturedQuad {
+                    x: x,
+                    y: y,
+                    width: width,
+                    height: height,
+                    texture: reader.read()?,
+                })
+            }
+            30 => Ok(BytecodeOp::DrawScene),
+            31 => {
+                let depth = reader.read()?;
+                let lights = reader.read()?;
+                let target = reader.read()?;
+                Ok(BytecodeOp::PostVolumetrics {
+                    depth: depth,
+                    lights: lights,
+                    target: target,
+                    density: reader.read()?,
+                })
+            }
+            32 => {
+                let effect = reader.read()?;
+                let source = reader.read()?;
+                Ok(BytecodeOp::PostLens {
+                    effect: effect,
+                    source: source,
+                    param: reader.read()?,
+                })
+            }
+            33 => Ok(BytecodeOp::ClearOit),
+            34 => {
+                let accum = reader.read()?;
+                let revealage = reader.read()?;
+                Ok(BytecodeOp::PostOitResolve {
+                    accum: accum,
+                    revealage: revealage,
+                    target: reader.read()?,
+                })
+            }
+            35 => Ok(BytecodeOp::DrawSkybox(reader.read()?)),
+            36 => {
+                let colors = reader.read()?;
+                Ok(BytecodeOp::ClearBuffers {
+                    colors: colors,
+                    depth: reader.read()?,
+                })
+            }
+            37 => {
+                let index = reader.read()?;
+                let a = reader.read()?;
+                let b = reader.read()?;
+                let c = reader.read()?;
+                let d = reader.read()?;
+                Ok(BytecodeOp::PipelineSetClipPlane(index, a, b, c, d))
+            }
+            38 => {
+                let target = reader.read()?;
+                Ok(BytecodeOp::BindScaledRt(target, reader.read()?))
+            }
+            39 => Ok(BytecodeOp::SetTitle(reader.read()?)),
+            40 => Ok(BytecodeOp::ShowCursor(reader.read()?)),
+            41 => {
+                let slot = reader.read()?;
+                let name = reader.read()?;
+                Ok(BytecodeOp::Let {
+                    slot: slot,
+                    name: name,
+                    expr: reader.read()?,
+                })
+            }
+            42 => Ok(BytecodeOp::DrawBatch(reader.read()?)),
+            43 => {
+                let uniform = reader.read()?;
+                Ok(BytecodeOp::UniformVec3(uniform, reader.read()?))
+            }
+            44 => {
+                let uniform = reader.read()?;
+                Ok(BytecodeOp::UniformMat4(uniform, reader.read()?))
+            }
+            45 => Ok(BytecodeOp::BindDrawParams(reader.read()?)),
+            46 => {
+                let eye = reader.read()?;
+                let center = reader.read()?;
+                Ok(BytecodeOp::CameraLookAt {
+                    eye: eye,
+                    center: center,
+                    up: reader.read()?,
+                })
+            }
+            47 => {
+                let fov_y = reader.read()?;
+                let near = reader.read()?;
+                Ok(BytecodeOp::CameraPerspective {
+                    fov_y: fov_y,
+                    near: near,
+                    far: reader.read()?,
+                })
+            }
+            48 => Ok(BytecodeOp::SetModelTransform(reader.read()?)),
+            tag => invalid_tag("BytecodeOp", tag),
+        }
+    }
+}
+
+impl Serialize for TextureDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.path);
+        writer.write(&self.srgb);
+        writer.write(&self.options);
+    }
+}
+impl Deserialize for TextureDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(TextureDef {
+            path: reader.read()?,
+            srgb: reader.read()?,
+            options: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for TextureImportOptions {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.exposure);
+        writer.write(&self.premultiply_alpha);
+        writer.write(&self.flip_y);
+    }
+}
+impl Deserialize for TextureImportOptions {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(TextureImportOptions {
+            exposure: reader.read()?,
+            premultiply_alpha: reader.read()?,
+            flip_y: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for ModelDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.path);
+        writer.write(&self.terrain);
+    }
+}
+impl Deserialize for ModelDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(ModelDef {
+            path: reader.read()?,
+            terrain: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for TerrainDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.size);
+        writer.write(&self.max_height);
+        writer.write(&self.resolution);
+    }
+}
+impl Deserialize for TerrainDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(TerrainDef {
+            size: reader.read()?,
+            max_height: reader.read()?,
+            resolution: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for IblDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.folder);
+    }
+}
+impl Deserialize for IblDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(IblDef { folder: reader.read()? })
+    }
+}
+
+impl Serialize for RenderTargetDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.name);
+        writer.write(&self.width);
+        writer.write(&self.height);
+        writer.write(&self.formats);
+        writer.write(&self.has_depth);
+        writer.write(&self.oit);
+    }
+}
+impl Deserialize for RenderTargetDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(RenderTargetDef {
+            name: reader.read()?,
+            width: reader.read()?,
+            height: reader.read()?,
+            formats: reader.read()?,
+            has_depth: reader.read()?,
+            oit: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for SceneObjectDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.name);
+        writer.write(&self.visible);
+        writer.write(&self.transform);
+        writer.write(&self.bytecode);
+    }
+}
+impl Deserialize for SceneObjectDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(SceneObjectDef {
+            name: reader.read()?,
+            visible: reader.read()?,
+            transform: reader.read()?,
+            bytecode: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for BatchDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.name);
+        writer.write(&self.parts);
+    }
+}
+impl Deserialize for BatchDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(BatchDef {
+            name: reader.read()?,
+            parts: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for BatchPartDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.model);
+        writer.write(&self.transform);
+    }
+}
+impl Deserialize for BatchPartDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(BatchPartDef {
+            model: reader.read()?,
+            transform: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for LightDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.name);
+        writer.write(&self.light_type);
+        writer.write(&self.position);
+        writer.write(&self.direction);
+        writer.write(&self.color);
+        writer.write(&self.intensity);
+        writer.write(&self.angle);
+    }
+}
+impl Deserialize for LightDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(LightDef {
+            name: reader.read()?,
+            light_type: reader.read()?,
+            position: reader.read()?,
+            direction: reader.read()?,
+            color: reader.read()?,
+            intensity: reader.read()?,
+            angle: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for ProgramDef {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.vert);
+        writer.write(&self.tess_ctrl);
+        writer.write(&self.tess_eval);
+        writer.write(&self.geom);
+        writer.write(&self.frag);
+        writer.write(&self.comp);
+        writer.write(&self.raymarch);
+    }
+}
+impl Deserialize for ProgramDef {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(ProgramDef {
+            vert: reader.read()?,
+            tess_ctrl: reader.read()?,
+            tess_eval: reader.read()?,
+            geom: reader.read()?,
+            frag: reader.read()?,
+            comp: reader.read()?,
+            raymarch: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for FunctionSignature {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.params);
+        writer.write(&self.return_type);
+    }
+}
+impl Deserialize for FunctionSignature {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(FunctionSignature {
+            params: reader.read()?,
+            return_type: reader.read()?,
+        })
+    }
+}
+
+impl Serialize for Function {
+    fn serialize(&self, writer: &mut Writer) {
+        writer.write(&self.source_slice);
+        writer.write(&self.name);
+        writer.write(&self.params);
+        writer.write(&self.return_type);
+        writer.write(&self.bytecode);
+    }
+}
+impl Deserialize for Function {
+    fn deserialize(reader: &mut Reader) -> Result<Self, String> {
+        Ok(Function {
+            source_slice: reader.read()?,
+            name: reader.read()?,
+            params: reader.read()?,
+            return_type: reader.read()?,
+            bytecode: reader.read()?,
+        })
+    }
+}
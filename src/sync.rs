@@ -1,12 +1,24 @@
+use gilrs::{self, Axis, Button, Gilrs};
+#[cfg(feature = "editor")]
 use rust_rocket::{Event, Rocket};
+use std::collections::HashMap;
 use time;
 
+use audio::Soundtrack;
+use bytecode::TrackHint;
+
 pub trait SyncTracker {
     fn require_track(&mut self, track: &str);
+    /// Applies the hints declared by the script for a track, as far as the tracker supports them
+    fn set_track_hint(&mut self, hint: &TrackHint);
 
     fn update(&mut self);
+    /// Stops playback at the given time, for rendering frames at times of the caller's choosing
+    fn seek(&mut self, time: f64);
     fn get_time(&self) -> f64;
     fn get_value(&self, track: &str) -> Option<f32>;
+    /// Plays the music of the demo along, its clock replaces the one of the tracker while it plays
+    fn set_soundtrack(&mut self, _soundtrack: Option<Soundtrack>) {}
 }
 
 // Describes the time at which playback started, or was resumed
@@ -14,13 +26,39 @@ pub struct PlayStartPoint {
     pub base_time: f64,
     pub real_time: f64,
 }
+impl PlayStartPoint {
+    /// The current time of the playback, given by the soundtrack if there is one
+    fn get_time(&self, soundtrack: Option<&Soundtrack>) -> f64 {
+        match soundtrack {
+            Some(soundtrack) => soundtrack.get_time(),
+            None => self.base_time + (time::precise_time_s() - self.real_time),
+        }
+    }
+}
 
+/// Makes the soundtrack follow a tracker which started or stopped playing at the given time
+fn sync_soundtrack(soundtrack: Option<&Soundtrack>, time: f64, playing: bool) {
+    if let Some(soundtrack) = soundtrack {
+        soundtrack.seek(time);
+        if playing {
+            soundtrack.play();
+        } else {
+            soundtrack.pause();
+        }
+    }
+}
+
+#[cfg(feature = "editor")]
 pub struct RocketSyncTracker {
     rocket: Rocket,
     fps: f64,
     time: f64,
     play_start_point: Option<PlayStartPoint>,
+    // (min, max) of the tracks with a declared range
+    ranges: HashMap<String, (f32, f32)>,
+    soundtrack: Option<Soundtrack>,
 }
+#[cfg(feature = "editor")]
 impl RocketSyncTracker {
     pub fn new(fps: f64) -> Result<Self, String> {
         let mut tracker = RocketSyncTracker {
@@ -28,6 +66,8 @@ impl RocketSyncTracker {
             fps: fps,
             time: 0.0,
             play_start_point: None,
+            ranges: HashMap::new(),
+            soundtrack: None,
         };
         tracker.play();
         Ok(tracker)
@@ -35,8 +75,9 @@ impl RocketSyncTracker {
 
     fn pause(&mut self) {
         if let Some(p) = self.play_start_point.take() {
-            self.time = p.base_time + (time::precise_time_s() - p.real_time);
+            self.time = p.get_time(self.soundtrack.as_ref());
         }
+        sync_soundtrack(self.soundtrack.as_ref(), self.time, false);
     }
 
     fn play(&mut self) {
@@ -44,6 +85,7 @@ impl RocketSyncTracker {
             base_time: self.time,
             real_time: time::precise_time_s(),
         });
+        sync_soundtrack(self.soundtrack.as_ref(), self.time, true);
     }
 
     fn go_to_time(&mut self, time: f64) {
@@ -55,11 +97,62 @@ impl RocketSyncTracker {
             self.time = time;
         }
     }
+
+    /// Samples the given tracks at each row from 0 to `duration_s`, for playing them without the editor
+    ///
+    /// The keys of tracks are only sent by the editor once they are requested, the editor is given some time to
+    /// answer before the tracks are sampled.
+    pub fn bake(&mut self, tracks: &[String], duration_s: f32) -> BakedTracks {
+        for track in tracks {
+            self.require_track(track);
+        }
+        let wait_until = time::precise_time_s() + ROCKET_KEY_WAIT_S;
+        while time::precise_time_s() < wait_until {
+            while self.rocket.poll_events().is_some() {}
+            ::std::thread::sleep(::std::time::Duration::from_millis(10));
+        }
+
+        let row_count = (duration_s as f64 * self.fps).ceil() as usize + 1;
+        let tracks = tracks
+            .iter()
+            .map(|track| {
+                let values = (0..row_count)
+                    .map(|row| {
+                        let value = self.rocket.get_track(track).map_or(0.0, |t| t.get_value(row as f32));
+                        match self.ranges.get(track) {
+                            Some(&(min, max)) => value.max(min).min(max),
+                            None => value,
+                        }
+                    })
+                    .collect();
+                (track.clone(), values)
+            })
+            .collect();
+        BakedTracks {
+            rows_per_second: self.fps as f32,
+            tracks: tracks,
+        }
+    }
 }
+#[cfg(feature = "editor")]
 impl SyncTracker for RocketSyncTracker {
     fn require_track(&mut self, track: &str) {
         self.rocket.get_track_mut(track);
     }
+    fn set_track_hint(&mut self, hint: &TrackHint) {
+        // The protocol has no messages for ranges or interpolations, the editor cannot be told about them. The range
+        // is enforced on the values instead, and the hints are listed for whoever sets up the editor.
+        if let Some(range) = hint.range {
+            self.ranges.insert(hint.name.clone(), range);
+        }
+        let range = hint
+            .range
+            .map_or(format!("any range"), |(min, max)| format!("{} to {}", min, max));
+        match hint.interpolation {
+            Some(interpolation) => println!(" ~ Track {}: {}, {:?}", hint.name, range, interpolation),
+            None => println!(" ~ Track {}: {}", hint.name, range),
+        }
+    }
 
     fn update(&mut self) {
         while let Some(event) = self.rocket.poll_events() {
@@ -80,10 +173,16 @@ impl SyncTracker for RocketSyncTracker {
         }
 
         if let Some(ref p) = self.play_start_point {
-            self.time = p.base_time + (time::precise_time_s() - p.real_time);
+            self.time = p.get_time(self.soundtrack.as_ref());
             self.rocket.set_row((self.time * self.fps) as u32);
         }
     }
+    fn seek(&mut self, time: f64) {
+        self.play_start_point = None;
+        self.time = time;
+        self.rocket.set_row((self.time * self.fps) as u32);
+        sync_soundtrack(self.soundtrack.as_ref(), self.time, false);
+    }
 
     fn get_time(&self) -> f64 {
         self.time
@@ -93,6 +192,197 @@ impl SyncTracker for RocketSyncTracker {
             .rocket
             .get_track(track)
             .map(|t| t.get_value((self.time * self.fps) as f32));
-        value
+        match self.ranges.get(track) {
+            Some(&(min, max)) => value.map(|v| v.max(min).min(max)),
+            None => value,
+        }
+    }
+    fn set_soundtrack(&mut self, soundtrack: Option<Soundtrack>) {
+        self.soundtrack = soundtrack;
+        sync_soundtrack(self.soundtrack.as_ref(), self.time, self.play_start_point.is_some());
+    }
+}
+
+/// Time given to the editor for sending the keys of the requested tracks when baking them
+#[cfg(feature = "editor")]
+const ROCKET_KEY_WAIT_S: f64 = 1.0;
+
+/// Values of sync tracks sampled at each row, as stored in packed demos
+#[derive(Debug, Clone, Default)]
+pub struct BakedTracks {
+    pub rows_per_second: f32,
+    pub tracks: HashMap<String, Vec<f32>>,
+}
+
+/// Plays baked tracks in real time, values are interpolated linearly between rows
+///
+/// Tracks are held at their last value after the end, and unknown tracks are 0 like unkeyed tracks in the editor.
+pub struct BakedSyncTracker {
+    baked: BakedTracks,
+    time: f64,
+    play_start_point: Option<PlayStartPoint>,
+    soundtrack: Option<Soundtrack>,
+}
+impl BakedSyncTracker {
+    pub fn new(baked: BakedTracks) -> Self {
+        BakedSyncTracker {
+            baked: baked,
+            time: 0.0,
+            play_start_point: Some(PlayStartPoint {
+                base_time: 0.0,
+                real_time: time::precise_time_s(),
+            }),
+            soundtrack: None,
+        }
+    }
+}
+impl SyncTracker for BakedSyncTracker {
+    fn require_track(&mut self, _track: &str) {}
+    fn set_track_hint(&mut self, _hint: &TrackHint) {}
+
+    fn update(&mut self) {
+        if let Some(ref p) = self.play_start_point {
+            self.time = p.get_time(self.soundtrack.as_ref());
+        }
+    }
+    fn seek(&mut self, time: f64) {
+        self.play_start_point = None;
+        self.time = time;
+        sync_soundtrack(self.soundtrack.as_ref(), self.time, false);
+    }
+
+    fn get_time(&self) -> f64 {
+        self.time
+    }
+    fn get_value(&self, track: &str) -> Option<f32> {
+        let values = match self.baked.tracks.get(track) {
+            Some(values) if !values.is_empty() => values,
+            _ => return Some(0.0),
+        };
+        let row = (self.time * self.baked.rows_per_second as f64).max(0.0);
+        let index = row.floor() as usize;
+        if index + 1 >= values.len() {
+            return values.last().cloned();
+        }
+        let t = (row - index as f64) as f32;
+        Some(values[index] * (1.0 - t) + values[index + 1] * t)
+    }
+    fn set_soundtrack(&mut self, soundtrack: Option<Soundtrack>) {
+        self.soundtrack = soundtrack;
+        sync_soundtrack(self.soundtrack.as_ref(), self.time, self.play_start_point.is_some());
+    }
+}
+
+/// Prefix of the tracks which are answered by `GamepadSyncTracker`, e.g. `sync.pad.lx` in scripts
+pub const GAMEPAD_TRACK_PREFIX: &str = "pad:";
+
+/// Sticks and the d-pad, from -1 to 1
+const GAMEPAD_AXES: [(&str, Axis); 6] = [
+    ("lx", Axis::LeftStickX),
+    ("ly", Axis::LeftStickY),
+    ("rx", Axis::RightStickX),
+    ("ry", Axis::RightStickY),
+    ("dx", Axis::DPadX),
+    ("dy", Axis::DPadY),
+];
+
+/// Buttons from 0 to 1, only the triggers take values in between
+const GAMEPAD_BUTTONS: [(&str, Button); 16] = [
+    ("a", Button::South),
+    ("b", Button::East),
+    ("x", Button::West),
+    ("y", Button::North),
+    ("lb", Button::LeftTrigger),
+    ("rb", Button::RightTrigger),
+    ("lt", Button::LeftTrigger2),
+    ("rt", Button::RightTrigger2),
+    ("ls", Button::LeftThumb),
+    ("rs", Button::RightThumb),
+    ("start", Button::Start),
+    ("select", Button::Select),
+    ("up", Button::DPadUp),
+    ("down", Button::DPadDown),
+    ("left", Button::DPadLeft),
+    ("right", Button::DPadRight),
+];
+
+/// Answers the `pad:*` tracks with the state of the first connected gamepad, and all other tracks with another tracker
+///
+/// The pad tracks are 0 while no gamepad is connected.
+pub struct GamepadSyncTracker<T: SyncTracker> {
+    tracker: T,
+    gilrs: Option<Gilrs>,
+}
+impl<T: SyncTracker> GamepadSyncTracker<T> {
+    pub fn new(tracker: T) -> Self {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(err) => {
+                println!("Warning: gamepads are not available: {}", err);
+                None
+            }
+        };
+        GamepadSyncTracker {
+            tracker: tracker,
+            gilrs: gilrs,
+        }
+    }
+
+    fn first_gamepad(&self) -> Option<gilrs::Gamepad<'_>> {
+        self.gilrs
+            .as_ref()
+            .and_then(|gilrs| gilrs.gamepads().map(|(_, gamepad)| gamepad).next())
+    }
+
+    fn get_gamepad_value(&self, name: &str) -> Option<f32> {
+        let gamepad = self.first_gamepad();
+        if let Some(&(_, axis)) = GAMEPAD_AXES.iter().find(|a| a.0 == name) {
+            return Some(gamepad.map_or(0.0, |gamepad| gamepad.value(axis)));
+        }
+        if let Some(&(_, button)) = GAMEPAD_BUTTONS.iter().find(|b| b.0 == name) {
+            return Some(
+                gamepad
+                    .and_then(|gamepad| gamepad.button_data(button).map(|data| data.value()))
+                    .unwrap_or(0.0),
+            );
+        }
+        None
+    }
+}
+impl<T: SyncTracker> SyncTracker for GamepadSyncTracker<T> {
+    fn require_track(&mut self, track: &str) {
+        if !track.starts_with(GAMEPAD_TRACK_PREFIX) {
+            self.tracker.require_track(track);
+        }
+    }
+    fn set_track_hint(&mut self, hint: &TrackHint) {
+        if !hint.name.starts_with(GAMEPAD_TRACK_PREFIX) {
+            self.tracker.set_track_hint(hint);
+        }
+    }
+
+    fn update(&mut self) {
+        // Events have to be consumed for the state of the gamepads to be updated
+        if let Some(gilrs) = self.gilrs.as_mut() {
+            while gilrs.next_event().is_some() {}
+        }
+        self.tracker.update();
+    }
+    fn seek(&mut self, time: f64) {
+        self.tracker.seek(time);
+    }
+
+    fn get_time(&self) -> f64 {
+        self.tracker.get_time()
+    }
+    fn get_value(&self, track: &str) -> Option<f32> {
+        if track.starts_with(GAMEPAD_TRACK_PREFIX) {
+            self.get_gamepad_value(&track[GAMEPAD_TRACK_PREFIX.len()..])
+        } else {
+            self.tracker.get_value(track)
+        }
+    }
+    fn set_soundtrack(&mut self, soundtrack: Option<Soundtrack>) {
+        self.tracker.set_soundtrack(soundtrack);
     }
 }
@@ -0,0 +1,701 @@
+use std::collections::HashMap;
+
+use ast::{self, Stmt, Type, ValueExpr};
+use bytecode::{FunctionSignature, SemanticError, GLOBALS, TRANSFORM_KEYS};
+use diagnostics;
+use easing;
+use types::BinaryOperator;
+
+/// Largest integer up to which all integers can be represented by a float
+const MAX_EXACT_INTEGER: u64 = 1 << 24;
+
+/// Checks that values passed around in the program have the expected types
+///
+/// This makes sure that functions with a declared return type return a value of that type on all code paths, and
+/// that only such functions are used as values inside expressions.
+pub struct TypeChecker<'a> {
+    source: &'a str,
+    signatures: &'a HashMap<String, FunctionSignature>,
+}
+impl<'a> TypeChecker<'a> {
+    pub fn new(source: &'a str, signatures: &'a HashMap<String, FunctionSignature>) -> Self {
+        TypeChecker {
+            source: source,
+            signatures: signatures,
+        }
+    }
+
+    pub fn check_program(&self, ast: &ast::Program) -> Result<(), SemanticError> {
+        let globals = HashMap::new();
+        for target_def in &ast.render_targets {
+            self.expect_type(&target_def.width, &globals, Type::Float32)?;
+            self.expect_type(&target_def.height, &globals, Type::Float32)?;
+        }
+
+        for object in &ast.objects {
+            self.check_scene_object(object)?;
+        }
+        for part in ast.batches.iter().flat_map(|b| &b.parts) {
+            self.check_batch_part(part)?;
+        }
+        for light in &ast.lights {
+            self.check_light(light)?;
+        }
+
+        for function in &ast.functions {
+            self.check_function(function)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the properties of an object, which can only depend on globals
+    fn check_scene_object(&self, object: &ast::SceneObject) -> Result<(), SemanticError> {
+        let globals = HashMap::new();
+        self.check_unique_properties(&object.properties)?;
+        for property in &object.properties {
+            let key = property.key.to_slice(self.source);
+            match (key, &property.value) {
+                ("mesh", value) => self.check_mesh(value, &globals)?,
+                ("visible", value) => self.expect_type(value, &globals, Type::Float32)?,
+                ("material", ValueExpr::Dictionary(material)) => self.check_material(material, &globals)?,
+                ("material", value) => {
+                    return Err(SemanticError::error_from_ast(
+                        value,
+                        format!("Expected a dictionary for `{}`", key),
+                    ))
+                }
+                ("transform", value) => self.check_float_dictionary(key, value, &TRANSFORM_KEYS)?,
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        &property.key,
+                        format!(
+                            "Unknown property `{}`, expected one of mesh, transform, material, visible",
+                            key
+                        ),
+                    ))
+                }
+            }
+        }
+
+        if object.get_property(self.source, "mesh").is_none() {
+            return Err(SemanticError::error_from_ast(
+                object,
+                format!("Object `{}` has no mesh", object.name.to_slice(self.source)),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the mesh and the transform of a part of a batch
+    fn check_batch_part(&self, part: &ast::BatchPart) -> Result<(), SemanticError> {
+        let globals = HashMap::new();
+        self.check_unique_properties(&part.properties)?;
+        for property in &part.properties {
+            let key = property.key.to_slice(self.source);
+            match key {
+                "mesh" => self.check_mesh(&property.value, &globals)?,
+                "transform" => self.check_float_dictionary(key, &property.value, &TRANSFORM_KEYS)?,
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        &property.key,
+                        format!("Unknown property `{}`, expected one of mesh, transform", key),
+                    ))
+                }
+            }
+        }
+
+        if part.get_property(self.source, "mesh").is_none() {
+            return Err(SemanticError::error_from_ast(part, format!("Batch part has no mesh")));
+        }
+        Ok(())
+    }
+
+    /// Checks the properties of a light, which can only depend on globals
+    fn check_light(&self, light: &ast::Light) -> Result<(), SemanticError> {
+        let globals = HashMap::new();
+        self.check_unique_properties(&light.properties)?;
+        for property in &light.properties {
+            let key = property.key.to_slice(self.source);
+            match key {
+                "type" => self.expect_type(&property.value, &globals, Type::Str)?,
+                "pos" | "dir" => self.check_float_dictionary(key, &property.value, &["x", "y", "z"])?,
+                "color" => self.expect_type(&property.value, &globals, Type::LinColor)?,
+                "intensity" | "angle" => self.expect_type(&property.value, &globals, Type::Float32)?,
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        &property.key,
+                        format!(
+                            "Unknown property `{}`, expected one of type, pos, dir, color, intensity, angle",
+                            key
+                        ),
+                    ))
+                }
+            }
+        }
+
+        if light.get_property(self.source, "type").is_none() {
+            return Err(SemanticError::error_from_ast(
+                light,
+                format!("Light `{}` has no type", light.name.to_slice(self.source)),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_unique_properties(&self, properties: &[ast::KeyValuePairExpr]) -> Result<(), SemanticError> {
+        for (i, property) in properties.iter().enumerate() {
+            let key = property.key.to_slice(self.source);
+            if properties[..i].iter().any(|p| p.key.to_slice(self.source) == key) {
+                return Err(SemanticError::error_from_ast(
+                    &property.key,
+                    format!("Property `{}` is set more than once", key),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a dictionary of floats with the given keys, which can only depend on globals
+    fn check_float_dictionary(&self, name: &str, value: &ValueExpr, keys: &[&str]) -> Result<(), SemanticError> {
+        let dict = match value {
+            ValueExpr::Dictionary(dict) => dict,
+            _ => {
+                return Err(SemanticError::error_from_ast(
+                    value,
+                    format!("Expected a dictionary for `{}`", name),
+                ))
+            }
+        };
+        for entry in &dict.entries {
+            let key = entry.key.to_slice(self.source);
+            if !keys.contains(&key) {
+                return Err(SemanticError::error_from_ast(
+                    &entry.key,
+                    format!(
+                        "Unknown key `{}` in `{}`, expected one of {}",
+                        key,
+                        name,
+                        keys.join(", ")
+                    ),
+                ));
+            }
+            self.expect_type(&entry.value, &HashMap::new(), Type::Float32)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the `params` of `draw_model`, which are written to a uniform block and hence have to be numbers
+    fn check_draw_params(
+        &self,
+        params: &ast::DictionaryExpr,
+        locals: &HashMap<String, Type>,
+    ) -> Result<(), SemanticError> {
+        for entry in &params.entries {
+            match self.check_expr(&entry.value, locals)? {
+                Some(Type::Float32) | Some(Type::LinColor) | Some(Type::Vec2) | Some(Type::Vec3) | Some(Type::Vec4)
+                | Some(Type::Mat4) => {}
+                _ => {
+                    return Err(SemanticError::error_from_ast(
+                        &entry.value,
+                        format!("Draw parameters have to be floats, colors, vectors or matrices"),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the material dictionary of `draw_model`, `draw_batch` or of an object
+    fn check_material(
+        &self,
+        material: &ast::DictionaryExpr,
+        locals: &HashMap<String, Type>,
+    ) -> Result<(), SemanticError> {
+        for entry in &material.entries {
+            match &entry.value {
+                ValueExpr::StringLiteral(_) | ValueExpr::ColorLiteral(..) => {}
+                ValueExpr::FunctionCall(call) if call.function.to_slice(self.source) == "LinColor" => {
+                    self.check_expr(&entry.value, locals)?;
+                }
+                // Only literals are bound as colors, everything else as floats
+                value => self.expect_type(value, locals, Type::Float32)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Meshes are model files, or terrains built from a heightmap
+    fn check_mesh(&self, mesh: &ValueExpr, locals: &HashMap<String, Type>) -> Result<(), SemanticError> {
+        match mesh {
+            ValueExpr::FunctionCall(call) if call.function.to_slice(self.source) == "terrain" => {
+                self.expect_args_count(call, 4)?;
+                self.expect_type(&call.args[0], locals, Type::Str)?;
+                for arg in &call.args[1..] {
+                    self.expect_type(arg, locals, Type::Float32)?;
+                }
+                Ok(())
+            }
+            _ => self.expect_type(mesh, locals, Type::Str),
+        }
+    }
+
+    /// The depth is cleared to a float, all other buffers to colors
+    fn check_clear_buffers(
+        &self,
+        buffers: &ast::DictionaryExpr,
+        locals: &HashMap<String, Type>,
+    ) -> Result<(), SemanticError> {
+        for entry in &buffers.entries {
+            if entry.key.to_slice(self.source) == "depth" {
+                self.expect_type(&entry.value, locals, Type::Float32)?;
+            } else {
+                self.expect_type(&entry.value, locals, Type::LinColor)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_function(&self, function: &ast::Function) -> Result<(), SemanticError> {
+        // Default values are evaluated by the caller, hence they cannot refer to other parameters
+        let globals = HashMap::new();
+        for param in &function.params {
+            if let Some(default) = &param.default {
+                self.expect_type(default, &globals, param.value_type)?;
+            }
+        }
+
+        let locals = function
+            .params
+            .iter()
+            .map(|p| (p.name.to_owned(self.source), p.value_type))
+            .collect();
+
+        let returns = self.check_block(&function.block, &locals, function)?;
+        if function.return_type.is_some() && !returns {
+            return Err(SemanticError::error_from_ast(
+                &function.name,
+                format!(
+                    "Function `{}` does not return a value on all code paths",
+                    function.name.to_slice(self.source)
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks all statements of a block, returns true if the block returns a value on all code paths
+    fn check_block(
+        &self,
+        block: &[Stmt],
+        locals: &HashMap<String, Type>,
+        function: &ast::Function,
+    ) -> Result<bool, SemanticError> {
+        // Locals declared by the block go out of scope at its end
+        let mut locals = locals.clone();
+        let locals = &mut locals;
+        let mut returns = false;
+        for stmt in block {
+            match stmt {
+                Stmt::FunctionCall(function_call) => {
+                    let name = function_call.function.to_slice(self.source);
+                    if let Some(signature) = self.signatures.get(name) {
+                        self.check_call_args(function_call, signature, locals)?;
+                    } else {
+                        if name == "uniform_vec3" || name == "uniform_mat4" {
+                            if let Some(value) = function_call.args.get(1) {
+                                let value_type = if name == "uniform_vec3" { Type::Vec3 } else { Type::Mat4 };
+                                self.expect_type(value, locals, value_type)?;
+                            }
+                        }
+                        if name == "camera_lookat" || name == "camera_perspective" || name == "set_model_transform" {
+                            let arg_type = match name {
+                                "camera_lookat" => Type::Vec3,
+                                "camera_perspective" => Type::Float32,
+                                _ => Type::Mat4,
+                            };
+                            for arg in &function_call.args {
+                                self.expect_type(arg, locals, arg_type)?;
+                            }
+                        }
+                        if name == "assert" || name == "show_cursor" {
+                            if let Some(condition) = function_call.args.first() {
+                                self.expect_type(condition, locals, Type::Float32)?;
+                            }
+                        }
+                        if name == "clear" {
+                            if let Some(ValueExpr::Dictionary(buffers)) = function_call.args.first() {
+                                self.check_clear_buffers(buffers, locals)?;
+                            }
+                        }
+                        if name == "bind_rt" {
+                            for named_arg in &function_call.named_args {
+                                if named_arg.key.to_slice(self.source) == "scale" {
+                                    self.expect_type(&named_arg.value, locals, Type::Float32)?;
+                                }
+                            }
+                        }
+                        // The mesh of `draw_model` is not a value
+                        let mut values = &function_call.args[..];
+                        if name == "draw_model" {
+                            if let Some(mesh) = function_call.args.first() {
+                                self.check_mesh(mesh, locals)?;
+                                values = &function_call.args[1..];
+                            }
+                            if let Some(ValueExpr::Dictionary(material)) = function_call.args.get(1) {
+                                self.check_material(material, locals)?;
+                            }
+                            for named_arg in &function_call.named_args {
+                                if let ValueExpr::Dictionary(params) = &named_arg.value {
+                                    self.check_draw_params(params, locals)?;
+                                }
+                            }
+                        }
+                        if name == "draw_batch" {
+                            if let Some(ValueExpr::Dictionary(material)) = function_call.args.get(1) {
+                                self.check_material(material, locals)?;
+                            }
+                        }
+                        for arg in values {
+                            self.check_expr(arg, locals)?;
+                        }
+                        for named_arg in &function_call.named_args {
+                            self.check_expr(&named_arg.value, locals)?;
+                        }
+                    }
+                }
+                Stmt::Return { expr } => {
+                    let value_type = self.check_expr(expr, locals)?;
+                    match function.return_type {
+                        Some(return_type) if value_type == Some(return_type) => {}
+                        Some(return_type) => {
+                            return Err(SemanticError::error_from_ast(
+                                expr,
+                                format!(
+                                    "Function `{}` should return {:?}, but the returned value is not",
+                                    function.name.to_slice(self.source),
+                                    return_type
+                                ),
+                            ))
+                        }
+                        None => {
+                            return Err(SemanticError::error_from_ast(
+                                expr,
+                                format!(
+                                    "Function `{}` has no return type, but returns a value",
+                                    function.name.to_slice(self.source)
+                                ),
+                            ))
+                        }
+                    }
+                    returns = true;
+                }
+                Stmt::Let { name, expr } => match self.check_expr(expr, locals)? {
+                    Some(value_type) if value_type != Type::Void => {
+                        locals.insert(name.to_owned(self.source), value_type);
+                    }
+                    _ => {
+                        return Err(SemanticError::error_from_ast(
+                            expr,
+                            format!("`{}` must be initialized with a value", name.to_slice(self.source)),
+                        ))
+                    }
+                },
+                Stmt::Conditional { condition, a, b } => {
+                    self.expect_type(condition, locals, Type::Float32)?;
+                    let a_returns = self.check_block(a, locals, function)?;
+                    let b_returns = match b {
+                        Some(b) => self.check_block(b, locals, function)?,
+                        None => false,
+                    };
+                    returns |= a_returns && b_returns;
+                }
+                Stmt::Loop {
+                    variable,
+                    start,
+                    end,
+                    block,
+                } => {
+                    self.expect_type(start, locals, Type::Float32)?;
+                    self.expect_type(end, locals, Type::Float32)?;
+                    let mut body_locals = locals.clone();
+                    body_locals.insert(variable.to_owned(self.source), Type::Float32);
+                    // The block may run zero times, so it never returns on all code paths
+                    self.check_block(block, &body_locals, function)?;
+                }
+            }
+        }
+        Ok(returns)
+    }
+
+    fn expect_type(
+        &self,
+        expr: &ValueExpr,
+        locals: &HashMap<String, Type>,
+        expected: Type,
+    ) -> Result<(), SemanticError> {
+        let value_type = self.check_expr(expr, locals)?;
+        if value_type != Some(expected) {
+            return Err(SemanticError::error_from_ast(
+                expr,
+                format!("Expected a value of type {:?}", expected),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks an expression and returns its type
+    ///
+    /// Dictionaries are not first class values, hence their type is `None`.
+    fn check_expr(&self, expr: &ValueExpr, locals: &HashMap<String, Type>) -> Result<Option<Type>, SemanticError> {
+        match expr {
+            ValueExpr::FloatLiteral(slice, _) => {
+                // Hex literals are used as masks, where rounding to the next float would silently change bits
+                let text = slice.to_slice(self.source);
+                let hex_digits = text.find(|c| c == 'x' || c == 'X').map(|i| &text[i + 1..]);
+                let exceeds = |digits: &str| {
+                    let value = digits
+                        .chars()
+                        .filter_map(|c| c.to_digit(16))
+                        .try_fold(0u64, |v, d| v.checked_mul(16).and_then(|v| v.checked_add(d as u64)));
+                    value.map_or(true, |v| v > MAX_EXACT_INTEGER)
+                };
+                if hex_digits.map_or(false, exceeds) {
+                    return Err(SemanticError::error_from_ast(
+                        expr,
+                        format!("Hex literal `{}` does not fit into the 24 bits of a float", text),
+                    ));
+                }
+                Ok(Some(Type::Float32))
+            }
+            ValueExpr::ColorLiteral(_, _) => Ok(Some(Type::LinColor)),
+            ValueExpr::StringLiteral(_) => Ok(Some(Type::Str)),
+            ValueExpr::Var(var) => {
+                let name = var.to_slice(self.source);
+                if let Some(value_type) = locals.get(name) {
+                    Ok(Some(*value_type))
+                } else if GLOBALS.contains(&name) {
+                    Ok(Some(Type::Float32))
+                } else {
+                    let candidates = locals.keys().map(|k| k.as_str()).chain(GLOBALS.iter().cloned());
+                    let error = SemanticError::error_from_ast(expr, format!("Unknown variable `{}`", name));
+                    Err(match diagnostics::closest_match(name, candidates) {
+                        Some(candidate) => error.with_note(format!("did you mean `{}`?", candidate)),
+                        None => error,
+                    })
+                }
+            }
+            ValueExpr::PropertyOf(_, v, _) => match **v {
+                ValueExpr::Var(v) if v.to_slice(self.source) == "sync" => Ok(Some(Type::Float32)),
+                _ => Err(SemanticError::error_from_ast(
+                    expr,
+                    format!("Right now `.` is only supported for sync expressions"),
+                )),
+            },
+            ValueExpr::Dictionary(d) => {
+                for kv in &d.entries {
+                    self.check_expr(&kv.value, locals)?;
+                }
+                Ok(None)
+            }
+            ValueExpr::FunctionCall(function_call) => self.check_function_call(function_call, locals).map(Some),
+            ValueExpr::BinaryOp(_, op, l, r) => self.check_binary_op(expr, op, l, r, locals).map(Some),
+        }
+    }
+
+    /// Checks the operands of a binary operator and returns the type of its result
+    ///
+    /// Besides floats, vectors can be added and subtracted, and multiplied or divided by floats. Matrices can be
+    /// multiplied with matrices and with `vec4`.
+    fn check_binary_op(
+        &self,
+        expr: &ValueExpr,
+        op: &BinaryOperator,
+        l: &ValueExpr,
+        r: &ValueExpr,
+        locals: &HashMap<String, Type>,
+    ) -> Result<Type, SemanticError> {
+        let l_type = self.check_expr(l, locals)?;
+        let r_type = self.check_expr(r, locals)?;
+        let is_vector = |t: Type| match t {
+            Type::Vec2 | Type::Vec3 | Type::Vec4 => true,
+            _ => false,
+        };
+        let result = match (op, l_type, r_type) {
+            (_, Some(Type::Float32), Some(Type::Float32)) => Some(Type::Float32),
+            (BinaryOperator::Add, Some(a), Some(b)) | (BinaryOperator::Sub, Some(a), Some(b))
+                if a == b && is_vector(a) =>
+            {
+                Some(a)
+            }
+            (BinaryOperator::Mul, Some(a), Some(Type::Float32))
+            | (BinaryOperator::Div, Some(a), Some(Type::Float32))
+                if is_vector(a) =>
+            {
+                Some(a)
+            }
+            (BinaryOperator::Mul, Some(Type::Float32), Some(b)) if is_vector(b) => Some(b),
+            (BinaryOperator::Mul, Some(Type::Mat4), Some(Type::Mat4)) => Some(Type::Mat4),
+            (BinaryOperator::Mul, Some(Type::Mat4), Some(Type::Vec4)) => Some(Type::Vec4),
+            _ => None,
+        };
+        result.ok_or_else(|| {
+            let type_name = |t: Option<Type>| t.map_or(format!("a dictionary"), |t| format!("{:?}", t));
+            SemanticError::error_from_ast(
+                expr,
+                format!(
+                    "Cannot apply `{}` to {} and {}",
+                    op,
+                    type_name(l_type),
+                    type_name(r_type)
+                ),
+            )
+        })
+    }
+
+    fn check_function_call(
+        &self,
+        function_call: &ast::FunctionCallExpr,
+        locals: &HashMap<String, Type>,
+    ) -> Result<Type, SemanticError> {
+        let name = function_call.function.to_slice(self.source);
+        match name {
+            "LinColor" => {
+                self.expect_args_count(function_call, 4)?;
+                for arg in &function_call.args {
+                    self.expect_type(arg, locals, Type::Float32)?;
+                }
+                Ok(Type::LinColor)
+            }
+            "Vec2" | "Vec3" | "Vec4" => {
+                let (count, value_type) = match name {
+                    "Vec2" => (2, Type::Vec2),
+                    "Vec3" => (3, Type::Vec3),
+                    _ => (4, Type::Vec4),
+                };
+                self.expect_args_count(function_call, count)?;
+                for arg in &function_call.args {
+                    self.expect_type(arg, locals, Type::Float32)?;
+                }
+                Ok(value_type)
+            }
+            "translate" => {
+                self.expect_args_count(function_call, 1)?;
+                self.expect_type(&function_call.args[0], locals, Type::Vec3)?;
+                Ok(Type::Mat4)
+            }
+            "rotate" => {
+                self.expect_args_count(function_call, 2)?;
+                self.expect_type(&function_call.args[0], locals, Type::Float32)?;
+                self.expect_type(&function_call.args[1], locals, Type::Vec3)?;
+                Ok(Type::Mat4)
+            }
+            "perspective" => {
+                self.expect_args_count(function_call, 4)?;
+                for arg in &function_call.args {
+                    self.expect_type(arg, locals, Type::Float32)?;
+                }
+                Ok(Type::Mat4)
+            }
+            "-" => {
+                self.expect_args_count(function_call, 1)?;
+                self.expect_type(&function_call.args[0], locals, Type::Float32)?;
+                Ok(Type::Float32)
+            }
+            "probe" => {
+                self.expect_args_count(function_call, 2)?;
+                match &function_call.args[0] {
+                    ValueExpr::StringLiteral(_) => {}
+                    arg => {
+                        return Err(SemanticError::error_from_ast(
+                            arg,
+                            format!("The label of `probe` has to be a string literal"),
+                        ))
+                    }
+                }
+                self.expect_type(&function_call.args[1], locals, Type::Float32)?;
+                Ok(Type::Float32)
+            }
+            "reduce_luminance" => {
+                self.expect_args_count(function_call, 1)?;
+                self.expect_type(&function_call.args[0], locals, Type::Str)?;
+                Ok(Type::Float32)
+            }
+            "histogram" => {
+                self.expect_args_count(function_call, 2)?;
+                self.expect_type(&function_call.args[0], locals, Type::Str)?;
+                self.expect_type(&function_call.args[1], locals, Type::Float32)?;
+                Ok(Type::Float32)
+            }
+            "readback" => {
+                self.expect_args_count(function_call, 3)?;
+                self.expect_type(&function_call.args[0], locals, Type::Str)?;
+                self.expect_type(&function_call.args[1], locals, Type::Float32)?;
+                self.expect_type(&function_call.args[2], locals, Type::Float32)?;
+                Ok(Type::LinColor)
+            }
+            "envelope" => {
+                self.expect_args_count(function_call, 5)?;
+                match &function_call.args[0] {
+                    ValueExpr::PropertyOf(..) => {}
+                    arg => {
+                        return Err(SemanticError::error_from_ast(
+                            arg,
+                            format!("The trigger of `envelope` has to be a sync track"),
+                        ))
+                    }
+                }
+                for arg in &function_call.args {
+                    self.expect_type(arg, locals, Type::Float32)?;
+                }
+                Ok(Type::Float32)
+            }
+            _ if easing::arg_count(name).is_some() => {
+                self.expect_args_count(function_call, easing::arg_count(name).unwrap())?;
+                for arg in &function_call.args {
+                    self.expect_type(arg, locals, Type::Float32)?;
+                }
+                Ok(Type::Float32)
+            }
+            _ => {
+                let signature = self.signatures.get(name).ok_or_else(|| {
+                    SemanticError::error_from_ast(function_call, format!("Call to unknown function `{}`", name))
+                })?;
+                self.check_call_args(function_call, signature, locals)?;
+                signature.return_type.ok_or_else(|| {
+                    SemanticError::error_from_ast(
+                        function_call,
+                        format!("Function `{}` does not return a value", name),
+                    )
+                })
+            }
+        }
+    }
+
+    fn check_call_args(
+        &self,
+        function_call: &ast::FunctionCallExpr,
+        signature: &FunctionSignature,
+        locals: &HashMap<String, Type>,
+    ) -> Result<(), SemanticError> {
+        self.expect_args_count(function_call, signature.params.len())?;
+        for (arg, param) in function_call.args.iter().zip(signature.params.iter()) {
+            self.expect_type(arg, locals, param.1)?;
+        }
+        Ok(())
+    }
+
+    fn expect_args_count(&self, function_call: &ast::FunctionCallExpr, args_count: usize) -> Result<(), SemanticError> {
+        if function_call.args.len() == args_count {
+            Ok(())
+        } else {
+            Err(SemanticError::error_from_ast(
+                function_call,
+                format!(
+                    "Expected {} arguments, but got {}.",
+                    args_count,
+                    function_call.args.len()
+                ),
+            ))
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BinaryOperator {
     Add,
@@ -13,6 +15,25 @@ pub enum BinaryOperator {
     Ne,
 }
 
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mul => "*",
+            BinaryOperator::Div => "/",
+
+            BinaryOperator::Lt => "<",
+            BinaryOperator::Le => "<=",
+            BinaryOperator::Gt => ">",
+            BinaryOperator::Ge => ">=",
+            BinaryOperator::Eq => "==",
+            BinaryOperator::Ne => "!=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RenderTargetFormat {
     // sRGB
@@ -21,12 +42,14 @@ pub enum RenderTargetFormat {
 
     // linear formats (8 bit)
     R8,
+    Rg8,
     Rgb8,
     Rgba8,
 
     // linear formats (16 bit)
     R16,
     R16F,
+    Rg16F,
     Rgb16,
     Rgb16F,
     Rgba16,
@@ -34,8 +57,54 @@ pub enum RenderTargetFormat {
 
     // linear formats (32 bits)
     R32F,
+    Rg32F,
     Rgb32F,
     Rgba32F,
+
+    // packed formats
+    R11FG11FB10F,
+    Rgb10A2,
+
+    // depth formats
+    Depth32F,
+}
+
+impl RenderTargetFormat {
+    /// sRGB buffers are encoded when written to, and decoded to linear when sampled
+    pub fn is_srgb(self) -> bool {
+        self == RenderTargetFormat::Srgb8 || self == RenderTargetFormat::Srgba8
+    }
+
+    /// Depth buffers are attached as the depth attachment of a target, instead of as a color buffer
+    pub fn is_depth(self) -> bool {
+        self == RenderTargetFormat::Depth32F
+    }
+
+    /// The keyword of the format in scripts
+    pub fn name(self) -> &'static str {
+        match self {
+            RenderTargetFormat::Srgb8 => "SRGB8",
+            RenderTargetFormat::Srgba8 => "SRGBA8",
+            RenderTargetFormat::R8 => "R8",
+            RenderTargetFormat::Rg8 => "RG8",
+            RenderTargetFormat::Rgb8 => "RGB8",
+            RenderTargetFormat::Rgba8 => "RGBA8",
+            RenderTargetFormat::R16 => "R16",
+            RenderTargetFormat::R16F => "R16F",
+            RenderTargetFormat::Rg16F => "RG16F",
+            RenderTargetFormat::Rgb16 => "RGB16",
+            RenderTargetFormat::Rgb16F => "RGB16F",
+            RenderTargetFormat::Rgba16 => "RGBA16",
+            RenderTargetFormat::Rgba16F => "RGBA16F",
+            RenderTargetFormat::R32F => "R32F",
+            RenderTargetFormat::Rg32F => "RG32F",
+            RenderTargetFormat::Rgb32F => "RGB32F",
+            RenderTargetFormat::Rgba32F => "RGBA32F",
+            RenderTargetFormat::R11FG11FB10F => "R11F_G11F_B10F",
+            RenderTargetFormat::Rgb10A2 => "RGB10_A2",
+            RenderTargetFormat::Depth32F => "DEPTH32F",
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -103,3 +172,120 @@ impl CullingMode {
         }
     }
 }
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LightType {
+    Directional,
+    Point,
+    Spot,
+}
+
+impl LightType {
+    pub fn from_str(str_value: &str) -> Option<Self> {
+        if str_value == "directional" {
+            Some(LightType::Directional)
+        } else if str_value == "point" {
+            Some(LightType::Point)
+        } else if str_value == "spot" {
+            Some(LightType::Spot)
+        } else {
+            None
+        }
+    }
+}
+
+/// How the scene is rendered for both eyes of a stereoscopic display
+///
+/// There is no layered mode which renders the eyes into the layers of an array target: render targets have a single
+/// layer, and only a headset compositor could show such a target, for which there is no output.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StereoMode {
+    /// The left eye is rendered to the left half of the screen, the right eye to the right half
+    SideBySide,
+}
+
+impl StereoMode {
+    pub fn from_str(str_value: &str) -> Option<Self> {
+        if str_value == "sbs" {
+            Some(StereoMode::SideBySide)
+        } else {
+            None
+        }
+    }
+}
+
+/// How the sync editor interpolates between the keys of a track, the key types of Rocket
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TrackInterpolation {
+    Step,
+    Linear,
+    Smooth,
+    Ramp,
+}
+
+impl TrackInterpolation {
+    pub fn from_str(str_value: &str) -> Option<Self> {
+        if str_value == "step" {
+            Some(TrackInterpolation::Step)
+        } else if str_value == "linear" {
+            Some(TrackInterpolation::Linear)
+        } else if str_value == "smooth" {
+            Some(TrackInterpolation::Smooth)
+        } else if str_value == "ramp" {
+            Some(TrackInterpolation::Ramp)
+        } else {
+            None
+        }
+    }
+}
+
+/// Built-in post-processing effects used for the final polish of an image, see `postfx::lens_effect`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LensEffect {
+    Flare,
+    ChromaticAberration,
+    Vignette,
+    Grain,
+}
+
+impl LensEffect {
+    /// The effect applied by the given built-in function
+    pub fn from_function(function: &str) -> Option<Self> {
+        if function == "post_lensflare" {
+            Some(LensEffect::Flare)
+        } else if function == "post_ca" {
+            Some(LensEffect::ChromaticAberration)
+        } else if function == "post_vignette" {
+            Some(LensEffect::Vignette)
+        } else if function == "post_grain" {
+            Some(LensEffect::Grain)
+        } else {
+            None
+        }
+    }
+}
+
+/// How `assert` statements in the script are handled
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum AssertMode {
+    /// A failed assertion aborts the frame with an error
+    Halt,
+    /// A failed assertion prints a warning, the frame continues
+    Warn,
+    /// Assertions are compiled out
+    Off,
+}
+
+impl AssertMode {
+    pub fn from_str(str_value: &str) -> Option<Self> {
+        if str_value == "halt" {
+            Some(AssertMode::Halt)
+        } else if str_value == "warn" {
+            Some(AssertMode::Warn)
+        } else if str_value == "off" {
+            Some(AssertMode::Off)
+        } else {
+            None
+        }
+    }
+}